@@ -1,10 +1,7 @@
-use std::rc::Rc;
-
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use rand::{thread_rng, Rng};
-use std::process::Command;
 use tempfile::TempDir;
 
 fn rand_string<R: Rng>(rng: &mut R) -> String {
@@ -18,7 +15,7 @@ fn rand_string<R: Rng>(rng: &mut R) -> String {
     string
 }
 
-fn write<S: AsRef<str>, E: KvsEngine + 'static>(name: S, c: &mut Criterion, mut engine: E) {
+fn write<S: AsRef<str>, E: KvsEngine + 'static>(name: S, c: &mut Criterion, engine: E) {
     let mut rng = thread_rng();
 
     // populate test data
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use futures::channel::oneshot;
+
+use crate::protocol::{
+    client_handshake, decode_response, encode_request, read_frame, write_frame, Codec, Hello,
+};
+use crate::tls::{split_client, ReadHalf, WriteHalf};
+use crate::{ClientConfig, Credentials, KvsError, Request, Response, Result, TaggedRequest};
+
+type ResponseSender = oneshot::Sender<Result<Response>>;
+// requests waiting for their response, by id, with the time they were sent
+type Pending = Arc<Mutex<Option<BTreeMap<u64, (ResponseSender, Instant)>>>>;
+
+/// Asynchronous kvs client.
+///
+/// Every operation returns a future and many of them can be in flight at the
+/// same time on the single connection. The futures do not depend on any
+/// particular runtime: the connection is driven by two background threads,
+/// one writing requests and one reading responses.
+///
+/// Clones share the same connection, which is closed once all clones are
+/// dropped.
+///
+/// ```rust,no_run
+/// # use kvs::{AsyncKvsClient, Result};
+/// # async fn try_main() -> Result<()> {
+/// let client = AsyncKvsClient::connect("127.0.0.1:4000".parse().unwrap()).await?;
+/// let (a, b) = futures::join!(client.get("a".to_owned()), client.get("b".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct AsyncKvsClient {
+    requests: Sender<(Request, ResponseSender)>,
+}
+
+impl AsyncKvsClient {
+    /// Connect to the given socket address, using the default
+    /// `ClientConfig`.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the connection or the handshake fails.
+    pub fn connect(addr: SocketAddr) -> impl Future<Output = Result<AsyncKvsClient>> {
+        AsyncKvsClient::connect_with_config(addr, &ClientConfig::default())
+    }
+
+    /// Connect to the given socket address, asking the server to encode
+    /// messages with the given codec.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the connection or the handshake fails.
+    pub fn connect_with_codec(
+        addr: SocketAddr,
+        codec: Codec,
+    ) -> impl Future<Output = Result<AsyncKvsClient>> {
+        let config = ClientConfig {
+            codec,
+            ..ClientConfig::default()
+        };
+        AsyncKvsClient::connect_with_config(addr, &config)
+    }
+
+    /// Connect to the given socket address with the given settings, as
+    /// `KvsClient::connect_with_config` does.
+    ///
+    /// The read timeout only applies while requests wait for their
+    /// response: once one waited longer, the connection fails.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the connection, the handshake or the
+    /// authentication fails.
+    pub fn connect_with_config(
+        addr: SocketAddr,
+        config: &ClientConfig,
+    ) -> impl Future<Output = Result<AsyncKvsClient>> {
+        let config = config.clone();
+        let (sender, receiver) = oneshot::channel();
+        let spawned = thread::Builder::new()
+            .name("kvs-client-connect".to_owned())
+            .spawn(move || {
+                let _ = sender.send(AsyncKvsClient::connect_blocking(addr, config));
+            });
+
+        async move {
+            spawned?;
+            receiver
+                .await
+                .unwrap_or_else(|_| Err(KvsError::Protocol("connection aborted".to_owned())))
+        }
+    }
+
+    fn connect_blocking(addr: SocketAddr, config: ClientConfig) -> Result<AsyncKvsClient> {
+        let stream = match config.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_read_timeout(config.read_timeout)?;
+        stream.set_write_timeout(config.write_timeout)?;
+        let tls = match &config.tls {
+            Some(tls) => Some(tls.connect(&addr)?),
+            None => None,
+        };
+        let (reader, writer) = split_client(stream, tls)?;
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let hello = client_handshake(&mut reader, &mut writer, config.codec)?;
+        let timeout_ms = config.request_timeout.map(|t| t.as_millis() as u64);
+        if let Some(credentials) = config.credentials {
+            authenticate(&mut reader, &mut writer, hello, credentials, timeout_ms)?;
+        }
+
+        let pending = Arc::new(Mutex::new(Some(BTreeMap::new())));
+        let (requests, receiver) = unbounded();
+
+        let writer_pending = pending.clone();
+        thread::Builder::new()
+            .name("kvs-client-writer".to_owned())
+            .spawn(move || write_requests(writer, hello, timeout_ms, receiver, writer_pending))?;
+        let read_timeout = config.read_timeout;
+        thread::Builder::new()
+            .name("kvs-client-reader".to_owned())
+            .spawn(move || read_responses(reader, hello, read_timeout, pending))?;
+
+        Ok(AsyncKvsClient { requests })
+    }
+
+    /// Set the given string value to the given string key.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> {
+        let resp = self.request(Request::Set { key, value });
+        async move { resp.await.map(|_| ()) }
+    }
+
+    /// Get the string value from the given string key.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> {
+        self.request(Request::Get { key })
+    }
+
+    /// Remove the given string key.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> {
+        let resp = self.request(Request::Remove { key });
+        async move { resp.await.map(|_| ()) }
+    }
+
+    /// Send any request and resolve to its response.
+    ///
+    /// The request is queued right away, before the future is polled.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn request(&self, request: Request) -> impl Future<Output = Result<Option<String>>> {
+        let (sender, receiver) = oneshot::channel();
+        let queued = self.requests.send((request, sender)).is_ok();
+
+        async move {
+            if !queued {
+                return Err(connection_closed());
+            }
+            match receiver.await {
+                Ok(resp) => resp?.into_result(),
+                Err(oneshot::Canceled) => Err(connection_closed()),
+            }
+        }
+    }
+}
+
+fn connection_closed() -> KvsError {
+    KvsError::Protocol("connection closed".to_owned())
+}
+
+// A copy of the error failing a connection for each request it fails, of
+// the same kind.
+fn copy_error(err: &KvsError) -> KvsError {
+    match err {
+        KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+        KvsError::Protocol(message) => KvsError::Protocol(message.clone()),
+        KvsError::Tls(message) => KvsError::Tls(message.clone()),
+        err => KvsError::from_response(err.code(), err.message()),
+    }
+}
+
+// Authenticates the connection before its threads start, as
+// `KvsClient::authenticate` does.
+fn authenticate(
+    reader: &mut BufReader<ReadHalf>,
+    writer: &mut BufWriter<WriteHalf>,
+    hello: Hello,
+    credentials: Credentials,
+    timeout_ms: Option<u64>,
+) -> Result<()> {
+    if hello.version < 4 {
+        return Err(KvsError::Protocol(format!(
+            "protocol version {} has no authentication",
+            hello.version
+        )));
+    }
+    let request = TaggedRequest {
+        id: 1,
+        request: Request::Auth { credentials },
+        timeout_ms,
+    };
+    write_frame(writer, &encode_request(&hello, &request)?)?;
+    writer.flush()?;
+    match read_frame(reader)? {
+        Some(payload) => decode_response(&hello, &payload)?
+            .response
+            .into_result()
+            .map(|_| ()),
+        None => Err(connection_closed()),
+    }
+}
+
+// Writes queued requests until every client handle is dropped. Once the
+// connection fails, requests are refused right away instead.
+fn write_requests(
+    mut writer: BufWriter<WriteHalf>,
+    hello: Hello,
+    timeout_ms: Option<u64>,
+    receiver: Receiver<(Request, ResponseSender)>,
+    pending: Pending,
+) {
+    if let Err(e) = write_queued(&mut writer, hello, timeout_ms, &receiver, &pending) {
+        fail_pending(&pending, &e);
+        for (_, sender) in receiver {
+            let _ = sender.send(Err(copy_error(&e)));
+        }
+    }
+
+    // all clients are gone: let the server close the connection, which stops the reader
+    let _ = writer.get_mut().shutdown();
+}
+
+// Requests queued together are flushed together.
+fn write_queued(
+    writer: &mut BufWriter<WriteHalf>,
+    hello: Hello,
+    timeout_ms: Option<u64>,
+    receiver: &Receiver<(Request, ResponseSender)>,
+    pending: &Pending,
+) -> Result<()> {
+    let mut next_id = 1;
+    while let Ok(first) = receiver.recv() {
+        let mut next = Some(first);
+        while let Some((request, sender)) = next {
+            let id = next_id;
+            next_id += 1;
+            let request = TaggedRequest {
+                id,
+                request,
+                timeout_ms,
+            };
+            let payload = encode_request(&hello, &request)?;
+            // register before writing so the response always finds its sender
+            match pending.lock().unwrap().as_mut() {
+                Some(pending) => pending.insert(id, (sender, Instant::now())),
+                None => {
+                    let _ = sender.send(Err(connection_closed()));
+                    return Err(connection_closed());
+                }
+            };
+            write_frame(writer, &payload)?;
+            next = receiver.try_recv().ok();
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// Completes pending requests with the responses of the server until the
+// connection is closed.
+fn read_responses(
+    mut reader: BufReader<ReadHalf>,
+    hello: Hello,
+    read_timeout: Option<Duration>,
+    pending: Pending,
+) {
+    let err = loop {
+        let resp = match read_frame(&mut reader) {
+            Ok(Some(payload)) => decode_response(&hello, &payload),
+            Ok(None) => break connection_closed(),
+            Err(KvsError::Io(ref e)) if idle(e, read_timeout, &pending) => continue,
+            Err(e) => break e,
+        };
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => break e,
+        };
+
+        let mut pending = pending.lock().unwrap();
+        let pending = match pending.as_mut() {
+            Some(pending) => pending,
+            // the writer failed and already closed the connection
+            None => return,
+        };
+        let sender = if hello.version < 2 {
+            // responses of version 1 arrive in request order
+            pending
+                .keys()
+                .next()
+                .cloned()
+                .and_then(|id| pending.remove(&id))
+        } else {
+            pending.remove(&resp.id)
+        };
+        if let Some((sender, _)) = sender {
+            let _ = sender.send(Ok(resp.response));
+        }
+    };
+    fail_pending(&pending, &err);
+    let _ = reader.get_ref().tcp().shutdown(Shutdown::Both);
+}
+
+// Whether a read timed out while no request waited for its response longer
+// than the read timeout, which leaves the connection usable.
+fn idle(err: &io::Error, read_timeout: Option<Duration>, pending: &Pending) -> bool {
+    let timeout = match (err.kind(), read_timeout) {
+        (io::ErrorKind::WouldBlock, Some(timeout)) | (io::ErrorKind::TimedOut, Some(timeout)) => {
+            timeout
+        }
+        _ => return false,
+    };
+    match pending.lock().unwrap().as_ref() {
+        Some(pending) => pending.values().all(|(_, sent)| sent.elapsed() < timeout),
+        None => false,
+    }
+}
+
+// Fails every pending request and refuses new ones.
+fn fail_pending(pending: &Pending, err: &KvsError) {
+    if let Some(pending) = pending.lock().unwrap().take() {
+        for (_, (sender, _)) in pending {
+            let _ = sender.send(Err(copy_error(err)));
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Authentication of clients and authorization of their requests.
+//!
+//! A server with an `AuthConfig` knows a set of users, each identified by a
+//! token or by a name and password, and granted read or read-write access to
+//! the keys starting with given prefixes. A connection is anonymous until it
+//! authenticates with `Request::Auth`, and requests are only executed if one
+//! of the grants of the user of the connection covers their key.
+//!
+//! The config is read from a JSON file:
+//!
+//! ```json
+//! {
+//!     "users": [
+//!         {
+//!             "name": "billing",
+//!             "token": "9f86d081884c7d65",
+//!             "grants": [
+//!                 { "prefix": "billing/", "access": "read_write" },
+//!                 { "prefix": "", "access": "read" }
+//!             ]
+//!         },
+//!         {
+//!             "name": "alice",
+//!             "password": "correct horse battery staple",
+//!             "grants": [{ "prefix": "alice/", "access": "read_write" }]
+//!         }
+//!     ],
+//!     "anonymous": [{ "prefix": "public/", "access": "read" }]
+//! }
+//! ```
+//!
+//! Secrets travel in clear text unless the connection uses TLS.
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::server::Transaction;
+use crate::{Credentials, KvsError, Request, Result};
+
+/// What a grant allows on the keys it covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// `get` only.
+    Read,
+    /// `get`, `set` and `remove`.
+    ReadWrite,
+}
+
+/// Access to the keys starting with a prefix. The empty prefix covers every
+/// key.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Grant {
+    /// Prefix of the keys covered.
+    pub prefix: String,
+    /// What is allowed on these keys.
+    pub access: Access,
+}
+
+/// A user known to the server.
+#[derive(Clone, Deserialize)]
+pub struct User {
+    /// Name of the user, used to authenticate with a password.
+    pub name: String,
+    /// Token authenticating the user.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Password authenticating the user along with the name.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// What the user may access. Grants add up.
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+}
+
+/// Users and permissions of a server.
+#[derive(Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    /// Known users.
+    #[serde(default)]
+    pub users: Vec<User>,
+    /// What connections may access before authenticating.
+    #[serde(default)]
+    pub anonymous: Vec<Grant>,
+}
+
+impl AuthConfig {
+    /// Read the config from a JSON file.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the file cannot be read or parsed, or if two users
+    /// share a name or a token.
+    pub fn from_file(path: &Path) -> Result<AuthConfig> {
+        let config: AuthConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that users can be told apart.
+    ///
+    /// # Error
+    ///
+    /// Return an error if two users share a name or a token.
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        for user in &self.users {
+            if !names.insert(&user.name) {
+                return Err(KvsError::AuthConfig(format!(
+                    "duplicate user {}",
+                    user.name
+                )));
+            }
+            if let Some(token) = &user.token {
+                if !tokens.insert(token) {
+                    return Err(KvsError::AuthConfig(format!(
+                        "token of user {} is not unique",
+                        user.name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Finds the user the credentials belong to.
+    fn authenticate(&self, credentials: &Credentials) -> Option<usize> {
+        // every user is compared, so that timing tells nothing about secrets
+        let mut found = None;
+        for (i, user) in self.users.iter().enumerate() {
+            let valid = match credentials {
+                Credentials::Token(token) => user
+                    .token
+                    .as_ref()
+                    .is_some_and(|expected| secret_eq(expected, token)),
+                Credentials::Password { username, password } => {
+                    user.name == *username
+                        && user
+                            .password
+                            .as_ref()
+                            .is_some_and(|expected| secret_eq(expected, password))
+                }
+            };
+            if valid {
+                found = Some(i);
+            }
+        }
+        found
+    }
+}
+
+// Compares secrets in a time independent of where they differ.
+fn secret_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// The user a connection is authenticated as, and the transaction it has
+/// open.
+#[derive(Clone, Default)]
+pub(crate) struct Session {
+    // `None` lets everything through
+    config: Option<Arc<AuthConfig>>,
+    user: Option<usize>,
+    pub(crate) transaction: Option<Transaction>,
+}
+
+impl Session {
+    pub(crate) fn new(config: Option<Arc<AuthConfig>>) -> Session {
+        Session {
+            config,
+            user: None,
+            transaction: None,
+        }
+    }
+
+    /// Authenticate the connection, which stays anonymous if the credentials
+    /// are not valid. Any credentials are valid without `AuthConfig`.
+    pub(crate) fn authenticate(&mut self, credentials: &Credentials) -> bool {
+        match &self.config {
+            Some(config) => {
+                self.user = config.authenticate(credentials);
+                self.user.is_some()
+            }
+            None => true,
+        }
+    }
+
+    /// Check that the user of the connection may execute the request.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::PermissionDenied` if the grants of the user do not
+    /// allow the request.
+    pub(crate) fn authorize(&self, request: &Request) -> Result<()> {
+        match request {
+            Request::Get { key } | Request::GetWithVersion { key } => {
+                self.authorize_key(key, false)
+            }
+            Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::SetIfVersion { key, .. } => self.authorize_key(key, true),
+            Request::MultiGet { keys } => keys
+                .iter()
+                .try_for_each(|key| self.authorize_key(key, false)),
+            Request::MultiSet { pairs } => pairs
+                .iter()
+                .try_for_each(|(key, _)| self.authorize_key(key, true)),
+            Request::Auth { .. } => Ok(()),
+            // watchers only get the changes of the keys they may read
+            Request::Watch { .. } => Ok(()),
+            // the keys of a transaction are authorized by its requests
+            Request::Begin | Request::Commit | Request::Abort => Ok(()),
+            // followers get every change, so they must be able to read every key
+            Request::Replicate { .. } => self.authorize_key("", false),
+        }
+    }
+
+    /// Check that the user of the connection may read, or write, the key.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::PermissionDenied` if the grants of the user do not
+    /// allow the access.
+    pub(crate) fn authorize_key(&self, key: &str, write: bool) -> Result<()> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let (name, grants) = match self.user {
+            Some(user) => (config.users[user].name.as_str(), &config.users[user].grants),
+            None => ("anonymous", &config.anonymous),
+        };
+        let allowed = grants.iter().any(|grant| {
+            key.starts_with(&grant.prefix) && (!write || grant.access == Access::ReadWrite)
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvsError::PermissionDenied(format!(
+                "{} may not {} key {}",
+                name,
+                if write { "write" } else { "read" },
+                key
+            )))
+        }
+    }
+}
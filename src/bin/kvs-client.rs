@@ -1,8 +1,9 @@
 use std::net::SocketAddr;
+use std::process::exit;
 
 use structopt::StructOpt;
 
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, KvsError, Result, ShardedClient};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-client")]
@@ -11,46 +12,93 @@ struct Opt {
     cmd: Command,
 }
 
+// the server to connect to, or the servers sharing the keys
+#[derive(StructOpt, Debug)]
+struct ServerOpt {
+    #[structopt(
+        long,
+        default_value = "127.0.0.1:4000",
+        value_name = "IP-PORT",
+        help = "Specify socket address to bound to",
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long,
+        value_name = "IP-PORT,...",
+        help = "Spread the keys over these servers instead of using --addr",
+        parse(try_from_str),
+        raw(use_delimiter = "true", require_delimiter = "true")
+    )]
+    servers: Vec<SocketAddr>,
+}
+
 #[derive(StructOpt, Debug)]
 enum Command {
     #[structopt(name = "set", about = "Set the value of a string key to a string")]
-    SET {
+    Set {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
 
         #[structopt(name = "VALUE", help = "A string value")]
         value: String,
 
-        #[structopt(
-            long,
-            default_value = "127.0.0.1:4000",
-            value_name = "IP-PORT",
-            help = "Specify socket address to bound to",
-            parse(try_from_str)
-        )]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        server: ServerOpt,
     },
 
     #[structopt(name = "rm", about = "Remove a given string key")]
-    REMOVE {
+    Remove {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
 
-        #[structopt(
-            long,
-            default_value = "127.0.0.1:4000",
-            value_name = "IP-PORT",
-            help = "Specify socket address to bound to",
-            parse(try_from_str)
-        )]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        server: ServerOpt,
     },
 
     #[structopt(name = "get", about = "Get the string value of a given string key")]
-    GET {
+    Get {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
 
+        #[structopt(flatten)]
+        server: ServerOpt,
+    },
+
+    #[structopt(name = "mget", about = "Get the string values of several string keys")]
+    MultiGet {
+        #[structopt(name = "KEY", help = "String keys", required = true)]
+        keys: Vec<String>,
+
+        #[structopt(flatten)]
+        server: ServerOpt,
+    },
+
+    #[structopt(name = "mset", about = "Set the values of several string keys")]
+    MultiSet {
+        #[structopt(
+            name = "KEY VALUE",
+            help = "String keys, each followed by its string value",
+            required = true
+        )]
+        args: Vec<String>,
+
+        #[structopt(flatten)]
+        server: ServerOpt,
+    },
+
+    #[structopt(
+        name = "watch",
+        about = "Print the changes to the keys starting with a prefix as they happen"
+    )]
+    Watch {
+        #[structopt(
+            name = "PREFIX",
+            default_value = "",
+            help = "A key prefix, every key by default"
+        )]
+        prefix: String,
+
         #[structopt(
             long,
             default_value = "127.0.0.1:4000",
@@ -63,27 +111,121 @@ enum Command {
 }
 
 fn main() -> Result<()> {
-    let cmd = Command::from_args();
+    let cmd = Opt::from_args().cmd;
 
     match cmd {
-        Command::GET { key, addr } => {
-            let mut client = KvsClient::connect(&addr)?;
+        Command::Get { key, server } => {
+            let mut client = Client::connect(server)?;
             match client.get(key)? {
                 Some(v) => println!("{}", v),
                 None => println!("Key not found"),
             }
         }
 
-        Command::SET { key, value, addr } => {
-            let mut client = KvsClient::connect(&addr)?;
+        Command::Set { key, value, server } => {
+            let mut client = Client::connect(server)?;
             client.set(key, value)?;
         }
 
-        Command::REMOVE { key, addr } => {
-            let mut client = KvsClient::connect(&addr)?;
-            client.remove(key)?;
+        Command::MultiGet { keys, server } => {
+            let mut client = Client::connect(server)?;
+            for value in client.get_many(keys)? {
+                match value {
+                    Some(v) => println!("{}", v),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+
+        Command::MultiSet { args, server } => {
+            if args.len() % 2 != 0 {
+                eprintln!("Every key needs a value");
+                exit(1);
+            }
+            let mut args = args.into_iter();
+            let mut pairs = Vec::new();
+            while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                pairs.push((key, value));
+            }
+            let mut client = Client::connect(server)?;
+            client.set_many(pairs)?;
+        }
+
+        Command::Watch { prefix, addr } => {
+            let client = KvsClient::connect(&addr)?;
+            for event in client.watch(prefix)? {
+                let event = event?;
+                match event.value {
+                    Some(value) => println!("set {} {}", event.key, value),
+                    None => println!("rm {}", event.key),
+                }
+            }
+        }
+
+        Command::Remove { key, server } => {
+            let mut client = Client::connect(server)?;
+            match client.remove(key) {
+                Err(KvsError::KeyNotFound) => {
+                    eprintln!("Key not found");
+                    exit(1);
+                }
+                res => res?,
+            }
         }
     }
 
     Ok(())
 }
+
+// A client of a single server, or of the servers sharing the keys.
+enum Client {
+    Single(Box<KvsClient>),
+    Sharded(ShardedClient),
+}
+
+impl Client {
+    // Connects to the servers sharing the keys, or to the single server at
+    // `addr` if none is given.
+    fn connect(server: ServerOpt) -> Result<Client> {
+        if server.servers.is_empty() {
+            Ok(Client::Single(Box::new(KvsClient::connect(&server.addr)?)))
+        } else {
+            Ok(Client::Sharded(ShardedClient::connect(&server.servers)?))
+        }
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self {
+            Client::Single(client) => client.get(key),
+            Client::Sharded(client) => client.get(key),
+        }
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self {
+            Client::Single(client) => client.set(key, value),
+            Client::Sharded(client) => client.set(key, value),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self {
+            Client::Single(client) => client.remove(key),
+            Client::Sharded(client) => client.remove(key),
+        }
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self {
+            Client::Single(client) => client.get_many(keys),
+            Client::Sharded(client) => client.get_many(keys),
+        }
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self {
+            Client::Single(client) => client.set_many(pairs),
+            Client::Sharded(client) => client.set_many(pairs),
+        }
+    }
+}
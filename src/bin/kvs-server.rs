@@ -1,6 +1,9 @@
+use std::io;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::thread;
+use std::time::Duration;
 
 use clap::arg_enum;
 use slog::{debug, error, info, warn, Logger};
@@ -9,10 +12,19 @@ use sloggers::types::Severity;
 use sloggers::Build;
 use structopt::StructOpt;
 
-use kvs::{KvStore, KvsEngine, KvsServer, RayonThreadPool, Result, SledKvsEngine, ThreadPool};
+use kvs::auth::AuthConfig;
+use kvs::raft::{ClusterConfig, RaftEngine};
+use kvs::tls::ServerTlsConfig;
+use kvs::{
+    BoxedKvsEngine, EngineRegistry, ExpiringEngine, FileStorage, Follower, KvsError, KvsServer,
+    RaftServer, RayonThreadPool, ReadOnlyEngine, RespServer, Result, ThreadPool, WatchedEngine,
+};
 
-const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_ENGINE: &str = "kvs";
+// the engine which never touches the data directory
+const MEMORY_ENGINE: &str = "memory";
 const DEFAULT_THREAD_POOL: Pool = Pool::rayon;
+const DEFAULT_MODE: Mode = Mode::threaded;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
@@ -26,13 +38,8 @@ struct Command {
     )]
     addr: SocketAddr,
 
-    #[structopt(
-        long,
-        value_name = "ENGINE-NAME",
-        help = "Specify which engine to use",
-        raw(possible_values = "&Engine::variants()")
-    )]
-    engine: Option<Engine>,
+    #[structopt(long, value_name = "ENGINE-NAME", help = "Specify which engine to use")]
+    engine: Option<String>,
 
     #[structopt(
         long,
@@ -41,28 +48,123 @@ struct Command {
         raw(possible_values = "&Pool::variants()")
     )]
     pool: Option<Pool>,
+
+    #[structopt(
+        long,
+        value_name = "MODE",
+        help = "Specify how connections are served: a pool thread per connection (threaded) \
+                or an event loop sharing the pool between all connections (evented)",
+        raw(possible_values = "&Mode::variants()")
+    )]
+    mode: Option<Mode>,
+
+    #[structopt(
+        long = "idle-timeout",
+        value_name = "SECONDS",
+        help = "Close connections which stay idle for longer than this"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[structopt(
+        long = "tls-cert",
+        value_name = "FILE",
+        help = "Serve TLS connections only, with the certificate chain of this PEM file",
+        requires = "tls_key",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-key",
+        value_name = "FILE",
+        help = "Specify the PEM file of the private key of the TLS certificate",
+        requires = "tls_cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-client-ca",
+        value_name = "FILE",
+        help = "Require TLS clients to present a certificate signed by a CA of this PEM file",
+        requires = "tls_cert",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(
+        long = "auth-config",
+        value_name = "FILE",
+        help = "Require clients to authenticate with the users and grants of this JSON file",
+        parse(from_os_str)
+    )]
+    auth_config: Option<PathBuf>,
+
+    #[structopt(
+        long = "resp-addr",
+        value_name = "IP-PORT",
+        help = "Also serve Redis clients on this socket address",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+
+    #[structopt(
+        long = "http-addr",
+        value_name = "IP-PORT",
+        help = "Also serve the keys over HTTP on this socket address",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
+
+    #[structopt(
+        long,
+        value_name = "LEADER-IP-PORT",
+        help = "Follow the server at this socket address, serving its keys read-only",
+        parse(try_from_str)
+    )]
+    follow: Option<SocketAddr>,
+
+    #[structopt(
+        long = "raft-cluster",
+        value_name = "FILE",
+        help = "Join the Raft cluster of the nodes of this JSON file, replicating writes to them",
+        requires = "raft_id",
+        // the messages between the nodes are neither encrypted nor
+        // authenticated yet
+        raw(conflicts_with_all = r#"&["follow", "tls_cert", "auth_config"]"#),
+        parse(from_os_str)
+    )]
+    raft_cluster: Option<PathBuf>,
+
+    #[structopt(
+        long = "raft-id",
+        value_name = "ID",
+        help = "Specify the id of this node in the Raft cluster",
+        requires = "raft_cluster"
+    )]
+    raft_id: Option<u64>,
 }
 
 arg_enum! {
     #[derive(Eq, PartialEq, Debug, Clone, Copy)]
     #[allow(non_camel_case_types)]
-    enum Engine {
-        kvs, sled
+    enum Pool {
+        raw, shared_queue, rayon
     }
-
 }
 
 arg_enum! {
     #[derive(Eq, PartialEq, Debug, Clone, Copy)]
     #[allow(non_camel_case_types)]
-    enum Pool {
-        raw, shared_queue, rayon
+    enum Mode {
+        threaded, evented
     }
 }
 
 fn main() -> Result<()> {
     let mut cmd = Command::from_args();
     let dir = std::env::current_dir()?;
+    let registry = EngineRegistry::default();
 
     let mut builder = TerminalLoggerBuilder::new();
     builder.level(Severity::Debug);
@@ -71,30 +173,41 @@ fn main() -> Result<()> {
     let logger_out = logger.clone();
 
     let state = detect_engine(&dir, logger.clone()).and_then(move |eng| {
+        if let Some(name) = &cmd.engine {
+            if !registry.contains(name) {
+                let names: Vec<_> = registry.names().collect();
+                info!(logger, "Available engines: {}", names.join(", "));
+                return Err(KvsError::UnknownEngine(name.clone()));
+            }
+        }
+
         if cmd.engine.is_none() {
-            cmd.engine = eng;
+            cmd.engine = eng.clone();
         }
 
-        if eng.is_some() && cmd.engine != eng {
+        if eng.is_some() && cmd.engine != eng && cmd.engine.as_deref() != Some(MEMORY_ENGINE) {
             error!(logger, "Wrong engine!");
             exit(1);
         }
 
-        run(cmd, logger.clone())
+        run(cmd, &registry, logger.clone())
     });
 
     if let Err(e) = state {
         error!(logger_out, "{}", e);
+        // flush the asynchronous logger before exiting
+        drop(logger_out);
         exit(1);
     }
 
     Ok(())
 }
 
-fn run(cmd: Command, logger: Logger) -> Result<()> {
+fn run(cmd: Command, registry: &EngineRegistry, logger: Logger) -> Result<()> {
     let dir = std::env::current_dir()?;
-    let engine = cmd.engine.unwrap_or(DEFAULT_ENGINE);
+    let engine = cmd.engine.as_deref().unwrap_or(DEFAULT_ENGINE);
     let pool = cmd.pool.unwrap_or(DEFAULT_THREAD_POOL);
+    let mode = cmd.mode.unwrap_or(DEFAULT_MODE);
 
     info!(
         logger,
@@ -103,30 +216,225 @@ fn run(cmd: Command, logger: Logger) -> Result<()> {
     );
     info!(
         logger,
-        "Config: IP address {}, storage engine {:?}", cmd.addr, cmd.engine
+        "Config: IP address {}, storage engine {}, mode {}", cmd.addr, engine, mode
     );
 
-    std::fs::write(dir.join("engine"), format!("{}", engine))?;
+    let tls = match (&cmd.tls_cert, &cmd.tls_key) {
+        (Some(cert), Some(key)) => {
+            info!(
+                logger,
+                "TLS enabled, client certificates {}",
+                if cmd.tls_client_ca.is_some() {
+                    "required"
+                } else {
+                    "not required"
+                }
+            );
+            Some(ServerTlsConfig::from_pem_files(
+                cert,
+                key,
+                cmd.tls_client_ca.as_deref(),
+            )?)
+        }
+        _ => None,
+    };
+
+    let auth = match &cmd.auth_config {
+        Some(path) => {
+            let auth = AuthConfig::from_file(path)?;
+            info!(
+                logger,
+                "Authentication enabled for {} users",
+                auth.users.len()
+            );
+            Some(auth)
+        }
+        None => None,
+    };
+
+    let raft = match (&cmd.raft_cluster, cmd.raft_id) {
+        (Some(path), Some(id)) => {
+            let cluster = ClusterConfig::from_file(path)?;
+            match cluster.node(id) {
+                Some(node) if node.addr != cmd.addr => warn!(
+                    logger,
+                    "Node {} serves clients on {}, not on {} as the cluster says",
+                    id,
+                    cmd.addr,
+                    node.addr
+                ),
+                Some(_) => {}
+                None => {
+                    return Err(KvsError::ClusterConfig(format!("no node with id {}", id)));
+                }
+            }
+            info!(
+                logger,
+                "Raft node {} of a cluster of {} nodes",
+                id,
+                cluster.nodes.len()
+            );
+            Some((id, cluster))
+        }
+        _ => None,
+    };
 
-    match engine {
-        Engine::kvs => run_with_engine(KvStore::open(dir)?, &cmd.addr, logger, pool),
-        Engine::sled => run_with_engine(SledKvsEngine::new(dir)?, &cmd.addr, logger, pool),
+    let kv_engine = registry.open(engine, &dir)?;
+    if engine != MEMORY_ENGINE {
+        std::fs::write(dir.join("engine"), engine)?;
     }
+
+    let idle_timeout = cmd.idle_timeout.map(Duration::from_secs);
+    let options = ServerOptions {
+        mode,
+        idle_timeout,
+        tls,
+        auth,
+        resp_addr: cmd.resp_addr,
+        http_addr: cmd.http_addr,
+        leader: cmd.follow,
+        raft,
+        dir,
+    };
+    run_with_engine(kv_engine, &cmd.addr, logger, pool, options)
+}
+
+// how the server serves connections
+struct ServerOptions {
+    mode: Mode,
+    idle_timeout: Option<Duration>,
+    tls: Option<ServerTlsConfig>,
+    auth: Option<AuthConfig>,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    leader: Option<SocketAddr>,
+    raft: Option<(u64, ClusterConfig)>,
+    // where the Raft log is kept
+    dir: PathBuf,
 }
 
-fn run_with_engine<E: KvsEngine>(
-    engine: E,
+fn run_with_engine(
+    engine: BoxedKvsEngine,
     addr: &SocketAddr,
     logger: Logger,
     _pool: Pool,
+    options: ServerOptions,
 ) -> Result<()> {
     let cpus = num_cpus::get() as u32;
+
+    // watchers see the changes made through every protocol, expiries too;
+    // writes are only serialized to publish them while someone watches
+    let engine = BoxedKvsEngine::new(WatchedEngine::new(engine));
+
+    // followers only take changes from their leader, and serve them onwards
+    let mut follower = None;
+    let engine = match options.leader {
+        Some(leader) => {
+            info!(logger, "Following {}", leader);
+            follower = Some(Follower::new(leader, engine.clone(), logger.clone()));
+            BoxedKvsEngine::new(ReadOnlyEngine::new(engine))
+        }
+        None => engine,
+    };
+
+    // Raft nodes only apply the writes their cluster committed
+    let mut raft_server = None;
+    let engine = match options.raft {
+        Some((id, cluster)) => {
+            let storage = FileStorage::new(options.dir.join("raft"))?;
+            let engine = RaftEngine::new(id, cluster, engine, storage)?;
+            raft_server = Some(RaftServer::new(engine.clone(), logger.clone()));
+            BoxedKvsEngine::new(engine)
+        }
+        None => engine,
+    };
+
+    // the Redis protocol needs keys to expire, on both servers alike
+    let mut resp_server = None;
+    let engine = match options.resp_addr {
+        Some(resp_addr) => {
+            if options.tls.is_some() {
+                warn!(logger, "Redis clients are served without TLS");
+            }
+            let engine = ExpiringEngine::new(engine);
+            let mut server =
+                RespServer::new(engine.clone(), logger.clone(), RayonThreadPool::new(cpus)?);
+            server.set_idle_timeout(options.idle_timeout);
+            server.set_auth(options.auth.clone());
+            resp_server = Some((server, resp_addr));
+            BoxedKvsEngine::new(engine)
+        }
+        None => engine,
+    };
+
     let pool = RayonThreadPool::new(cpus)?;
-    let mut server = KvsServer::new(engine, logger, pool);
-    server.run(addr)
+    let mut server = KvsServer::new(engine, logger.clone(), pool);
+    server.set_idle_timeout(options.idle_timeout);
+    server.set_tls(options.tls);
+    server.set_auth(options.auth);
+    server.set_http_addr(options.http_addr);
+
+    // SIGINT and SIGTERM stop the servers, letting them answer the requests
+    // they received and flush the engine
+    let shutdown = server.shutdown_handle();
+    let resp_shutdown = resp_server
+        .as_ref()
+        .map(|(server, _)| server.shutdown_handle());
+    let follower_shutdown = follower.as_ref().map(Follower::shutdown_handle);
+    let raft_shutdown = raft_server.as_ref().map(RaftServer::shutdown_handle);
+    let handler_resp_shutdown = resp_shutdown.clone();
+    let handler_follower_shutdown = follower_shutdown.clone();
+    let handler_raft_shutdown = raft_shutdown.clone();
+    ctrlc::set_handler(move || {
+        shutdown.shutdown();
+        if let Some(resp_shutdown) = &handler_resp_shutdown {
+            resp_shutdown.shutdown();
+        }
+        if let Some(follower_shutdown) = &handler_follower_shutdown {
+            follower_shutdown.shutdown();
+        }
+        if let Some(raft_shutdown) = &handler_raft_shutdown {
+            raft_shutdown.shutdown();
+        }
+    })
+    .map_err(io::Error::other)?;
+
+    let follower_thread = follower.map(|mut follower| thread::spawn(move || follower.run()));
+    let raft_thread = raft_server.map(|mut server| thread::spawn(move || server.run()));
+
+    let resp_logger = logger.clone();
+    let resp_thread = resp_server.map(|(mut server, resp_addr)| {
+        thread::spawn(move || {
+            if let Err(e) = server.run(&resp_addr) {
+                error!(resp_logger, "RESP server failed: {}", e);
+            }
+        })
+    });
+    let res = match options.mode {
+        Mode::threaded => server.run(addr),
+        Mode::evented => server.run_evented(addr),
+    };
+    if let (Some(resp_thread), Some(resp_shutdown)) = (resp_thread, resp_shutdown) {
+        // the RESP server stops along with the other one, even if it failed
+        resp_shutdown.shutdown();
+        let _ = resp_thread.join();
+    }
+    if let (Some(follower_thread), Some(follower_shutdown)) = (follower_thread, follower_shutdown) {
+        follower_shutdown.shutdown();
+        if let Ok(Err(e)) = follower_thread.join() {
+            error!(logger, "Follower failed: {}", e);
+        }
+    }
+    if let (Some(raft_thread), Some(raft_shutdown)) = (raft_thread, raft_shutdown) {
+        raft_shutdown.shutdown();
+        if let Ok(Err(e)) = raft_thread.join() {
+            error!(logger, "Raft server failed: {}", e);
+        }
+    }
+    res
 }
 
-fn detect_engine(path: &Path, logger: Logger) -> Result<Option<Engine>> {
+fn detect_engine(path: &Path, logger: Logger) -> Result<Option<String>> {
     let engine_path = path.join("engine");
 
     if !engine_path.exists() {
@@ -134,11 +442,11 @@ fn detect_engine(path: &Path, logger: Logger) -> Result<Option<Engine>> {
         return Ok(None);
     }
 
-    match std::fs::read_to_string(engine_path)?.parse() {
-        Ok(v) => Ok(Some(v)),
-        Err(e) => {
-            warn!(logger, "Content of engine file invalid: {}", e);
-            Ok(None)
-        }
+    let engine = std::fs::read_to_string(engine_path)?.trim().to_owned();
+    if engine.is_empty() {
+        warn!(logger, "Content of engine file invalid: empty engine name");
+        Ok(None)
+    } else {
+        Ok(Some(engine))
     }
 }
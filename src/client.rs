@@ -1,34 +1,231 @@
-use std::io::{BufReader, BufWriter, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem;
 use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
 
-use serde::Deserialize;
-use serde_json::de::IoRead;
-use serde_json::Deserializer;
+use rustls::StreamOwned;
 
-use crate::{KvsError, Request, Response, Result};
+use crate::protocol::{
+    client_handshake, decode_response, encode_request, read_frame, write_frame, Codec, Hello,
+};
+use crate::tls::{ClientTlsConfig, SharedStream, Stream};
+use crate::{
+    ChangeEvent, Credentials, ErrorCode, KvsError, Request, Response, Result, Snapshot,
+    TaggedRequest, TaggedResponse,
+};
+
+// the maximum number of requests `KvsClient::pipeline` keeps in flight, so that
+// neither side blocks on a full socket buffer while the other one is writing
+const PIPELINE_WINDOW: usize = 128;
+// the number of times a request follows the nodes of a Raft cluster
+// redirecting it to their leader
+const MAX_REDIRECTS: usize = 10;
+// pause before asking again a node which does not know the leader yet
+const REDIRECT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Options of a `KvsClient` connection.
+///
+/// Every timeout defaults to `None`, which waits forever.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Codec the server is asked to encode messages with.
+    pub codec: Codec,
+    /// Maximum time to establish the connection.
+    pub connect_timeout: Option<Duration>,
+    /// Maximum time to wait for data from the server. Once a response timed
+    /// out, `is_healthy` reports the connection as unusable.
+    pub read_timeout: Option<Duration>,
+    /// Maximum time a write to the server may block.
+    pub write_timeout: Option<Duration>,
+    /// Deadline sent with every request: the server gives up on requests it
+    /// could not start within this time. Dropped by servers older than
+    /// protocol version 3.
+    pub request_timeout: Option<Duration>,
+    /// Encrypt the connection with TLS, verifying the certificate of the
+    /// server. Defaults to `None`, a plain TCP connection.
+    pub tls: Option<ClientTlsConfig>,
+    /// Authenticate the connection right after opening it. Defaults to
+    /// `None`, which leaves it anonymous.
+    pub credentials: Option<Credentials>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            codec: Codec::Bincode,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            request_timeout: None,
+            tls: None,
+            credentials: None,
+        }
+    }
+}
 
 /// Kvs client.
+///
+/// Besides the blocking `set`, `get` and `remove`, requests can be pipelined:
+/// `send` queues a request without waiting and returns its id, and `recv`
+/// returns responses as they arrive, tagged with the id of their request.
+///
+/// Requests refused by a node of a Raft cluster which is not its leader are
+/// sent again to the leader, reconnecting to it with the same options.
+/// Pipelined requests are not, nor are those of a transaction, which only
+/// lives on its connection.
+///
+/// Between `begin` and `commit`, the writes of the client are only applied
+/// by the commit, which fails with `KvsError::Conflict` if a key the client
+/// read meanwhile was changed by another one:
+///
+/// ```rust,no_run
+/// # use kvs::{KvsClient, KvsError, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut client = KvsClient::connect(&"127.0.0.1:4000".parse().unwrap())?;
+/// loop {
+///     client.begin()?;
+///     let count: u64 = client.get("count".to_owned())?.map_or(0, |v| v.parse().unwrap());
+///     client.set("count".to_owned(), (count + 1).to_string())?;
+///     match client.commit() {
+///         Err(KvsError::Conflict(_)) => continue,
+///         res => break res,
+///     }
+/// }
+/// # }
+/// ```
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<SharedStream>,
+    writer: BufWriter<SharedStream>,
+    hello: Hello,
+    config: ClientConfig,
+    request_timeout: Option<Duration>,
+    next_id: u64,
+    // ids of requests sent and not answered yet, in sending order
+    in_flight: VecDeque<u64>,
+    // responses received while waiting for another one
+    received: VecDeque<TaggedResponse>,
+    // whether the server holds a transaction open for the connection
+    in_transaction: bool,
 }
 
 impl KvsClient {
-    /// Connect to the given socket address.
+    /// Connect to the given socket address, using the binary codec.
     ///
     /// # Error
     ///
-    /// Return an error if the connection fails.
+    /// Return an error if the connection or the handshake fails.
     pub fn connect(addr: &SocketAddr) -> Result<KvsClient> {
-        let writer = TcpStream::connect(addr)?;
-        let reader = writer.try_clone()?;
+        KvsClient::connect_with_codec(addr, Codec::Bincode)
+    }
+
+    /// Connect to the given socket address, asking the server to encode
+    /// messages with the given codec.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the connection or the handshake fails.
+    pub fn connect_with_codec(addr: &SocketAddr, codec: Codec) -> Result<KvsClient> {
+        KvsClient::connect_with_config(
+            addr,
+            &ClientConfig {
+                codec,
+                ..ClientConfig::default()
+            },
+        )
+    }
+
+    /// Connect to the given socket address with the given options.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the connection or the handshake fails or times out.
+    pub fn connect_with_config(addr: &SocketAddr, config: &ClientConfig) -> Result<KvsClient> {
+        let stream = match config.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_read_timeout(config.read_timeout)?;
+        stream.set_write_timeout(config.write_timeout)?;
+        // the TLS handshake happens along with the first exchange
+        let stream = SharedStream::new(match &config.tls {
+            Some(tls) => Stream::Client(Box::new(StreamOwned::new(tls.connect(addr)?, stream))),
+            None => Stream::Plain(stream),
+        });
+        let mut reader = BufReader::new(stream.clone());
+        let mut writer = BufWriter::new(stream);
+
+        let hello = client_handshake(&mut reader, &mut writer, config.codec)?;
+
+        let mut client = KvsClient {
+            reader,
+            writer,
+            hello,
+            config: config.clone(),
+            request_timeout: config.request_timeout,
+            next_id: 1,
+            in_flight: VecDeque::new(),
+            received: VecDeque::new(),
+            in_transaction: false,
+        };
+        if let Some(credentials) = &config.credentials {
+            client.authenticate(credentials.clone())?;
+        }
+        Ok(client)
+    }
+
+    /// The protocol version negotiated with the server.
+    pub fn protocol_version(&self) -> u16 {
+        self.hello.version
+    }
+
+    /// Set the deadline sent with the following requests, replacing the
+    /// `request_timeout` of the `ClientConfig`.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
 
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(reader)),
-            writer: BufWriter::new(writer),
+    /// Check, without blocking, that the connection is still usable: the
+    /// server has not closed it, no request is in flight and no transaction
+    /// is open.
+    pub fn is_healthy(&self) -> bool {
+        if !self.in_flight.is_empty() || !self.reader.buffer().is_empty() || self.in_transaction {
+            return false;
+        }
+        self.reader.get_ref().with_tcp(|stream| {
+            if stream.set_nonblocking(true).is_err() {
+                return false;
+            }
+            let mut buf = [0; 1];
+            // a closed connection reads as end of stream, or a TLS alert,
+            // while a live one has nothing to read
+            let healthy = match stream.peek(&mut buf) {
+                Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+                Ok(_) => false,
+            };
+            stream.set_nonblocking(false).is_ok() && healthy
         })
     }
 
+    /// Authenticate the connection, so that the following requests are
+    /// authorized for the user of the credentials.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::AuthenticationFailed` if the server refuses the
+    /// credentials, or an error if the network fails or the server is older
+    /// than protocol version 4.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        if self.hello.version < 4 {
+            return Err(KvsError::Protocol(format!(
+                "protocol version {} has no authentication",
+                self.hello.version
+            )));
+        }
+        self.request(Request::Auth { credentials }).map(|_| ())
+    }
+
     /// Set the given string value to the given string key by sending
     /// a request to the kvs server.
     ///
@@ -37,15 +234,7 @@ impl KvsClient {
     /// Return an error if the network fails or if the request is not
     /// processed successfully on the server side.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let req = Request::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-
-        let response = Response::deserialize(&mut self.reader)?;
-        match response {
-            Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::ServerError(e)),
-        }
+        self.request(Request::Set { key, value }).map(|_| ())
     }
 
     /// Get the string value from the given string key by sending a request
@@ -56,14 +245,7 @@ impl KvsClient {
     /// Return an error if the network fails or if the request is not
     /// processed successfully on the server side.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-
-        let response = Response::deserialize(&mut self.reader)?;
-        match response {
-            Response::Ok(v) => Ok(v),
-            Response::Err(e) => Err(KvsError::ServerError(e)),
-        }
+        self.request(Request::Get { key })
     }
 
     /// Remove the given string key by sending a request to the kvs server.
@@ -73,13 +255,449 @@ impl KvsClient {
     /// Return an error if the network fails or if the request is not
     /// processed successfully on the server side.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
+        self.request(Request::Remove { key }).map(|_| ())
+    }
+
+    /// Get the string value of the given string key and its version, which
+    /// every write of the key increases.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails, if the request is not processed
+    /// successfully on the server side, e.g. because its engine does not
+    /// track versions, or if the server is older than protocol version 11.
+    pub fn get_with_version(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        self.require_versions()?;
+        self.exchange(Request::GetWithVersion { key })?
+            .into_versioned()
+    }
+
+    /// Set the given string value to the given string key, provided that
+    /// the key is at the given version, or does not exist if `version` is
+    /// `None`. Returns the new version of the key.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::Conflict` if the key is at another version, or an
+    /// error as `get_with_version` does.
+    pub fn set_if_version(
+        &mut self,
+        key: String,
+        value: String,
+        version: Option<u64>,
+    ) -> Result<u64> {
+        self.require_versions()?;
+        self.exchange(Request::SetIfVersion {
+            key,
+            value,
+            version,
+        })?
+        .into_version()
+    }
+
+    /// Get the string values of several keys in one request, in the order
+    /// of the keys. Servers older than protocol version 6 are sent a
+    /// pipeline of `Request::Get` instead.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        if self.hello.version < 6 {
+            let requests = keys.into_iter().map(|key| Request::Get { key }).collect();
+            return self.pipeline(requests)?.into_iter().collect();
+        }
+        self.exchange(Request::MultiGet { keys })?.into_values()
+    }
+
+    /// Set several keys to string values in one request, in order. Servers
+    /// older than protocol version 6 are sent a pipeline of `Request::Set`
+    /// instead.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side, in which case some of the
+    /// keys may have been set.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        if self.hello.version < 6 {
+            let requests = pairs
+                .into_iter()
+                .map(|(key, value)| Request::Set { key, value })
+                .collect();
+            return self
+                .pipeline(requests)?
+                .into_iter()
+                .try_for_each(|res| res.map(|_| ()));
+        }
+        self.request(Request::MultiSet { pairs }).map(|_| ())
+    }
+
+    /// Open a transaction: the following writes are buffered by the server
+    /// until `commit`, and reads see them.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails, if a transaction is already
+    /// open or if the server is older than protocol version 10.
+    pub fn begin(&mut self) -> Result<()> {
+        if self.hello.version < 10 {
+            return Err(KvsError::Protocol(format!(
+                "protocol version {} has no transactions",
+                self.hello.version
+            )));
+        }
+        self.request(Request::Begin)?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    /// Apply the writes of the transaction at once, ending it.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::Conflict` if a key read by the transaction changed
+    /// since, in which case nothing is written, or an error if the network
+    /// fails, if no transaction is open or if the writes are not applied
+    /// successfully on the server side.
+    pub fn commit(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        self.request(Request::Commit).map(|_| ())
+    }
+
+    /// Drop the writes of the transaction, ending it.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if no transaction is open.
+    pub fn abort(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        self.request(Request::Abort).map(|_| ())
+    }
+
+    /// Watch the changes to the keys starting with `prefix`, turning the
+    /// connection into a stream of changes.
+    ///
+    /// Only the changes committed once the server answered are reported,
+    /// and with authentication only those to keys the user may read.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails, if the server cannot watch
+    /// its engine or if it is older than protocol version 7.
+    pub fn watch(mut self, prefix: String) -> Result<Watch> {
+        if self.hello.version < 7 {
+            return Err(KvsError::Protocol(format!(
+                "protocol version {} has no watches",
+                self.hello.version
+            )));
+        }
+        let id = self.next_id;
+        self.request(Request::Watch { prefix })?;
+        Ok(Watch { client: self, id })
+    }
+
+    /// Follow every change, turning the connection into a stream of
+    /// changes, starting with the change numbered `from` of the history
+    /// `epoch`.
+    ///
+    /// If the server no longer has that change, it first returns a
+    /// `Snapshot` of every key, which the changes then follow. Passing an
+    /// `epoch` of 0 always gets a snapshot.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails, if the user may not read every
+    /// key, if the server cannot publish its changes or if it is older than
+    /// protocol version 8.
+    pub fn replicate(self, epoch: u64, from: u64) -> Result<(Option<Snapshot>, Watch)> {
+        let mut pairs = Vec::new();
+        let (snapshot, watch) = self.replicate_paged(epoch, from, |page| {
+            pairs.extend(page);
+            Ok(())
+        })?;
+        let snapshot = snapshot.map(|snapshot| Snapshot { pairs, ..snapshot });
+        Ok((snapshot, watch))
+    }
+
+    /// Follow every change like `replicate`, handing the keys of a snapshot
+    /// to `page` as they arrive, in key order, rather than keeping them all
+    /// in the returned `Snapshot`, which then has none.
+    ///
+    /// # Error
+    ///
+    /// Return an error as `replicate` does, or the first error of `page`.
+    pub fn replicate_paged<F>(
+        mut self,
+        epoch: u64,
+        from: u64,
+        mut page: F,
+    ) -> Result<(Option<Snapshot>, Watch)>
+    where
+        F: FnMut(Vec<(String, String)>) -> Result<()>,
+    {
+        if self.hello.version < 8 {
+            return Err(KvsError::Protocol(format!(
+                "protocol version {} has no replication",
+                self.hello.version
+            )));
+        }
+        let id = self.next_id;
+        let mut resp = self.exchange(Request::Replicate { epoch, from })?;
+        let snapshot = loop {
+            match resp {
+                Response::SnapshotPage(pairs) => page(pairs)?,
+                Response::Snapshot(mut snapshot) => {
+                    // older servers send the keys in the snapshot itself
+                    if !snapshot.pairs.is_empty() {
+                        page(mem::take(&mut snapshot.pairs))?;
+                    }
+                    break Some(snapshot);
+                }
+                resp => {
+                    resp.into_result()?;
+                    break None;
+                }
+            }
+            resp = self.read_streamed(id)?;
+        };
+        Ok((snapshot, Watch { client: self, id }))
+    }
+
+    /// Queue a request without waiting for its response.
+    ///
+    /// Returns the id the response will be tagged with. Queued requests are
+    /// written to the server by `flush` or when waiting for a response.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails.
+    pub fn send(&mut self, request: Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let req = TaggedRequest {
+            id,
+            request,
+            timeout_ms: self.request_timeout.map(|t| t.as_millis() as u64),
+        };
+        let payload = encode_request(&self.hello, &req)?;
+        write_frame(&mut self.writer, &payload)?;
+        self.in_flight.push_back(id);
+        Ok(id)
+    }
+
+    /// Write all queued requests to the server.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails.
+    pub fn flush(&mut self) -> Result<()> {
         self.writer.flush()?;
+        Ok(())
+    }
 
-        let response = Response::deserialize(&mut self.reader)?;
-        match response {
-            Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::ServerError(e)),
+    /// Wait for the next response to any request sent with `send`.
+    ///
+    /// Responses do not necessarily arrive in the order of their requests.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or no request is in flight.
+    pub fn recv(&mut self) -> Result<TaggedResponse> {
+        match self.received.pop_front() {
+            Some(resp) => Ok(resp),
+            None => self.read_response(),
         }
     }
+
+    /// Send all requests, pipelined, and wait for all their responses.
+    ///
+    /// Returns the results in the order of the requests.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails. Failures of individual requests
+    /// on the server side are reported in the returned results.
+    pub fn pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<Result<Option<String>>>> {
+        let first_id = self.next_id;
+        let count = requests.len();
+        let mut responses = HashMap::with_capacity(count);
+
+        for request in requests {
+            if self.in_flight.len() >= PIPELINE_WINDOW {
+                self.collect_response(first_id, &mut responses)?;
+            }
+            self.send(request)?;
+        }
+        while responses.len() < count {
+            self.collect_response(first_id, &mut responses)?;
+        }
+
+        Ok((first_id..first_id + count as u64)
+            .map(|id| {
+                let resp: Response = responses.remove(&id).expect("response received");
+                resp.into_result()
+            })
+            .collect())
+    }
+
+    fn require_versions(&self) -> Result<()> {
+        if self.hello.version < 11 {
+            return Err(KvsError::Protocol(format!(
+                "protocol version {} has no versions",
+                self.hello.version
+            )));
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, request: Request) -> Result<Option<String>> {
+        self.exchange(request)?.into_result()
+    }
+
+    // the socket of the connection, for its owner to close it from another
+    // thread while this one is blocked reading
+    pub(crate) fn try_clone_tcp(&self) -> Result<TcpStream> {
+        Ok(self.reader.get_ref().with_tcp(TcpStream::try_clone)?)
+    }
+
+    // sends a request and waits for its response, following the redirections
+    // to the leader if nothing else is in flight and no transaction is open
+    fn exchange(&mut self, request: Request) -> Result<Response> {
+        let mut redirects = 0;
+        loop {
+            match self.exchange_once(request.clone())? {
+                Response::Error {
+                    code: ErrorCode::NotLeader,
+                    message,
+                } if redirects < MAX_REDIRECTS
+                    && self.in_flight.is_empty()
+                    && self.received.is_empty()
+                    && !self.in_transaction =>
+                {
+                    redirects += 1;
+                    self.redirect(&message);
+                }
+                response => return Ok(response),
+            }
+        }
+    }
+
+    // reconnects to the leader at the given address, or waits for the cluster
+    // to elect one if the address is unknown or unreachable, e.g. because
+    // the leader just failed
+    fn redirect(&mut self, leader: &str) {
+        let client = leader
+            .parse::<SocketAddr>()
+            .ok()
+            .and_then(|addr| KvsClient::connect_with_config(&addr, &self.config).ok());
+        match client {
+            Some(client) => {
+                let request_timeout = self.request_timeout;
+                *self = client;
+                self.request_timeout = request_timeout;
+            }
+            None => thread::sleep(REDIRECT_BACKOFF),
+        }
+    }
+
+    fn exchange_once(&mut self, request: Request) -> Result<Response> {
+        let id = self.send(request)?;
+        loop {
+            let resp = self.read_response()?;
+            if resp.id == id {
+                return Ok(resp.response);
+            }
+            self.received.push_back(resp);
+        }
+    }
+
+    // reads a response from the server, keeping it in `responses` if it
+    // answers a request with an id from `first_id` on
+    fn collect_response(
+        &mut self,
+        first_id: u64,
+        responses: &mut HashMap<u64, Response>,
+    ) -> Result<()> {
+        let resp = self.read_response()?;
+        if resp.id >= first_id {
+            responses.insert(resp.id, resp.response);
+        } else {
+            self.received.push_back(resp);
+        }
+        Ok(())
+    }
+
+    // reads a further response to the request `id`, which already got its
+    // first one
+    fn read_streamed(&mut self, id: u64) -> Result<Response> {
+        let payload = read_frame(&mut self.reader)?
+            .ok_or_else(|| KvsError::Protocol("connection closed by server".to_owned()))?;
+        let resp = decode_response(&self.hello, &payload)?;
+        if resp.id != id {
+            return Err(KvsError::Protocol(format!(
+                "response to unknown request {}",
+                resp.id
+            )));
+        }
+        Ok(resp.response)
+    }
+
+    fn read_response(&mut self) -> Result<TaggedResponse> {
+        if self.in_flight.is_empty() {
+            return Err(KvsError::Protocol("no request in flight".to_owned()));
+        }
+        self.flush()?;
+
+        let payload = read_frame(&mut self.reader)?
+            .ok_or_else(|| KvsError::Protocol("connection closed by server".to_owned()))?;
+        let mut resp = decode_response(&self.hello, &payload)?;
+        if self.hello.version < 2 {
+            // responses of version 1 arrive in request order
+            resp.id = self.in_flight[0];
+        }
+        match self.in_flight.iter().position(|&id| id == resp.id) {
+            Some(pos) => {
+                self.in_flight.remove(pos);
+                Ok(resp)
+            }
+            None => Err(KvsError::Protocol(format!(
+                "response to unknown request {}",
+                resp.id
+            ))),
+        }
+    }
+}
+
+/// The changes to the keys watched with `KvsClient::watch`, in the order the
+/// server committed them.
+///
+/// Iterating blocks until the next change, or for the `read_timeout` of the
+/// connection at most. It ends when the server closes the connection.
+pub struct Watch {
+    client: KvsClient,
+    id: u64,
+}
+
+impl Iterator for Watch {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Result<ChangeEvent>> {
+        let payload = match read_frame(&mut self.client.reader) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        let resp = match decode_response(&self.client.hello, &payload) {
+            Ok(resp) => resp,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(match resp.response {
+            Response::Event(event) if resp.id == self.id => Ok(event),
+            response => response
+                .into_result()
+                .and_then(|_| Err(KvsError::Protocol("expected an event".to_owned()))),
+        })
+    }
 }
@@ -0,0 +1,226 @@
+use std::cmp;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::{ClientConfig, KvsClient, KvsError, Result};
+
+/// Configuration of a `KvsClientPool`.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// The maximum number of connections, and so of requests in flight.
+    /// Callers beyond it wait for a connection to be released.
+    pub max_size: usize,
+    /// Options of the connections.
+    pub client: ClientConfig,
+    /// How many times a request is retried after a connection failure.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on every further retry.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 8,
+            client: ClientConfig::default(),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A pool of connections to a kvs server, shared between threads.
+///
+/// Connections are opened on demand and reused. Before reuse a connection is
+/// checked for health, and broken ones are replaced transparently. Failures
+/// to connect are retried with exponential backoff, and so are `get`s failing
+/// because the connection broke or because the server missed their deadline,
+/// since reading twice is harmless. `set` and `remove` are not retried once
+/// sent, as they could have been applied already.
+///
+/// Clones share the same connections.
+///
+/// ```rust,no_run
+/// # use kvs::{KvsClientPool, PoolConfig, Result};
+/// # fn try_main() -> Result<()> {
+/// let pool = KvsClientPool::new("127.0.0.1:4000".parse().unwrap(), PoolConfig::default());
+/// pool.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(pool.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addr: SocketAddr,
+    config: PoolConfig,
+    idle: Mutex<Vec<KvsClient>>,
+    // number of connections which may still be checked out
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+// Gives the connection slot back when dropped.
+struct Slot<'a> {
+    inner: &'a PoolInner,
+}
+
+impl KvsClientPool {
+    /// Create a pool of connections to the given address. No connection is
+    /// opened until the first request.
+    pub fn new(addr: SocketAddr, config: PoolConfig) -> KvsClientPool {
+        assert!(config.max_size > 0);
+        KvsClientPool {
+            inner: Arc::new(PoolInner {
+                addr,
+                available: Mutex::new(config.max_size),
+                config,
+                idle: Mutex::new(Vec::new()),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Set the given string value to the given string key.
+    ///
+    /// # Error
+    ///
+    /// Return an error if no connection can be opened, if the connection
+    /// fails or if the request is not processed successfully on the server
+    /// side.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.request(false, |client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Get the string value from the given string key, retrying on another
+    /// connection if the connection fails.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the retries are exhausted or if the request is not
+    /// processed successfully on the server side.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.request(true, |client| client.get(key.clone()))
+    }
+
+    /// Remove the given string key.
+    ///
+    /// # Error
+    ///
+    /// Return an error if no connection can be opened, if the connection
+    /// fails or if the request is not processed successfully on the server
+    /// side.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.request(false, |client| client.remove(key.clone()))
+    }
+
+    /// Get the string values of several keys, in the order of the keys,
+    /// retrying on another connection if the connection fails.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the retries are exhausted or if the request is not
+    /// processed successfully on the server side.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.request(true, |client| client.get_many(keys.clone()))
+    }
+
+    /// Set several keys to string values, in order.
+    ///
+    /// # Error
+    ///
+    /// Return an error if no connection can be opened, if the connection
+    /// fails or if the request is not processed successfully on the server
+    /// side.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.request(false, |client| client.set_many(pairs.clone()))
+    }
+
+    /// The number of open connections waiting to be reused.
+    pub fn idle_connections(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    fn request<T, F>(&self, idempotent: bool, mut op: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        let _slot = self.inner.acquire();
+        let config = &self.inner.config;
+        let mut backoff = config.initial_backoff;
+        let mut retries = 0;
+        loop {
+            // whether the request may be sent again after the error
+            let (err, retry) = match self.inner.checkout() {
+                Ok(mut client) => match op(&mut client) {
+                    Err(e) if is_connection_error(&e) => (e, idempotent),
+                    // the connection itself is fine
+                    Err(e) if idempotent && e.code().is_retriable() => {
+                        self.inner.idle.lock().unwrap().push(client);
+                        (e, true)
+                    }
+                    res => {
+                        self.inner.idle.lock().unwrap().push(client);
+                        return res;
+                    }
+                },
+                // nothing was sent
+                Err(e) => (e, true),
+            };
+            if !retry || retries >= config.max_retries {
+                return Err(err);
+            }
+            retries += 1;
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, config.max_backoff);
+        }
+    }
+}
+
+impl PoolInner {
+    fn acquire(&self) -> Slot<'_> {
+        let mut available = self
+            .released
+            .wait_while(self.available.lock().unwrap(), |available| *available == 0)
+            .unwrap();
+        *available -= 1;
+        Slot { inner: self }
+    }
+
+    // Takes a healthy idle connection, or opens a new one.
+    fn checkout(&self) -> Result<KvsClient> {
+        loop {
+            let client = self.idle.lock().unwrap().pop();
+            match client {
+                Some(client) if client.is_healthy() => return Ok(client),
+                // broken connections are dropped
+                Some(_) => {}
+                None => return KvsClient::connect_with_config(&self.addr, &self.config.client),
+            }
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.inner.available.lock().unwrap() += 1;
+        self.inner.released.notify_one();
+    }
+}
+
+// Errors after which the connection cannot be trusted anymore, as opposed to
+// errors reported by the server.
+fn is_connection_error(err: &KvsError) -> bool {
+    matches!(
+        err,
+        KvsError::Io(_) | KvsError::Protocol(_) | KvsError::Bincode(_) | KvsError::Serde(_)
+    )
+}
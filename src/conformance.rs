@@ -0,0 +1,561 @@
+//! A reusable conformance test suite for `KvsEngine` implementations.
+//!
+//! Every check is a plain function that opens engines through a factory
+//! closure and panics on the first violated expectation, so it can be called
+//! from any test. The `engine_conformance_tests!` macro generates one `#[test]`
+//! per check:
+//!
+//! ```rust,no_run
+//! mod memory {
+//!     use kvs::MemoryKvsEngine;
+//!     use std::path::Path;
+//!
+//!     kvs::engine_conformance_tests!(|_: &Path| Ok(MemoryKvsEngine::new()));
+//! }
+//!
+//! mod kvs_store {
+//!     use kvs::KvStore;
+//!     use std::path::Path;
+//!
+//!     kvs::engine_conformance_tests!(persistent |path: &Path| KvStore::open(path), scan, transactions);
+//! }
+//! ```
+//!
+//! The factory receives a fresh, empty directory for each check. Persistent
+//! checks call it again with the same directory after dropping every handle
+//! to the first engine and expect the data to survive.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use crate::{KvsEngine, KvsError, Result};
+
+const CONCURRENCY: usize = 100;
+
+/// A temporary directory removed when dropped.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Create a new, empty directory under the system temporary directory.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the directory cannot be created.
+    pub fn new() -> Result<TestDir> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "kvs-conformance-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(TestDir { path })
+    }
+
+    /// Path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A stored value should be returned by `get`.
+pub fn get_stored_value<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Setting an existing key should overwrite its value.
+pub fn overwrite_value<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Getting a non-existent key should return `None`.
+pub fn get_non_existent_value<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+/// A removed key should no longer be found.
+pub fn remove_key<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    // the key can be set again afterwards
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Removing a non-existent key should fail with `KvsError::KeyNotFound`,
+/// including a key that was already removed.
+pub fn remove_non_existent_key<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    assert_key_not_found(engine.remove("key1".to_owned()));
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_key_not_found(engine.remove("key1".to_owned()));
+    Ok(())
+}
+
+/// Clones of an engine should share the same data.
+pub fn clones_share_data<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    let clone = engine.clone();
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+    clone.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+/// Scans should page through the keys present, in ascending order.
+pub fn scan_keys<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    assert!(engine.scan(None, 10)?.is_empty());
+    for i in (0..10).rev() {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key4".to_owned())?;
+
+    let mut keys = Vec::new();
+    let mut after = None;
+    loop {
+        let page = engine.scan(after, 3)?;
+        assert!(page.len() <= 3);
+        if page.is_empty() {
+            break;
+        }
+        after = page.last().cloned();
+        keys.extend(page);
+    }
+    let expected: Vec<_> = (0..10)
+        .filter(|&i| i != 4)
+        .map(|i| format!("key{}", i))
+        .collect();
+    assert_eq!(keys, expected);
+
+    // the key to start after does not need to exist
+    assert_eq!(
+        engine.scan(Some("key40".to_owned()), 2)?,
+        vec!["key5".to_owned(), "key6".to_owned()]
+    );
+    assert!(engine.scan(None, 0)?.is_empty());
+    Ok(())
+}
+
+/// Several keys should be set and read at once, in order.
+pub fn many_keys<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    engine.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key1".to_owned(), "value3".to_owned()),
+    ])?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        engine.get_many(vec![
+            "key2".to_owned(),
+            "key3".to_owned(),
+            "key1".to_owned(),
+            "key2".to_owned(),
+        ])?,
+        vec![
+            Some("value2".to_owned()),
+            None,
+            Some("value3".to_owned()),
+            Some("value2".to_owned()),
+        ]
+    );
+    engine.set_many(Vec::new())?;
+    assert!(engine.get_many(Vec::new())?.is_empty());
+    Ok(())
+}
+
+/// Transactions should apply all their writes if the keys they read are
+/// still at the versions they were read at, and none of them otherwise.
+pub fn transactions<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    let pair = |key: &str, value: Option<&str>| (key.to_owned(), value.map(str::to_owned));
+    let version = |key: &str| -> Result<(String, Option<u64>)> {
+        let version = engine.get_with_version(key.to_owned())?;
+        Ok((key.to_owned(), version.map(|(_, version)| version)))
+    };
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let key1 = version("key1")?;
+    let key2 = version("key2")?;
+    engine.transact(
+        vec![key1.clone(), version("key3")?],
+        vec![
+            pair("key1", None),
+            pair("key3", Some("value3")),
+            // removing a missing key is not an error
+            pair("key4", None),
+        ],
+    )?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    for read in [key1, ("key2".to_owned(), None)] {
+        let key = read.0.clone();
+        let res = engine.transact(vec![read], vec![pair("key2", Some("lost"))]);
+        assert_conflict(res, &key);
+    }
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // a key changed back to the value it was read with changed all the same
+    engine.set("key2".to_owned(), "other".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let res = engine.transact(vec![key2], vec![pair("key2", Some("lost"))]);
+    assert_conflict(res, "key2");
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // a transaction which only reads writes nothing
+    engine.transact(vec![version("key2")?], Vec::new())?;
+    Ok(())
+}
+
+/// Every write of a key should move it to a higher version, and conditional
+/// writes should only apply at the version given.
+pub fn key_versions<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    assert_eq!(engine.get_with_version("key".to_owned())?, None);
+    let first = engine.set_if_version("key".to_owned(), "value1".to_owned(), None)?;
+    assert_eq!(
+        engine.get_with_version("key".to_owned())?,
+        Some(("value1".to_owned(), first))
+    );
+    assert_conflict(
+        engine.set_if_version("key".to_owned(), "lost".to_owned(), None),
+        "key",
+    );
+
+    // writing the same value again still changes the version
+    engine.set("key".to_owned(), "value1".to_owned())?;
+    let (value, second) = engine.get_with_version("key".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert!(second > first);
+    assert_conflict(
+        engine.set_if_version("key".to_owned(), "lost".to_owned(), Some(first)),
+        "key",
+    );
+    let third = engine.set_if_version("key".to_owned(), "value2".to_owned(), Some(second))?;
+    assert!(third > second);
+
+    engine.remove("key".to_owned())?;
+    assert_eq!(engine.get_with_version("key".to_owned())?, None);
+    assert_conflict(
+        engine.set_if_version("key".to_owned(), "lost".to_owned(), Some(third)),
+        "key",
+    );
+    assert!(engine.set_if_version("key".to_owned(), "value3".to_owned(), None)? > third);
+    Ok(())
+}
+
+/// Writes from many threads should all be visible afterwards.
+pub fn concurrent_set<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+
+    let barrier = Arc::new(Barrier::new(CONCURRENCY));
+    let handles: Vec<_> = (0..CONCURRENCY)
+        .map(|i| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                engine
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..CONCURRENCY {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+/// Reads from many threads should all see the stored values.
+pub fn concurrent_get<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    for i in 0..CONCURRENCY {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let handles: Vec<_> = (0..CONCURRENCY)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..CONCURRENCY {
+                    let key_id = (i + thread_id) % CONCURRENCY;
+                    assert_eq!(
+                        engine.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+/// Stored and overwritten values should survive reopening the engine.
+pub fn reopen_keeps_values<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    // the reopened engine keeps accepting writes
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    drop(engine);
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+/// Removed keys should stay removed after reopening the engine.
+pub fn reopen_keeps_removals<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_key_not_found(engine.remove("key1".to_owned()));
+    Ok(())
+}
+
+/// Concurrent writes should all survive reopening the engine.
+pub fn reopen_after_concurrent_set<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = open(dir.path())?;
+    let handles: Vec<_> = (0..CONCURRENCY)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                engine
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(engine);
+
+    let engine = open(dir.path())?;
+    for i in 0..CONCURRENCY {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+fn assert_conflict<T: std::fmt::Debug>(res: Result<T>, expected: &str) {
+    match res {
+        Err(KvsError::Conflict(key)) => assert_eq!(key, expected),
+        Err(e) => panic!("expected KvsError::Conflict, got error: {}", e),
+        Ok(value) => panic!("expected KvsError::Conflict, got Ok({:?})", value),
+    }
+}
+
+fn assert_key_not_found(res: Result<()>) {
+    match res {
+        Err(KvsError::KeyNotFound) => {}
+        Err(e) => panic!("expected KvsError::KeyNotFound, got error: {}", e),
+        Ok(()) => panic!("expected KvsError::KeyNotFound, got Ok"),
+    }
+}
+
+/// Generate a `#[test]` for every check of the conformance suite.
+///
+/// The argument is an expression of type `Fn(&Path) -> Result<E>` opening the
+/// engine under test. Prefix it with `persistent` to also run the checks that
+/// reopen the engine on the same directory. The checks of optional
+/// operations only run for the capabilities listed after the expression:
+///
+/// - `scan`: `KvsEngine::scan`
+/// - `transactions`: `KvsEngine::transact`, along with
+///   `KvsEngine::get_with_version` to read the versions it checks
+/// - `versions`: `KvsEngine::get_with_version` and `KvsEngine::set_if_version`
+///
+/// Invoke the macro inside its own module since the generated tests are named
+/// after the checks.
+#[macro_export]
+macro_rules! engine_conformance_tests {
+    (persistent $open:expr $(, $cap:ident)*) => {
+        $crate::engine_conformance_tests!($open $(, $cap)*);
+        $crate::engine_conformance_tests!(@tests $open;
+            reopen_keeps_values,
+            reopen_keeps_removals,
+            reopen_after_concurrent_set
+        );
+    };
+    (@tests $open:expr; $($check:ident),*) => {
+        $(
+            #[test]
+            fn $check() -> $crate::Result<()> {
+                $crate::conformance::$check($open)
+            }
+        )*
+    };
+    (@cap $open:expr; scan) => {
+        $crate::engine_conformance_tests!(@tests $open; scan_keys);
+    };
+    (@cap $open:expr; transactions) => {
+        $crate::engine_conformance_tests!(@tests $open; transactions);
+    };
+    (@cap $open:expr; versions) => {
+        $crate::engine_conformance_tests!(@tests $open; key_versions);
+    };
+    ($open:expr $(, $cap:ident)*) => {
+        $crate::engine_conformance_tests!(@tests $open;
+            get_stored_value,
+            overwrite_value,
+            get_non_existent_value,
+            remove_key,
+            remove_non_existent_key,
+            clones_share_data,
+            many_keys,
+            concurrent_set,
+            concurrent_get
+        );
+        $($crate::engine_conformance_tests!(@cap $open; $cap);)*
+    };
+}
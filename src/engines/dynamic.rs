@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::ChangeSink;
+use super::{KvStore, MemoryKvsEngine, SledKvsEngine};
+use crate::{ChangeEvent, KvsEngine, KvsError, Result, Snapshot};
+
+// Declares `DynKvsEngine` with the given methods, and forwards each of them
+// from every `KvsEngine` to `DynKvsEngine`, and back from `BoxedKvsEngine`,
+// so that a method listed once cannot be missed by one of the three. The
+// body of a provided method is its default.
+macro_rules! dyn_kvs_engine {
+    (
+        required:
+        $(
+            $(#[$rattr:meta])*
+            fn $rname:ident(&$rself:ident $(, $rarg:ident: $rty:ty)* $(,)?) -> $rret:ty;
+        )*
+        provided:
+        $(
+            $(#[$pattr:meta])*
+            fn $pname:ident(&$pself:ident $(, $parg:ident: $pty:ty)* $(,)?) -> $pret:ty
+            $default:block
+        )*
+    ) => {
+        /// Object-safe counterpart of `KvsEngine`.
+        ///
+        /// `KvsEngine` requires `Clone`, which rules out `dyn KvsEngine`. Every
+        /// `KvsEngine` that is also `Sync` implements this trait, and engines
+        /// that cannot be cloned may implement it directly. Wrap it in a
+        /// `BoxedKvsEngine` to use it wherever a `KvsEngine` is expected.
+        pub trait DynKvsEngine: Send + Sync + 'static {
+            $(
+                $(#[$rattr])*
+                fn $rname(&$rself $(, $rarg: $rty)*) -> $rret;
+            )*
+            $(
+                $(#[$pattr])*
+                fn $pname(&$pself $(, $parg: $pty)*) -> $pret $default
+            )*
+        }
+
+        impl<E: KvsEngine + Sync> DynKvsEngine for E {
+            $(
+                fn $rname(&$rself $(, $rarg: $rty)*) -> $rret {
+                    KvsEngine::$rname($rself $(, $rarg)*)
+                }
+            )*
+            $(
+                fn $pname(&$pself $(, $parg: $pty)*) -> $pret {
+                    KvsEngine::$pname($pself $(, $parg)*)
+                }
+            )*
+        }
+
+        impl KvsEngine for BoxedKvsEngine {
+            $(
+                fn $rname(&$rself $(, $rarg: $rty)*) -> $rret {
+                    $rself.0.$rname($($rarg),*)
+                }
+            )*
+            $(
+                fn $pname(&$pself $(, $parg: $pty)*) -> $pret {
+                    $pself.0.$pname($($parg),*)
+                }
+            )*
+        }
+    };
+}
+
+dyn_kvs_engine! {
+    required:
+    /// Set the value of a string key to a value.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the value is not written successfully.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Get the string value of a string key.
+    /// If the key does not exist, return `None`.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the value is not read successfully.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Remove a string key.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the key is not present or
+    /// the value is not read successfully.
+    fn remove(&self, key: String) -> Result<()>;
+
+    provided:
+
+    /// Get the values of several keys, in the order of the keys.
+    ///
+    /// # Error
+    ///
+    /// Return an error if a value is not read successfully.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Set several keys, in order.
+    ///
+    /// # Error
+    ///
+    /// Return an error if a value is not written successfully.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        pairs
+            .into_iter()
+            .try_for_each(|(key, value)| self.set(key, value))
+    }
+
+    /// Get the string value of a string key and its version.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the value is not read successfully.
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        let _ = key;
+        Err(KvsError::Unsupported("versions".to_owned()))
+    }
+
+    /// Set the value of a string key, provided that the key is at the given
+    /// version, or does not exist if `version` is `None`.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::Conflict` if the key is at another version, or an
+    /// error if the value is not written successfully.
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        let _ = (key, value, version);
+        Err(KvsError::Unsupported("versions".to_owned()))
+    }
+
+    /// Apply the writes together, provided that every key read is still at
+    /// the version it was read at.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::Conflict` with the first key whose version changed,
+    /// or an error if a value is not read or written successfully.
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let _ = (reads, writes);
+        Err(KvsError::Unsupported("transactions".to_owned()))
+    }
+
+    /// Make every write so far durable.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the writes cannot be persisted.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get up to `limit` keys following `after` in ascending order.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the keys are not read successfully, or if the
+    /// engine cannot enumerate its keys.
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let _ = (after, limit);
+        Err(KvsError::Unsupported("scan".to_owned()))
+    }
+
+    /// Get up to `limit` changes in sequence order, starting with the change
+    /// numbered `from`.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::HistoryTruncated` if that change is no longer kept,
+    /// or an error if the engine does not keep the history of its changes.
+    fn changes_since(&self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        let _ = (from, limit);
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+    /// Get the sequence number of the latest change.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the engine does not keep the history of its
+    /// changes.
+    fn last_seq(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+    /// Get the identifier of the history of the engine.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the engine does not keep the history of its
+    /// changes.
+    fn history_id(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+    /// Call `sink` with every change committed from now on to a key starting
+    /// with `prefix`, until it returns `false`.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the engine cannot publish its changes.
+    fn watch(&self, prefix: String, sink: ChangeSink) -> Result<()> {
+        let _ = (prefix, sink);
+        Err(KvsError::Unsupported("watch".to_owned()))
+    }
+
+    /// Call `sink` with every change committed to any key, in commit order,
+    /// until it returns `false`, starting with the change numbered `from` of
+    /// the history `epoch`, or return a `Snapshot` without its keys, which
+    /// are then read with `scan`, if the engine no longer has that change.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the engine cannot publish its changes, or if the
+    /// keys are not read successfully.
+    fn replicate(&self, epoch: u64, from: u64, sink: ChangeSink) -> Result<Option<Snapshot>> {
+        let _ = (epoch, from, sink);
+        Err(KvsError::Unsupported("replication".to_owned()))
+    }
+}
+
+/// A `KvsEngine` whose implementation is chosen at runtime.
+///
+/// Clones share the same underlying engine.
+#[derive(Clone)]
+pub struct BoxedKvsEngine(Arc<dyn DynKvsEngine>);
+
+impl BoxedKvsEngine {
+    /// Wrap the given engine.
+    pub fn new<E: DynKvsEngine>(engine: E) -> Self {
+        BoxedKvsEngine(Arc::new(engine))
+    }
+}
+
+impl From<Arc<dyn DynKvsEngine>> for BoxedKvsEngine {
+    fn from(engine: Arc<dyn DynKvsEngine>) -> Self {
+        BoxedKvsEngine(engine)
+    }
+}
+
+type EngineOpener = Box<dyn Fn(&Path) -> Result<BoxedKvsEngine> + Send + Sync>;
+
+/// A set of engines that can be opened by name.
+///
+/// `EngineRegistry::default()` contains the built-in `kvs`, `sled` and
+/// `memory` engines. Additional engines can be registered under new names,
+/// or replace a built-in one by reusing its name.
+///
+/// ```rust
+/// # use kvs::{EngineRegistry, MemoryKvsEngine, Result};
+/// # use std::path::Path;
+/// # fn try_main() -> Result<()> {
+/// let mut registry = EngineRegistry::default();
+/// registry.register("cache", |_: &Path| Ok(MemoryKvsEngine::new()));
+/// let engine = registry.open("cache", Path::new("."))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct EngineRegistry {
+    engines: BTreeMap<String, EngineOpener>,
+}
+
+impl EngineRegistry {
+    /// Create a registry without any engine.
+    pub fn new() -> Self {
+        EngineRegistry {
+            engines: BTreeMap::new(),
+        }
+    }
+
+    /// Register an engine under the given name.
+    ///
+    /// `open` receives the data directory of the server.
+    pub fn register<E, F>(&mut self, name: impl Into<String>, open: F)
+    where
+        E: DynKvsEngine,
+        F: Fn(&Path) -> Result<E> + Send + Sync + 'static,
+    {
+        self.engines.insert(
+            name.into(),
+            Box::new(move |path| open(path).map(BoxedKvsEngine::new)),
+        );
+    }
+
+    /// Open the engine registered under the given name.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::UnknownEngine` if no engine has that name, or any
+    /// error from opening the engine.
+    pub fn open(&self, name: &str, path: &Path) -> Result<BoxedKvsEngine> {
+        match self.engines.get(name) {
+            Some(open) => open(path),
+            None => Err(KvsError::UnknownEngine(name.to_owned())),
+        }
+    }
+
+    /// Return whether an engine is registered under the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.engines.contains_key(name)
+    }
+
+    /// Names of all registered engines, in sorted order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.engines.keys().map(String::as_str)
+    }
+}
+
+impl Default for EngineRegistry {
+    fn default() -> Self {
+        let mut registry = EngineRegistry::new();
+        registry.register("kvs", |path: &Path| KvStore::open(path));
+        registry.register("sled", |path: &Path| SledKvsEngine::new(path));
+        registry.register("memory", |_: &Path| Ok(MemoryKvsEngine::new()));
+        registry
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::ChangeSink;
+use crate::{ChangeEvent, KvsEngine, KvsError, Result, Snapshot};
+
+/// Engine wrapper letting keys expire after a time to live.
+///
+/// Expired keys read as absent right away, and are removed from the wrapped
+/// engine when next accessed or by `remove_expired`. Setting or removing a
+/// key clears its time to live. Times to live are only kept in memory: they
+/// are lost, and their keys kept, when the process stops. Watchers of the
+/// wrapped engine see expired keys removed once they actually are.
+#[derive(Clone)]
+pub struct ExpiringEngine<E: KvsEngine> {
+    engine: E,
+    // writes hold the lock, so that a key and its deadline change together
+    deadlines: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl<E: KvsEngine> ExpiringEngine<E> {
+    /// Wrap an engine, with no key expiring.
+    pub fn new(engine: E) -> Self {
+        ExpiringEngine {
+            engine,
+            deadlines: Arc::default(),
+        }
+    }
+
+    /// Set the value of a key, which expires after `ttl`.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the value is not written successfully.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        self.engine.set(key.clone(), value)?;
+        deadlines.insert(key, Instant::now() + ttl);
+        Ok(())
+    }
+
+    /// Let an existing key expire after `ttl`, replacing its previous time
+    /// to live. Returns `false` if the key does not exist.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the key is not read successfully.
+    pub fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if self.live_value(&mut deadlines, &key)?.is_none() {
+            return Ok(false);
+        }
+        deadlines.insert(key, Instant::now() + ttl);
+        Ok(true)
+    }
+
+    /// Remove every expired key from the wrapped engine. Returns how many
+    /// keys were removed.
+    ///
+    /// # Error
+    ///
+    /// Return an error if a key is not removed successfully; the keys that
+    /// are left are retried by the next call.
+    pub fn remove_expired(&self) -> Result<usize> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let now = Instant::now();
+        let expired: Vec<String> = deadlines
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            remove_present(&self.engine, key.clone())?;
+            deadlines.remove(key);
+        }
+        Ok(expired.len())
+    }
+
+    // Reads a key, removing it instead if it expired.
+    fn live_value(
+        &self,
+        deadlines: &mut HashMap<String, Instant>,
+        key: &str,
+    ) -> Result<Option<String>> {
+        match deadlines.get(key) {
+            Some(&deadline) if deadline <= Instant::now() => {
+                remove_present(&self.engine, key.to_owned())?;
+                deadlines.remove(key);
+                Ok(None)
+            }
+            _ => self.engine.get(key.to_owned()),
+        }
+    }
+
+    // Removes the key from the wrapped engine if it expired.
+    fn remove_if_expired(&self, deadlines: &mut HashMap<String, Instant>, key: &str) -> Result<()> {
+        if deadlines.get(key).is_some_and(|&d| d <= Instant::now()) {
+            remove_present(&self.engine, key.to_owned())?;
+            deadlines.remove(key);
+        }
+        Ok(())
+    }
+}
+
+// Removes a key which may already be gone.
+fn remove_present<E: KvsEngine>(engine: &E, key: String) -> Result<()> {
+    match engine.remove(key) {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        res => res,
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ExpiringEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        self.engine.set(key.clone(), value)?;
+        deadlines.remove(&key);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let expired = {
+            let deadlines = self.deadlines.lock().unwrap();
+            deadlines.get(&key).is_some_and(|&d| d <= Instant::now())
+        };
+        if !expired {
+            return self.engine.get(key);
+        }
+        self.live_value(&mut self.deadlines.lock().unwrap(), &key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if deadlines.contains_key(&key) && self.live_value(&mut deadlines, &key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.engine.remove(key.clone())?;
+        deadlines.remove(&key);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            let now = Instant::now();
+            for key in &keys {
+                if deadlines.get(key).is_some_and(|&d| d <= now) {
+                    remove_present(&self.engine, key.clone())?;
+                    deadlines.remove(key);
+                }
+            }
+        }
+        self.engine.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        self.engine.set_many(pairs)?;
+        for key in &keys {
+            deadlines.remove(key);
+        }
+        Ok(())
+    }
+
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        self.remove_if_expired(&mut deadlines, &key)?;
+        self.engine.get_with_version(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        // an expired key is at no version
+        self.remove_if_expired(&mut deadlines, &key)?;
+        let new_version = self.engine.set_if_version(key.clone(), value, version)?;
+        deadlines.remove(&key);
+        Ok(new_version)
+    }
+
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        // expired keys were read as absent
+        for (key, _) in &reads {
+            self.remove_if_expired(&mut deadlines, key)?;
+        }
+        let keys: Vec<String> = writes.iter().map(|(key, _)| key.clone()).collect();
+        self.engine.transact(reads, writes)?;
+        for key in &keys {
+            deadlines.remove(key);
+        }
+        Ok(())
+    }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut after = after;
+        // pages shrunk by expired keys are topped up from the following ones
+        while keys.len() < limit {
+            let page = self.engine.scan(after, limit - keys.len())?;
+            let exhausted = page.len() < limit - keys.len();
+            after = page.last().cloned();
+            {
+                let deadlines = self.deadlines.lock().unwrap();
+                let now = Instant::now();
+                keys.extend(
+                    page.into_iter()
+                        .filter(|key| deadlines.get(key).is_none_or(|&d| d > now)),
+                );
+            }
+            if exhausted || after.is_none() {
+                break;
+            }
+        }
+        Ok(keys)
+    }
+
+    fn changes_since(&self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        self.engine.changes_since(from, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64> {
+        self.engine.last_seq()
+    }
+
+    fn history_id(&self) -> Result<u64> {
+        self.engine.history_id()
+    }
+
+    fn watch(&self, prefix: String, sink: ChangeSink) -> Result<()> {
+        self.engine.watch(prefix, sink)
+    }
+
+    fn replicate(&self, epoch: u64, from: u64, sink: ChangeSink) -> Result<Option<Snapshot>> {
+        self.engine.replicate(epoch, from, sink)
+    }
+}
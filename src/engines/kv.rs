@@ -1,25 +1,57 @@
-use std::collections::{BTreeMap, HashMap};
-use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
-use std::path::{Path, PathBuf};
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::BufReader;
+use std::ops::{Bound, Range};
+use std::path::PathBuf;
+use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::KvsEngine;
+use crate::storage::{FileStorage, Storage, StorageFile, StorageReader};
+use crate::{ChangeEvent, KvsEngine};
 use crate::{KvsError, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// size of the buffer used to batch appends to the compaction file
+const COMPACTION_BUFFER_SIZE: usize = 64 * 1024;
+// the number of changes `Changes` reads from the log at once
+const CHANGES_PAGE_SIZE: usize = 1024;
+// changes between the offsets kept to seek to a change in a log
+const CHANGES_SEEK_INTERVAL: u64 = 256;
+// how many of the latest changes compactions keep, by default
+const DEFAULT_RETAINED_CHANGES: u64 = 10_000;
+
+// the file holding the identifier of the history
+const HISTORY_ID_FILE: &str = "history.id";
+
+// offsets of some of the changes of a log, by sequence number
+type SeekIndex = BTreeMap<u64, u64>;
 
 /// The `KvStore` stores string key/value pairs.
 ///
-/// Key/value pairs are persisted to disk in log files. Log files are named after
+/// Key/value pairs are persisted to a `Storage` in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A `BTreeMap` in memory stores the keys and the value locations for fast query.
 ///
+/// `KvStore::open` stores the logs in a directory of the local filesystem, while
+/// `KvStore::with_storage` accepts any other `Storage`, e.g. a `MemoryStorage`.
+///
+/// Every change gets a sequence number, one more than the previous change,
+/// which is stored in the log along with it. `changes` reads the changes
+/// back from the log. Compactions drop the history the log holds, except for
+/// the latest changes kept with `set_retained_changes`, which move to
+/// history files named after their first and last sequence numbers. The
+/// history gets a random identifier when the store is created, which tells
+/// it apart from the history of any other store.
+///
+/// The version of a key is the sequence number of the change which last set
+/// it, 0 for keys set before changes had sequence numbers. `set_if_version`
+/// only sets a key still at the version a client read.
+///
 /// ```rust
 /// # use kvs::{KvStore, Result};
 /// # fn try_main() -> Result<()> {
@@ -31,8 +63,8 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// # Ok(())
 /// # }
 /// ```
-pub struct KvStore {
-    data: Arc<Mutex<KvStoreData>>,
+pub struct KvStore<S: Storage = FileStorage> {
+    data: Arc<Mutex<KvStoreData<S>>>,
 }
 
 impl KvStore {
@@ -44,7 +76,18 @@ impl KvStore {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let data = KvStoreData::open(path)?;
+        KvStore::with_storage(FileStorage::new(path)?)
+    }
+}
+
+impl<S: Storage> KvStore<S> {
+    /// Opens a `KvStore` whose log files live in the given storage.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn with_storage(storage: S) -> Result<KvStore<S>> {
+        let data = KvStoreData::open(storage)?;
         Ok(KvStore {
             data: Arc::new(Mutex::new(data)),
         })
@@ -70,6 +113,54 @@ impl KvStore {
         data.get(key)
     }
 
+    /// Gets the string value of a given string key and its version.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        let mut data = self.data.lock().unwrap();
+        data.get_with_version(key)
+    }
+
+    /// Sets the value of a string key to a string, provided that the key is
+    /// at the given version, or does not exist if `version` is `None`.
+    ///
+    /// Returns the new version of the key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Conflict` if the key is at another version.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        let mut data = self.data.lock().unwrap();
+        if data.index.get(&key).map(|cmd_pos| cmd_pos.seq) != version {
+            return Err(KvsError::Conflict(key));
+        }
+        data.set(key, value)?;
+        Ok(data.last_seq)
+    }
+
+    /// Gets the values of several keys, in the order of the keys, under a
+    /// single lock.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut data = self.data.lock().unwrap();
+        keys.into_iter().map(|key| data.get(key)).collect()
+    }
+
+    /// Sets several keys, in order, under a single lock so that no other
+    /// write comes in between.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log, in
+    /// which case the keys before the failure are set.
+    pub fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        pairs
+            .into_iter()
+            .try_for_each(|(key, value)| data.set(key, value))
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
@@ -81,9 +172,130 @@ impl KvStore {
         let mut data = self.data.lock().unwrap();
         data.remove(key)
     }
+
+    /// Applies the writes, a `None` value removing its key if present,
+    /// provided that every key read is still at the version it was read at.
+    /// The check and the writes happen under a single lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Conflict` with the first key whose version
+    /// changed, in which case nothing is written.
+    ///
+    /// It propagates I/O or serialization errors during writing the log, in
+    /// which case the writes before the failure are applied.
+    pub fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        for (key, version) in reads {
+            if data.index.get(&key).map(|cmd_pos| cmd_pos.seq) != version {
+                return Err(KvsError::Conflict(key));
+            }
+        }
+        writes.into_iter().try_for_each(|(key, value)| match value {
+            Some(value) => data.set(key, value),
+            None if data.index.contains_key(&key) => data.remove(key),
+            None => Ok(()),
+        })
+    }
+
+    /// Gets up to `limit` keys following `after` in ascending order, or the
+    /// first keys if `after` is `None`.
+    pub fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let data = self.data.lock().unwrap();
+        let start = after.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(data
+            .index
+            .range::<String, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    /// Gets up to `limit` changes in sequence order, starting with the
+    /// change numbered `from`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::HistoryTruncated` if `from` precedes
+    /// `first_retained_seq`.
+    ///
+    /// It propagates I/O or deserialization errors during reading the log.
+    pub fn changes_since(&self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        let mut data = self.data.lock().unwrap();
+        data.changes_since(from, limit)
+    }
+
+    /// Iterates over the changes in sequence order, starting with the change
+    /// numbered `from` and ending with the latest one.
+    ///
+    /// The changes are read from the log a page at a time, so changes made
+    /// while iterating are included. `Changes::next_seq` tells where to
+    /// resume once the iteration has ended.
+    pub fn changes(&self, from: u64) -> Changes<S> {
+        Changes {
+            store: self.clone(),
+            next_seq: from,
+            page: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// Returns the sequence number of the latest change, 0 before the first
+    /// one.
+    pub fn last_seq(&self) -> u64 {
+        self.data.lock().unwrap().last_seq
+    }
+
+    /// Returns the random identifier of the history of the store, kept
+    /// along with its files.
+    pub fn history_id(&self) -> u64 {
+        self.data.lock().unwrap().history_id
+    }
+
+    /// Returns the sequence number of the oldest change still in the log or
+    /// in the history files; changes from there on can be read.
+    pub fn first_retained_seq(&self) -> u64 {
+        self.data.lock().unwrap().first_retained_seq()
+    }
+
+    /// Sets how many of the latest changes compactions keep readable, as
+    /// history files, at least. Defaults to 10000; 0 keeps only the changes
+    /// made since the last compaction.
+    ///
+    /// The setting is not persisted: stores opened again start with the
+    /// default.
+    pub fn set_retained_changes(&self, changes: u64) {
+        self.data.lock().unwrap().retained_changes = changes;
+    }
+
+    /// Compacts the log right away instead of waiting for enough stale
+    /// entries to accumulate.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during rewriting the log.
+    pub fn compact(&self) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.compact()
+    }
+
+    /// Syncs the current log to the storage, so that every write so far
+    /// survives a crash of the machine.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during syncing the log.
+    pub fn flush(&self) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.flush()
+    }
 }
 
-impl KvsEngine for KvStore {
+impl<S: Storage> KvsEngine for KvStore<S> {
     fn set(&self, key: String, value: String) -> Result<()> {
         self.set(key, value)
     }
@@ -92,12 +304,56 @@ impl KvsEngine for KvStore {
         self.get(key)
     }
 
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.get_with_version(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        self.set_if_version(key, value, version)
+    }
+
     fn remove(&self, key: String) -> Result<()> {
         self.remove(key)
     }
+
+    fn flush(&self) -> Result<()> {
+        self.flush()
+    }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.scan(after, limit)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.set_many(pairs)
+    }
+
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        self.transact(reads, writes)
+    }
+
+    fn changes_since(&self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        self.changes_since(from, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64> {
+        Ok(KvStore::last_seq(self))
+    }
+
+    fn history_id(&self) -> Result<u64> {
+        Ok(KvStore::history_id(self))
+    }
 }
 
-impl Clone for KvStore {
+impl<S: Storage> Clone for KvStore<S> {
     fn clone(&self) -> Self {
         KvStore {
             data: self.data.clone(),
@@ -105,59 +361,139 @@ impl Clone for KvStore {
     }
 }
 
-struct KvStoreData {
-    // directory for the log and other data
-    path: PathBuf,
-    // map generation number to the file reader
-    readers: HashMap<u64, BufReaderWithPos<File>>,
-    // writer of the current log
-    writer: BufWriterWithPos<File>,
+/// Iterator over the changes of a `KvStore`, created by `KvStore::changes`.
+pub struct Changes<S: Storage = FileStorage> {
+    store: KvStore<S>,
+    next_seq: u64,
+    page: VecDeque<ChangeEvent>,
+    // iteration ends after an error
+    failed: bool,
+}
+
+impl<S: Storage> Changes<S> {
+    /// Returns the sequence number of the next change to return.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+impl<S: Storage> Iterator for Changes<S> {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Result<ChangeEvent>> {
+        if self.page.is_empty() && !self.failed {
+            match self.store.changes_since(self.next_seq, CHANGES_PAGE_SIZE) {
+                Ok(page) => self.page = page.into(),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        let change = self.page.pop_front()?;
+        self.next_seq = change.seq + 1;
+        Some(Ok(change))
+    }
+}
+
+struct KvStoreData<S: Storage> {
+    storage: S,
+    // map generation number to the log file
+    files: HashMap<u64, S::File>,
+    // generation of the log new commands are appended to
     current_gen: u64,
     index: BTreeMap<String, CommandPos>,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // sequence number of the latest change
+    last_seq: u64,
+    // first and last sequence numbers of the changes in each log holding
+    // some; compacted logs are left out, their changes are not in order
+    log_seqs: BTreeMap<u64, (u64, u64)>,
+    // seek index of each log in `log_seqs`
+    log_seeks: HashMap<u64, SeekIndex>,
+    // last sequence number of each history file, by its first one
+    history: BTreeMap<u64, u64>,
+    // seek index of each history file, by its first sequence number, built
+    // when first read for the files of previous runs
+    history_seeks: HashMap<u64, SeekIndex>,
+    // sequence number of the latest change the latest compaction covers
+    compacted_seq: u64,
+    // how many of the latest changes compactions keep as history
+    retained_changes: u64,
+    history_id: u64,
 }
 
-impl KvStoreData {
-    fn open(path: impl Into<PathBuf>) -> Result<KvStoreData> {
-        let path = path.into();
-        fs::create_dir_all(&path)?;
+impl<S: Storage> KvStoreData<S> {
+    fn open(storage: S) -> Result<KvStoreData<S>> {
+        // a compaction file that was never renamed is an unfinished compaction
+        for name in storage.list()? {
+            if name.ends_with(".compact") {
+                storage.delete(&name)?;
+            }
+        }
 
-        let mut readers = HashMap::new();
+        let mut files = HashMap::new();
         let mut index = BTreeMap::new();
 
-        let gen_list = sorted_gen_list(&path)?;
+        let gen_list = sorted_gen_list(&storage)?;
         let mut uncompacted = 0;
+        let mut log_seqs = BTreeMap::new();
+        let mut log_seeks = HashMap::new();
+        let mut compacted_seq = 0;
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
+            let mut file = storage.open(&log_name(gen))?;
+            let loaded = load(gen, &mut file, &mut index)?;
+            uncompacted += loaded.uncompacted;
+            match loaded.compacted_seq {
+                Some(seq) => compacted_seq = compacted_seq.max(seq),
+                None => {
+                    if let Some(seqs) = loaded.seqs {
+                        log_seqs.insert(gen, seqs);
+                        log_seeks.insert(gen, loaded.seeks);
+                    }
+                }
+            }
+            files.insert(gen, file);
         }
-
+        let history = history_list(&storage)?;
+        let history_id = load_history_id(&storage)?;
+
+        let last_seq = log_seqs
+            .values()
+            .map(|&(_, last)| last)
+            .chain(history.values().cloned())
+            .fold(compacted_seq, u64::max);
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        files.insert(current_gen, storage.open(&log_name(current_gen))?);
 
         Ok(KvStoreData {
-            path,
-            readers,
-            writer,
+            storage,
+            files,
             current_gen,
             index,
             uncompacted,
+            last_seq,
+            log_seqs,
+            log_seeks,
+            history,
+            history_seeks: HashMap::new(),
+            compacted_seq,
+            retained_changes: DEFAULT_RETAINED_CHANGES,
+            history_id,
         })
     }
 
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
+        let cmd = Command::set(key, value, self.last_seq + 1);
+        let range = self.append(&cmd)?;
+        self.changed(range.start);
+        if let Command::Set { key, seq, .. } = cmd {
             if let Some(old_cmd) = self
                 .index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
+                .insert(key, (self.current_gen, range, seq).into())
             {
                 self.uncompacted += old_cmd.len;
             }
@@ -170,15 +506,19 @@ impl KvStoreData {
     }
 
     fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_with_version(key)?.map(|(value, _)| value))
+    }
+
+    fn get_with_version(&mut self, key: String) -> Result<Option<(String, u64)>> {
         if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
+            let file = self
+                .files
                 .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
+                .expect("Cannot find log file");
+            let mut buf = vec![0; cmd_pos.len as usize];
+            file.read_exact_at(cmd_pos.pos, &mut buf)?;
+            if let Command::Set { value, seq, .. } = serde_json::from_slice(&buf)? {
+                Ok(Some((value, seq)))
             } else {
                 Err(KvsError::UnexpectedCommandType)
             }
@@ -189,10 +529,10 @@ impl KvStoreData {
 
     fn remove(&mut self, key: String) -> Result<()> {
         if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
+            let cmd = Command::remove(key, self.last_seq + 1);
+            let range = self.append(&cmd)?;
+            self.changed(range.start);
+            if let Command::Remove { key, .. } = cmd {
                 let old_cmd = self.index.remove(&key).expect("key not found");
                 self.uncompacted += old_cmd.len;
             }
@@ -202,223 +542,397 @@ impl KvStoreData {
         }
     }
 
+    /// Serializes the command to the end of the current log.
+    ///
+    /// Returns the range the command occupies in the log.
+    fn append(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        let buf = serde_json::to_vec(cmd)?;
+        let file = self
+            .files
+            .get_mut(&self.current_gen)
+            .expect("Cannot find current log file");
+        let pos = file.append(&buf)?;
+        Ok(pos..pos + buf.len() as u64)
+    }
+
+    // Counts the change just appended to the current log at `pos`.
+    fn changed(&mut self, pos: u64) {
+        self.last_seq += 1;
+        let seq = self.last_seq;
+        self.log_seqs
+            .entry(self.current_gen)
+            .and_modify(|(_, last)| *last = seq)
+            .or_insert((seq, seq));
+        add_seek(
+            self.log_seeks.entry(self.current_gen).or_default(),
+            seq,
+            pos,
+        );
+    }
+
+    fn first_retained_seq(&self) -> u64 {
+        match self.history.keys().next() {
+            Some(&first) => first,
+            None => self.compacted_seq + 1,
+        }
+    }
+
+    fn changes_since(&mut self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        let first = self.first_retained_seq();
+        if from < first {
+            return Err(KvsError::HistoryTruncated(first));
+        }
+        let mut changes = Vec::new();
+        // history files hold the changes preceding those of the logs
+        for (&first, &last) in &self.history {
+            if last >= from && changes.len() < limit {
+                let mut file = self.storage.open(&history_name(first, last))?;
+                let seeks = match self.history_seeks.entry(first) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(load_seeks(&mut file)?),
+                };
+                read_changes(&mut file, seek(seeks, from), from, limit, &mut changes)?;
+            }
+        }
+        for (gen, &(_, last)) in &self.log_seqs {
+            if last >= from && changes.len() < limit {
+                let file = self.files.get_mut(gen).expect("Cannot find log file");
+                let pos = self.log_seeks.get(gen).map_or(0, |seeks| seek(seeks, from));
+                read_changes(file, pos, from, limit, &mut changes)?;
+            }
+        }
+        Ok(changes)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.files
+            .get_mut(&self.current_gen)
+            .expect("Cannot find current log file")
+            .sync()?;
+        Ok(())
+    }
+
     /// Clears stale entries in the log.
+    ///
+    /// Live entries are copied to a temporary file which is renamed to its
+    /// final log name only once it is complete, so an interrupted compaction
+    /// never leaves a partial log behind.
     fn compact(&mut self) -> Result<()> {
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
-
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
-
-        let mut new_pos = 0; // pos in the new log file
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
+        let current_file = self.storage.open(&log_name(self.current_gen))?;
+        self.files.insert(self.current_gen, current_file);
+
+        let compaction_name = format!("{}.compact", log_name(compaction_gen));
+        let mut compaction_file = self.storage.open(&compaction_name)?;
+
+        let mut new_positions = Vec::with_capacity(self.index.len());
+        let mut buf = Vec::with_capacity(COMPACTION_BUFFER_SIZE);
+        // the compacted log opens with the changes it covers
+        serde_json::to_writer(&mut buf, &Command::Compacted { seq: self.last_seq })?;
+        let mut new_pos = buf.len() as u64; // pos in the new log file
+        for cmd_pos in self.index.values() {
+            let file = self
+                .files
                 .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+                .expect("Cannot find log file");
+            let start = buf.len();
+            buf.resize(start + cmd_pos.len as usize, 0);
+            file.read_exact_at(cmd_pos.pos, &mut buf[start..])?;
+            new_positions.push(new_pos..new_pos + cmd_pos.len);
+            new_pos += cmd_pos.len;
+
+            if buf.len() >= COMPACTION_BUFFER_SIZE {
+                compaction_file.append(&buf)?;
+                buf.clear();
             }
-
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+        }
+        compaction_file.append(&buf)?;
+        compaction_file.sync()?;
+        drop(compaction_file);
+
+        self.storage
+            .rename(&compaction_name, &log_name(compaction_gen))?;
+        let compaction_file = self.storage.open(&log_name(compaction_gen))?;
+        self.files.insert(compaction_gen, compaction_file);
+
+        for (cmd_pos, range) in self.index.values_mut().zip(new_positions) {
+            cmd_pos.gen = compaction_gen;
+            cmd_pos.pos = range.start;
         }
 
         let stale_gens: Vec<_> = self
-            .readers
+            .files
             .keys()
             .filter(|&&gen| gen < compaction_gen)
             .cloned()
             .collect();
 
+        // the latest changes are kept as history, older ones are dropped
+        let retained_from = self.last_seq.saturating_sub(self.retained_changes) + 1;
+        let stale_history: Vec<_> = self
+            .history
+            .iter()
+            .filter(|&(_, &last)| last < retained_from)
+            .map(|(&first, &last)| (first, last))
+            .collect();
+        for (first, last) in stale_history {
+            self.history.remove(&first);
+            self.history_seeks.remove(&first);
+            self.storage.delete(&history_name(first, last))?;
+        }
         for stale_gen in stale_gens {
-            compaction_writer.flush()?;
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+            self.files.remove(&stale_gen);
+            let seeks = self.log_seeks.remove(&stale_gen);
+            match self.log_seqs.remove(&stale_gen) {
+                Some((first, last)) if last >= retained_from => {
+                    self.storage
+                        .rename(&log_name(stale_gen), &history_name(first, last))?;
+                    self.history.insert(first, last);
+                    if let Some(seeks) = seeks {
+                        self.history_seeks.insert(first, seeks);
+                    }
+                }
+                _ => self.storage.delete(&log_name(stale_gen))?,
+            }
         }
 
+        self.compacted_seq = self.last_seq;
         self.uncompacted = 0;
         Ok(())
     }
-
-    /// Create a new log file with given generation number and add the reader to the readers map.
-    ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
-    }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
-///
-/// Returns the writer to the log.
-fn new_log_file(
-    path: &Path,
-    gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
-    Ok(writer)
-}
-
-/// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
+/// Returns sorted generation numbers in the given storage
+fn sorted_gen_list<S: Storage>(storage: &S) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = storage
+        .list()?
+        .iter()
+        .filter(|name| name.ends_with(".log"))
+        .flat_map(|name| name.trim_end_matches(".log").parse::<u64>())
         .collect();
     gen_list.sort_unstable();
     Ok(gen_list)
 }
 
+/// Returns the first and last sequence numbers of the history files in the
+/// given storage
+fn history_list<S: Storage>(storage: &S) -> Result<BTreeMap<u64, u64>> {
+    Ok(storage
+        .list()?
+        .iter()
+        .filter_map(|name| {
+            let (first, last) = name.strip_suffix(".history")?.split_once('-')?;
+            Some((first.parse().ok()?, last.parse().ok()?))
+        })
+        .collect())
+}
+
+/// Returns the identifier of the history of the given storage, creating it
+/// for a storage without one, e.g. a new one.
+fn load_history_id<S: Storage>(storage: &S) -> Result<u64> {
+    let mut file = storage.open(HISTORY_ID_FILE)?;
+    let mut buf = vec![0; file.size() as usize];
+    file.read_exact_at(0, &mut buf)?;
+    if let Some(id) = String::from_utf8(buf).ok().and_then(|id| id.parse().ok()) {
+        return Ok(id);
+    }
+    // the standard library draws random keys for its hash maps
+    let mut hasher = RandomState::new().build_hasher();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    hasher.write_u128(now);
+    hasher.write_u32(process::id());
+    let id = hasher.finish().max(1);
+    // a partly written identifier is never read back
+    let new_name = format!("{}.new", HISTORY_ID_FILE);
+    if storage.list()?.contains(&new_name) {
+        storage.delete(&new_name)?;
+    }
+    let mut new_file = storage.open(&new_name)?;
+    new_file.append(id.to_string().as_bytes())?;
+    new_file.sync()?;
+    storage.rename(&new_name, HISTORY_ID_FILE)?;
+    Ok(id)
+}
+
+/// What loading a log file found out.
+struct Loaded {
+    /// How many bytes can be saved after a compaction
+    uncompacted: u64,
+    /// First and last sequence numbers of the changes in the log, if any
+    seqs: Option<(u64, u64)>,
+    /// Seek index of the changes in the log
+    seeks: SeekIndex,
+    /// The sequence number of the latest change covered, for a compacted log
+    compacted_seq: Option<u64>,
+}
+
 /// Load the whole log file and store value locations in the index map.
-///
-/// Returns how many bytes can be saved after a compaction.
-fn load(
+fn load<F: StorageFile>(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
+    file: &mut F,
     index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+) -> Result<Loaded> {
+    let reader = BufReader::new(StorageReader::new(file));
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
+    let mut pos = 0;
+    let mut loaded = Loaded {
+        uncompacted: 0,
+        seqs: None,
+        seeks: SeekIndex::new(),
+        compacted_seq: None,
+    };
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
+        let seq = match cmd? {
+            Command::Set { key, seq, .. } => {
+                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos, seq).into()) {
+                    loaded.uncompacted += old_cmd.len;
                 }
+                seq
             }
-            Command::Remove { key } => {
+            Command::Remove { key, seq } => {
                 if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+                    loaded.uncompacted += old_cmd.len;
                 }
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+                loaded.uncompacted += new_pos - pos;
+                seq
+            }
+            Command::Compacted { seq } => {
+                loaded.compacted_seq = Some(seq);
+                0
             }
+        };
+        // commands written before sequence numbers have 0
+        if seq > 0 {
+            loaded.seqs = Some(loaded.seqs.map_or((seq, seq), |(first, _)| (first, seq)));
+            add_seek(&mut loaded.seeks, seq, pos);
         }
         pos = new_pos;
     }
-    Ok(uncompacted)
-}
-
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
-}
-
-/// Struct representing a command
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Ok(loaded)
 }
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
-    }
-
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+/// Keep the offset of the change if it is the first of its log, or every
+/// `CHANGES_SEEK_INTERVAL` changes.
+fn add_seek(seeks: &mut SeekIndex, seq: u64, pos: u64) {
+    if seeks.is_empty() || seq.is_multiple_of(CHANGES_SEEK_INTERVAL) {
+        seeks.insert(seq, pos);
     }
 }
 
-/// Represents the position and length of a json-serialized command in the log
-struct CommandPos {
-    gen: u64,
-    pos: u64,
-    len: u64,
+/// The offset to read a log from to find the change numbered `from`.
+fn seek(seeks: &SeekIndex, from: u64) -> u64 {
+    seeks.range(..=from).next_back().map_or(0, |(_, &pos)| pos)
 }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
-        CommandPos {
-            gen,
-            pos: range.start,
-            len: range.end - range.start,
+/// Build the seek index of a log file.
+fn load_seeks<F: StorageFile>(file: &mut F) -> Result<SeekIndex> {
+    let reader = BufReader::new(StorageReader::new(file));
+    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut seeks = SeekIndex::new();
+    let mut pos = 0;
+    while let Some(cmd) = stream.next() {
+        match cmd? {
+            Command::Set { seq, .. } | Command::Remove { seq, .. } if seq > 0 => {
+                add_seek(&mut seeks, seq, pos)
+            }
+            _ => {}
         }
+        pos = stream.byte_offset() as u64;
     }
+    Ok(seeks)
 }
 
-struct BufReaderWithPos<R: Read + Seek> {
-    reader: BufReader<R>,
+/// Append the changes of the log file numbered from `from` on to `changes`,
+/// until it holds `limit` of them, reading from the offset `pos`.
+fn read_changes<F: StorageFile>(
+    file: &mut F,
     pos: u64,
-}
-
-impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
-        Ok(BufReaderWithPos {
-            reader: BufReader::new(inner),
-            pos,
-        })
+    from: u64,
+    limit: usize,
+    changes: &mut Vec<ChangeEvent>,
+) -> Result<()> {
+    let reader = BufReader::new(StorageReader::at(file, pos));
+    for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+        if changes.len() >= limit {
+            break;
+        }
+        match cmd? {
+            Command::Set { key, value, seq } if seq >= from => changes.push(ChangeEvent {
+                seq,
+                key,
+                value: Some(value),
+            }),
+            Command::Remove { key, seq } if seq >= from => changes.push(ChangeEvent {
+                seq,
+                key,
+                value: None,
+            }),
+            _ => {}
+        }
     }
+    Ok(())
 }
 
-impl<R: Read + Seek> Read for BufReaderWithPos<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.reader.read(buf)
-    }
+fn log_name(gen: u64) -> String {
+    format!("{}.log", gen)
 }
 
-impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.reader.seek(pos)?;
-        Ok(self.pos)
-    }
+fn history_name(first: u64, last: u64) -> String {
+    format!("{}-{}.history", first, last)
 }
 
-struct BufWriterWithPos<W: Write + Seek> {
-    writer: BufWriter<W>,
-    pos: u64,
+/// Struct representing a command
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    Remove {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    /// Opens a compacted log, whose commands are the live ones up to the
+    /// change numbered `seq`, out of order.
+    Compacted { seq: u64 },
 }
 
-impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
-        Ok(BufWriterWithPos {
-            writer: BufWriter::new(inner),
-            pos,
-        })
+impl Command {
+    fn set(key: String, value: String, seq: u64) -> Command {
+        Command::Set { key, value, seq }
     }
-}
 
-impl<W: Write + Seek> Write for BufWriterWithPos<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let len = self.writer.write(buf)?;
-        self.pos += len as u64;
-        Ok(len)
+    fn remove(key: String, seq: u64) -> Command {
+        Command::Remove { key, seq }
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
-    }
+/// Represents the position and length of a json-serialized command in the log,
+/// and the sequence number it carries: the version of its key
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    seq: u64,
 }
 
-impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
-        Ok(self.pos)
+impl From<(u64, Range<u64>, u64)> for CommandPos {
+    fn from((gen, range, seq): (u64, Range<u64>, u64)) -> Self {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            seq,
+        }
     }
 }
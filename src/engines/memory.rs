@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// Key/value storage backend that keeps everything in memory.
+///
+/// Nothing is persisted, so all data is lost once the last clone is dropped.
+/// Readers only take a shared lock and do not block each other.
+///
+/// Every write gets the next version of the engine, which the key keeps
+/// until its next write.
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    data: Arc<RwLock<MemoryData>>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    // every key, its value and its version
+    map: BTreeMap<String, (String, u64)>,
+    last_version: u64,
+}
+
+impl MemoryData {
+    fn set(&mut self, key: String, value: String) -> u64 {
+        self.last_version += 1;
+        self.map.insert(key, (value, self.last_version));
+        self.last_version
+    }
+}
+
+impl MemoryKvsEngine {
+    /// Create a new, empty in-memory engine.
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.data.write().unwrap().set(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let data = self.data.read().unwrap();
+        Ok(data.map.get(&key).map(|(value, _)| value.clone()))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.data
+            .write()
+            .unwrap()
+            .map
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let data = self.data.read().unwrap();
+        Ok(keys
+            .iter()
+            .map(|key| data.map.get(key).map(|(value, _)| value.clone()))
+            .collect())
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        for (key, value) in pairs {
+            data.set(key, value);
+        }
+        Ok(())
+    }
+
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        Ok(self.data.read().unwrap().map.get(&key).cloned())
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        let mut data = self.data.write().unwrap();
+        if data.map.get(&key).map(|(_, version)| *version) != version {
+            return Err(KvsError::Conflict(key));
+        }
+        Ok(data.set(key, value))
+    }
+
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        if let Some((key, _)) = reads
+            .into_iter()
+            .find(|(key, version)| data.map.get(key).map(|(_, version)| *version) != *version)
+        {
+            return Err(KvsError::Conflict(key));
+        }
+        for (key, value) in writes {
+            match value {
+                Some(value) => {
+                    data.set(key, value);
+                }
+                None => {
+                    data.map.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = after.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(self
+            .data
+            .read()
+            .unwrap()
+            .map
+            .range::<String, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
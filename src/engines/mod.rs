@@ -1,9 +1,25 @@
-pub use self::kv::KvStore;
+use std::collections::HashSet;
+
+pub use self::dynamic::{BoxedKvsEngine, DynKvsEngine, EngineRegistry};
+pub use self::expiring::ExpiringEngine;
+pub use self::kv::{Changes, KvStore};
+pub use self::memory::MemoryKvsEngine;
+pub use self::read_only::ReadOnlyEngine;
 pub use self::sled::SledKvsEngine;
-use crate::Result;
+pub use self::watched::WatchedEngine;
+use crate::{ChangeEvent, KvsError, Result, Snapshot};
 
+mod dynamic;
+mod expiring;
 mod kv;
+mod memory;
+mod read_only;
 mod sled;
+mod watched;
+
+/// Receiver of the changes to watched keys. It returns `false` once it no
+/// longer wants any change.
+pub type ChangeSink = Box<dyn Fn(ChangeEvent) -> bool + Send>;
 
 /// Define the storage interface for a key/value engine.
 pub trait KvsEngine: Clone + Send + 'static {
@@ -29,4 +45,221 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Return an error if the key is not present or
     /// the value is not read successfully.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Get the values of several keys, in the order of the keys.
+    ///
+    /// The default gets the keys one by one; engines override it to read
+    /// them together.
+    ///
+    /// # Error
+    ///
+    /// Return an error if a value is not read successfully.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Set several keys, in order, so that a key given twice ends up with
+    /// its last value.
+    ///
+    /// The default sets the keys one by one; engines override it to write
+    /// them together.
+    ///
+    /// # Error
+    ///
+    /// Return an error if a value is not written successfully, in which case
+    /// the values before it may be written.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        pairs
+            .into_iter()
+            .try_for_each(|(key, value)| self.set(key, value))
+    }
+
+    /// Get the string value of a string key and its version, which every
+    /// write of the key increases. If the key does not exist, return `None`.
+    ///
+    /// Engines that do not track versions keep the default, which fails.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the value is not read successfully.
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        let _ = key;
+        Err(KvsError::Unsupported("versions".to_owned()))
+    }
+
+    /// Set the value of a string key, provided that the key is at the given
+    /// version, or does not exist if `version` is `None`. Returns the new
+    /// version of the key.
+    ///
+    /// Like `get_with_version`, the default fails.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::Conflict` if the key is at another version, or an
+    /// error if the value is not written successfully.
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        let _ = (key, value, version);
+        Err(KvsError::Unsupported("versions".to_owned()))
+    }
+
+    /// Apply the writes together, provided that every key read is still at
+    /// the version `get_with_version` gave, `None` standing for a missing
+    /// key. A write of `None` removes the key, if present.
+    ///
+    /// Comparing versions rather than values catches a key changed and then
+    /// changed back since it was read. Engines that cannot check and write
+    /// atomically keep the default, which fails.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::Conflict` with the first key whose version changed,
+    /// in which case nothing is written, or an error if a value is not read
+    /// or written successfully.
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let _ = (reads, writes);
+        Err(KvsError::Unsupported("transactions".to_owned()))
+    }
+
+    /// Make every write so far durable, e.g. before shutting down.
+    ///
+    /// Engines that persist each write right away keep the default, which
+    /// does nothing.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the writes cannot be persisted.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get up to `limit` keys following `after` in ascending order, or the
+    /// first keys if `after` is `None`.
+    ///
+    /// Engines that cannot enumerate their keys keep the default, which
+    /// fails.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the keys are not read successfully.
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let _ = (after, limit);
+        Err(KvsError::Unsupported("scan".to_owned()))
+    }
+
+    /// Get up to `limit` of the changes the engine keeps a history of, in
+    /// sequence order, starting with the change numbered `from`.
+    ///
+    /// Engines that do not keep the history of their changes keep the
+    /// default, which fails.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::HistoryTruncated` if the change numbered `from` is
+    /// no longer kept, or an error if the changes are not read successfully.
+    fn changes_since(&self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        let _ = (from, limit);
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+    /// Get the sequence number of the latest change in the history of the
+    /// engine, 0 before the first one.
+    ///
+    /// Like `changes_since`, the default fails.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the engine does not keep the history of its
+    /// changes.
+    fn last_seq(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+    /// Get the identifier of the history of the engine, which tells it apart
+    /// from the history of any other engine, e.g. one whose files were
+    /// created anew.
+    ///
+    /// Like `changes_since`, the default fails.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the engine does not keep the history of its
+    /// changes.
+    fn history_id(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+    /// Call `sink` with every change committed from now on to a key starting
+    /// with `prefix`, in commit order, until it returns `false`.
+    ///
+    /// Engines that do not publish their changes keep the default, which
+    /// fails; wrap them in a `WatchedEngine` instead.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the engine cannot publish its changes.
+    fn watch(&self, prefix: String, sink: ChangeSink) -> Result<()> {
+        let _ = (prefix, sink);
+        Err(KvsError::Unsupported("watch".to_owned()))
+    }
+
+    /// Call `sink` with every change committed to any key, in commit order,
+    /// until it returns `false`, starting with the change numbered `from` of
+    /// the history `epoch`.
+    ///
+    /// If the engine no longer has that change, it returns a `Snapshot`
+    /// instead, and `sink` gets the changes committed after it. The snapshot
+    /// comes without its keys, which are then read with `scan` and
+    /// `get_many`: writes go on meanwhile, so keys may be read after later
+    /// changes, which the follower then applies again. Like `watch`, the
+    /// default fails; wrap the engine in a `WatchedEngine`.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::SinkClosed` if `sink` refuses one of the changes
+    /// it missed, or an error if the engine cannot publish its changes.
+    fn replicate(&self, epoch: u64, from: u64, sink: ChangeSink) -> Result<Option<Snapshot>> {
+        let _ = (epoch, from, sink);
+        Err(KvsError::Unsupported("replication".to_owned()))
+    }
+}
+
+// the number of keys read at once by `replace_keys`
+const REPLACE_PAGE_SIZE: usize = 1024;
+
+/// Replace the keys of the engine following `after`, up to `last` included
+/// or to the end if `None`, with the pairs, which must be in that range.
+///
+/// Going through sorted pages of pairs one after another, each starting
+/// after the last key of the previous one, replaces every key of the engine
+/// without holding them all in memory.
+pub(crate) fn replace_keys<E: KvsEngine>(
+    engine: &E,
+    mut after: Option<String>,
+    last: Option<&str>,
+    pairs: Vec<(String, String)>,
+) -> Result<()> {
+    let kept: HashSet<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    loop {
+        let keys = engine.scan(after, REPLACE_PAGE_SIZE)?;
+        let exhausted = keys.len() < REPLACE_PAGE_SIZE;
+        after = keys.last().cloned();
+        for key in keys {
+            if last.is_some_and(|last| key.as_str() > last) {
+                return engine.set_many(pairs);
+            }
+            if !kept.contains(key.as_str()) {
+                match engine.remove(key) {
+                    Err(KvsError::KeyNotFound) => {}
+                    res => res?,
+                }
+            }
+        }
+        if exhausted {
+            return engine.set_many(pairs);
+        }
+    }
 }
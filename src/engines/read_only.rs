@@ -0,0 +1,90 @@
+use super::ChangeSink;
+use crate::{ChangeEvent, KvsEngine, KvsError, Result, Snapshot};
+
+/// Engine wrapper refusing writes, for followers serving reads of an engine
+/// which only their leader's changes may write.
+///
+/// Writes fail with `KvsError::ReadOnly`; everything else is forwarded to
+/// the wrapped engine.
+#[derive(Clone)]
+pub struct ReadOnlyEngine<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> ReadOnlyEngine<E> {
+    /// Wrap an engine.
+    pub fn new(engine: E) -> Self {
+        ReadOnlyEngine { engine }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReadOnlyEngine<E> {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.engine.get_many(keys)
+    }
+
+    fn set_many(&self, _pairs: Vec<(String, String)>) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.engine.get_with_version(key)
+    }
+
+    fn set_if_version(&self, _key: String, _value: String, _version: Option<u64>) -> Result<u64> {
+        Err(KvsError::ReadOnly)
+    }
+
+    // transactions which only read write nothing
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        if writes.is_empty() {
+            self.engine.transact(reads, writes)
+        } else {
+            Err(KvsError::ReadOnly)
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.engine.scan(after, limit)
+    }
+
+    fn changes_since(&self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        self.engine.changes_since(from, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64> {
+        self.engine.last_seq()
+    }
+
+    fn history_id(&self) -> Result<u64> {
+        self.engine.history_id()
+    }
+
+    fn watch(&self, prefix: String, sink: ChangeSink) -> Result<()> {
+        self.engine.watch(prefix, sink)
+    }
+
+    fn replicate(&self, epoch: u64, from: u64, sink: ChangeSink) -> Result<Option<Snapshot>> {
+        self.engine.replicate(epoch, from, sink)
+    }
+}
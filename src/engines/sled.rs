@@ -1,12 +1,20 @@
+use std::ops::Bound;
 use std::option::Option;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use crate::{KvsEngine, KvsError, Result};
 
-use sled::Db;
+use sled::{Db, Tree};
+
+// the tree keeping the version of each key
+const VERSIONS_TREE: &[u8] = b"versions";
 
 /// Key/value storage backend wrapper around Sled.
+///
+/// The versions of the keys are ids generated by sled, which increase
+/// across restarts, kept in a tree of their own. Keys written before
+/// versions were kept are at version 0.
 pub struct SledKvsEngine {
     data: Arc<Mutex<SledKvsEngineData>>,
 }
@@ -44,6 +52,51 @@ impl KvsEngine for SledKvsEngine {
 
         data.remove(key)
     }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let data = self.data.lock().unwrap();
+
+        data.scan(after, limit)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut data = self.data.lock().unwrap();
+
+        keys.into_iter().map(|key| data.get(key)).collect()
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+
+        data.set_many(pairs)
+    }
+
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        let mut data = self.data.lock().unwrap();
+
+        data.get_with_version(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        let mut data = self.data.lock().unwrap();
+
+        if data.version(&key)? != version {
+            return Err(KvsError::Conflict(key));
+        }
+        let version = data.write(key, value)?;
+        data.db.flush()?;
+        Ok(version)
+    }
+
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+
+        data.transact(reads, writes)
+    }
 }
 
 impl Clone for SledKvsEngine {
@@ -56,13 +109,54 @@ impl Clone for SledKvsEngine {
 
 struct SledKvsEngineData {
     db: Db,
+    versions: Arc<Tree>,
 }
 
 impl SledKvsEngineData {
     fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
         let db = Db::start_default(path)?;
+        let versions = db.open_tree(VERSIONS_TREE)?;
+
+        Ok(SledKvsEngineData { db, versions })
+    }
 
-        Ok(SledKvsEngineData { db })
+    fn get_with_version(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        let version = self.version(&key)?;
+        Ok(self
+            .get(key)?
+            .map(|value| (value, version.unwrap_or_default())))
+    }
+
+    // `None` if the key does not exist
+    fn version(&self, key: &str) -> Result<Option<u64>> {
+        if !self.db.contains_key(key)? {
+            return Ok(None);
+        }
+        Ok(Some(match self.versions.get(key)? {
+            Some(bytes) => {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(&bytes);
+                u64::from_be_bytes(buf)
+            }
+            None => 0,
+        }))
+    }
+
+    // Sets the key to a new version, then to the value, without flushing,
+    // so that a crash in between at worst changes the version alone.
+    fn write(&mut self, key: String, value: String) -> Result<u64> {
+        let version = self.db.generate_id()? + 1;
+        self.versions
+            .set(key.as_bytes(), version.to_be_bytes().to_vec())?;
+        self.db.set(key, value.into_bytes())?;
+        Ok(version)
+    }
+
+    // Removes the key, then its version, without flushing.
+    fn delete(&mut self, key: String) -> Result<bool> {
+        let existed = self.db.del(&key)?.is_some();
+        self.versions.del(key)?;
+        Ok(existed)
     }
 
     fn get(&mut self, key: String) -> Result<Option<String>> {
@@ -75,14 +169,60 @@ impl SledKvsEngineData {
     }
 
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.set(key, value.into_bytes())?;
+        self.write(key, value)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // flushes once for all the keys
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.write(key, value)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // checks the reads, then flushes once for all the writes
+    fn transact(
+        &mut self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        for (key, version) in reads {
+            if self.version(&key)? != version {
+                return Err(KvsError::Conflict(key));
+            }
+        }
+        for (key, value) in writes {
+            match value {
+                Some(value) => {
+                    self.write(key, value)?;
+                }
+                None => {
+                    self.delete(key)?;
+                }
+            }
+        }
         self.db.flush()?;
         Ok(())
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
-        self.db.del(key)?.ok_or(KvsError::KeyNotFound)?;
+        if !self.delete(key)? {
+            return Err(KvsError::KeyNotFound);
+        }
         self.db.flush()?;
         Ok(())
     }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = after.map_or(Bound::Unbounded, |key| Bound::Excluded(key.into_bytes()));
+        self.db
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .keys()
+            .take(limit)
+            .map(|key| Ok(String::from_utf8(key?)?))
+            .collect()
+    }
 }
@@ -0,0 +1,492 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::ChangeSink;
+use crate::{ChangeEvent, KvsEngine, KvsError, Result, Snapshot};
+
+// how many of the latest changes are kept for followers by default
+const DEFAULT_BACKLOG: usize = 1024;
+// the number of changes read at once from the history of the engine
+const HISTORY_PAGE_SIZE: usize = 1024;
+
+/// Engine wrapper publishing the changes committed to the wrapped engine to
+/// its watchers.
+///
+/// Changes are only published while there are watchers, or once a
+/// follower came: until then, writes go straight to the engine, and run
+/// concurrently as it allows. Published changes are serialized, and each
+/// gets a sequence number, counting the published changes since the engine
+/// was wrapped from 1. Sinks are called in commit order once the write is
+/// done, by the writer or by another one already calling them, so they
+/// should hand the change over rather than process it.
+///
+/// The latest changes are kept in a backlog, so that followers which
+/// reconnect get the changes they missed. Sequence numbers start over with
+/// every wrapping, which begins a new history, told apart by its epoch:
+/// followers of another history, or further behind than the backlog, get a
+/// snapshot instead, whose keys are read without holding up writes.
+///
+/// Engines keeping the history of their changes, like `KvStore`, number
+/// them instead, and their history identifier is the epoch: the history
+/// then lasts across wrappings and restarts, and followers get the changes
+/// they missed from it rather than from a backlog, as long as the engine
+/// retains them. Their changes are only published while there are sinks.
+#[derive(Clone)]
+pub struct WatchedEngine<E: KvsEngine> {
+    engine: E,
+    // whether writes publish their changes; held for reading by writes, so
+    // that it only changes between them, and taken before `watchers`
+    publishing: Arc<RwLock<bool>>,
+    // writes hold the lock, so that changes are published in commit order
+    watchers: Arc<Mutex<Watchers>>,
+    // held while calling the sinks, which is never done under `watchers`;
+    // taken first when both are needed
+    sinks: Arc<Mutex<Vec<Sink>>>,
+}
+
+struct Watchers {
+    epoch: u64,
+    last_seq: u64,
+    // whether the sequence numbers are those of the engine's history
+    persisted: bool,
+    // whether a follower came, which may come back for the backlog
+    followed: bool,
+    backlog: VecDeque<ChangeEvent>,
+    backlog_len: usize,
+    // changes published, but not handed to the sinks yet
+    pending: VecDeque<ChangeEvent>,
+}
+
+struct Sink {
+    prefix: String,
+    // the latest change committed before the sink was added
+    after: u64,
+    sink: ChangeSink,
+}
+
+impl Watchers {
+    fn publish(&mut self, key: String, value: Option<String>) {
+        self.last_seq += 1;
+        let event = ChangeEvent {
+            seq: self.last_seq,
+            key,
+            value,
+        };
+        if !self.persisted && self.backlog_len > 0 {
+            if self.backlog.len() == self.backlog_len {
+                self.backlog.pop_front();
+            }
+            self.backlog.push_back(event.clone());
+        }
+        self.pending.push_back(event);
+    }
+}
+
+impl<E: KvsEngine> WatchedEngine<E> {
+    /// Wrap an engine, without watchers.
+    pub fn new(engine: E) -> Self {
+        // the history kept by the engine lasts across wrappings
+        let (epoch, last_seq, persisted) = match (engine.history_id(), engine.last_seq()) {
+            (Ok(history_id), Ok(last_seq)) => (history_id, last_seq, true),
+            _ => {
+                // a history started at another time has another epoch
+                let epoch = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(1, |elapsed| elapsed.as_nanos() as u64);
+                (epoch, 0, false)
+            }
+        };
+        let watchers = Watchers {
+            epoch,
+            last_seq,
+            persisted,
+            followed: false,
+            backlog: VecDeque::new(),
+            backlog_len: DEFAULT_BACKLOG,
+            pending: VecDeque::new(),
+        };
+        WatchedEngine {
+            engine,
+            publishing: Arc::new(RwLock::new(false)),
+            watchers: Arc::new(Mutex::new(watchers)),
+            sinks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Sets how many of the latest changes are kept for followers which
+    /// reconnect. Defaults to 1024, and unused for engines keeping the
+    /// history of their changes.
+    pub fn set_backlog(&self, changes: usize) {
+        let mut watchers = self.watchers();
+        watchers.backlog_len = changes;
+        while watchers.backlog.len() > changes {
+            watchers.backlog.pop_front();
+        }
+    }
+
+    // Hands the pending changes to the sinks, unless another thread already
+    // is, which then hands over these changes too.
+    fn deliver(&self) {
+        loop {
+            let mut sinks = match self.sinks.try_lock() {
+                Ok(sinks) => sinks,
+                Err(_) => return,
+            };
+            let events: Vec<_> = self.watchers().pending.drain(..).collect();
+            for event in events {
+                // sinks which no longer want changes are dropped
+                sinks.retain(|sink| {
+                    event.seq <= sink.after
+                        || !event.key.starts_with(&sink.prefix)
+                        || (sink.sink)(event.clone())
+                });
+            }
+            if sinks.is_empty() {
+                self.stop_publishing();
+            }
+            drop(sinks);
+            // changes published after the drain, whose writer found the
+            // sinks taken
+            if self.watchers().pending.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn watchers(&self) -> MutexGuard<'_, Watchers> {
+        self.watchers.lock().unwrap()
+    }
+
+    // Held by writes, which publish their changes if it is true. Released
+    // before delivering them.
+    fn publishing(&self) -> RwLockReadGuard<'_, bool> {
+        self.publishing.read().unwrap()
+    }
+
+    // Makes the writes publish their changes, once those in progress are
+    // done. Called with the sinks held, before adding one.
+    fn start_publishing(&self) -> Result<()> {
+        let mut publishing = self.publishing.write().unwrap();
+        if !*publishing {
+            let mut watchers = self.watchers();
+            // the engine numbered the changes made meanwhile
+            if watchers.persisted {
+                watchers.last_seq = self.engine.last_seq()?;
+            }
+            *publishing = true;
+        }
+        Ok(())
+    }
+
+    // Lets the writes go straight to the engine, unless a follower may come
+    // back for the backlog. Called with the sinks held, once none is left.
+    fn stop_publishing(&self) {
+        {
+            let watchers = self.watchers();
+            if !watchers.persisted && watchers.followed && watchers.backlog_len > 0 {
+                return;
+            }
+        }
+        *self.publishing.write().unwrap() = false;
+    }
+
+    // Publishes the changes of a write, numbered like the engine numbered
+    // them if it keeps their history.
+    fn publish(
+        &self,
+        watchers: &mut Watchers,
+        changes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        if watchers.persisted {
+            watchers.last_seq = self.engine.last_seq()? - changes.len() as u64;
+        }
+        for (key, value) in changes {
+            watchers.publish(key, value);
+        }
+        Ok(())
+    }
+
+    // The pairs a failed `set_many` set before failing: those the engine
+    // numbered if it keeps their history, otherwise those whose key holds
+    // their value.
+    fn applied_pairs(
+        &self,
+        watchers: &Watchers,
+        pairs: Vec<(String, String)>,
+    ) -> Result<Vec<(String, Option<String>)>> {
+        if watchers.persisted {
+            let changes = self
+                .engine
+                .changes_since(watchers.last_seq + 1, pairs.len())?;
+            return Ok(changes
+                .into_iter()
+                .map(|event| (event.key, event.value))
+                .collect());
+        }
+        let values = self
+            .engine
+            .get_many(pairs.iter().map(|(key, _)| key.clone()).collect())?;
+        Ok(pairs
+            .into_iter()
+            .zip(values)
+            .filter(|((_, value), current)| current.as_ref() == Some(value))
+            .map(|((key, value), _)| (key, Some(value)))
+            .collect())
+    }
+
+    // Hands the changes the engine keeps from `from` on to the sink, a page
+    // at a time without holding up the other watchers, while they are more
+    // than a page behind the latest one. Returns the change to go on from.
+    fn catch_up(&self, epoch: u64, mut from: u64, sink: &ChangeSink) -> Result<u64> {
+        loop {
+            {
+                let watchers = self.watchers();
+                if !watchers.persisted || epoch != watchers.epoch {
+                    return Ok(from);
+                }
+            }
+            // the changes may not be published, so the engine knows better
+            if from + HISTORY_PAGE_SIZE as u64 > self.engine.last_seq()? {
+                return Ok(from);
+            }
+            let page = match self.engine.changes_since(from, HISTORY_PAGE_SIZE) {
+                Ok(page) => page,
+                Err(KvsError::HistoryTruncated(_)) => return Ok(from),
+                Err(e) => return Err(e),
+            };
+            let next = match page.last() {
+                Some(event) => event.seq + 1,
+                None => return Ok(from),
+            };
+            for event in page {
+                if !sink(event) {
+                    return Err(KvsError::SinkClosed);
+                }
+            }
+            from = next;
+        }
+    }
+
+    // The changes a follower missed, from `from` to the latest one, along
+    // with the number of the latest one, or `None` if the follower needs a
+    // snapshot instead.
+    fn missed_changes(&self, epoch: u64, from: u64) -> Result<Option<(u64, Vec<ChangeEvent>)>> {
+        let watchers = self.watchers();
+        let last_seq = watchers.last_seq;
+        if epoch != watchers.epoch || from > last_seq + 1 {
+            return Ok(None);
+        }
+        if !watchers.persisted {
+            let oldest = watchers
+                .backlog
+                .front()
+                .map_or(last_seq + 1, |event| event.seq);
+            if oldest > from {
+                return Ok(None);
+            }
+            let missed = watchers
+                .backlog
+                .iter()
+                .filter(|event| event.seq >= from)
+                .cloned()
+                .collect();
+            return Ok(Some((last_seq, missed)));
+        }
+        // the history is read without holding up writes
+        drop(watchers);
+        let mut missed = Vec::new();
+        let mut next = from;
+        while next <= last_seq {
+            let page = match self.engine.changes_since(next, HISTORY_PAGE_SIZE) {
+                Ok(page) => page,
+                Err(KvsError::HistoryTruncated(_)) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if page.is_empty() {
+                return Ok(None);
+            }
+            next = page.last().map_or(next, |event| event.seq + 1);
+            missed.extend(page.into_iter().filter(|event| event.seq <= last_seq));
+        }
+        Ok(Some((last_seq, missed)))
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for WatchedEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let publishing = self.publishing();
+        if !*publishing {
+            return self.engine.set(key, value);
+        }
+        {
+            let mut watchers = self.watchers();
+            self.engine.set(key.clone(), value.clone())?;
+            self.publish(&mut watchers, vec![(key, Some(value))])?;
+        }
+        drop(publishing);
+        self.deliver();
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let publishing = self.publishing();
+        if !*publishing {
+            return self.engine.remove(key);
+        }
+        {
+            let mut watchers = self.watchers();
+            self.engine.remove(key.clone())?;
+            self.publish(&mut watchers, vec![(key, None)])?;
+        }
+        drop(publishing);
+        self.deliver();
+        Ok(())
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.engine.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let publishing = self.publishing();
+        if !*publishing {
+            return self.engine.set_many(pairs);
+        }
+        let res = {
+            let mut watchers = self.watchers();
+            let res = self.engine.set_many(pairs.clone());
+            // the pairs set before a failure are committed all the same
+            let changes = match res {
+                Ok(()) => pairs
+                    .into_iter()
+                    .map(|(key, value)| (key, Some(value)))
+                    .collect(),
+                Err(_) => self.applied_pairs(&watchers, pairs)?,
+            };
+            self.publish(&mut watchers, changes)?;
+            res
+        };
+        drop(publishing);
+        self.deliver();
+        res
+    }
+
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.engine.get_with_version(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        let publishing = self.publishing();
+        if !*publishing {
+            return self.engine.set_if_version(key, value, version);
+        }
+        let new_version = {
+            let mut watchers = self.watchers();
+            let new_version = self
+                .engine
+                .set_if_version(key.clone(), value.clone(), version)?;
+            self.publish(&mut watchers, vec![(key, Some(value))])?;
+            new_version
+        };
+        drop(publishing);
+        self.deliver();
+        Ok(new_version)
+    }
+
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        let publishing = self.publishing();
+        if !*publishing {
+            return self.engine.transact(reads, writes);
+        }
+        {
+            let mut watchers = self.watchers();
+            // removing a missing key changes nothing
+            let present = self
+                .engine
+                .get_many(writes.iter().map(|(key, _)| key.clone()).collect())?;
+            self.engine.transact(reads, writes.clone())?;
+            let changes = writes
+                .into_iter()
+                .zip(present)
+                .filter(|((_, value), present)| value.is_some() || present.is_some())
+                .map(|(change, _)| change)
+                .collect();
+            self.publish(&mut watchers, changes)?;
+        }
+        drop(publishing);
+        self.deliver();
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.engine.scan(after, limit)
+    }
+
+    fn changes_since(&self, from: u64, limit: usize) -> Result<Vec<ChangeEvent>> {
+        self.engine.changes_since(from, limit)
+    }
+
+    fn last_seq(&self) -> Result<u64> {
+        self.engine.last_seq()
+    }
+
+    fn history_id(&self) -> Result<u64> {
+        self.engine.history_id()
+    }
+
+    fn watch(&self, prefix: String, sink: ChangeSink) -> Result<()> {
+        let mut sinks = self.sinks.lock().unwrap();
+        self.start_publishing()?;
+        let after = self.watchers().last_seq;
+        sinks.push(Sink {
+            prefix,
+            after,
+            sink,
+        });
+        Ok(())
+    }
+
+    fn replicate(&self, epoch: u64, from: u64, sink: ChangeSink) -> Result<Option<Snapshot>> {
+        let from = self.catch_up(epoch, from, &sink)?;
+        // no change is handed to the sinks until this one is added
+        let mut sinks = self.sinks.lock().unwrap();
+        self.start_publishing()?;
+        self.watchers().followed = true;
+        let (after, missed, snapshot) = match self.missed_changes(epoch, from)? {
+            Some((after, missed)) => (after, missed, None),
+            None => {
+                let watchers = self.watchers();
+                let snapshot = Snapshot {
+                    epoch: watchers.epoch,
+                    seq: watchers.last_seq,
+                    pairs: Vec::new(),
+                };
+                (watchers.last_seq, Vec::new(), Some(snapshot))
+            }
+        };
+        for event in missed {
+            if !sink(event) {
+                return Err(KvsError::SinkClosed);
+            }
+        }
+        sinks.push(Sink {
+            prefix: String::new(),
+            after,
+            sink,
+        });
+        Ok(snapshot)
+    }
+}
@@ -1,4 +1,8 @@
+// `failure_derive` expands to impls nested in anonymous constants.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
+use serde::{Deserialize, Serialize};
 use std::io;
 
 use rayon::ThreadPoolBuildError;
@@ -22,7 +26,8 @@ pub enum KvsError {
     /// Slogger initialization error
     #[fail(display = "{}", _0)]
     Sloggers(#[cause] sloggers::Error),
-    /// Error response from server when request is not processed successfully.
+    /// Error response from a server older than protocol version 5, when
+    /// request is not processed successfully.
     #[fail(display = "{}", _0)]
     ServerError(String),
     /// Sled Error
@@ -34,6 +39,166 @@ pub enum KvsError {
     /// Rayon thread pool initialization error
     #[fail(display = "{}", _0)]
     RayonThreadPoolBuildError(#[cause] ThreadPoolBuildError),
+    /// No engine is registered under the given name
+    #[fail(display = "Unknown engine: {}", _0)]
+    UnknownEngine(String),
+    /// Bincode serialization or deserialization error
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+    /// Violation of the wire protocol by the peer
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    /// The request could not be executed before its deadline
+    #[fail(display = "Deadline exceeded")]
+    DeadlineExceeded,
+    /// Invalid TLS settings or failed TLS session
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+    /// The server refused the credentials
+    #[fail(display = "Authentication failed")]
+    AuthenticationFailed,
+    /// The user is not allowed to access the key
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(String),
+    /// Invalid users or permissions in the authentication config
+    #[fail(display = "Invalid auth config: {}", _0)]
+    AuthConfig(String),
+    /// The engine does not support the operation
+    #[fail(display = "Unsupported operation: {}", _0)]
+    Unsupported(String),
+    /// The changes requested are no longer kept; those from the given
+    /// sequence number on are
+    #[fail(display = "Changes are only kept from sequence number {}", _0)]
+    HistoryTruncated(u64),
+    /// The server follows a leader, and only serves reads
+    #[fail(display = "Read-only follower")]
+    ReadOnly,
+    /// The node is not the leader of its Raft cluster; the client address
+    /// of the leader is given if the node knows it
+    #[fail(display = "Not the leader")]
+    NotLeader(Option<String>),
+    /// Invalid nodes in the Raft cluster config
+    #[fail(display = "Invalid cluster config: {}", _0)]
+    ClusterConfig(String),
+    /// The Raft cluster failed to commit a write
+    #[fail(display = "Raft error: {}", _0)]
+    Raft(String),
+    /// A key read by the transaction changed before it committed, or a
+    /// conditional write found its key at another version; the key is given
+    #[fail(display = "Transaction conflict on key {}", _0)]
+    Conflict(String),
+    /// A sharded client was given no server to connect to
+    #[fail(display = "No server address given")]
+    NoServers,
+    /// A follower stopped taking the changes it missed before catching up
+    #[fail(display = "Follower stopped taking changes")]
+    SinkClosed,
+    /// Error response from server, with no matching variant on this side
+    #[fail(display = "{}", message)]
+    Server {
+        /// What kind of error the server reported
+        code: ErrorCode,
+        /// Description of the error by the server
+        message: String,
+    },
+}
+
+/// Kind of a `KvsError`, as sent by the server in `Response::Error`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Removing non-existent key
+    KeyNotFound,
+    /// The request could not be executed before its deadline
+    DeadlineExceeded,
+    /// The server refused the credentials
+    AuthenticationFailed,
+    /// The user is not allowed to access the key
+    PermissionDenied,
+    /// The server could not decode the request
+    MalformedRequest,
+    /// Failure to read or write the storage of the server
+    Io,
+    /// Any other failure
+    Internal,
+    /// The server follows a leader, and refuses writes. Only sent since
+    /// protocol version 8
+    ReadOnly,
+    /// The server is not the leader of its Raft cluster; the message is the
+    /// client address of the leader, empty if unknown. Only sent since
+    /// protocol version 9
+    NotLeader,
+    /// A key read by the transaction changed before it committed, or a
+    /// conditional write found its key at another version; the message is
+    /// the key. Only sent since protocol version 10
+    Conflict,
+}
+
+impl ErrorCode {
+    /// Whether the same request may succeed if sent again later.
+    ///
+    /// Only a missed deadline is: a storage failure of the server may have
+    /// happened after part of a write was applied, so replaying the request
+    /// is left to the caller.
+    pub fn is_retriable(self) -> bool {
+        match self {
+            ErrorCode::DeadlineExceeded => true,
+            ErrorCode::KeyNotFound
+            | ErrorCode::Io
+            | ErrorCode::AuthenticationFailed
+            | ErrorCode::PermissionDenied
+            | ErrorCode::MalformedRequest
+            | ErrorCode::Internal
+            | ErrorCode::ReadOnly
+            | ErrorCode::NotLeader
+            | ErrorCode::Conflict => false,
+        }
+    }
+}
+
+impl KvsError {
+    /// The kind of the error.
+    ///
+    /// Errors of servers older than protocol version 5 are `Internal`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::DeadlineExceeded => ErrorCode::DeadlineExceeded,
+            KvsError::AuthenticationFailed => ErrorCode::AuthenticationFailed,
+            KvsError::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            KvsError::Protocol(_) => ErrorCode::MalformedRequest,
+            KvsError::Io(_) | KvsError::Sled(_) => ErrorCode::Io,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::NotLeader(_) => ErrorCode::NotLeader,
+            KvsError::Conflict(_) => ErrorCode::Conflict,
+            KvsError::Server { code, .. } => *code,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// The message describing the error in a `Response::Error`.
+    pub(crate) fn message(&self) -> String {
+        match self {
+            KvsError::PermissionDenied(reason) => reason.clone(),
+            KvsError::NotLeader(leader) => leader.clone().unwrap_or_default(),
+            KvsError::Conflict(key) => key.clone(),
+            err => err.to_string(),
+        }
+    }
+
+    /// Rebuild an error reported by the server in a `Response::Error`.
+    pub(crate) fn from_response(code: ErrorCode, message: String) -> KvsError {
+        match code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::DeadlineExceeded => KvsError::DeadlineExceeded,
+            ErrorCode::AuthenticationFailed => KvsError::AuthenticationFailed,
+            ErrorCode::PermissionDenied => KvsError::PermissionDenied(message),
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::NotLeader if message.is_empty() => KvsError::NotLeader(None),
+            ErrorCode::NotLeader => KvsError::NotLeader(Some(message)),
+            ErrorCode::Conflict => KvsError::Conflict(message),
+            code => KvsError::Server { code, message },
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -48,6 +213,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<sloggers::Error> for KvsError {
     fn from(err: sloggers::Error) -> KvsError {
         KvsError::Sloggers(err)
@@ -66,6 +237,12 @@ impl From<std::string::FromUtf8Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::Tls(err.to_string())
+    }
+}
+
 impl From<ThreadPoolBuildError> for KvsError {
     fn from(err: ThreadPoolBuildError) -> KvsError {
         KvsError::RayonThreadPoolBuildError(err)
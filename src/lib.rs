@@ -1,16 +1,34 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
-pub use messages::{Request, Response};
-pub use server::KvsServer;
+pub use async_client::AsyncKvsClient;
+pub use client::{ClientConfig, KvsClient, Watch};
+pub use client_pool::{KvsClientPool, PoolConfig};
+pub use engines::{
+    BoxedKvsEngine, ChangeSink, Changes, DynKvsEngine, EngineRegistry, ExpiringEngine, KvStore,
+    KvsEngine, MemoryKvsEngine, ReadOnlyEngine, SledKvsEngine, WatchedEngine,
+};
+pub use error::{ErrorCode, KvsError, Result};
+pub use messages::{
+    ChangeEvent, Credentials, Request, Response, Snapshot, TaggedRequest, TaggedResponse,
+};
+pub use server::{Follower, KvsServer, RaftServer, RespServer, ShutdownHandle};
+pub use sharded_client::ShardedClient;
+pub use storage::{FileStorage, MemoryStorage, Storage};
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 
+mod async_client;
+pub mod auth;
 mod client;
+mod client_pool;
+pub mod conformance;
 mod engines;
 mod error;
 mod messages;
+pub mod protocol;
+pub mod raft;
 mod server;
+mod sharded_client;
+pub mod storage;
 pub mod thread_pool;
+pub mod tls;
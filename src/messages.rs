@@ -1,6 +1,10 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::{ErrorCode, KvsError, Result};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 /// Request sent by client to server.
 pub enum Request {
     /// Set a given string key to a string value.
@@ -22,6 +26,140 @@ pub enum Request {
         /// A string key.
         key: String,
     },
+
+    /// Authenticate the connection. The following requests are authorized
+    /// for the authenticated user. Only sent since protocol version 4.
+    Auth {
+        /// The secret identifying the user.
+        credentials: Credentials,
+    },
+
+    /// Get the string values of several string keys, answered with
+    /// `Response::Values`. Only sent since protocol version 6.
+    MultiGet {
+        /// The string keys.
+        keys: Vec<String>,
+    },
+
+    /// Set several string keys to string values, in order. Only sent since
+    /// protocol version 6.
+    MultiSet {
+        /// Pairs of a string key and a string value.
+        pairs: Vec<(String, String)>,
+    },
+
+    /// Subscribe to the changes of the keys starting with a prefix. Once
+    /// answered with `Response::Ok`, the connection only carries the
+    /// `Response::Event` of every change, tagged with the id of this request,
+    /// and the server ignores any further request. Only sent since protocol
+    /// version 7.
+    Watch {
+        /// Prefix of the keys to watch; empty to watch every key.
+        prefix: String,
+    },
+
+    /// Follow every change, from the change numbered `from` of the history
+    /// `epoch` on. Answered with `Response::Ok` if the server still has
+    /// these changes, and with a `Response::Snapshot` of every key
+    /// otherwise, whose keys come in `Response::SnapshotPage`s before it
+    /// since protocol version 12. The connection then carries the
+    /// `Response::Event` of every change like a watch of every key.
+    /// Requires read access to every key. Only sent since protocol version
+    /// 8.
+    Replicate {
+        /// History of the changes, as told by a previous `Snapshot`.
+        epoch: u64,
+        /// Sequence number of the first change to send.
+        from: u64,
+    },
+
+    /// Open a transaction on the connection. The following writes are
+    /// buffered by the server, and reads see them, until `Request::Commit`
+    /// or `Request::Abort`. Only sent since protocol version 10.
+    Begin,
+
+    /// Apply the writes of the transaction at once, provided that none of
+    /// the keys it read changed since. Fails with `ErrorCode::Conflict`
+    /// otherwise, writing nothing. Either way the transaction ends. Only
+    /// sent since protocol version 10.
+    Commit,
+
+    /// Drop the writes of the transaction, ending it. Only sent since
+    /// protocol version 10.
+    Abort,
+
+    /// Get the string value of a string key and its version, answered with
+    /// `Response::Versioned`. Only sent since protocol version 11.
+    GetWithVersion {
+        /// A string key.
+        key: String,
+    },
+
+    /// Set a string key to a string value, provided that the key is at the
+    /// given version, or does not exist if `version` is `None`. Answered
+    /// with the new version in a `Response::Version`, and fails with
+    /// `ErrorCode::Conflict` otherwise. Only sent since protocol version 11.
+    SetIfVersion {
+        /// A string key.
+        key: String,
+        /// A string value.
+        value: String,
+        /// The version the key must be at.
+        version: Option<u64>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// A change committed to a key.
+pub struct ChangeEvent {
+    /// Sequence number of the change: later changes have larger numbers.
+    pub seq: u64,
+    /// The changed key.
+    pub key: String,
+    /// The new value of the key, `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// Every key of an engine and its value, as of a change.
+pub struct Snapshot {
+    /// History of the changes the snapshot starts: sequence numbers only
+    /// follow each other within a history.
+    pub epoch: u64,
+    /// Sequence number of the latest change the snapshot includes. Keys
+    /// may have been read after later changes too, which the changes
+    /// following the snapshot then apply again.
+    pub seq: u64,
+    /// Every key and its value, in key order; empty when the keys came in
+    /// pages instead.
+    pub pairs: Vec<(String, String)>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Secret identifying a user to the server.
+pub enum Credentials {
+    /// A token given to the user.
+    Token(String),
+    /// The name and password of the user.
+    Password {
+        /// Name of the user.
+        username: String,
+        /// Password of the user.
+        password: String,
+    },
+}
+
+// keeps secrets out of logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => f.write_str("Token(..)"),
+            Credentials::Password { username, .. } => f
+                .debug_struct("Password")
+                .field("username", username)
+                .finish_non_exhaustive(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,4 +172,171 @@ pub enum Response {
 
     /// Request is not processed successfully and the cause is returned.
     Err(String),
+
+    /// The credentials of a `Request::Auth` are not valid. Only sent with
+    /// protocol version 4.
+    AuthenticationFailed,
+
+    /// The user of the connection is not allowed to access the key of the
+    /// request, and the cause is returned. Only sent with protocol version
+    /// 4.
+    PermissionDenied(String),
+
+    /// Request is not processed successfully: the kind of error and its
+    /// description are returned. Only sent since protocol version 5, which
+    /// sends it instead of `Err`, `AuthenticationFailed` and
+    /// `PermissionDenied`.
+    Error {
+        /// Kind of the error.
+        code: ErrorCode,
+        /// Description of the error.
+        message: String,
+    },
+
+    /// A `Request::MultiGet` is processed successfully: the value of each
+    /// requested key is returned, in the order of the keys. Only sent since
+    /// protocol version 6.
+    Values(Vec<Option<String>>),
+
+    /// A change to a key watched by a `Request::Watch`. Only sent since
+    /// protocol version 7.
+    Event(ChangeEvent),
+
+    /// Answer to a `Request::Replicate` whose changes the server no longer
+    /// has. Only sent since protocol version 8.
+    Snapshot(Snapshot),
+
+    /// A `Request::GetWithVersion` is processed successfully: the value of
+    /// the key and its version are returned, or `None` if the key does not
+    /// exist. Only sent since protocol version 11.
+    Versioned(Option<(String, u64)>),
+
+    /// A `Request::SetIfVersion` is processed successfully: the new version
+    /// of the key is returned. Only sent since protocol version 11.
+    Version(u64),
+
+    /// Keys of a snapshot and their values, in key order, following those
+    /// of the previous page. Ends with the `Response::Snapshot` itself.
+    /// Only sent since protocol version 12.
+    SnapshotPage(Vec<(String, String)>),
+}
+
+impl Response {
+    /// The response reporting the given error.
+    pub fn error(err: &KvsError) -> Response {
+        Response::Error {
+            code: err.code(),
+            message: err.message(),
+        }
+    }
+
+    /// Convert the response into the result of the request.
+    ///
+    /// # Error
+    ///
+    /// Return the `KvsError` matching the code of a `Response::Error`, and
+    /// `KvsError::ServerError` for a `Response::Err`.
+    pub fn into_result(self) -> Result<Option<String>> {
+        match self {
+            Response::Ok(v) => Ok(v),
+            Response::Err(e) => Err(KvsError::ServerError(e)),
+            Response::AuthenticationFailed => Err(KvsError::AuthenticationFailed),
+            Response::PermissionDenied(e) => Err(KvsError::PermissionDenied(e)),
+            Response::Error { code, message } => Err(KvsError::from_response(code, message)),
+            Response::Values(_) => Err(KvsError::Protocol("unexpected values".to_owned())),
+            Response::Event(_) => Err(KvsError::Protocol("unexpected event".to_owned())),
+            Response::Snapshot(_) | Response::SnapshotPage(_) => {
+                Err(KvsError::Protocol("unexpected snapshot".to_owned()))
+            }
+            Response::Versioned(_) | Response::Version(_) => {
+                Err(KvsError::Protocol("unexpected version".to_owned()))
+            }
+        }
+    }
+
+    /// Convert the response to a `Request::MultiGet` into its result.
+    ///
+    /// # Error
+    ///
+    /// Return the error of the response as `into_result` does, and
+    /// `KvsError::Protocol` for a `Response::Ok`.
+    pub fn into_values(self) -> Result<Vec<Option<String>>> {
+        match self {
+            Response::Values(values) => Ok(values),
+            resp => {
+                resp.into_result()?;
+                Err(KvsError::Protocol("expected values".to_owned()))
+            }
+        }
+    }
+
+    /// Convert the response to a `Request::GetWithVersion` into its result.
+    ///
+    /// # Error
+    ///
+    /// Return the error of the response as `into_result` does, and
+    /// `KvsError::Protocol` for a `Response::Ok`.
+    pub fn into_versioned(self) -> Result<Option<(String, u64)>> {
+        match self {
+            Response::Versioned(versioned) => Ok(versioned),
+            resp => {
+                resp.into_result()?;
+                Err(KvsError::Protocol("expected a versioned value".to_owned()))
+            }
+        }
+    }
+
+    /// Convert the response to a `Request::SetIfVersion` into its result.
+    ///
+    /// # Error
+    ///
+    /// Return the error of the response as `into_result` does, and
+    /// `KvsError::Protocol` for a `Response::Ok`.
+    pub fn into_version(self) -> Result<u64> {
+        match self {
+            Response::Version(version) => Ok(version),
+            resp => {
+                resp.into_result()?;
+                Err(KvsError::Protocol("expected a version".to_owned()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Request tagged with an id chosen by the client.
+///
+/// The response to the request carries the same id, which lets a client send
+/// several requests before reading any response.
+pub struct TaggedRequest {
+    /// Id of the request. Clients should not use 0, which the server uses
+    /// for responses to requests it cannot decode.
+    pub id: u64,
+    /// The request itself.
+    pub request: Request,
+    /// Deadline of the request, in milliseconds from its arrival at the
+    /// server. The server answers with a "Deadline exceeded" error instead
+    /// of executing a request which could not start in time. Only sent since
+    /// protocol version 3.
+    pub timeout_ms: Option<u64>,
+}
+
+impl TaggedRequest {
+    /// Tag a request with the given id, without deadline.
+    pub fn new(id: u64, request: Request) -> TaggedRequest {
+        TaggedRequest {
+            id,
+            request,
+            timeout_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Response tagged with the id of the request it answers.
+pub struct TaggedResponse {
+    /// Id of the request.
+    pub id: u64,
+    /// The response itself.
+    pub response: Response,
 }
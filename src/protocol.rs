@@ -0,0 +1,420 @@
+//! Wire protocol spoken between `KvsClient` and `KvsServer`.
+//!
+//! A connection starts with a handshake. The client sends a `Hello` carrying
+//! the highest protocol version it speaks and the codec it wants, and the
+//! server answers with a `Hello` carrying the version both sides will use.
+//! A version of 0 in the answer means the server rejected the connection.
+//!
+//! ```text
+//! hello: MAGIC (4 bytes) | version (u16, big endian) | codec (u8)
+//! frame: length (u32, big endian) | payload (`length` bytes)
+//! ```
+//!
+//! After the handshake every request and response travels in its own frame,
+//! encoded with the negotiated codec. Since version 2 these are
+//! `TaggedRequest` and `TaggedResponse`, so responses may be matched to
+//! requests out of order. Version 1 carries bare `Request` and `Response`
+//! values, answered in the order the requests were sent. Version 3 adds the
+//! deadline of `TaggedRequest`. Version 4 adds authentication with
+//! `Request::Auth`, and the `Response::AuthenticationFailed` and
+//! `Response::PermissionDenied` refusals, which older clients receive as
+//! `Response::Err`. Version 5 replaces these errors with `Response::Error`,
+//! which tells the kind of error with an `ErrorCode`. Version 6 adds
+//! `Request::MultiGet` and `Request::MultiSet`, which act on several keys
+//! in one round trip, and the `Response::Values` answering the former.
+//! Version 7 adds `Request::Watch`, after which the server streams a
+//! `Response::Event` for every change to the watched keys. Version 8 adds
+//! `Request::Replicate`, which streams every change to a follower after a
+//! `Response::Snapshot` of the keys if needed, and the `ErrorCode::ReadOnly`
+//! refusal of followers, which older clients receive as `ErrorCode::Internal`.
+//! Version 9 adds the `ErrorCode::NotLeader` refusal of the nodes of a Raft
+//! cluster which are not its leader, likewise received as
+//! `ErrorCode::Internal` by older clients. Version 10 adds transactions,
+//! opened by `Request::Begin` and ended by `Request::Commit` or
+//! `Request::Abort`, and the `ErrorCode::Conflict` failure of commits,
+//! likewise received as `ErrorCode::Internal` by older clients. Version 11
+//! adds `Request::GetWithVersion` and `Request::SetIfVersion`, answered with
+//! `Response::Versioned` and `Response::Version`, which read and check the
+//! version of a key. Version 12 sends the keys of the `Response::Snapshot`
+//! answering `Request::Replicate` in `Response::SnapshotPage`s before it,
+//! rather than all of them in one frame.
+//!
+//! Connections which do not start with `MAGIC` are served with the legacy
+//! protocol: a stream of bare JSON values without framing or handshake.
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{ErrorCode, KvsError, Request, Response, Result, TaggedRequest, TaggedResponse};
+
+/// Bytes opening the handshake of a framed connection.
+pub const MAGIC: [u8; 4] = *b"KVSP";
+
+/// The newest protocol version this crate speaks.
+pub const PROTOCOL_VERSION: u16 = 12;
+
+/// The oldest protocol version this crate still accepts.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Frames with a larger payload are rejected.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+// the most allocated up front for reading a frame
+const FRAME_READ_CAPACITY: usize = 64 * 1024;
+
+pub(crate) const HELLO_LEN: usize = 7;
+
+/// Encoding of the frame payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Compact binary encoding, using bincode.
+    Bincode,
+    /// JSON encoding, for peers without a bincode implementation.
+    Json,
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::Bincode => 1,
+            Codec::Json => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Codec> {
+        match byte {
+            1 => Some(Codec::Bincode),
+            2 => Some(Codec::Json),
+            _ => None,
+        }
+    }
+
+    /// Encode a message with this codec.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the message cannot be serialized.
+    pub fn encode<T: Serialize>(self, msg: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Codec::Bincode => bincode::serialize(msg)?,
+            Codec::Json => serde_json::to_vec(msg)?,
+        })
+    }
+
+    /// Decode a message encoded with this codec.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the bytes are not a valid message.
+    pub fn decode<T: DeserializeOwned>(self, buf: &[u8]) -> Result<T> {
+        Ok(match self {
+            Codec::Bincode => bincode::deserialize(buf)?,
+            Codec::Json => serde_json::from_slice(buf)?,
+        })
+    }
+}
+
+/// The handshake message exchanged when a framed connection opens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    /// Protocol version. Sent by the client, it is the newest version the
+    /// client speaks; sent by the server, it is the version to use.
+    pub version: u16,
+    /// Codec of the frame payloads.
+    pub codec: Codec,
+}
+
+impl Hello {
+    /// Write the handshake message.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the writer fails.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut buf = [0; HELLO_LEN];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6] = self.codec.to_byte();
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read a handshake message.
+    ///
+    /// Returns `None` for a rejection, i.e. a version of 0.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the reader fails or the message is malformed.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Hello>> {
+        let mut buf = [0; HELLO_LEN];
+        reader.read_exact(&mut buf)?;
+        if buf[..4] != MAGIC {
+            return Err(KvsError::Protocol("invalid handshake".to_owned()));
+        }
+        let version = u16::from_be_bytes([buf[4], buf[5]]);
+        if version == 0 {
+            return Ok(None);
+        }
+        let codec = Codec::from_byte(buf[6])
+            .ok_or_else(|| KvsError::Protocol(format!("unknown codec {}", buf[6])))?;
+        Ok(Some(Hello { version, codec }))
+    }
+
+    /// Write a rejection of the client's handshake.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the writer fails.
+    pub fn write_rejection<W: Write>(writer: &mut W) -> Result<()> {
+        let mut buf = [0; HELLO_LEN];
+        buf[..4].copy_from_slice(&MAGIC);
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Choose the answer of the server to a client `Hello`.
+    ///
+    /// Returns `None` if the client only speaks versions older than
+    /// `MIN_PROTOCOL_VERSION`.
+    pub fn negotiate(&self) -> Option<Hello> {
+        let version = self.version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            None
+        } else {
+            Some(Hello {
+                version,
+                codec: self.codec,
+            })
+        }
+    }
+}
+
+/// Perform the client side of the handshake, asking for the newest protocol
+/// version and the given codec.
+///
+/// Returns the `Hello` chosen by the server.
+pub(crate) fn client_handshake<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    codec: Codec,
+) -> Result<Hello> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        codec,
+    };
+    hello.write_to(writer)?;
+    writer.flush()?;
+    Hello::read_from(reader)?.ok_or_else(|| {
+        KvsError::Protocol(format!(
+            "server does not support protocol version {}",
+            PROTOCOL_VERSION
+        ))
+    })
+}
+
+/// Write a payload as a frame.
+///
+/// # Error
+///
+/// Return an error if the payload exceeds `MAX_FRAME_LEN` or the writer fails.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes exceeds the limit",
+            payload.len()
+        )));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Read the payload of the next frame.
+///
+/// Returns `None` if the stream ends before a new frame starts.
+///
+/// # Error
+///
+/// Return an error if the reader fails, the stream ends in the middle of a
+/// frame or the frame exceeds `MAX_FRAME_LEN`.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes exceeds the limit",
+            len
+        )));
+    }
+    // the buffer grows as the bytes arrive, rather than trusting the length
+    // of a frame from a client not even authenticated
+    let mut payload = Vec::with_capacity((len as usize).min(FRAME_READ_CAPACITY));
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(payload))
+}
+
+/// Split the first complete frame off a buffer of received bytes.
+///
+/// Returns the payload and the number of bytes the frame takes, or `None` if
+/// the frame is not complete yet.
+pub(crate) fn split_frame(buf: &[u8]) -> Result<Option<(&[u8], usize)>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes exceeds the limit",
+            len
+        )));
+    }
+    let end = 4 + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    Ok(Some((&buf[4..end], end)))
+}
+
+// `TaggedRequest` of version 2, which has no deadline
+#[derive(Serialize, Deserialize)]
+struct TaggedRequestV2<R> {
+    id: u64,
+    request: R,
+}
+
+/// Encode a request for a connection using the negotiated `Hello`.
+///
+/// Version 1 has no request ids, so the id is dropped. Versions before 3
+/// have no deadlines, so the deadline is dropped.
+///
+/// # Error
+///
+/// Return an error if the request cannot be serialized.
+pub fn encode_request(hello: &Hello, req: &TaggedRequest) -> Result<Vec<u8>> {
+    match hello.version {
+        1 => hello.codec.encode(&req.request),
+        2 => hello.codec.encode(&TaggedRequestV2 {
+            id: req.id,
+            request: &req.request,
+        }),
+        _ => hello.codec.encode(req),
+    }
+}
+
+/// Decode a request received on a connection using the negotiated `Hello`.
+///
+/// Requests of version 1 get the id 0. Requests before version 3 have no
+/// deadline.
+///
+/// # Error
+///
+/// Return an error if the payload is not a valid request.
+pub fn decode_request(hello: &Hello, payload: &[u8]) -> Result<TaggedRequest> {
+    match hello.version {
+        1 => Ok(TaggedRequest::new(0, hello.codec.decode(payload)?)),
+        2 => {
+            let req: TaggedRequestV2<Request> = hello.codec.decode(payload)?;
+            Ok(TaggedRequest::new(req.id, req.request))
+        }
+        _ => hello.codec.decode(payload),
+    }
+}
+
+/// Encode a response for a connection using the negotiated `Hello`.
+///
+/// Errors are sent as the responses the version knows.
+///
+/// # Error
+///
+/// Return an error if the response cannot be serialized.
+pub fn encode_response(hello: &Hello, resp: &TaggedResponse) -> Result<Vec<u8>> {
+    let downgraded = downgrade_response(&resp.response, hello.version);
+    let response = downgraded.as_ref().unwrap_or(&resp.response);
+    if hello.version < 2 {
+        hello.codec.encode(response)
+    } else {
+        hello.codec.encode(&TaggedResponseRef {
+            id: resp.id,
+            response,
+        })
+    }
+}
+
+// `TaggedResponse` borrowing its response
+#[derive(Serialize)]
+struct TaggedResponseRef<'a> {
+    id: u64,
+    response: &'a Response,
+}
+
+/// The response standing for a `Response::Error` on connections of versions
+/// before 5, or with an `ErrorCode` the version does not know, or `None` if
+/// the version knows the response. Legacy connections count as version 1.
+pub(crate) fn downgrade_response(response: &Response, version: u16) -> Option<Response> {
+    let err = match response {
+        Response::Error { code, message } if version < 5 => {
+            KvsError::from_response(*code, message.clone())
+        }
+        Response::Error {
+            code: ErrorCode::ReadOnly,
+            message,
+        } if version < 8 => {
+            return Some(Response::Error {
+                code: ErrorCode::Internal,
+                message: message.clone(),
+            })
+        }
+        Response::Error {
+            code: ErrorCode::NotLeader,
+            message,
+        } if version < 9 => {
+            return Some(Response::Error {
+                code: ErrorCode::Internal,
+                message: KvsError::from_response(ErrorCode::NotLeader, message.clone()).to_string(),
+            })
+        }
+        Response::Error {
+            code: ErrorCode::Conflict,
+            message,
+        } if version < 10 => {
+            return Some(Response::Error {
+                code: ErrorCode::Internal,
+                message: KvsError::from_response(ErrorCode::Conflict, message.clone()).to_string(),
+            })
+        }
+        _ => return None,
+    };
+    Some(match err {
+        KvsError::AuthenticationFailed if version == 4 => Response::AuthenticationFailed,
+        KvsError::PermissionDenied(reason) if version == 4 => Response::PermissionDenied(reason),
+        err => Response::Err(err.to_string()),
+    })
+}
+
+/// Decode a response received on a connection using the negotiated `Hello`.
+///
+/// Responses of version 1 get the id 0.
+///
+/// # Error
+///
+/// Return an error if the payload is not a valid response.
+pub fn decode_response(hello: &Hello, payload: &[u8]) -> Result<TaggedResponse> {
+    if hello.version < 2 {
+        Ok(TaggedResponse {
+            id: 0,
+            response: hello.codec.decode::<Response>(payload)?,
+        })
+    } else {
+        hello.codec.decode(payload)
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+
+use super::{ClusterConfig, Command, Message, RaftNode, Role};
+use crate::storage::Storage;
+use crate::{ChangeSink, KvsEngine, KvsError, Result, Snapshot};
+
+// how long a write waits to be committed
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Engine of a node of a Raft cluster.
+///
+/// Writes are proposed to the log of the node, and return once the cluster
+/// committed them and the node applied them to the wrapped engine. Requests
+/// sent to a node which is not the leader fail with `KvsError::NotLeader`,
+/// carrying the client address of the leader if the node knows it.
+///
+/// Reads are served by the leader from its own engine. A leader cut off
+/// from the cluster keeps serving them until it learns about its successor,
+/// so they may miss the latest writes.
+///
+/// A `RaftServer` carries the messages between the nodes.
+pub struct RaftEngine<E: KvsEngine, S: Storage> {
+    shared: Arc<Shared<E, S>>,
+}
+
+struct Shared<E: KvsEngine, S: Storage> {
+    id: u64,
+    cluster: ClusterConfig,
+    state: Mutex<State<E, S>>,
+    // signaled whenever the node applies entries
+    applied: Condvar,
+    outbox: Sender<Message>,
+    messages: Receiver<Message>,
+}
+
+struct State<E: KvsEngine, S: Storage> {
+    node: RaftNode<E, S>,
+    // results of the entries awaited by writes, by index: the term of the
+    // entry applied at the index, and what applying it returned
+    results: HashMap<u64, (u64, Result<()>)>,
+}
+
+impl<E: KvsEngine, S: Storage> Clone for RaftEngine<E, S> {
+    fn clone(&self) -> Self {
+        RaftEngine {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine, S: Storage> RaftEngine<E, S> {
+    /// Create the engine of the node with the given id in the cluster,
+    /// persisting its log in the storage.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::ClusterConfig` if the cluster is not valid or has
+    /// no node with the id, or an error if the node cannot be restored.
+    pub fn new(id: u64, cluster: ClusterConfig, engine: E, storage: S) -> Result<Self> {
+        cluster.validate()?;
+        if cluster.node(id).is_none() {
+            return Err(KvsError::ClusterConfig(format!("no node with id {}", id)));
+        }
+        let peers = cluster.nodes.iter().map(|node| node.id).collect();
+        let node = RaftNode::new(id, peers, engine, storage)?;
+        let (outbox, messages) = unbounded();
+        Ok(RaftEngine {
+            shared: Arc::new(Shared {
+                id,
+                cluster,
+                state: Mutex::new(State {
+                    node,
+                    results: HashMap::new(),
+                }),
+                applied: Condvar::new(),
+                outbox,
+                messages,
+            }),
+        })
+    }
+
+    /// Id of the node.
+    pub fn id(&self) -> u64 {
+        self.shared.id
+    }
+
+    /// The nodes of the cluster.
+    pub fn cluster(&self) -> &ClusterConfig {
+        &self.shared.cluster
+    }
+
+    /// What the node currently is.
+    pub fn role(&self) -> Role {
+        self.shared.state.lock().unwrap().node.role()
+    }
+
+    /// Id of the leader of the current term, if the node knows it.
+    pub fn leader(&self) -> Option<u64> {
+        self.shared.state.lock().unwrap().node.leader()
+    }
+
+    // Advances the logical clock of the node.
+    pub(crate) fn tick(&self) -> Result<()> {
+        self.drive(|node| node.tick())
+    }
+
+    // Hands the node a message from another node.
+    pub(crate) fn step(&self, msg: Message) -> Result<()> {
+        self.drive(|node| node.step(msg))
+    }
+
+    // The messages the node sends to the other nodes.
+    pub(crate) fn messages(&self) -> &Receiver<Message> {
+        &self.shared.messages
+    }
+
+    // Address the node with the given id serves the other nodes on.
+    pub(crate) fn raft_addr(&self, id: u64) -> Option<SocketAddr> {
+        self.shared.cluster.node(id).map(|node| node.raft_addr)
+    }
+
+    // Runs an operation on the node, then sends its messages and hands its
+    // applied entries to the writes awaiting them.
+    fn drive<T>(&self, op: impl FnOnce(&mut RaftNode<E, S>) -> Result<T>) -> Result<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        let res = op(&mut state.node);
+        self.dispatch(&mut state);
+        if let Ok(Some(mut task)) = state.node.start_snapshot() {
+            // the node keeps going while the engine is read; a snapshot that
+            // fails is taken again later
+            let shared = self.shared.clone();
+            thread::spawn(move || {
+                let _ = task.run();
+                let _ = shared.state.lock().unwrap().node.finish_snapshot(task);
+            });
+        }
+        res
+    }
+
+    fn dispatch(&self, state: &mut State<E, S>) {
+        for msg in state.node.take_messages() {
+            // the receiver lives as long as the sender
+            let _ = self.shared.outbox.send(msg);
+        }
+        let applied = state.node.take_applied();
+        if applied.is_empty() {
+            return;
+        }
+        for entry in applied {
+            if let Some(result) = state.results.get_mut(&entry.index) {
+                *result = (entry.term, entry.result);
+            }
+        }
+        self.shared.applied.notify_all();
+    }
+
+    // The error refusing requests on a node which is not the leader.
+    fn not_leader(&self, node: &RaftNode<E, S>) -> KvsError {
+        let leader = node
+            .leader()
+            .and_then(|id| self.shared.cluster.node(id))
+            .map(|node| node.addr.to_string());
+        KvsError::NotLeader(leader)
+    }
+
+    // The engine to read from, unless the node is not the leader.
+    fn leader_engine(&self) -> Result<E> {
+        let state = self.shared.state.lock().unwrap();
+        if state.node.role() == Role::Leader {
+            Ok(state.node.engine().clone())
+        } else {
+            Err(self.not_leader(&state.node))
+        }
+    }
+
+    // The wrapped engine, whatever the role of the node.
+    fn engine(&self) -> E {
+        self.shared.state.lock().unwrap().node.engine().clone()
+    }
+
+    // Proposes the commands and waits for them to be applied. Returns the
+    // index of the last one.
+    fn write(&self, commands: Vec<Command>) -> Result<u64> {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut proposals = Vec::with_capacity(commands.len());
+        for command in commands {
+            match state.node.propose(command) {
+                Ok((index, term)) => {
+                    // a sentinel term no entry has, until the entry is applied
+                    state.results.insert(index, (0, Ok(())));
+                    proposals.push((index, term));
+                }
+                Err(KvsError::NotLeader(_)) => {
+                    let err = self.not_leader(&state.node);
+                    self.forget(&mut state, &proposals);
+                    return Err(err);
+                }
+                Err(e) => {
+                    self.dispatch(&mut state);
+                    self.forget(&mut state, &proposals);
+                    return Err(e);
+                }
+            }
+        }
+        self.dispatch(&mut state);
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        let mut res = Ok(());
+        for &(index, term) in &proposals {
+            state = self.wait_applied(state, index, deadline);
+            let last_applied = state.node.last_applied();
+            let outcome = match state.results.get_mut(&index) {
+                Some((0, _)) if last_applied >= index => Err(KvsError::Raft(
+                    "lost track of a write, which may still be committed".to_owned(),
+                )),
+                Some((0, _)) => Err(KvsError::Raft(
+                    "timed out waiting for a write to commit, which may still happen".to_owned(),
+                )),
+                Some((applied_term, result)) if *applied_term == term => {
+                    std::mem::replace(result, Ok(()))
+                }
+                // another leader replaced the entry
+                Some(_) => Err(self.not_leader(&state.node)),
+                None => unreachable!("awaited entry forgotten"),
+            };
+            if res.is_ok() {
+                res = outcome;
+            }
+        }
+        self.forget(&mut state, &proposals);
+        res.map(|()| proposals.last().map_or(0, |&(index, _)| index))
+    }
+
+    fn wait_applied<'a>(
+        &self,
+        state: MutexGuard<'a, State<E, S>>,
+        index: u64,
+        deadline: Instant,
+    ) -> MutexGuard<'a, State<E, S>> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (state, _) = self
+            .shared
+            .applied
+            .wait_timeout_while(state, timeout, |state| {
+                state.results[&index].0 == 0 && state.node.last_applied() < index
+            })
+            .unwrap();
+        state
+    }
+
+    fn forget(&self, state: &mut State<E, S>, proposals: &[(u64, u64)]) {
+        for (index, _) in proposals {
+            state.results.remove(index);
+        }
+    }
+}
+
+impl<E: KvsEngine, S: Storage> KvsEngine for RaftEngine<E, S> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(vec![Command::Set { key, value }]).map(|_| ())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.leader_engine()?.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.write(vec![Command::Remove { key }]).map(|_| ())
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.leader_engine()?.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.write(
+            pairs
+                .into_iter()
+                .map(|(key, value)| Command::Set { key, value })
+                .collect(),
+        )
+        .map(|_| ())
+    }
+
+    // the version of a key is the index of the entry which last wrote it
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        let state = self.shared.state.lock().unwrap();
+        if state.node.role() != Role::Leader {
+            return Err(self.not_leader(&state.node));
+        }
+        match state.node.version(&key) {
+            Some(version) => Ok(state.node.engine().get(key)?.map(|value| (value, version))),
+            None => Ok(None),
+        }
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: Option<u64>) -> Result<u64> {
+        self.write(vec![Command::Transact {
+            reads: vec![(key.clone(), version)],
+            writes: vec![(key, Some(value))],
+        }])
+    }
+
+    fn transact(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        self.write(vec![Command::Transact { reads, writes }])
+            .map(|_| ())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine().flush()
+    }
+
+    fn scan(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.leader_engine()?.scan(after, limit)
+    }
+
+    fn watch(&self, prefix: String, sink: ChangeSink) -> Result<()> {
+        self.engine().watch(prefix, sink)
+    }
+
+    fn replicate(&self, epoch: u64, from: u64, sink: ChangeSink) -> Result<Option<Snapshot>> {
+        self.engine().replicate(epoch, from, sink)
+    }
+}
@@ -0,0 +1,387 @@
+use std::io::BufReader;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use super::Entry;
+use crate::storage::{Storage, StorageFile, StorageReader};
+use crate::{KvsError, Result};
+
+const STATE_NAME: &str = "state";
+const LOG_NAME: &str = "log";
+const SNAPSHOT_NAME: &str = "snapshot";
+// a snapshot being taken from the engine
+const NEW_SNAPSHOT_NAME: &str = "snapshot.new";
+// a snapshot being received from the leader
+const RECEIVED_SNAPSHOT_NAME: &str = "snapshot.recv";
+const APPLIED_NAME: &str = "applied";
+
+/// The term and vote of a node, which must survive restarts so that it
+/// never votes twice in a term.
+#[derive(Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<u64>,
+}
+
+/// Record of the log file: entries are appended, and a truncation drops the
+/// entries from the given index on.
+#[derive(Serialize, Deserialize)]
+enum LogRecord {
+    Entry(Entry),
+    Truncate(u64),
+}
+
+/// Record of a snapshot file: the latest entry the snapshot includes comes
+/// first, then the version of every key, then every key and its value, both
+/// in pages of ascending keys.
+#[derive(Serialize, Deserialize)]
+pub(super) enum SnapshotRecord {
+    Meta { index: u64, term: u64 },
+    Versions(Vec<(String, u64)>),
+    Pairs(Vec<(String, String)>),
+}
+
+// The part of a snapshot received so far.
+struct Received<F> {
+    index: u64,
+    term: u64,
+    file: F,
+}
+
+/// The persisted state of a Raft node: its term and vote, the latest
+/// snapshot, the entries following the snapshot, and the latest entry the
+/// engine is known to keep.
+pub(super) struct RaftLog<S: Storage> {
+    storage: S,
+    file: S::File,
+    term: u64,
+    voted_for: Option<u64>,
+    // whether a snapshot was ever persisted, rather than the log starting
+    // from an empty one
+    has_snapshot: bool,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    applied: u64,
+    // entries from `snapshot_index + 1` on
+    entries: Vec<Entry>,
+    received: Option<Received<S::File>>,
+}
+
+impl<S: Storage> RaftLog<S> {
+    /// Load the state persisted in the storage, empty if there is none.
+    pub(super) fn open(storage: S) -> Result<Self> {
+        let hard: HardState = read_json(&storage, STATE_NAME)?.unwrap_or_default();
+        let has_snapshot = storage.list()?.iter().any(|file| file == SNAPSHOT_NAME);
+        let (snapshot_index, snapshot_term) = if has_snapshot {
+            let mut file = storage.open(SNAPSHOT_NAME)?;
+            let reader = BufReader::new(StorageReader::new(&mut file));
+            match Deserializer::from_reader(reader)
+                .into_iter::<SnapshotRecord>()
+                .next()
+                .transpose()?
+            {
+                Some(SnapshotRecord::Meta { index, term }) => (index, term),
+                _ => return Err(KvsError::Raft("snapshot without its index".to_owned())),
+            }
+        } else {
+            (0, 0)
+        };
+        let applied = read_json(&storage, APPLIED_NAME)?.unwrap_or_default();
+
+        let mut file = storage.open(LOG_NAME)?;
+        let mut entries: Vec<Entry> = Vec::new();
+        let reader = BufReader::new(StorageReader::new(&mut file));
+        for record in Deserializer::from_reader(reader).into_iter::<LogRecord>() {
+            match record? {
+                LogRecord::Entry(entry) => {
+                    if entry.index > snapshot_index {
+                        entries.truncate((entry.index - snapshot_index - 1) as usize);
+                        entries.push(entry);
+                    }
+                }
+                LogRecord::Truncate(index) => {
+                    entries.truncate(index.saturating_sub(snapshot_index + 1) as usize)
+                }
+            }
+        }
+
+        Ok(RaftLog {
+            storage,
+            file,
+            term: hard.term,
+            voted_for: hard.voted_for,
+            has_snapshot,
+            snapshot_index,
+            snapshot_term,
+            applied,
+            entries,
+            received: None,
+        })
+    }
+
+    pub(super) fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub(super) fn voted_for(&self) -> Option<u64> {
+        self.voted_for
+    }
+
+    /// Persist the term and vote.
+    pub(super) fn set_hard_state(&mut self, term: u64, voted_for: Option<u64>) -> Result<()> {
+        let hard = HardState { term, voted_for };
+        write_file(&self.storage, STATE_NAME, &serde_json::to_vec(&hard)?)?;
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    /// Whether a snapshot was installed, rather than the log starting from
+    /// an empty one.
+    pub(super) fn has_snapshot(&self) -> bool {
+        self.has_snapshot
+    }
+
+    /// Index of the latest entry the snapshot includes.
+    pub(super) fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    /// Index of the latest entry the engine was flushed with.
+    pub(super) fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Persist that the engine was flushed with the entries up to the index.
+    pub(super) fn set_applied(&mut self, index: u64) -> Result<()> {
+        write_file(&self.storage, APPLIED_NAME, &serde_json::to_vec(&index)?)?;
+        self.applied = index;
+        Ok(())
+    }
+
+    pub(super) fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub(super) fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at the index, `None` if the log does not have
+    /// it, or no longer does.
+    pub(super) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else {
+            self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    pub(super) fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    /// At most `limit` entries from the given index on, which must follow
+    /// the snapshot.
+    pub(super) fn entries_from(&self, index: u64, limit: usize) -> Vec<Entry> {
+        let start = (index - self.snapshot_index - 1) as usize;
+        self.entries
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Append entries following those of the log, replacing the entries
+    /// they conflict with and everything after them. Entries the log
+    /// already has, or which the snapshot covers, are skipped.
+    pub(super) fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.entries
+                        .truncate((entry.index - self.snapshot_index - 1) as usize);
+                    serde_json::to_writer(&mut buf, &LogRecord::Truncate(entry.index))?;
+                }
+                None => {}
+            }
+            serde_json::to_writer(&mut buf, &LogRecord::Entry(entry.clone()))?;
+            self.entries.push(entry);
+        }
+        if !buf.is_empty() {
+            self.file.append(&buf)?;
+            self.file.sync()?;
+        }
+        Ok(())
+    }
+
+    /// An empty file to write a new snapshot to, which `install_new`
+    /// later turns into the snapshot of the log.
+    pub(super) fn new_snapshot(&self) -> Result<S::File> {
+        remove_file(&self.storage, NEW_SNAPSHOT_NAME)?;
+        Ok(self.storage.open(NEW_SNAPSHOT_NAME)?)
+    }
+
+    /// Make the snapshot written to the file of `new_snapshot` the snapshot
+    /// of the log.
+    pub(super) fn install_new(&mut self, index: u64, term: u64) -> Result<()> {
+        self.storage.rename(NEW_SNAPSHOT_NAME, SNAPSHOT_NAME)?;
+        self.compact(index, term)
+    }
+
+    /// Drop the snapshot written to the file of `new_snapshot`.
+    pub(super) fn discard_new(&self) -> Result<()> {
+        remove_file(&self.storage, NEW_SNAPSHOT_NAME)
+    }
+
+    /// Add a chunk of the snapshot sent by the leader, which starts a new
+    /// snapshot at offset 0. Chunks which do not follow the part received
+    /// so far are ignored.
+    ///
+    /// Returns how many bytes of the snapshot were received.
+    pub(super) fn receive(
+        &mut self,
+        index: u64,
+        term: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u64> {
+        if offset == 0 {
+            self.received = None;
+            remove_file(&self.storage, RECEIVED_SNAPSHOT_NAME)?;
+            self.received = Some(Received {
+                index,
+                term,
+                file: self.storage.open(RECEIVED_SNAPSHOT_NAME)?,
+            });
+        }
+        match &mut self.received {
+            Some(received) if received.index == index && received.term == term => {
+                if received.file.size() == offset {
+                    received.file.append(data)?;
+                }
+                Ok(received.file.size())
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Make the snapshot received from the leader the snapshot of the log.
+    pub(super) fn install_received(&mut self) -> Result<()> {
+        let mut received = match self.received.take() {
+            Some(received) => received,
+            None => return Err(KvsError::Raft("no snapshot received".to_owned())),
+        };
+        received.file.sync()?;
+        drop(received.file);
+        self.storage.rename(RECEIVED_SNAPSHOT_NAME, SNAPSHOT_NAME)?;
+        self.compact(received.index, received.term)
+    }
+
+    /// Up to `len` bytes of the snapshot file from the offset, and whether
+    /// they reach its end.
+    pub(super) fn snapshot_chunk(&self, offset: u64, len: usize) -> Result<(Vec<u8>, bool)> {
+        let mut file = self.storage.open(SNAPSHOT_NAME)?;
+        let size = file.size();
+        let len = (size.saturating_sub(offset)).min(len as u64);
+        let mut data = vec![0; len as usize];
+        file.read_exact_at(offset, &mut data)?;
+        Ok((data, offset + len >= size))
+    }
+
+    /// Call `f` with every page of versions, then of keys and values, of
+    /// the snapshot, in order.
+    pub(super) fn read_snapshot<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(SnapshotRecord) -> Result<()>,
+    {
+        if !self.has_snapshot {
+            return Ok(());
+        }
+        let mut file = self.storage.open(SNAPSHOT_NAME)?;
+        let reader = BufReader::new(StorageReader::new(&mut file));
+        for record in Deserializer::from_reader(reader).into_iter::<SnapshotRecord>() {
+            match record? {
+                SnapshotRecord::Meta { .. } => {}
+                record => f(record)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the entries the new snapshot covers. The entries following
+    // it are kept if the log agrees with it, and dropped otherwise.
+    fn compact(&mut self, index: u64, term: u64) -> Result<()> {
+        let kept = if self.term_at(index) == Some(term) {
+            self.entries
+                .split_off((index - self.snapshot_index) as usize)
+        } else {
+            Vec::new()
+        };
+
+        // the log only keeps the entries following the snapshot
+        let mut buf = Vec::new();
+        for entry in &kept {
+            serde_json::to_writer(&mut buf, &LogRecord::Entry(entry.clone()))?;
+        }
+        write_file(&self.storage, LOG_NAME, &buf)?;
+        self.file = self.storage.open(LOG_NAME)?;
+
+        self.has_snapshot = true;
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.entries = kept;
+        Ok(())
+    }
+}
+
+/// Write a record to a snapshot file.
+pub(super) fn write_record<F: StorageFile>(file: &mut F, record: &SnapshotRecord) -> Result<()> {
+    file.append(&serde_json::to_vec(record)?)?;
+    Ok(())
+}
+
+/// Read a JSON value from the whole file, `None` if there is no such file.
+fn read_json<S: Storage, T: DeserializeOwned>(storage: &S, name: &str) -> Result<Option<T>> {
+    if !storage.list()?.iter().any(|file| file == name) {
+        return Ok(None);
+    }
+    let mut file = storage.open(name)?;
+    let mut buf = vec![0; file.size() as usize];
+    file.read_exact_at(0, &mut buf)?;
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+/// Delete a file if it exists.
+fn remove_file<S: Storage>(storage: &S, name: &str) -> Result<()> {
+    if storage.list()?.iter().any(|file| file == name) {
+        storage.delete(name)?;
+    }
+    Ok(())
+}
+
+/// Replace the content of a file at once, writing a new file before
+/// renaming it over the old one.
+fn write_file<S: Storage>(storage: &S, name: &str, data: &[u8]) -> Result<()> {
+    let tmp_name = format!("{}.tmp", name);
+    remove_file(storage, &tmp_name)?;
+    let mut file = storage.open(&tmp_name)?;
+    file.append(data)?;
+    file.sync()?;
+    drop(file);
+    storage.rename(&tmp_name, name)?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! Replication of writes through the Raft consensus algorithm.
+//!
+//! A cluster is a fixed group of nodes, each with its own engine. Writes are
+//! appended to the log of the elected leader, which replicates them to the
+//! other nodes. A write is applied to the engines once a majority of the
+//! nodes have it in their log, so it survives the loss of a minority of the
+//! nodes.
+//!
+//! `RaftNode` is the algorithm itself. It does no I/O besides persisting its
+//! log: the driver feeds it with ticks of a logical clock and with the
+//! messages of the other nodes, and delivers the messages it produces. This
+//! lets tests run whole clusters over a simulated network. `RaftEngine`
+//! drives a node on behalf of a `KvsServer`, while a `RaftServer` carries
+//! the messages between the nodes over TCP.
+//!
+//! Once enough entries are applied, a snapshot of the engine replaces them
+//! in the log. It is taken while the node keeps going, and sent to the
+//! followers which need it in chunks.
+//!
+//! The messages between the nodes are sent in plaintext, and a node takes
+//! them from anyone reaching its Raft port. Until they are secured, the
+//! server refuses to join a cluster while it serves TLS or authenticates its
+//! clients, as the Raft port would bypass both.
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+pub use self::engine::RaftEngine;
+pub use self::node::{Applied, RaftNode, Role, SnapshotTask};
+use crate::{KvsError, Result};
+
+mod engine;
+mod log;
+mod node;
+
+/// A change to the keys, ordered by the log.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Changes nothing: every leader appends one to commit the entries of
+    /// previous terms.
+    Noop,
+    /// Set a key to a value.
+    Set {
+        /// A string key.
+        key: String,
+        /// A string value.
+        value: String,
+    },
+    /// Remove a key.
+    Remove {
+        /// A string key.
+        key: String,
+    },
+    /// Apply the writes unless a key read changed, as checked by every node
+    /// when applying the entry.
+    Transact {
+        /// Keys read by the transaction and the versions they were at: the
+        /// index of the entry which last wrote them.
+        reads: Vec<(String, Option<u64>)>,
+        /// Values written by the transaction, `None` removing the key.
+        writes: Vec<(String, Option<String>)>,
+    },
+}
+
+/// An entry of the log.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Term of the leader which appended the entry.
+    pub term: u64,
+    /// Position of the entry in the log, from 1.
+    pub index: u64,
+    /// The change the entry carries.
+    pub command: Command,
+}
+
+/// Message from a node to another.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Message {
+    /// Id of the sending node.
+    pub from: u64,
+    /// Id of the receiving node.
+    pub to: u64,
+    /// Term of the sending node.
+    pub term: u64,
+    /// What the message says.
+    pub body: MessageBody,
+}
+
+/// The content of a `Message`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MessageBody {
+    /// A candidate asks for a vote.
+    RequestVote {
+        /// Index of the last entry of the candidate.
+        last_index: u64,
+        /// Term of the last entry of the candidate.
+        last_term: u64,
+    },
+    /// Answer to a `RequestVote`.
+    Vote {
+        /// Whether the vote is granted.
+        granted: bool,
+    },
+    /// The leader appends entries to the log of a follower, or only tells it
+    /// about itself if there are none.
+    Append {
+        /// Index of the entry preceding the entries.
+        prev_index: u64,
+        /// Term of the entry preceding the entries.
+        prev_term: u64,
+        /// The entries to append.
+        entries: Vec<Entry>,
+        /// Index of the latest entry the leader knows to be committed.
+        commit: u64,
+    },
+    /// Answer to an `Append` or an `InstallSnapshot`.
+    AppendReply {
+        /// Whether the follower's log now matches the leader's.
+        success: bool,
+        /// On success, the index of the last entry known to match; on
+        /// failure, where the leader should look for a match.
+        last_index: u64,
+    },
+    /// A chunk of the snapshot of the leader, which replaces the log of a
+    /// follower missing entries the leader no longer has.
+    InstallSnapshot {
+        /// Index of the latest entry the snapshot includes.
+        index: u64,
+        /// Term of that entry.
+        term: u64,
+        /// Where the chunk starts in the snapshot, in bytes.
+        offset: u64,
+        /// The bytes of the chunk.
+        data: Vec<u8>,
+        /// Whether the chunk is the last of the snapshot.
+        done: bool,
+    },
+    /// Answer to an `InstallSnapshot` which is not the last chunk.
+    InstallSnapshotReply {
+        /// Index of the latest entry the snapshot includes.
+        index: u64,
+        /// How many bytes of the snapshot the follower received, where the
+        /// next chunk starts.
+        offset: u64,
+    },
+}
+
+/// The nodes of a cluster.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// Every node of the cluster.
+    pub nodes: Vec<NodeConfig>,
+}
+
+/// A node of a cluster.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeConfig {
+    /// Id of the node, unique in the cluster.
+    pub id: u64,
+    /// Address the node serves clients on, where other nodes redirect
+    /// clients to.
+    pub addr: SocketAddr,
+    /// Address the node serves the other nodes on.
+    pub raft_addr: SocketAddr,
+}
+
+impl ClusterConfig {
+    /// Load the cluster from a JSON file, and validate it.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the file cannot be read or parsed, or if the
+    /// cluster is not valid.
+    pub fn from_file(path: &Path) -> Result<ClusterConfig> {
+        let config: ClusterConfig = serde_json::from_slice(&fs::read(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the cluster has nodes, with different ids.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::ClusterConfig` describing the first problem found.
+    pub fn validate(&self) -> Result<()> {
+        if self.nodes.is_empty() {
+            return Err(KvsError::ClusterConfig("no nodes".to_owned()));
+        }
+        let mut ids = HashSet::new();
+        for node in &self.nodes {
+            if !ids.insert(node.id) {
+                return Err(KvsError::ClusterConfig(format!(
+                    "duplicate node id {}",
+                    node.id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The node with the given id.
+    pub fn node(&self, id: u64) -> Option<&NodeConfig> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+}
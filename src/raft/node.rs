@@ -0,0 +1,837 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+
+use super::log::{write_record, RaftLog, SnapshotRecord};
+use super::{Command, Entry, Message, MessageBody};
+use crate::engines::replace_keys;
+use crate::storage::{Storage, StorageFile};
+use crate::{KvsEngine, KvsError, Result};
+
+// ticks without hearing from a leader before a follower starts an election,
+// at least; the actual timeout is randomized up to twice as much
+const ELECTION_TICKS: u32 = 10;
+// ticks between the heartbeats of a leader
+const HEARTBEAT_TICKS: u32 = 3;
+// the most entries sent in one `Append`
+const MAX_APPEND_ENTRIES: usize = 64;
+// applied entries kept in the log before a snapshot replaces them
+const DEFAULT_SNAPSHOT_THRESHOLD: u64 = 1024;
+// the number of keys read at once to take a snapshot
+const SNAPSHOT_PAGE_SIZE: usize = 1024;
+// the most bytes of a snapshot sent in one `InstallSnapshot`, by default
+const DEFAULT_SNAPSHOT_CHUNK_LEN: usize = 1024 * 1024;
+
+/// What a node currently is in its cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Follows the leader, voting in elections.
+    Follower,
+    /// Asks the other nodes to vote for it.
+    Candidate,
+    /// Accepts writes and replicates them to the followers.
+    Leader,
+}
+
+/// An entry applied to the engine.
+#[derive(Debug)]
+pub struct Applied {
+    /// Index of the entry.
+    pub index: u64,
+    /// Term of the entry: a write proposed in another term was replaced by
+    /// this entry, and is lost.
+    pub term: u64,
+    /// What applying the entry returned, e.g. `KvsError::KeyNotFound` for
+    /// the removal of a missing key.
+    pub result: Result<()>,
+}
+
+/// A snapshot of the engine to take, from `RaftNode::start_snapshot`.
+///
+/// `run` reads the whole engine, so it is meant to be called without
+/// holding on to the node, which keeps applying entries meanwhile. The
+/// snapshot then includes every entry up to its index, and maybe some of
+/// the following ones, which applying them again on top of it fixes.
+pub struct SnapshotTask<E, F> {
+    index: u64,
+    term: u64,
+    engine: E,
+    // the versions of the keys as of the index
+    versions: BTreeMap<String, u64>,
+    file: F,
+    // the restores of the engine by the node when the task started
+    restores: u64,
+    written: bool,
+}
+
+impl<E: KvsEngine, F: StorageFile> SnapshotTask<E, F> {
+    /// Index of the latest entry the snapshot includes.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Write every key of the engine to the snapshot.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the keys cannot be read or written.
+    pub fn run(&mut self) -> Result<()> {
+        write_snapshot(
+            &self.engine,
+            &mut self.file,
+            self.index,
+            self.term,
+            &self.versions,
+        )?;
+        // the node may then count on the engine having the entries
+        self.engine.flush()?;
+        self.written = true;
+        Ok(())
+    }
+}
+
+/// A node of a Raft cluster, applying the committed entries of its log to
+/// an engine.
+///
+/// The node only moves on when its driver calls it: `tick` advances its
+/// logical clock, `step` hands it a message from another node, and
+/// `propose` appends a write to the log of a leader. The messages it sends
+/// in response are collected by `take_messages`, and the results of the
+/// entries it applied by `take_applied`. Once enough entries are applied,
+/// `start_snapshot` gives the snapshot to take, to hand back to
+/// `finish_snapshot`.
+///
+/// The version of a key is the index of the entry which last wrote it, 0 for
+/// the keys the engine had before the node first started. The node keeps
+/// them along with its snapshots, so that every node checks the reads of a
+/// transaction against the same versions, whatever its engine.
+pub struct RaftNode<E: KvsEngine, S: Storage> {
+    id: u64,
+    peers: Vec<u64>,
+    engine: E,
+    log: RaftLog<S>,
+    // the version of every key applied to the engine
+    versions: BTreeMap<String, u64>,
+    role: Role,
+    leader: Option<u64>,
+    commit_index: u64,
+    last_applied: u64,
+    // ticks since the last heartbeat sent or received, or since the election
+    elapsed: u32,
+    election_timeout: u32,
+    votes: HashSet<u64>,
+    // what the leader knows of the logs of its followers
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    // the snapshot index and offset of the chunk to send next to followers
+    // receiving a snapshot
+    snapshot_offsets: HashMap<u64, (u64, u64)>,
+    snapshot_threshold: u64,
+    snapshot_chunk_len: usize,
+    // whether a `SnapshotTask` is running
+    snapshotting: bool,
+    // how many times the engine was restored from a snapshot
+    restores: u64,
+    rng: u64,
+    messages: Vec<Message>,
+    applied: Vec<Applied>,
+}
+
+impl<E: KvsEngine, S: Storage> RaftNode<E, S> {
+    /// Create the node with the given id, in a cluster with the given other
+    /// nodes, restoring the state persisted in the storage.
+    ///
+    /// An engine which kept the entries up to the latest snapshot is used as
+    /// is, while one that lost them, e.g. an in-memory engine, gets the keys
+    /// of the snapshot back. Either way, the following entries are applied
+    /// again once the node learns that they are committed. A new node starts
+    /// with an empty engine, as keys the cluster never committed would only
+    /// be on this node.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the persisted state cannot be read, the engine
+    /// cannot be read or restored, or the engine of a new node has keys.
+    pub fn new(id: u64, peers: Vec<u64>, engine: E, storage: S) -> Result<Self> {
+        let mut log = RaftLog::open(storage)?;
+        let mut versions = BTreeMap::new();
+        if !log.has_snapshot() {
+            if log.last_index() == 0 && !is_empty(&engine)? {
+                return Err(KvsError::Raft(
+                    "the engine of a new node has keys the cluster never committed".to_owned(),
+                ));
+            }
+        } else if log.snapshot_index() > log.applied() || is_empty(&engine)? {
+            versions = restore(&engine, &log)?;
+            engine.flush()?;
+            let index = log.snapshot_index();
+            log.set_applied(index)?;
+        } else {
+            log.read_snapshot(|record| {
+                if let SnapshotRecord::Versions(page) = record {
+                    versions.extend(page);
+                }
+                Ok(())
+            })?;
+        }
+        let snapshot_index = log.snapshot_index();
+        let mut node = RaftNode {
+            id,
+            peers: peers.into_iter().filter(|&peer| peer != id).collect(),
+            engine,
+            log,
+            versions,
+            role: Role::Follower,
+            leader: None,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            elapsed: 0,
+            election_timeout: ELECTION_TICKS,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            snapshot_offsets: HashMap::new(),
+            snapshot_threshold: DEFAULT_SNAPSHOT_THRESHOLD,
+            snapshot_chunk_len: DEFAULT_SNAPSHOT_CHUNK_LEN,
+            snapshotting: false,
+            restores: 0,
+            // nodes have different ids, so that they time out differently
+            rng: id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            messages: Vec::new(),
+            applied: Vec::new(),
+        };
+        node.reset_election_timeout();
+        Ok(node)
+    }
+
+    /// Set how many applied entries the log keeps before replacing them
+    /// with a snapshot of the engine. Defaults to 1024.
+    pub fn set_snapshot_threshold(&mut self, entries: u64) {
+        self.snapshot_threshold = entries.max(1);
+    }
+
+    /// Set the most bytes of a snapshot sent to a follower in one message.
+    /// Defaults to 1 MiB.
+    pub fn set_snapshot_chunk_len(&mut self, len: usize) {
+        self.snapshot_chunk_len = len.max(1);
+    }
+
+    /// The engine the node applies entries to.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Id of the node.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// What the node currently is.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Id of the leader of the current term, if the node knows it.
+    pub fn leader(&self) -> Option<u64> {
+        self.leader
+    }
+
+    /// The current term.
+    pub fn term(&self) -> u64 {
+        self.log.term()
+    }
+
+    /// Index of the latest entry known to be committed.
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// Index of the latest entry applied to the engine.
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    /// The version of the key as of the latest entry applied, `None` if the
+    /// key does not exist.
+    pub fn version(&self, key: &str) -> Option<u64> {
+        self.versions.get(key).copied()
+    }
+
+    /// Index of the latest entry the latest snapshot includes.
+    pub fn snapshot_index(&self) -> u64 {
+        self.log.snapshot_index()
+    }
+
+    /// The snapshot to take, once the log has enough applied entries and
+    /// no snapshot is being taken.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the file of the snapshot cannot be created.
+    pub fn start_snapshot(&mut self) -> Result<Option<SnapshotTask<E, S::File>>> {
+        if self.snapshotting
+            || self.last_applied - self.log.snapshot_index() < self.snapshot_threshold
+        {
+            return Ok(None);
+        }
+        let task = SnapshotTask {
+            index: self.last_applied,
+            term: self.log.term_at(self.last_applied).unwrap_or(0),
+            engine: self.engine.clone(),
+            versions: self.versions.clone(),
+            file: self.log.new_snapshot()?,
+            restores: self.restores,
+            written: false,
+        };
+        self.snapshotting = true;
+        Ok(Some(task))
+    }
+
+    /// Replace the entries of the log with the snapshot, once written. A
+    /// snapshot which failed, or which the engine was restored from another
+    /// one during, is dropped.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the snapshot cannot be installed.
+    pub fn finish_snapshot(&mut self, task: SnapshotTask<E, S::File>) -> Result<()> {
+        self.snapshotting = false;
+        let SnapshotTask {
+            index,
+            term,
+            file,
+            restores,
+            written,
+            ..
+        } = task;
+        drop(file);
+        if !written || restores != self.restores || index <= self.log.snapshot_index() {
+            return self.log.discard_new();
+        }
+        self.log.install_new(index, term)?;
+        self.log.set_applied(index)
+    }
+
+    /// The messages to deliver to other nodes since the last call.
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        mem::take(&mut self.messages)
+    }
+
+    /// The entries applied to the engine since the last call.
+    pub fn take_applied(&mut self) -> Vec<Applied> {
+        mem::take(&mut self.applied)
+    }
+
+    /// Advance the logical clock by one tick: leaders send heartbeats, and
+    /// other nodes start an election once they go without a leader for
+    /// long enough.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the node fails to persist its state.
+    pub fn tick(&mut self) -> Result<()> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader if self.elapsed >= HEARTBEAT_TICKS => {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+            Role::Leader => {}
+            Role::Follower | Role::Candidate if self.elapsed >= self.election_timeout => {
+                self.campaign()?;
+            }
+            Role::Follower | Role::Candidate => {}
+        }
+        Ok(())
+    }
+
+    /// Append a write to the log, which is applied once committed.
+    ///
+    /// Returns the index and term of the new entry: the write took effect
+    /// if `take_applied` later reports an entry with this index and term.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::NotLeader` if the node is not the leader, or an
+    /// error if the node fails to persist the entry.
+    pub fn propose(&mut self, command: Command) -> Result<(u64, u64)> {
+        if self.role != Role::Leader {
+            return Err(KvsError::NotLeader(None));
+        }
+        let entry = Entry {
+            term: self.term(),
+            index: self.log.last_index() + 1,
+            command,
+        };
+        let proposal = (entry.index, entry.term);
+        self.log.append(vec![entry])?;
+        self.advance_commit()?;
+        self.broadcast_append();
+        Ok(proposal)
+    }
+
+    /// Handle a message from another node.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the node fails to persist its state, or to apply
+    /// committed entries to the engine.
+    pub fn step(&mut self, msg: Message) -> Result<()> {
+        if msg.term > self.term() {
+            self.become_follower(msg.term)?;
+        }
+        if msg.term < self.term() {
+            // stale nodes learn about the current term from the answer
+            match msg.body {
+                MessageBody::RequestVote { .. } => {
+                    self.send(msg.from, MessageBody::Vote { granted: false })
+                }
+                MessageBody::Append { .. } | MessageBody::InstallSnapshot { .. } => self.send(
+                    msg.from,
+                    MessageBody::AppendReply {
+                        success: false,
+                        last_index: self.log.last_index(),
+                    },
+                ),
+                MessageBody::Vote { .. }
+                | MessageBody::AppendReply { .. }
+                | MessageBody::InstallSnapshotReply { .. } => {}
+            }
+            return Ok(());
+        }
+
+        match msg.body {
+            MessageBody::RequestVote {
+                last_index,
+                last_term,
+            } => {
+                // only candidates with a log as recent as ours get the vote
+                let up_to_date =
+                    (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+                let free = self.log.voted_for().is_none_or(|id| id == msg.from);
+                let granted = up_to_date && free && self.role != Role::Leader;
+                if granted {
+                    self.log.set_hard_state(self.term(), Some(msg.from))?;
+                    self.elapsed = 0;
+                }
+                self.send(msg.from, MessageBody::Vote { granted });
+            }
+            MessageBody::Vote { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(msg.from);
+                    if self.has_quorum(self.votes.len()) {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            MessageBody::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                self.follow(msg.from);
+                self.append(msg.from, prev_index, prev_term, entries, commit)?;
+            }
+            MessageBody::AppendReply {
+                success,
+                last_index,
+            } => {
+                if self.role == Role::Leader {
+                    self.append_reply(msg.from, success, last_index)?;
+                }
+            }
+            MessageBody::InstallSnapshot {
+                index,
+                term,
+                offset,
+                data,
+                done,
+            } => {
+                self.follow(msg.from);
+                self.install_snapshot(msg.from, index, term, offset, &data, done)?;
+            }
+            MessageBody::InstallSnapshotReply { index, offset } => {
+                if self.role == Role::Leader && index == self.log.snapshot_index() {
+                    self.snapshot_offsets.insert(msg.from, (index, offset));
+                    self.send_append(msg.from);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn install_snapshot(
+        &mut self,
+        leader: u64,
+        index: u64,
+        term: u64,
+        offset: u64,
+        data: &[u8],
+        done: bool,
+    ) -> Result<()> {
+        if index > self.commit_index {
+            let received = self.log.receive(index, term, offset, data)?;
+            if !done || received != offset + data.len() as u64 {
+                self.send(
+                    leader,
+                    MessageBody::InstallSnapshotReply {
+                        index,
+                        offset: received,
+                    },
+                );
+                return Ok(());
+            }
+            // the engine is restored on restart if this is interrupted
+            self.log.install_received()?;
+            self.restores += 1;
+            self.versions = restore(&self.engine, &self.log)?;
+            self.engine.flush()?;
+            self.log.set_applied(index)?;
+            self.commit_index = index;
+            self.last_applied = index;
+        }
+        self.send(
+            leader,
+            MessageBody::AppendReply {
+                success: true,
+                last_index: index,
+            },
+        );
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        leader: u64,
+        prev_index: u64,
+        prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<()> {
+        let last_new = prev_index + entries.len() as u64;
+        let snapshot_index = self.log.snapshot_index();
+        if prev_index < snapshot_index {
+            // the snapshot holds committed entries, which match the leader's
+            entries.retain(|entry| entry.index > snapshot_index);
+        } else if self.log.term_at(prev_index) != Some(prev_term) {
+            let hint = self.log.last_index().min(prev_index.saturating_sub(1));
+            self.send(
+                leader,
+                MessageBody::AppendReply {
+                    success: false,
+                    last_index: hint,
+                },
+            );
+            return Ok(());
+        }
+        self.log.append(entries)?;
+        if commit > self.commit_index {
+            self.commit_index = commit.min(last_new.max(snapshot_index));
+            self.apply()?;
+        }
+        self.send(
+            leader,
+            MessageBody::AppendReply {
+                success: true,
+                last_index: last_new.max(snapshot_index),
+            },
+        );
+        Ok(())
+    }
+
+    fn append_reply(&mut self, peer: u64, success: bool, last_index: u64) -> Result<()> {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1);
+        if success {
+            let matched = self.match_index.entry(peer).or_insert(0);
+            *matched = (*matched).max(last_index);
+            let matched = *matched;
+            self.next_index.insert(peer, matched + 1);
+            self.advance_commit()?;
+            if matched < self.log.last_index() {
+                self.send_append(peer);
+            }
+        } else {
+            // back off to where the follower's log may match
+            let next = next.saturating_sub(1).min(last_index + 1).max(1);
+            self.next_index.insert(peer, next);
+            self.send_append(peer);
+        }
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        let term = self.term() + 1;
+        self.log.set_hard_state(term, Some(self.id))?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::new();
+        self.votes.insert(self.id);
+        self.elapsed = 0;
+        self.reset_election_timeout();
+        if self.has_quorum(self.votes.len()) {
+            return self.become_leader();
+        }
+        for peer in self.peers.clone() {
+            self.send(
+                peer,
+                MessageBody::RequestVote {
+                    last_index: self.log.last_index(),
+                    last_term: self.log.last_term(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64) -> Result<()> {
+        self.log.set_hard_state(term, None)?;
+        self.role = Role::Follower;
+        self.leader = None;
+        self.elapsed = 0;
+        Ok(())
+    }
+
+    // Hears from the leader of the current term.
+    fn follow(&mut self, leader: u64) {
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.elapsed = 0;
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        let next = self.log.last_index() + 1;
+        self.next_index = self.peers.iter().map(|&peer| (peer, next)).collect();
+        self.match_index = self.peers.iter().map(|&peer| (peer, 0)).collect();
+        // entries of previous terms only commit along with one of this term
+        self.propose(Command::Noop)?;
+        Ok(())
+    }
+
+    fn has_quorum(&self, count: usize) -> bool {
+        count * 2 > self.peers.len() + 1
+    }
+
+    // Commits the latest entry of the current term a majority has.
+    fn advance_commit(&mut self) -> Result<()> {
+        let mut index = self.log.last_index();
+        while index > self.commit_index {
+            if self.log.term_at(index) == Some(self.term()) {
+                let count = 1 + self
+                    .match_index
+                    .values()
+                    .filter(|&&matched| matched >= index)
+                    .count();
+                if self.has_quorum(count) {
+                    self.commit_index = index;
+                    return self.apply();
+                }
+            } else {
+                // earlier entries are of earlier terms
+                break;
+            }
+            index -= 1;
+        }
+        Ok(())
+    }
+
+    // Applies the committed entries to the engine.
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let entry = self
+                .log
+                .entry(self.last_applied + 1)
+                .expect("committed entry not in the log")
+                .clone();
+            let result = match entry.command {
+                Command::Noop => Ok(()),
+                Command::Set { key, value } => self.write(entry.index, key, Some(value)),
+                Command::Remove { key } if self.versions.contains_key(&key) => {
+                    self.write(entry.index, key, None)
+                }
+                Command::Remove { .. } => Err(KvsError::KeyNotFound),
+                Command::Transact { reads, writes } => self.transact(entry.index, reads, writes),
+            };
+            let result = match result {
+                // failures of the command itself, the same on every node
+                Err(e @ (KvsError::KeyNotFound | KvsError::Conflict(_))) => Err(e),
+                Err(e) => return Err(e),
+                Ok(()) => Ok(()),
+            };
+            self.last_applied = entry.index;
+            self.applied.push(Applied {
+                index: entry.index,
+                term: entry.term,
+                result,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks the versions of the keys read, then applies the writes.
+    fn transact(
+        &mut self,
+        index: u64,
+        reads: Vec<(String, Option<u64>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        if let Some((key, _)) = reads
+            .into_iter()
+            .find(|(key, version)| self.version(key) != *version)
+        {
+            return Err(KvsError::Conflict(key));
+        }
+        writes
+            .into_iter()
+            .try_for_each(|(key, value)| self.write(index, key, value))
+    }
+
+    // Sets the key to the value, or removes it if `None`, as written by the
+    // entry at the index.
+    fn write(&mut self, index: u64, key: String, value: Option<String>) -> Result<()> {
+        match value {
+            Some(value) => {
+                self.versions.insert(key.clone(), index);
+                self.engine.set(key, value)
+            }
+            None => {
+                self.versions.remove(&key);
+                // the engine lacks the key if it was missing, or if the entry
+                // is applied again on top of a snapshot which has it applied
+                match self.engine.remove(key) {
+                    Err(KvsError::KeyNotFound) => Ok(()),
+                    res => res,
+                }
+            }
+        }
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    // Sends the entries the follower is missing, or the next chunk of the
+    // snapshot if the log no longer has them.
+    fn send_append(&mut self, peer: u64) {
+        let next = self
+            .next_index
+            .get(&peer)
+            .copied()
+            .unwrap_or(self.log.last_index() + 1);
+        let snapshot_index = self.log.snapshot_index();
+        let body = if next <= snapshot_index {
+            let offset = match self.snapshot_offsets.get(&peer) {
+                Some(&(index, offset)) if index == snapshot_index => offset,
+                _ => 0,
+            };
+            match self.log.snapshot_chunk(offset, self.snapshot_chunk_len) {
+                Ok((data, done)) => MessageBody::InstallSnapshot {
+                    index: snapshot_index,
+                    term: self.log.term_at(snapshot_index).unwrap_or(0),
+                    offset,
+                    data,
+                    done,
+                },
+                // the next heartbeat tries again
+                Err(_) => return,
+            }
+        } else {
+            let prev_index = next - 1;
+            MessageBody::Append {
+                prev_index,
+                prev_term: self.log.term_at(prev_index).unwrap_or(0),
+                entries: self.log.entries_from(next, MAX_APPEND_ENTRIES),
+                commit: self.commit_index,
+            }
+        };
+        self.send(peer, body);
+    }
+
+    fn send(&mut self, to: u64, body: MessageBody) {
+        self.messages.push(Message {
+            from: self.id,
+            to,
+            term: self.term(),
+            body,
+        });
+    }
+
+    fn reset_election_timeout(&mut self) {
+        // xorshift
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.election_timeout = ELECTION_TICKS + (self.rng % ELECTION_TICKS as u64) as u32;
+    }
+}
+
+/// Write the versions, then every key of the engine, to a snapshot including
+/// the entries up to the index.
+fn write_snapshot<E: KvsEngine, F: StorageFile>(
+    engine: &E,
+    file: &mut F,
+    index: u64,
+    term: u64,
+    versions: &BTreeMap<String, u64>,
+) -> Result<()> {
+    write_record(file, &SnapshotRecord::Meta { index, term })?;
+    let versions: Vec<_> = versions
+        .iter()
+        .map(|(key, version)| (key.clone(), *version))
+        .collect();
+    for page in versions.chunks(SNAPSHOT_PAGE_SIZE) {
+        write_record(file, &SnapshotRecord::Versions(page.to_vec()))?;
+    }
+    let mut after = None;
+    loop {
+        let keys = engine.scan(after, SNAPSHOT_PAGE_SIZE)?;
+        let exhausted = keys.len() < SNAPSHOT_PAGE_SIZE;
+        after = keys.last().cloned();
+        let values = engine.get_many(keys.clone())?;
+        let pairs: Vec<_> = keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        if !pairs.is_empty() {
+            write_record(file, &SnapshotRecord::Pairs(pairs))?;
+        }
+        if exhausted {
+            file.sync()?;
+            return Ok(());
+        }
+    }
+}
+
+/// Whether the engine has no key at all.
+fn is_empty<E: KvsEngine>(engine: &E) -> Result<bool> {
+    Ok(engine.scan(None, 1)?.is_empty())
+}
+
+/// Replace every key of the engine with those of the snapshot of the log,
+/// a page at a time, and return the versions of the snapshot.
+fn restore<E: KvsEngine, S: Storage>(
+    engine: &E,
+    log: &RaftLog<S>,
+) -> Result<BTreeMap<String, u64>> {
+    let mut versions = BTreeMap::new();
+    let mut after: Option<String> = None;
+    log.read_snapshot(|record| {
+        match record {
+            SnapshotRecord::Versions(page) => versions.extend(page),
+            SnapshotRecord::Pairs(pairs) => {
+                let last = pairs.last().map(|(key, _)| key.clone());
+                replace_keys(engine, after.take(), last.as_deref(), pairs)?;
+                after = last;
+            }
+            SnapshotRecord::Meta { .. } => {}
+        }
+        Ok(())
+    })?;
+    replace_keys(engine, after, None, Vec::new())?;
+    Ok(versions)
+}
@@ -1,17 +1,67 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError};
+use rayon::prelude::*;
+use rustls::StreamOwned;
 use serde_json::Deserializer;
-use slog::{debug, error, info, Logger};
+use slog::{debug, error, info, warn, Logger};
 
-use crate::{KvsEngine, Request, Response, Result, ThreadPool};
+pub use self::follower::Follower;
+pub use self::raft::RaftServer;
+pub use self::resp::RespServer;
+use self::shutdown::Connections;
+pub use self::shutdown::ShutdownHandle;
+pub(crate) use self::transaction::Transaction;
+use crate::auth::{AuthConfig, Session};
+use crate::protocol::{
+    decode_request, downgrade_response, encode_response, read_frame, write_frame, Hello, MAGIC,
+};
+use crate::tls::{ServerTlsConfig, SharedStream, Stream};
+use crate::{
+    ChangeEvent, ChangeSink, ErrorCode, KvsEngine, KvsError, Request, Response, Result, Snapshot,
+    TaggedRequest, TaggedResponse, ThreadPool,
+};
+
+mod evented;
+mod follower;
+mod http;
+mod raft;
+mod resp;
+mod shutdown;
+mod transaction;
+
+// the maximum number of pipelined requests processed together
+const MAX_BATCH: usize = 128;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how often a connection streaming events checks whether it was closed
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// the events of a watch waiting to be written, past which the engine drops
+// the watch, e.g. when the client does not read them
+const WATCH_QUEUE_LEN: usize = 16 * 1024;
+// the number of keys read at once for a snapshot sent to a follower
+const SNAPSHOT_PAGE_SIZE: usize = 1024;
+// the number of changes read at once for a follower catching up
+const HISTORY_PAGE_SIZE: usize = 1024;
 
 /// Kvs Server.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     logger: Arc<Logger>,
     pool: P,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    tls: Option<ServerTlsConfig>,
+    auth: Option<Arc<AuthConfig>>,
+    http_addr: Option<SocketAddr>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -21,79 +71,874 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
             engine,
             logger: Arc::new(logger),
             pool,
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            idle_timeout: None,
+            tls: None,
+            auth: None,
+            http_addr: None,
         }
     }
 
+    /// Get a handle to stop the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Set how long a shutdown waits for the requests already received to
+    /// be answered before closing the remaining connections. Defaults to 5
+    /// seconds.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Set how long a connection may stay without sending a request, or
+    /// without accepting the bytes of a response, before the server closes
+    /// it. Defaults to `None`, which keeps connections open forever.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Require connections to be encrypted with TLS. Defaults to `None`,
+    /// which serves plain TCP connections.
+    pub fn set_tls(&mut self, tls: Option<ServerTlsConfig>) {
+        self.tls = tls;
+    }
+
+    /// Require clients to authenticate, and only execute the requests the
+    /// grants of their user allow. Defaults to `None`, which lets any client
+    /// access every key.
+    pub fn set_auth(&mut self, auth: Option<AuthConfig>) {
+        self.auth = auth.map(Arc::new);
+    }
+
+    /// Also serve the engine over HTTP/1.1 on the given address, with the
+    /// same TLS and authentication settings. Defaults to `None`, which
+    /// serves the kvs protocol only.
+    ///
+    /// - `GET /keys/{key}` answers `{"key": ..., "value": ...}`, or 404.
+    /// - `PUT /keys/{key}` sets the key to the `value` of a `{"value": ...}`
+    ///   body.
+    /// - `DELETE /keys/{key}` removes the key, or answers 404.
+    /// - `GET /keys?after={key}&limit={n}` answers `{"keys": [...], "next":
+    ///   ...}` with up to `limit` keys following `after`. `next` is the
+    ///   `after` of the following page, or `null` after the last one.
+    ///
+    /// Keys are percent-decoded and may contain `/`. Failures answer
+    /// `{"error": {"code": ..., "message": ...}}` with the `ErrorCode` of the
+    /// failure. With `set_auth`, requests authenticate with an
+    /// `Authorization: Bearer {token}` or `Basic` header.
+    pub fn set_http_addr(&mut self, addr: Option<SocketAddr>) {
+        self.http_addr = addr;
+    }
+
     /// Start KvsServer to serve incoming requests.
-    pub fn run(&mut self, addr: &SocketAddr) -> Result<()> {
+    ///
+    /// Every connection, watching ones included, occupies a pool thread
+    /// until it is closed. Returns once a shutdown is requested through a
+    /// `ShutdownHandle`.
+    pub fn run(&mut self, addr: &SocketAddr) -> Result<()>
+    where
+        P: Sync,
+    {
+        self.with_http(|server| server.accept(addr))
+    }
+
+    /// Start KvsServer in event-driven mode.
+    ///
+    /// A single thread waits for readiness events on every connection, so
+    /// idle connections cost no thread. Requests are executed on the thread
+    /// pool, one batch per connection at a time. HTTP connections, if any,
+    /// are served as in `run`. Returns once a shutdown is requested through
+    /// a `ShutdownHandle`.
+    pub fn run_evented(&mut self, addr: &SocketAddr) -> Result<()>
+    where
+        P: Sync,
+    {
+        self.with_http(|server| evented::run(server, addr))
+    }
+
+    // Runs the given server loop, along with the HTTP gateway on another
+    // thread sharing the pool, then flushes the engine.
+    fn with_http(&self, serve: impl FnOnce(&Self) -> Result<()>) -> Result<()>
+    where
+        P: Sync,
+    {
+        let http_addr = match self.http_addr {
+            Some(http_addr) => http_addr,
+            None => {
+                serve(self)?;
+                return self.stopped();
+            }
+        };
+        let listener = TcpListener::bind(http_addr)?;
+        info!(self.logger, "HTTP gateway bound to {:?}", http_addr);
+
+        let engine = self.engine.clone();
+        let logger = self.logger.clone();
+        let idle_timeout = self.idle_timeout;
+        let tls = self.tls.clone();
+        let auth = self.auth.clone();
+        let serve_http = move |stream| {
+            http::serve(
+                stream,
+                engine.clone(),
+                logger.clone(),
+                idle_timeout,
+                tls.clone(),
+                auth.clone(),
+            )
+        };
+        let shutdown = self.shutdown.clone();
+        let shutdown_timeout = self.shutdown_timeout;
+        let logger = self.logger.clone();
+        let pool = &self.pool;
+        let (res, http_res) = thread::scope(|scope| {
+            let gateway = scope.spawn(move || {
+                accept_loop(
+                    listener,
+                    &shutdown,
+                    shutdown_timeout,
+                    &logger,
+                    pool,
+                    serve_http,
+                )
+            });
+            let res = serve(self);
+            if res.is_err() {
+                // the gateway does not outlive the server
+                self.shutdown.shutdown();
+            }
+            (res, gateway.join().expect("HTTP gateway panicked"))
+        });
+        res?;
+        http_res?;
+        self.stopped()
+    }
+
+    // Serves each connection on a pool thread.
+    fn accept(&self, addr: &SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
         info!(self.logger, "Bind to address {:?}", addr);
 
-        for stream in listener.incoming() {
-            let logger = self.logger.clone();
-            let logger_copy = self.logger.clone();
-            let engine = self.engine.clone();
+        let engine = self.engine.clone();
+        let logger = self.logger.clone();
+        let idle_timeout = self.idle_timeout;
+        let tls = self.tls.clone();
+        let auth = self.auth.clone();
+        accept_loop(
+            listener,
+            &self.shutdown,
+            self.shutdown_timeout,
+            &self.logger,
+            &self.pool,
+            move |stream| {
+                let session = Session::new(auth.clone());
+                serve(
+                    stream,
+                    engine.clone(),
+                    logger.clone(),
+                    idle_timeout,
+                    tls.clone(),
+                    session,
+                )
+            },
+        )
+    }
 
-            self.pool.spawn(move || match stream {
-                Ok(s) => {
-                    if let Err(e) = serve(s, engine, logger) {
-                        error!(logger_copy, "Error processing incoming request: {}", e);
-                    }
-                }
-                Err(e) => error!(logger, "Connection failed: {}", e),
-            });
+    fn stopped(&self) -> Result<()> {
+        self.shutdown.clear_wakes();
+        self.engine.flush()?;
+        info!(self.logger, "Server stopped");
+        Ok(())
+    }
+}
+
+// Accepts connections until a shutdown is requested, serving each one on a
+// pool thread, then waits for the connections to end.
+fn accept_loop<P, F>(
+    listener: TcpListener,
+    shutdown: &ShutdownHandle,
+    shutdown_timeout: Duration,
+    logger: &Arc<Logger>,
+    pool: &P,
+    serve: F,
+) -> Result<()>
+where
+    P: ThreadPool,
+    F: Fn(TcpStream) -> Result<()> + Clone + Send + 'static,
+{
+    // a connection to the listener wakes the blocked `accept` up
+    let mut wake_addr = listener.local_addr()?;
+    if wake_addr.ip().is_unspecified() {
+        wake_addr.set_ip(match wake_addr {
+            SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+            SocketAddr::V6(_) => [0, 0, 0, 0, 0, 0, 0, 1].into(),
+        });
+    }
+    shutdown.add_wake(Box::new(move || {
+        let _ = TcpStream::connect(wake_addr);
+    }));
+
+    let connections = Arc::new(Connections::default());
+    while !shutdown.is_requested() {
+        let stream = listener.accept().map(|(s, _)| s);
+        if shutdown.is_requested() {
+            break;
         }
+        let stream = stream.and_then(|s| Ok((connections.track(&s)?, s)));
+        let logger = logger.clone();
+        let serve = serve.clone();
 
-        Ok(())
+        pool.spawn(move || match stream {
+            Ok((_tracked, s)) => {
+                if let Err(e) = serve(s) {
+                    error!(logger, "Error processing incoming request: {}", e);
+                }
+            }
+            Err(e) => error!(logger, "Connection failed: {}", e),
+        });
     }
+    drop(listener);
+
+    info!(
+        logger,
+        "Shutting down, waiting for {} connections",
+        connections.len()
+    );
+    // connections end once the requests already received are answered
+    connections.shutdown(Shutdown::Read);
+    if !connections.wait_closed(shutdown_timeout) {
+        warn!(
+            logger,
+            "Closing {} connections after the shutdown timeout",
+            connections.len()
+        );
+        connections.shutdown(Shutdown::Both);
+    }
+    Ok(())
 }
 
-fn serve<E: KvsEngine>(stream: TcpStream, engine: E, logger: Arc<Logger>) -> Result<()> {
+fn serve<E: KvsEngine>(
+    stream: TcpStream,
+    engine: E,
+    logger: Arc<Logger>,
+    idle_timeout: Option<Duration>,
+    tls: Option<ServerTlsConfig>,
+    mut session: Session,
+) -> Result<()> {
     let peer_addr = stream.peer_addr()?;
-    let reader = BufReader::new(stream.try_clone()?);
-    let mut writer = BufWriter::new(stream);
+    stream.set_read_timeout(idle_timeout)?;
+    stream.set_write_timeout(idle_timeout)?;
+    // the TLS handshake happens along with the first read
+    let stream = SharedStream::new(match tls {
+        Some(tls) => Stream::Server(Box::new(StreamOwned::new(tls.accept()?, stream))),
+        None => Stream::Plain(stream),
+    });
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = BufWriter::new(stream.clone());
 
-    let reader = Deserializer::from_reader(reader).into_iter::<Request>();
+    let res = serve_connection(
+        &mut reader,
+        &mut writer,
+        peer_addr,
+        &engine,
+        &mut session,
+        &logger,
+    );
+    if res.is_ok() {
+        // the client may be gone already
+        let _ = stream.close_notify();
+    }
+    match res {
+        Err(KvsError::Io(ref e))
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            debug!(logger, "Closing idle connection from {}", peer_addr);
+            Ok(())
+        }
+        res => res,
+    }
+}
 
-    macro_rules! send_resp {
-        ( $op: expr, $resp: expr ) => {
-            let rep = $resp;
-            serde_json::to_writer(&mut writer, &rep)?;
+fn serve_connection<E: KvsEngine>(
+    reader: &mut BufReader<SharedStream>,
+    writer: &mut BufWriter<SharedStream>,
+    peer_addr: SocketAddr,
+    engine: &E,
+    session: &mut Session,
+    logger: &Logger,
+) -> Result<()> {
+    // peek at the first byte to tell framed clients from legacy ones
+    let framed = match reader.fill_buf()?.first() {
+        Some(&byte) => byte == MAGIC[0],
+        None => return Ok(()),
+    };
+
+    if !framed {
+        debug!(logger, "Legacy connection from {}", peer_addr);
+        let reader = Deserializer::from_reader(reader).into_iter::<Request>();
+        for req in reader {
+            let resp = handle_in_order(engine, session, req?);
+            let resp = downgrade_response(&resp, 1).unwrap_or(resp);
+            serde_json::to_writer(&mut *writer, &resp)?;
             writer.flush()?;
-            debug!(logger, "{} response sent to {}: {:?}", $op, peer_addr, rep);
-        };
+            debug!(logger, "Response sent to {}: {:?}", peer_addr, resp);
+        }
+        return Ok(());
     }
 
-    for req in reader {
-        match req? {
-            Request::Set { key, value } => {
-                send_resp!("Set", {
-                    match engine.set(key, value) {
-                        Ok(_) => Response::Ok(None),
-                        Err(e) => Response::Err(e.to_string()),
-                    }
-                });
+    let hello = match Hello::read_from(reader)?.and_then(|hello| hello.negotiate()) {
+        Some(hello) => hello,
+        None => {
+            Hello::write_rejection(writer)?;
+            writer.flush()?;
+            info!(logger, "Unsupported protocol version from {}", peer_addr);
+            return Ok(());
+        }
+    };
+    hello.write_to(writer)?;
+    writer.flush()?;
+    debug!(
+        logger,
+        "Connection from {} uses protocol version {} with {:?}",
+        peer_addr,
+        hello.version,
+        hello.codec
+    );
+
+    while let Some(payload) = read_frame(reader)? {
+        let received = Instant::now();
+        // gather the requests the client has already pipelined
+        let mut batch = vec![payload];
+        while batch.len() < MAX_BATCH && !reader.buffer().is_empty() {
+            match read_frame(reader)? {
+                Some(payload) => batch.push(payload),
+                None => break,
             }
+        }
 
-            Request::Get { key } => {
-                send_resp!("Get", {
-                    match engine.get(key) {
-                        Ok(v) => Response::Ok(v),
-                        Err(e) => Response::Err(e.to_string()),
+        let (responses, watch) = handle_frames(engine, session, &hello, &batch, received);
+        for resp in responses {
+            write_frame(writer, &encode_response(&hello, &resp)?)?;
+            debug!(logger, "Response sent to {}: {:?}", peer_addr, resp);
+        }
+        if let Some(watch) = watch {
+            let (sender, events) = bounded(WATCH_QUEUE_LEN);
+            let deliver = move |event| sender.try_send(event).is_ok();
+            match start_watch(engine, session, watch, hello.version, deliver) {
+                Ok(mut answer) => {
+                    while let Some(resp) = answer.next(engine)? {
+                        write_frame(writer, &encode_response(&hello, &resp)?)?;
                     }
-                });
+                    writer.flush()?;
+                    debug!(logger, "Connection from {} watches changes", peer_addr);
+                    return stream_events(reader, writer, &hello, &events);
+                }
+                Err(resp) => write_frame(writer, &encode_response(&hello, &resp)?)?,
             }
+        }
+        writer.flush()?;
+    }
 
-            Request::Remove { key } => {
-                send_resp!("Remove", {
-                    match engine.remove(key) {
-                        Ok(_) => Response::Ok(None),
-                        Err(e) => Response::Err(e.to_string()),
+    Ok(())
+}
+
+// Writes the events of a watch until the client closes the connection, or
+// the engine drops the watch.
+fn stream_events(
+    reader: &mut BufReader<SharedStream>,
+    writer: &mut BufWriter<SharedStream>,
+    hello: &Hello,
+    events: &Receiver<TaggedResponse>,
+) -> Result<()> {
+    loop {
+        match events.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => {
+                write_frame(writer, &encode_response(hello, &event)?)?;
+                for event in events.try_iter() {
+                    write_frame(writer, &encode_response(hello, &event)?)?;
+                }
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if read_closed(reader)? {
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+// Whether the peer closed the connection, discarding whatever else it sent
+// without blocking.
+fn read_closed(reader: &mut BufReader<SharedStream>) -> Result<bool> {
+    reader
+        .get_ref()
+        .with_tcp(|stream| stream.set_nonblocking(true))?;
+    let mut buf = [0; 1024];
+    let res = loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break Ok(true),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e.into()),
+        }
+    };
+    reader
+        .get_ref()
+        .with_tcp(|stream| stream.set_nonblocking(false))?;
+    res
+}
+
+/// A `Request::Watch` or `Request::Replicate`, after which a connection only
+/// carries events.
+struct WatchRequest {
+    id: u64,
+    request: Request,
+}
+
+/// Subscribes to the changes of the watched keys the user of the session
+/// may read, which are handed to `deliver` as long as it returns `true`.
+/// Followers get the changes they missed first, through the answer.
+///
+/// Returns the answer to the request, or the response refusing it.
+fn start_watch<E, F>(
+    engine: &E,
+    session: &Session,
+    watch: WatchRequest,
+    version: u16,
+    deliver: F,
+) -> std::result::Result<WatchAnswer, TaggedResponse>
+where
+    E: KvsEngine,
+    F: Fn(TaggedResponse) -> bool + Send + 'static,
+{
+    let id = watch.id;
+    let refuse = |e: &KvsError| TaggedResponse {
+        id,
+        response: Response::error(e),
+    };
+    session.authorize(&watch.request).map_err(|e| refuse(&e))?;
+    let session = session.clone();
+    let sink = move |event: ChangeEvent| {
+        session.authorize_key(&event.key, false).is_err()
+            || deliver(TaggedResponse {
+                id,
+                response: Response::Event(event),
+            })
+    };
+    let state = match watch.request {
+        Request::Watch { prefix } => {
+            engine
+                .watch(prefix, Box::new(sink))
+                .map_err(|e| refuse(&e))?;
+            AnswerState::Done
+        }
+        Request::Replicate { epoch, from } => AnswerState::Changes {
+            epoch,
+            next: from,
+            sink: Box::new(sink),
+            answered: false,
+        },
+        _ => unreachable!("only watches and followers stream changes"),
+    };
+    let mut queued = VecDeque::new();
+    if let AnswerState::Done = state {
+        queued.push_back(Response::Ok(None));
+    }
+    Ok(WatchAnswer {
+        id,
+        version,
+        state,
+        queued,
+    })
+}
+
+/// The answer to a watch which started, sent before its events.
+///
+/// A follower first gets the changes it missed which the engine keeps, read
+/// a page at a time as it takes them, and is only subscribed to the changes
+/// once less than a page behind. A follower getting a snapshot instead is
+/// subscribed first, then the keys are read a page at a time and sent in
+/// `Response::SnapshotPage`s before the `Response::Snapshot`; protocol
+/// versions before 12 get them in the snapshot itself.
+pub(super) struct WatchAnswer {
+    id: u64,
+    version: u16,
+    state: AnswerState,
+    // responses to send before reading on
+    queued: VecDeque<Response>,
+}
+
+enum AnswerState {
+    // the changes of the history `epoch` from `next` on; `answered` once the
+    // request got its response
+    Changes {
+        epoch: u64,
+        next: u64,
+        sink: ChangeSink,
+        answered: bool,
+    },
+    // the keys of the snapshot after `after`, from the first one if `None`
+    Snapshot {
+        snapshot: Snapshot,
+        after: Option<String>,
+    },
+    Done,
+}
+
+impl WatchAnswer {
+    /// Reads the next response to send, `None` once the answer is sent.
+    fn next<E: KvsEngine>(&mut self, engine: &E) -> Result<Option<TaggedResponse>> {
+        loop {
+            if let Some(response) = self.queued.pop_front() {
+                return Ok(Some(TaggedResponse {
+                    id: self.id,
+                    response,
+                }));
+            }
+            match mem::replace(&mut self.state, AnswerState::Done) {
+                AnswerState::Changes {
+                    epoch,
+                    next,
+                    sink,
+                    answered,
+                } => match self.changes(engine, epoch, next, sink, answered) {
+                    Err(e) if !answered => self.queued.push_back(Response::error(&e)),
+                    res => res?,
+                },
+                AnswerState::Snapshot { snapshot, after } => {
+                    self.snapshot_page(engine, snapshot, after)?
+                }
+                AnswerState::Done => return Ok(None),
+            }
+        }
+    }
+
+    // Queues the next page of the changes the engine keeps, or subscribes to
+    // the changes once less than a page behind.
+    fn changes<E: KvsEngine>(
+        &mut self,
+        engine: &E,
+        epoch: u64,
+        next: u64,
+        sink: ChangeSink,
+        answered: bool,
+    ) -> Result<()> {
+        if engine.history_id().ok() == Some(epoch) {
+            match engine.changes_since(next, HISTORY_PAGE_SIZE) {
+                Ok(page) if page.len() == HISTORY_PAGE_SIZE => {
+                    if !answered {
+                        self.queued.push_back(Response::Ok(None));
                     }
-                });
+                    let next = page.last().map_or(next, |event| event.seq + 1);
+                    self.queued.extend(page.into_iter().map(Response::Event));
+                    self.state = AnswerState::Changes {
+                        epoch,
+                        next,
+                        sink,
+                        answered: true,
+                    };
+                    return Ok(());
+                }
+                // the engine hands the last changes over along with the sink
+                Ok(_) | Err(KvsError::HistoryTruncated(_)) => {}
+                Err(e) => return Err(e),
             }
         }
+        match engine.replicate(epoch, next, sink)? {
+            None if answered => {}
+            None => self.queued.push_back(Response::Ok(None)),
+            Some(snapshot) if !answered => {
+                self.state = AnswerState::Snapshot {
+                    snapshot,
+                    after: None,
+                }
+            }
+            // the history was dropped while the follower caught up
+            Some(snapshot) => return Err(KvsError::HistoryTruncated(snapshot.seq + 1)),
+        }
+        Ok(())
     }
 
-    Ok(())
+    // Queues the next page of the keys of the snapshot, or the snapshot
+    // itself once every key is read.
+    fn snapshot_page<E: KvsEngine>(
+        &mut self,
+        engine: &E,
+        mut snapshot: Snapshot,
+        after: Option<String>,
+    ) -> Result<()> {
+        let keys = engine.scan(after, SNAPSHOT_PAGE_SIZE)?;
+        let last = keys.last().cloned();
+        let exhausted = keys.len() < SNAPSHOT_PAGE_SIZE;
+        let values = engine.get_many(keys.clone())?;
+        // keys removed since the scan are left out
+        let pairs: Vec<_> = keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        if self.version < 12 {
+            snapshot.pairs.extend(pairs);
+        } else if !pairs.is_empty() {
+            self.queued.push_back(Response::SnapshotPage(pairs));
+        }
+        if exhausted {
+            self.queued.push_back(Response::Snapshot(snapshot));
+        } else {
+            self.state = AnswerState::Snapshot {
+                snapshot,
+                after: last,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Decodes and processes a batch of request frames received at the given
+/// instant, returning the responses in request order, and the watch which
+/// ends the batch if any.
+fn handle_frames<E: KvsEngine>(
+    engine: &E,
+    session: &mut Session,
+    hello: &Hello,
+    batch: &[Vec<u8>],
+    received: Instant,
+) -> (Vec<TaggedResponse>, Option<WatchRequest>) {
+    // a malformed request is answered with an error, the connection stays usable
+    let requests = batch
+        .iter()
+        .map(|payload| {
+            decode_request(hello, payload).map_err(|e| TaggedResponse {
+                id: 0,
+                response: Response::Error {
+                    code: ErrorCode::MalformedRequest,
+                    message: format!("Malformed request: {}", e),
+                },
+            })
+        })
+        .collect();
+    handle_batch(engine, session, requests, received)
+}
+
+/// Processes a batch of requests, returning the responses in request order.
+///
+/// Runs of consecutive reads are executed concurrently, while writes and
+/// authentications are executed one at a time in order so that they are
+/// never reordered with respect to any other request of the batch. Reads
+/// within a transaction are executed in order too, since they record what
+/// they read. A watch is left to the caller, and the requests following it
+/// are dropped.
+fn handle_batch<E: KvsEngine>(
+    engine: &E,
+    session: &mut Session,
+    requests: Vec<std::result::Result<TaggedRequest, TaggedResponse>>,
+    received: Instant,
+) -> (Vec<TaggedResponse>, Option<WatchRequest>) {
+    let mut responses = Vec::with_capacity(requests.len());
+    let mut reads = Vec::new();
+    let mut watch = None;
+    for req in requests {
+        match req {
+            Ok(TaggedRequest {
+                id,
+                request: request @ (Request::Watch { .. } | Request::Replicate { .. }),
+                ..
+            }) => {
+                watch = Some(WatchRequest { id, request });
+                break;
+            }
+            Ok(
+                req @ TaggedRequest {
+                    request:
+                        Request::Get { .. } | Request::MultiGet { .. } | Request::GetWithVersion { .. },
+                    ..
+                },
+            ) if session.transaction.is_none() => reads.push(req),
+            Ok(req) => {
+                handle_reads(engine, session, &mut reads, &mut responses, received);
+                responses.push(handle_tagged(engine, session, req, received));
+            }
+            Err(resp) => {
+                handle_reads(engine, session, &mut reads, &mut responses, received);
+                responses.push(resp);
+            }
+        }
+    }
+    handle_reads(engine, session, &mut reads, &mut responses, received);
+    (responses, watch)
+}
+
+fn handle_reads<E: KvsEngine>(
+    engine: &E,
+    session: &mut Session,
+    reads: &mut Vec<TaggedRequest>,
+    responses: &mut Vec<TaggedResponse>,
+    received: Instant,
+) {
+    if reads.len() == 1 {
+        let req = reads.pop().unwrap();
+        responses.push(handle_tagged(engine, session, req, received));
+    } else {
+        // reads leave the session as it is
+        responses.par_extend(reads.par_drain(..).map_with(
+            (engine.clone(), session.clone()),
+            |(engine, session), req| handle_tagged(engine, session, req, received),
+        ));
+    }
+}
+
+// Executes a request unless its deadline, counted from the instant it was
+// received, has already passed.
+fn handle_tagged<E: KvsEngine>(
+    engine: &E,
+    session: &mut Session,
+    req: TaggedRequest,
+    received: Instant,
+) -> TaggedResponse {
+    let expired = req
+        .timeout_ms
+        .is_some_and(|ms| received.elapsed() >= Duration::from_millis(ms));
+    let response = if expired {
+        Response::error(&KvsError::DeadlineExceeded)
+    } else {
+        handle_in_order(engine, session, req.request)
+    };
+    TaggedResponse {
+        id: req.id,
+        response,
+    }
+}
+
+// Executes a request on behalf of the user of the session, which an
+// authentication or the requests of a transaction change.
+fn handle_in_order<E: KvsEngine>(engine: &E, session: &mut Session, req: Request) -> Response {
+    let res = match req {
+        Request::Auth { credentials } => {
+            if session.authenticate(&credentials) {
+                Ok(())
+            } else {
+                Err(KvsError::AuthenticationFailed)
+            }
+        }
+        Request::Begin if session.transaction.is_some() => Err(KvsError::Protocol(
+            "a transaction is already open".to_owned(),
+        )),
+        Request::Begin => {
+            session.transaction = Some(Transaction::default());
+            Ok(())
+        }
+        // the transaction ends even if its commit fails
+        Request::Commit => match session.transaction.take() {
+            Some(transaction) => transaction.commit(engine),
+            None => Err(no_transaction()),
+        },
+        Request::Abort => session
+            .transaction
+            .take()
+            .map(drop)
+            .ok_or_else(no_transaction),
+        req => return handle(engine, session, req),
+    };
+    match res {
+        Ok(()) => Response::Ok(None),
+        Err(e) => Response::error(&e),
+    }
+}
+
+fn handle<E: KvsEngine>(engine: &E, session: &mut Session, req: Request) -> Response {
+    if let Err(e) = session.authorize(&req) {
+        return Response::error(&e);
+    }
+    if let Some(transaction) = &mut session.transaction {
+        return handle_in_transaction(engine, transaction, req);
+    }
+    let res = match req {
+        Request::MultiGet { keys } => {
+            return match engine.get_many(keys) {
+                Ok(values) => Response::Values(values),
+                Err(e) => Response::error(&e),
+            };
+        }
+        Request::GetWithVersion { key } => {
+            return match engine.get_with_version(key) {
+                Ok(versioned) => Response::Versioned(versioned),
+                Err(e) => Response::error(&e),
+            };
+        }
+        Request::SetIfVersion {
+            key,
+            value,
+            version,
+        } => {
+            return match engine.set_if_version(key, value, version) {
+                Ok(version) => Response::Version(version),
+                Err(e) => Response::error(&e),
+            };
+        }
+        Request::MultiSet { pairs } => engine.set_many(pairs).map(|_| None),
+        Request::Set { key, value } => engine.set(key, value).map(|_| None),
+        Request::Get { key } => engine.get(key),
+        Request::Remove { key } => engine.remove(key).map(|_| None),
+        Request::Auth { .. } | Request::Begin | Request::Commit | Request::Abort => {
+            unreachable!("authentication and transactions change the session")
+        }
+        // framed connections watch through `start_watch`
+        Request::Watch { .. } | Request::Replicate { .. } => Err(KvsError::Unsupported(
+            "watch without protocol framing".to_owned(),
+        )),
+    };
+    match res {
+        Ok(v) => Response::Ok(v),
+        Err(e) => Response::error(&e),
+    }
+}
+
+fn no_transaction() -> KvsError {
+    KvsError::Protocol("no transaction is open".to_owned())
+}
+
+// Executes a request within the open transaction, which buffers writes
+// until the commit.
+fn handle_in_transaction<E: KvsEngine>(
+    engine: &E,
+    transaction: &mut Transaction,
+    req: Request,
+) -> Response {
+    let res = match req {
+        Request::MultiGet { keys } => {
+            return match transaction.get_many(engine, keys) {
+                Ok(values) => Response::Values(values),
+                Err(e) => Response::error(&e),
+            };
+        }
+        Request::MultiSet { pairs } => {
+            transaction.set_many(pairs);
+            Ok(None)
+        }
+        Request::Set { key, value } => {
+            transaction.set(key, value);
+            Ok(None)
+        }
+        Request::Get { key } => transaction.get(engine, key),
+        Request::Remove { key } => transaction.remove(engine, key).map(|_| None),
+        Request::Auth { .. } | Request::Begin | Request::Commit | Request::Abort => {
+            unreachable!("authentication and transactions change the session")
+        }
+        Request::Watch { .. } | Request::Replicate { .. } => Err(KvsError::Unsupported(
+            "watch within a transaction".to_owned(),
+        )),
+        // the commit checks what the transaction read instead
+        Request::GetWithVersion { .. } | Request::SetIfVersion { .. } => Err(
+            KvsError::Unsupported("versions within a transaction".to_owned()),
+        ),
+    };
+    match res {
+        Ok(v) => Response::Ok(v),
+        Err(e) => Response::error(&e),
+    }
 }
@@ -0,0 +1,680 @@
+//! Event-driven serving of connections.
+//!
+//! The thread calling `run` owns every connection. It reads whatever the
+//! readiness events make available, cuts the received bytes into requests and
+//! hands each batch of requests to the thread pool. The pool sends the
+//! encoded responses back through a channel and wakes the event loop, which
+//! writes them out. A connection has at most one batch in the pool at a time,
+//! so its responses keep the order of its requests.
+//!
+//! On shutdown the listener is dropped and nothing more is read. Connections
+//! are closed as soon as the requests they already sent are answered, or
+//! when the shutdown timeout expires.
+//!
+//! Idle connections are looked for periodically, at half the idle timeout.
+//!
+//! A connection which starts watching changes gets a channel of its own.
+//! The engine sends the events to it and wakes the event loop, which writes
+//! them out like responses. Events stay in the channel while the client
+//! does not read them, until it is full and the engine drops the watch,
+//! which closes the connection. The watch is started on the pool, which
+//! also reads the answer to a follower, the changes it missed or the pages
+//! of a snapshot, a part at a time as the client reads them, before the
+//! events.
+//!
+//! With TLS, every connection owns a rustls session: received bytes go
+//! through the session before being cut into requests, and responses are
+//! encrypted by it before being written.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use rustls::ServerConnection;
+use serde_json::Deserializer;
+use slog::{debug, error, info, warn, Logger};
+
+use super::{
+    handle_frames, handle_in_order, start_watch, KvsServer, WatchAnswer, MAX_BATCH, WATCH_QUEUE_LEN,
+};
+use crate::auth::Session;
+use crate::protocol::{
+    downgrade_response, encode_response, split_frame, write_frame, Hello, HELLO_LEN, MAGIC,
+};
+use crate::{KvsEngine, Request, Result, TaggedResponse, ThreadPool};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+// the bytes of events waiting to be written past which no more are taken
+// from the channel of the watch
+const MAX_EVENT_OUTPUT: usize = 64 * 1024;
+
+// what the next bytes received on a connection are
+enum Protocol {
+    // the first byte tells framed clients from legacy ones
+    Detecting,
+    Handshake,
+    Legacy,
+    Framed(Hello),
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    protocol: Protocol,
+    input: Vec<u8>,
+    // when the oldest byte of `input` was received
+    input_since: Option<Instant>,
+    output: Vec<u8>,
+    // when bytes were last received or sent
+    last_active: Instant,
+    // a batch of requests of this connection is in the pool
+    busy: bool,
+    // nothing more will be read: the peer closed its side or was rejected
+    read_closed: bool,
+    tls: Option<ServerConnection>,
+    // in the pool along with the batch
+    session: Option<Session>,
+    // events of the watch the connection started, after which no request
+    // is executed
+    events: Option<Receiver<TaggedResponse>>,
+    // the answer to the watch, until it is sent
+    answer: Option<WatchAnswer>,
+}
+
+// a batch of requests to execute on the pool
+enum Batch {
+    Legacy(Vec<Request>),
+    Framed(Hello, Vec<Vec<u8>>, Instant),
+}
+
+// what the pool sends back along with the session it leaves
+type Done = (Token, Session, Result<Output>);
+
+// the work of the pool for a connection
+enum Work {
+    Batch(Batch),
+    // the next part of the answer to the watch
+    Answer(Hello, WatchAnswer),
+}
+
+// what the pool did for a connection
+struct Output {
+    // encoded responses
+    bytes: Vec<u8>,
+    // the events of the watch the batch started
+    events: Option<Receiver<TaggedResponse>>,
+    // what is left to send of the answer to the watch
+    answer: Option<WatchAnswer>,
+}
+
+struct EventLoop<'a, E: KvsEngine, P: ThreadPool> {
+    engine: &'a E,
+    pool: &'a P,
+    logger: &'a Arc<Logger>,
+    done: Sender<Done>,
+    waker: Arc<Waker>,
+}
+
+pub(super) fn run<E: KvsEngine, P: ThreadPool>(
+    server: &KvsServer<E, P>,
+    addr: &SocketAddr,
+) -> Result<()> {
+    let logger = &server.logger;
+    let shutdown = &server.shutdown;
+    let mut poll = Poll::new()?;
+    let mut listener = TcpListener::bind(*addr)?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    info!(logger, "Bind to address {:?}", addr);
+
+    let (done, finished) = unbounded();
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let shutdown_waker = waker.clone();
+    shutdown.add_wake(Box::new(move || {
+        let _ = shutdown_waker.wake();
+    }));
+    let event_loop = EventLoop {
+        engine: &server.engine,
+        pool: &server.pool,
+        logger,
+        done,
+        waker,
+    };
+
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = WAKER.0 + 1;
+    let mut events = Events::with_capacity(1024);
+    let mut deadline = None;
+    let mut next_sweep = server.idle_timeout.map(|idle| Instant::now() + idle / 2);
+    loop {
+        if deadline.is_none() && shutdown.is_requested() {
+            info!(
+                logger,
+                "Shutting down, waiting for {} connections",
+                connections.len()
+            );
+            poll.registry().deregister(&mut listener)?;
+            deadline = Some(Instant::now() + server.shutdown_timeout);
+            let tokens: Vec<_> = connections.keys().cloned().collect();
+            for token in tokens {
+                let conn = connections.get_mut(&token).expect("connection exists");
+                conn.read_closed = true;
+                let res = event_loop.advance(token, conn);
+                event_loop.close_if_done(&poll, &mut connections, token, res)?;
+            }
+        }
+        if let (Some(idle), Some(sweep)) = (server.idle_timeout, next_sweep) {
+            if Instant::now() >= sweep {
+                let tokens: Vec<_> = connections
+                    .iter()
+                    .filter(|(_, conn)| {
+                        !conn.busy && conn.events.is_none() && conn.last_active.elapsed() >= idle
+                    })
+                    .map(|(&token, _)| token)
+                    .collect();
+                for token in tokens {
+                    let mut conn = connections.remove(&token).expect("connection exists");
+                    debug!(logger, "Closing idle connection from {}", conn.peer_addr);
+                    poll.registry().deregister(&mut conn.stream)?;
+                }
+                next_sweep = Some(Instant::now() + idle / 2);
+            }
+        }
+        let timeout = match deadline {
+            Some(_) if connections.is_empty() => return Ok(()),
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(timeout) => Some(timeout),
+                None => {
+                    warn!(
+                        logger,
+                        "Closing {} connections after the shutdown timeout",
+                        connections.len()
+                    );
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        // wake up for the next look for idle connections
+        let timeout = match next_sweep {
+            Some(sweep) => {
+                let until_sweep = sweep.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(until_sweep, |timeout| timeout.min(until_sweep)))
+            }
+            None => timeout,
+        };
+
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => loop {
+                    let (mut stream, peer_addr) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            error!(logger, "Connection failed: {}", e);
+                            break;
+                        }
+                    };
+                    // a failure only drops this connection
+                    let tls = match server.tls.as_ref().map(|tls| tls.accept()).transpose() {
+                        Ok(tls) => tls,
+                        Err(e) => {
+                            error!(logger, "TLS setup for {} failed: {}", peer_addr, e);
+                            continue;
+                        }
+                    };
+                    let token = Token(next_token);
+                    next_token += 1;
+                    if let Err(e) = poll.registry().register(
+                        &mut stream,
+                        token,
+                        Interest::READABLE | Interest::WRITABLE,
+                    ) {
+                        error!(logger, "Registering {} failed: {}", peer_addr, e);
+                        continue;
+                    }
+                    let session = Session::new(server.auth.clone());
+                    connections.insert(token, Connection::new(stream, peer_addr, tls, session));
+                },
+                // finished batches are collected below
+                WAKER => {}
+                token => {
+                    if let Some(conn) = connections.get_mut(&token) {
+                        let res = conn
+                            .read_input()
+                            .and_then(|()| event_loop.advance(token, conn));
+                        event_loop.close_if_done(&poll, &mut connections, token, res)?;
+                    }
+                }
+            }
+        }
+
+        for (token, session, res) in finished.try_iter() {
+            if let Some(conn) = connections.get_mut(&token) {
+                conn.busy = false;
+                conn.session = Some(session);
+                conn.last_active = Instant::now();
+                let res = res.and_then(|output| {
+                    conn.output.extend_from_slice(&output.bytes);
+                    if let Some(events) = output.events {
+                        debug!(logger, "Connection from {} watches changes", conn.peer_addr);
+                        conn.events = Some(events);
+                    }
+                    conn.answer = output.answer;
+                    event_loop.advance(token, conn)
+                });
+                event_loop.close_if_done(&poll, &mut connections, token, res)?;
+            }
+        }
+
+        let watching: Vec<_> = connections
+            .iter()
+            .filter(|(_, conn)| conn.events.is_some())
+            .map(|(&token, _)| token)
+            .collect();
+        for token in watching {
+            let conn = connections.get_mut(&token).expect("connection exists");
+            let res = conn
+                .take_events()
+                .and_then(|()| event_loop.advance(token, conn));
+            event_loop.close_if_done(&poll, &mut connections, token, res)?;
+        }
+    }
+}
+
+impl<'a, E: KvsEngine, P: ThreadPool> EventLoop<'a, E, P> {
+    // Writes pending output and dispatches the next part of the answer to
+    // the watch, or the next batch of requests, if the connection is idle.
+    // Clients which do not read their responses get no new ones.
+    fn advance(&self, token: Token, conn: &mut Connection) -> Result<()> {
+        conn.write_output()?;
+        if !conn.busy && !conn.has_output() {
+            let work = match (conn.answer.take(), &conn.protocol) {
+                (Some(answer), &Protocol::Framed(hello)) => Some(Work::Answer(hello, answer)),
+                (Some(_), _) => unreachable!("only framed connections watch"),
+                (None, _) if conn.events.is_none() => conn.next_batch()?.map(Work::Batch),
+                (None, _) => None,
+            };
+            if let Some(work) = work {
+                conn.busy = true;
+                let session = conn.session.take().expect("session not in the pool");
+                self.dispatch(token, conn.peer_addr, session, work);
+            }
+        }
+        conn.write_output()?;
+        Ok(())
+    }
+
+    fn dispatch(&self, token: Token, peer_addr: SocketAddr, mut session: Session, work: Work) {
+        let engine = self.engine.clone();
+        let logger = self.logger.clone();
+        let done = self.done.clone();
+        let waker = self.waker.clone();
+        self.pool.spawn(move || {
+            let output = match work {
+                Work::Batch(batch) => {
+                    execute(&engine, &mut session, &logger, peer_addr, batch, &waker)
+                }
+                Work::Answer(hello, answer) => take_answer(&engine, hello, answer),
+            };
+            if done.send((token, session, output)).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
+
+    fn close_if_done(
+        &self,
+        poll: &Poll,
+        connections: &mut HashMap<Token, Connection>,
+        token: Token,
+        res: Result<()>,
+    ) -> Result<()> {
+        let conn = &connections[&token];
+        match res {
+            Err(e) => error!(self.logger, "Error processing incoming request: {}", e),
+            Ok(()) if conn.read_closed && !conn.busy && !conn.has_output() => {
+                debug!(self.logger, "Connection from {} closed", conn.peer_addr)
+            }
+            Ok(()) => return Ok(()),
+        }
+        let mut conn = connections.remove(&token).expect("connection exists");
+        if let Some(tls) = &mut conn.tls {
+            // best effort, the client may be gone already
+            tls.send_close_notify();
+            let _ = tls.write_tls(&mut conn.stream);
+        }
+        poll.registry().deregister(&mut conn.stream)?;
+        Ok(())
+    }
+}
+
+impl Connection {
+    fn new(
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        tls: Option<ServerConnection>,
+        session: Session,
+    ) -> Connection {
+        Connection {
+            stream,
+            peer_addr,
+            protocol: Protocol::Detecting,
+            input: Vec::new(),
+            input_since: None,
+            output: Vec::new(),
+            last_active: Instant::now(),
+            busy: false,
+            read_closed: false,
+            tls,
+            session: Some(session),
+            events: None,
+            answer: None,
+        }
+    }
+
+    // Appends the events received so far once the answer to the watch of
+    // the connection is sent, as long as the client keeps up with them. Once
+    // the engine dropped the watch, the connection closes.
+    fn take_events(&mut self) -> Result<()> {
+        if self.busy || self.answer.is_some() {
+            return Ok(());
+        }
+        if let (Some(events), Protocol::Framed(hello)) = (&self.events, &self.protocol) {
+            while self.output.len() < MAX_EVENT_OUTPUT {
+                match events.try_recv() {
+                    Ok(event) => write_frame(&mut self.output, &encode_response(hello, &event)?)?,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.read_closed = true;
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether bytes wait to be written, responses or TLS records.
+    fn has_output(&self) -> bool {
+        !self.output.is_empty() || self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+
+    fn received(&mut self, data: &[u8]) {
+        // requests following a watch are ignored
+        if data.is_empty() || self.events.is_some() {
+            return;
+        }
+        self.last_active = Instant::now();
+        if self.input.is_empty() {
+            self.input_since = Some(self.last_active);
+        }
+        self.input.extend_from_slice(data);
+    }
+
+    // Reads everything the socket has, as events only report new data.
+    fn read_input(&mut self) -> Result<()> {
+        if self.tls.is_some() {
+            return self.read_tls_input();
+        }
+        let mut buf = [0; READ_BUFFER_SIZE];
+        while !self.read_closed {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.read_closed = true,
+                Ok(n) => self.received(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_tls_input(&mut self) -> Result<()> {
+        while !self.read_closed {
+            let tls = self.tls.as_mut().expect("TLS connection");
+            match tls.read_tls(&mut self.stream) {
+                Ok(0) => self.read_closed = true,
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let state = match tls.process_new_packets() {
+                Ok(state) => state,
+                Err(e) => {
+                    // tell the client why, best effort
+                    let _ = tls.write_tls(&mut self.stream);
+                    return Err(e.into());
+                }
+            };
+            let mut plaintext = vec![0; state.plaintext_bytes_to_read()];
+            tls.reader().read_exact(&mut plaintext)?;
+            if state.peer_has_closed() {
+                self.read_closed = true;
+            }
+            self.received(&plaintext);
+        }
+        Ok(())
+    }
+
+    fn write_output(&mut self) -> Result<()> {
+        if self.tls.is_some() {
+            return self.write_tls_output();
+        }
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.stream.write(&self.output[written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if written > 0 {
+            self.last_active = Instant::now();
+            self.output.drain(..written);
+        }
+        Ok(())
+    }
+
+    // Hands responses to the TLS session as far as its buffer allows, and
+    // writes the records it produces.
+    fn write_tls_output(&mut self) -> Result<()> {
+        loop {
+            let tls = self.tls.as_mut().expect("TLS connection");
+            if !self.output.is_empty() {
+                let n = tls.writer().write(&self.output)?;
+                self.output.drain(..n);
+            }
+            if !tls.wants_write() {
+                return Ok(());
+            }
+            match tls.write_tls(&mut self.stream) {
+                Ok(_) => self.last_active = Instant::now(),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // Takes the complete requests received so far, answering the handshake
+    // on the way.
+    fn next_batch(&mut self) -> Result<Option<Batch>> {
+        let batch = self.take_batch();
+        if self.input.is_empty() {
+            self.input_since = None;
+        }
+        batch
+    }
+
+    fn take_batch(&mut self) -> Result<Option<Batch>> {
+        loop {
+            match self.protocol {
+                Protocol::Detecting => match self.input.first() {
+                    Some(&byte) if byte == MAGIC[0] => self.protocol = Protocol::Handshake,
+                    Some(_) => self.protocol = Protocol::Legacy,
+                    None => return Ok(None),
+                },
+                Protocol::Handshake => {
+                    if self.input.len() < HELLO_LEN {
+                        return Ok(None);
+                    }
+                    let hello = Hello::read_from(&mut &self.input[..HELLO_LEN])?;
+                    self.input.drain(..HELLO_LEN);
+                    match hello.and_then(|hello| hello.negotiate()) {
+                        Some(hello) => {
+                            hello.write_to(&mut self.output)?;
+                            self.protocol = Protocol::Framed(hello);
+                        }
+                        None => {
+                            Hello::write_rejection(&mut self.output)?;
+                            self.read_closed = true;
+                            self.input.clear();
+                            return Ok(None);
+                        }
+                    }
+                }
+                Protocol::Legacy => return self.legacy_batch(),
+                Protocol::Framed(hello) => return self.framed_batch(hello),
+            }
+        }
+    }
+
+    fn legacy_batch(&mut self) -> Result<Option<Batch>> {
+        let mut stream = Deserializer::from_slice(&self.input).into_iter::<Request>();
+        let mut requests = Vec::new();
+        let mut consumed = 0;
+        while requests.len() < MAX_BATCH {
+            match stream.next() {
+                Some(Ok(req)) => {
+                    requests.push(req);
+                    consumed = stream.byte_offset();
+                }
+                // the rest of the request has not arrived yet
+                Some(Err(ref e)) if e.is_eof() => break,
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        self.input.drain(..consumed);
+        Ok(if requests.is_empty() {
+            None
+        } else {
+            Some(Batch::Legacy(requests))
+        })
+    }
+
+    fn framed_batch(&mut self, hello: Hello) -> Result<Option<Batch>> {
+        let mut frames = Vec::new();
+        let mut consumed = 0;
+        while frames.len() < MAX_BATCH {
+            match split_frame(&self.input[consumed..])? {
+                Some((payload, len)) => {
+                    frames.push(payload.to_vec());
+                    consumed += len;
+                }
+                None => break,
+            }
+        }
+        self.input.drain(..consumed);
+        Ok(if frames.is_empty() {
+            None
+        } else {
+            // deadlines count from when the first of these requests started
+            // arriving, which is conservative for the others
+            let received = self.input_since.unwrap_or_else(Instant::now);
+            Some(Batch::Framed(hello, frames, received))
+        })
+    }
+}
+
+// Executes a batch on the pool, returning the encoded responses. A watch
+// ending the batch is started here, its events posted with the waker.
+fn execute<E: KvsEngine>(
+    engine: &E,
+    session: &mut Session,
+    logger: &Logger,
+    peer_addr: SocketAddr,
+    batch: Batch,
+    waker: &Arc<Waker>,
+) -> Result<Output> {
+    let mut output = Output {
+        bytes: Vec::new(),
+        events: None,
+        answer: None,
+    };
+    match batch {
+        Batch::Legacy(requests) => {
+            for req in requests {
+                let resp = handle_in_order(engine, session, req);
+                let resp = downgrade_response(&resp, 1).unwrap_or(resp);
+                serde_json::to_writer(&mut output.bytes, &resp)?;
+                debug!(logger, "Response sent to {}: {:?}", peer_addr, resp);
+            }
+        }
+        Batch::Framed(hello, frames, received) => {
+            let (responses, watch) = handle_frames(engine, session, &hello, &frames, received);
+            for resp in responses {
+                write_frame(&mut output.bytes, &encode_response(&hello, &resp)?)?;
+                debug!(logger, "Response sent to {}: {:?}", peer_addr, resp);
+            }
+            if let Some(watch) = watch {
+                let (sender, events) = bounded(WATCH_QUEUE_LEN);
+                let waker = waker.clone();
+                let deliver = move |event| {
+                    let sent = sender.try_send(event).is_ok();
+                    let _ = waker.wake();
+                    sent
+                };
+                match start_watch(engine, session, watch, hello.version, deliver) {
+                    Ok(answer) => {
+                        output.events = Some(events);
+                        output.answer = Some(answer);
+                    }
+                    Err(resp) => write_frame(&mut output.bytes, &encode_response(&hello, &resp)?)?,
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+// Reads the next part of the answer to a watch on the pool, as much as the
+// connection takes at once.
+fn take_answer<E: KvsEngine>(engine: &E, hello: Hello, mut answer: WatchAnswer) -> Result<Output> {
+    let mut bytes = Vec::new();
+    while bytes.len() < MAX_EVENT_OUTPUT {
+        match answer.next(engine)? {
+            Some(resp) => write_frame(&mut bytes, &encode_response(&hello, &resp)?)?,
+            None => {
+                return Ok(Output {
+                    bytes,
+                    events: None,
+                    answer: None,
+                })
+            }
+        }
+    }
+    Ok(Output {
+        bytes,
+        events: None,
+        answer: Some(answer),
+    })
+}
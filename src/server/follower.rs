@@ -0,0 +1,177 @@
+//! Replication of a leader `KvsServer` to a follower engine.
+//!
+//! The follower asks the leader for every change with `Request::Replicate`,
+//! from the change after the last one it applied. A follower which has
+//! nothing yet, or which fell further behind than the backlog of the leader,
+//! gets a snapshot of every key first, whose pages it applies as they come.
+//! The connection then carries every change the leader commits, which the
+//! follower applies in order.
+//!
+//! When the connection fails, the follower connects again after a while and
+//! carries on from where it stopped.
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use slog::{debug, info, warn, Logger};
+
+use super::ShutdownHandle;
+use crate::engines::replace_keys;
+use crate::{ClientConfig, KvsClient, KvsEngine, KvsError, Result};
+
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// how often a follower waiting to connect again checks for a shutdown
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Follower of a leader `KvsServer`, applying every change committed on the
+/// leader to its own engine.
+///
+/// The leader must publish its changes, e.g. by serving a `WatchedEngine`.
+/// Serve the engine of the follower wrapped in a `ReadOnlyEngine`, so that
+/// it only changes along with the leader.
+///
+/// Where the follower stands in the history of the leader is only kept in
+/// memory: a follower started again gets a whole snapshot first, even if
+/// its engine kept the keys.
+pub struct Follower<E: KvsEngine> {
+    leader: SocketAddr,
+    engine: E,
+    logger: Logger,
+    config: ClientConfig,
+    retry_interval: Duration,
+    shutdown: ShutdownHandle,
+    // where the follower stands in the history of the leader
+    epoch: u64,
+    next_seq: u64,
+}
+
+impl<E: KvsEngine> Follower<E> {
+    /// Create a follower of the leader listening on the given address.
+    pub fn new(leader: SocketAddr, engine: E, logger: Logger) -> Self {
+        Follower {
+            leader,
+            engine,
+            logger,
+            config: ClientConfig::default(),
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            shutdown: ShutdownHandle::default(),
+            epoch: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// Set the options of the connections to the leader, e.g. to
+    /// authenticate as a user who may read every key.
+    pub fn set_client_config(&mut self, config: ClientConfig) {
+        self.config = config;
+    }
+
+    /// Set how long the follower waits before connecting again to the
+    /// leader after a failure. Defaults to 1 second.
+    pub fn set_retry_interval(&mut self, interval: Duration) {
+        self.retry_interval = interval;
+    }
+
+    /// Get a handle to stop the follower once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Follow the leader until shut down.
+    ///
+    /// # Error
+    ///
+    /// Failures to reach the leader or to apply its changes are logged and
+    /// retried; this only returns once the follower is shut down.
+    pub fn run(&mut self) -> Result<()> {
+        // closing the connection to the leader wakes the follower up
+        let connection: Arc<Mutex<Option<TcpStream>>> = Arc::default();
+        let wake_connection = connection.clone();
+        self.shutdown.add_wake(Box::new(move || {
+            if let Some(stream) = &*wake_connection.lock().unwrap() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }));
+
+        while !self.shutdown.is_requested() {
+            match self.follow(&connection) {
+                Ok(()) => info!(self.logger, "Leader {} closed the connection", self.leader),
+                Err(e) => warn!(self.logger, "Following {} failed: {}", self.leader, e),
+            }
+            connection.lock().unwrap().take();
+
+            let retry = Instant::now() + self.retry_interval;
+            while !self.shutdown.is_requested() && Instant::now() < retry {
+                thread::sleep(SHUTDOWN_POLL_INTERVAL.min(self.retry_interval));
+            }
+        }
+        self.shutdown.clear_wakes();
+        info!(self.logger, "Follower stopped");
+        Ok(())
+    }
+
+    // Applies the changes of the leader until the connection ends.
+    fn follow(&mut self, connection: &Mutex<Option<TcpStream>>) -> Result<()> {
+        let client = KvsClient::connect_with_config(&self.leader, &self.config)?;
+        *connection.lock().unwrap() = Some(client.try_clone_tcp()?);
+        // a shutdown may have missed the connection
+        if self.shutdown.is_requested() {
+            return Ok(());
+        }
+
+        // each page of a snapshot replaces the keys up to its last one
+        let (epoch, from) = (self.epoch, self.next_seq);
+        let engine = &self.engine;
+        let mut after: Option<String> = None;
+        let mut restored = 0;
+        let res = client.replicate_paged(epoch, from, |pairs| {
+            let last = pairs.last().map(|(key, _)| key.clone());
+            restored += pairs.len();
+            replace_keys(engine, after.take(), last.as_deref(), pairs)?;
+            after = last;
+            Ok(())
+        });
+        if restored > 0 {
+            // the keys no longer match where the follower stood
+            self.epoch = 0;
+        }
+        let (snapshot, changes) = res?;
+        match snapshot {
+            Some(snapshot) => {
+                replace_keys(&self.engine, after, None, Vec::new())?;
+                self.epoch = snapshot.epoch;
+                self.next_seq = snapshot.seq + 1;
+                info!(
+                    self.logger,
+                    "Restored a snapshot of {} keys from {}", restored, self.leader
+                );
+            }
+            None => info!(
+                self.logger,
+                "Following {} from change {}", self.leader, self.next_seq
+            ),
+        }
+
+        for change in changes {
+            let change = change?;
+            if change.seq != self.next_seq {
+                return Err(KvsError::Protocol(format!(
+                    "expected change {}, got {}",
+                    self.next_seq, change.seq
+                )));
+            }
+            debug!(self.logger, "Applying change {}", change.seq);
+            match change.value {
+                Some(value) => self.engine.set(change.key, value)?,
+                // the key may have been missing from a snapshot already
+                None => match self.engine.remove(change.key) {
+                    Err(KvsError::KeyNotFound) => {}
+                    res => res?,
+                },
+            }
+            self.next_seq += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+//! The HTTP/1.1 gateway of `KvsServer`, serving JSON to tools like curl and
+//! browsers. The endpoints are described by `KvsServer::set_http_addr`.
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::StreamOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{debug, Logger};
+
+use crate::auth::{AuthConfig, Session};
+use crate::protocol::MAX_FRAME_LEN;
+use crate::tls::{ServerTlsConfig, SharedStream, Stream};
+use crate::{Credentials, ErrorCode, KvsEngine, KvsError, Result};
+
+// the longest request line or header
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+pub(super) fn serve<E: KvsEngine>(
+    stream: TcpStream,
+    engine: E,
+    logger: Arc<Logger>,
+    idle_timeout: Option<Duration>,
+    tls: Option<ServerTlsConfig>,
+    auth: Option<Arc<AuthConfig>>,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    debug!(logger, "HTTP connection from {}", peer_addr);
+    stream.set_read_timeout(idle_timeout)?;
+    stream.set_write_timeout(idle_timeout)?;
+    let stream = SharedStream::new(match tls {
+        Some(tls) => Stream::Server(Box::new(StreamOwned::new(tls.accept()?, stream))),
+        None => Stream::Plain(stream),
+    });
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = BufWriter::new(stream.clone());
+
+    let res = serve_requests(&mut reader, &mut writer, &engine, &auth);
+    if res.is_ok() {
+        // the client may be gone already
+        let _ = stream.close_notify();
+    }
+    match res {
+        Err(KvsError::Io(ref e))
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            debug!(logger, "Closing idle connection from {}", peer_addr);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn serve_requests<E: KvsEngine>(
+    reader: &mut BufReader<SharedStream>,
+    writer: &mut BufWriter<SharedStream>,
+    engine: &E,
+    auth: &Option<Arc<AuthConfig>>,
+) -> Result<()> {
+    loop {
+        let req = match read_request(reader, writer) {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(KvsError::Protocol(e)) => {
+                // the stream cannot be resynchronized
+                let resp = HttpResponse::error(
+                    400,
+                    ErrorCode::MalformedRequest,
+                    format!("Malformed request: {}", e),
+                );
+                resp.write_to(writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let keep_alive = req.keep_alive();
+        handle(engine, auth, &req).write_to(writer, keep_alive)?;
+        writer.flush()?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    target: String,
+    // HTTP/1.0 closes connections by default
+    http_10: bool,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(connection) if connection.eq_ignore_ascii_case("close") => false,
+            Some(connection) if connection.eq_ignore_ascii_case("keep-alive") => true,
+            _ => !self.http_10,
+        }
+    }
+}
+
+// Reads the next request. Returns `None` at the end of the stream.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<HttpRequest>> {
+    // empty lines may precede a request
+    let line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(KvsError::Protocol("invalid request line".to_owned())),
+    };
+    let http_10 = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => {
+            return Err(KvsError::Protocol(format!(
+                "unsupported version {}",
+                version
+            )))
+        }
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?
+            .ok_or_else(|| KvsError::Protocol("unexpected end of stream".to_owned()))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(KvsError::Protocol("too many headers".to_owned()));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| KvsError::Protocol("invalid header".to_owned()))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    let mut req = HttpRequest {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        http_10,
+        headers,
+        body: Vec::new(),
+    };
+
+    if req.header("Transfer-Encoding").is_some() {
+        return Err(KvsError::Protocol(
+            "bodies must have a Content-Length".to_owned(),
+        ));
+    }
+    let len = match req.header("Content-Length") {
+        Some(len) => len
+            .parse::<u64>()
+            .map_err(|_| KvsError::Protocol("invalid Content-Length".to_owned()))?,
+        None => 0,
+    };
+    if len > u64::from(MAX_FRAME_LEN) {
+        return Err(KvsError::Protocol("body too long".to_owned()));
+    }
+    if len > 0 {
+        if req
+            .header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+        {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            writer.flush()?;
+        }
+        // the buffer grows with the bytes received, not the announced length
+        reader.by_ref().take(len).read_to_end(&mut req.body)?;
+        if (req.body.len() as u64) < len {
+            return Err(KvsError::Protocol("unexpected end of stream".to_owned()));
+        }
+    }
+    Ok(Some(req))
+}
+
+// Reads a line without its terminating CRLF or LF. Returns `None` at the end
+// of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(KvsError::Protocol(if line.len() >= MAX_LINE {
+            "line too long".to_owned()
+        } else {
+            "unexpected end of stream".to_owned()
+        }));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| KvsError::Protocol("invalid UTF-8 in header".to_owned()))
+}
+
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(&'static str, &'static str)>,
+    body: Option<Value>,
+}
+
+impl HttpResponse {
+    fn ok(body: Value) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: Some(body),
+        }
+    }
+
+    fn no_content() -> HttpResponse {
+        HttpResponse {
+            status: 204,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    fn error(status: u16, code: ErrorCode, message: String) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Some(json!({ "error": { "code": code, "message": message } })),
+        }
+    }
+
+    fn from_error(e: &KvsError) -> HttpResponse {
+        let status = match e {
+            KvsError::Unsupported(_) => 501,
+            e => match e.code() {
+                ErrorCode::KeyNotFound => 404,
+                ErrorCode::DeadlineExceeded => 503,
+                ErrorCode::AuthenticationFailed => 401,
+                ErrorCode::PermissionDenied | ErrorCode::ReadOnly => 403,
+                ErrorCode::MalformedRequest => 400,
+                ErrorCode::NotLeader => 421,
+                ErrorCode::Conflict => 409,
+                ErrorCode::Io | ErrorCode::Internal => 500,
+            },
+        };
+        let mut resp = HttpResponse::error(status, e.code(), e.message());
+        if status == 401 {
+            resp.headers
+                .push(("WWW-Authenticate", "Bearer, Basic realm=\"kvs\""));
+        }
+        resp
+    }
+
+    fn bad_request(message: impl Into<String>) -> HttpResponse {
+        HttpResponse::error(400, ErrorCode::MalformedRequest, message.into())
+    }
+
+    fn not_found() -> HttpResponse {
+        HttpResponse::error(
+            404,
+            ErrorCode::MalformedRequest,
+            "No such resource".to_owned(),
+        )
+    }
+
+    fn method_not_allowed(allow: &'static str) -> HttpResponse {
+        let mut resp = HttpResponse::error(
+            405,
+            ErrorCode::MalformedRequest,
+            "Method not allowed".to_owned(),
+        );
+        resp.headers.push(("Allow", allow));
+        resp
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> Result<()> {
+        let body = match &self.body {
+            Some(body) => serde_json::to_vec(body)?,
+            None => Vec::new(),
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if self.body.is_some() {
+            writer.write_all(b"Content-Type: application/json\r\n")?;
+        }
+        write!(writer, "Content-Length: {}\r\n", body.len())?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&body)?;
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        421 => "Misdirected Request",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[derive(Deserialize)]
+struct SetBody {
+    value: String,
+}
+
+fn handle<E: KvsEngine>(
+    engine: &E,
+    auth: &Option<Arc<AuthConfig>>,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let mut session = Session::new(auth.clone());
+    match credentials(req) {
+        Ok(Some(credentials)) => {
+            if !session.authenticate(&credentials) {
+                return HttpResponse::from_error(&KvsError::AuthenticationFailed);
+            }
+        }
+        Ok(None) => {}
+        Err(resp) => return resp,
+    }
+
+    let (path, query) = match req.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (req.target.as_str(), ""),
+    };
+    let res = match path.strip_prefix("/keys") {
+        Some("") | Some("/") => match req.method.as_str() {
+            "GET" => scan(engine, &session, query),
+            _ => return HttpResponse::method_not_allowed("GET"),
+        },
+        Some(key) if key.starts_with('/') => {
+            let key = match percent_decode(&key[1..], false) {
+                Some(key) => key,
+                None => return HttpResponse::bad_request("Invalid key encoding"),
+            };
+            match req.method.as_str() {
+                "GET" => get(engine, &session, key),
+                "PUT" => match serde_json::from_slice::<SetBody>(&req.body) {
+                    Ok(body) => session
+                        .authorize_key(&key, true)
+                        .and_then(|()| engine.set(key, body.value))
+                        .map(|()| HttpResponse::no_content()),
+                    Err(e) => return HttpResponse::bad_request(format!("Invalid body: {}", e)),
+                },
+                "DELETE" => session
+                    .authorize_key(&key, true)
+                    .and_then(|()| engine.remove(key))
+                    .map(|()| HttpResponse::no_content()),
+                _ => return HttpResponse::method_not_allowed("GET, PUT, DELETE"),
+            }
+        }
+        _ => return HttpResponse::not_found(),
+    };
+    res.unwrap_or_else(|e| HttpResponse::from_error(&e))
+}
+
+fn get<E: KvsEngine>(engine: &E, session: &Session, key: String) -> Result<HttpResponse> {
+    session.authorize_key(&key, false)?;
+    match engine.get(key.clone())? {
+        Some(value) => Ok(HttpResponse::ok(json!({ "key": key, "value": value }))),
+        None => Err(KvsError::KeyNotFound),
+    }
+}
+
+// Answers a page of the keys the user may read.
+fn scan<E: KvsEngine>(engine: &E, session: &Session, query: &str) -> Result<HttpResponse> {
+    let mut after = None;
+    let mut limit = DEFAULT_SCAN_LIMIT;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = match percent_decode(value, true) {
+            Some(value) => value,
+            None => return Ok(HttpResponse::bad_request("Invalid query encoding")),
+        };
+        match name {
+            "after" => after = Some(value),
+            "limit" => match value.parse::<usize>() {
+                Ok(n) if n > 0 => limit = n.min(MAX_SCAN_LIMIT),
+                _ => return Ok(HttpResponse::bad_request("Invalid limit")),
+            },
+            _ => {}
+        }
+    }
+
+    let keys = engine.scan(after, limit)?;
+    let next = match keys.last() {
+        Some(last) if keys.len() == limit => Some(last.clone()),
+        _ => None,
+    };
+    let keys: Vec<String> = keys
+        .into_iter()
+        .filter(|key| session.authorize_key(key, false).is_ok())
+        .collect();
+    Ok(HttpResponse::ok(json!({ "keys": keys, "next": next })))
+}
+
+// Finds the credentials of the `Authorization` header, answering the
+// response to send if they cannot be read.
+fn credentials(req: &HttpRequest) -> std::result::Result<Option<Credentials>, HttpResponse> {
+    let header = match req.header("Authorization") {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let (scheme, param) = header.split_once(' ').unwrap_or((header, ""));
+    let param = param.trim();
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        return Ok(Some(Credentials::Token(param.to_owned())));
+    }
+    if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = base64_decode(param).and_then(|bytes| String::from_utf8(bytes).ok());
+        if let Some((username, password)) = decoded.as_ref().and_then(|d| d.split_once(':')) {
+            return Ok(Some(Credentials::Password {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            }));
+        }
+    }
+    Err(HttpResponse::bad_request("Invalid Authorization header"))
+}
+
+// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(s: &str, query: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if query => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Decodes standard, padded base64.
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    fn sextet(c: u8) -> Option<u32> {
+        Some(match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        } as u32)
+    }
+
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0;
+        for &c in &chunk[..4 - padding] {
+            n = n << 6 | sextet(c)?;
+        }
+        n <<= 6 * padding;
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
@@ -0,0 +1,214 @@
+//! Transport of the messages between the nodes of a Raft cluster.
+//!
+//! Every node listens on its `raft_addr`, and connects to each other node
+//! to send it messages. A connection only carries messages one way: frames
+//! holding a bincode-encoded `Message` each, without handshake. Messages to
+//! a node which cannot be reached are dropped, which Raft tolerates: they
+//! are sent again along with the following heartbeats.
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError};
+use slog::{debug, info, warn, Logger};
+
+use super::{accept_loop, ShutdownHandle, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::protocol::{read_frame, write_frame};
+use crate::raft::{Message, RaftEngine};
+use crate::storage::Storage;
+use crate::{KvsEngine, KvsError, NaiveThreadPool, Result, ThreadPool};
+
+const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(50);
+// how long sending to a node may block before the connection is dropped
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+// how long messages to a node are dropped after failing to connect to it
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Server carrying the messages of a node of a Raft cluster to and from the
+/// other nodes, and driving the clock of the node.
+///
+/// A `KvsServer` serves clients with the same `RaftEngine`.
+pub struct RaftServer<E: KvsEngine, S: Storage> {
+    engine: RaftEngine<E, S>,
+    logger: Arc<Logger>,
+    shutdown: ShutdownHandle,
+    tick_interval: Duration,
+}
+
+impl<E: KvsEngine, S: Storage> RaftServer<E, S> {
+    /// Create a new RaftServer for the node of the engine.
+    pub fn new(engine: RaftEngine<E, S>, logger: Logger) -> Self {
+        RaftServer {
+            engine,
+            logger: Arc::new(logger),
+            shutdown: ShutdownHandle::default(),
+            tick_interval: DEFAULT_TICK_INTERVAL,
+        }
+    }
+
+    /// Get a handle to stop the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Set the duration of a tick of the clock of the node. Leaders send
+    /// heartbeats every 3 ticks, and followers start an election after 10
+    /// to 20 ticks without one. Defaults to 50 milliseconds.
+    pub fn set_tick_interval(&mut self, interval: Duration) {
+        self.tick_interval = interval;
+    }
+
+    /// Listen on the `raft_addr` of the node for the messages of the other
+    /// nodes, and send them those of the node.
+    ///
+    /// Returns once a shutdown is requested through a `ShutdownHandle`.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the address cannot be bound.
+    pub fn run(&mut self) -> Result<()> {
+        let id = self.engine.id();
+        let addr = self.engine.raft_addr(id).expect("node of the cluster");
+        let listener = TcpListener::bind(addr)?;
+        info!(self.logger, "Raft node {} bound to {:?}", id, addr);
+
+        // the clock and the outgoing messages stop once the sender is dropped
+        let (stop, stopped) = channel::bounded::<()>(0);
+        let ticker = {
+            let engine = self.engine.clone();
+            let logger = self.logger.clone();
+            let stopped = stopped.clone();
+            let interval = self.tick_interval;
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(e) = engine.tick() {
+                        warn!(logger, "Raft tick failed: {}", e);
+                    }
+                }
+            })
+        };
+        let dispatcher = {
+            let engine = self.engine.clone();
+            let logger = self.logger.clone();
+            thread::spawn(move || dispatch(engine, stopped, logger))
+        };
+
+        let engine = self.engine.clone();
+        let logger = self.logger.clone();
+        let res = NaiveThreadPool::new(0).and_then(|pool| {
+            accept_loop(
+                listener,
+                &self.shutdown,
+                DEFAULT_SHUTDOWN_TIMEOUT,
+                &self.logger,
+                &pool,
+                move |stream| receive(stream, &engine, &logger),
+            )
+        });
+        drop(stop);
+        let _ = ticker.join();
+        let _ = dispatcher.join();
+        res?;
+
+        self.shutdown.clear_wakes();
+        self.engine.flush()?;
+        info!(self.logger, "Raft server stopped");
+        Ok(())
+    }
+}
+
+// Hands the messages of a connection to the node, until it is closed.
+fn receive<E: KvsEngine, S: Storage>(
+    stream: TcpStream,
+    engine: &RaftEngine<E, S>,
+    logger: &Logger,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream);
+    while let Some(payload) = read_frame(&mut reader)? {
+        let msg: Message = bincode::deserialize(&payload)?;
+        engine.step(msg)?;
+    }
+    debug!(logger, "Raft connection from {} closed", peer_addr);
+    Ok(())
+}
+
+// Routes the messages of the node to a sender thread per other node, until
+// `stopped` is disconnected.
+fn dispatch<E: KvsEngine, S: Storage>(
+    engine: RaftEngine<E, S>,
+    stopped: Receiver<()>,
+    logger: Arc<Logger>,
+) {
+    let mut senders = HashMap::new();
+    let mut threads = Vec::new();
+    for node in &engine.cluster().nodes {
+        if node.id == engine.id() {
+            continue;
+        }
+        let (sender, messages) = channel::unbounded();
+        let addr = node.raft_addr;
+        let logger = logger.clone();
+        senders.insert(node.id, sender);
+        threads.push(thread::spawn(move || send(addr, messages, &logger)));
+    }
+
+    loop {
+        channel::select! {
+            recv(engine.messages()) -> msg => {
+                // the engine keeps the sending side alive
+                let msg = msg.expect("engine outbox closed");
+                if let Some(sender) = senders.get(&msg.to) {
+                    let _ = sender.send(msg);
+                }
+            }
+            recv(stopped) -> _ => break,
+        }
+    }
+    drop(senders);
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+// Sends the messages to the node at the given address, until the channel is
+// disconnected.
+fn send(addr: SocketAddr, messages: Receiver<Message>, logger: &Logger) {
+    let mut connection: Option<BufWriter<TcpStream>> = None;
+    let mut retry = Instant::now();
+    while let Ok(msg) = messages.recv() {
+        if connection.is_none() {
+            if Instant::now() < retry {
+                continue;
+            }
+            match connect(addr) {
+                Ok(stream) => connection = Some(BufWriter::new(stream)),
+                Err(e) => {
+                    debug!(logger, "Failed to connect to Raft node {}: {}", addr, e);
+                    retry = Instant::now() + RECONNECT_INTERVAL;
+                    continue;
+                }
+            }
+        }
+        let writer = connection.as_mut().expect("connected");
+        // the messages already queued go along in the same write
+        let res = std::iter::once(msg)
+            .chain(messages.try_iter())
+            .try_for_each(|msg| write_frame(writer, &bincode::serialize(&msg)?))
+            .and_then(|()| writer.flush().map_err(KvsError::from));
+        if let Err(e) = res {
+            debug!(logger, "Failed to send to Raft node {}: {}", addr, e);
+            connection = None;
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, SEND_TIMEOUT)?;
+    stream.set_write_timeout(Some(SEND_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
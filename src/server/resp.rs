@@ -0,0 +1,594 @@
+//! A server speaking a subset of the Redis protocol (RESP), so that Redis
+//! clients and tools can use the store.
+//!
+//! Supported commands are `PING`, `AUTH`, `GET`, `SET` (with `EX` or `PX`),
+//! `DEL`, `EXISTS`, `MGET`, `MSET`, `SCAN` (with `MATCH` and `COUNT`),
+//! `EXPIRE`, `SELECT 0` and `QUIT`. Keys and values must be valid UTF-8.
+//! `MSET` is not atomic, and `AUTH password` authenticates with a token.
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{self, RecvTimeoutError};
+use slog::{debug, info, warn, Logger};
+
+use super::{accept_loop, ShutdownHandle, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::auth::{AuthConfig, Session};
+use crate::protocol::MAX_FRAME_LEN;
+use crate::{Credentials, ExpiringEngine, KvsEngine, KvsError, Result, ThreadPool};
+
+// the longest inline command or header line
+const MAX_LINE: usize = 64 * 1024;
+// the most arguments of a command
+const MAX_ARGS: usize = 1024 * 1024;
+// the most keys a SCAN returns, whatever COUNT asks for
+const MAX_SCAN_COUNT: usize = 1000;
+const DEFAULT_SCAN_COUNT: usize = 10;
+// the most SCAN iterations remembered, the oldest being forgotten first
+const MAX_CURSORS: usize = 4096;
+// the longest time to live, about 136 years
+const MAX_TTL_SECS: i64 = u32::MAX as i64;
+// how often keys nobody reads are checked for expiry
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server of the Redis protocol.
+///
+/// Keys may expire, so the engine is wrapped in an `ExpiringEngine`, which a
+/// `KvsServer` may share to serve the same keys.
+pub struct RespServer<E: KvsEngine, P: ThreadPool> {
+    engine: ExpiringEngine<E>,
+    logger: Arc<Logger>,
+    pool: P,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    auth: Option<Arc<AuthConfig>>,
+    cursors: Arc<Cursors>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> RespServer<E, P> {
+    /// Create a new RespServer.
+    pub fn new(engine: ExpiringEngine<E>, logger: Logger, pool: P) -> Self {
+        RespServer {
+            engine,
+            logger: Arc::new(logger),
+            pool,
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            idle_timeout: None,
+            auth: None,
+            cursors: Arc::default(),
+        }
+    }
+
+    /// Get a handle to stop the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Set how long a shutdown waits for the commands already received to
+    /// be answered before closing the remaining connections. Defaults to 5
+    /// seconds.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Set how long a connection may stay without sending a command, or
+    /// without accepting the bytes of a reply, before the server closes it.
+    /// Defaults to `None`, which keeps connections open forever.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Require clients to authenticate with `AUTH`, and only execute the
+    /// commands the grants of their user allow. Defaults to `None`, which
+    /// lets any client access every key.
+    pub fn set_auth(&mut self, auth: Option<AuthConfig>) {
+        self.auth = auth.map(Arc::new);
+    }
+
+    /// Start RespServer to serve incoming commands.
+    ///
+    /// Every connection occupies a pool thread until it is closed. Returns
+    /// once a shutdown is requested through a `ShutdownHandle`.
+    pub fn run(&mut self, addr: &SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!(self.logger, "RESP server bound to {:?}", addr);
+
+        // expired keys nobody reads are removed in the background, until the
+        // sender is dropped
+        let (stop_sweeper, stopped) = channel::bounded::<()>(0);
+        let sweeper = {
+            let engine = self.engine.clone();
+            let logger = self.logger.clone();
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SWEEP_INTERVAL) {
+                    if let Err(e) = engine.remove_expired() {
+                        warn!(logger, "Failed to remove expired keys: {}", e);
+                    }
+                }
+            })
+        };
+
+        let engine = self.engine.clone();
+        let logger = self.logger.clone();
+        let idle_timeout = self.idle_timeout;
+        let auth = self.auth.clone();
+        let cursors = self.cursors.clone();
+        let res = accept_loop(
+            listener,
+            &self.shutdown,
+            self.shutdown_timeout,
+            &self.logger,
+            &self.pool,
+            move |stream| {
+                let conn = Connection {
+                    engine: engine.clone(),
+                    session: Session::new(auth.clone()),
+                    cursors: cursors.clone(),
+                };
+                serve(stream, conn, &logger, idle_timeout)
+            },
+        );
+        drop(stop_sweeper);
+        let _ = sweeper.join();
+        res?;
+
+        self.shutdown.clear_wakes();
+        self.engine.flush()?;
+        info!(self.logger, "RESP server stopped");
+        Ok(())
+    }
+}
+
+fn serve<E: KvsEngine>(
+    stream: TcpStream,
+    mut conn: Connection<E>,
+    logger: &Logger,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    debug!(logger, "RESP connection from {}", peer_addr);
+    stream.set_read_timeout(idle_timeout)?;
+    stream.set_write_timeout(idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    match serve_commands(&mut reader, &mut writer, &mut conn) {
+        Err(KvsError::Io(ref e))
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            debug!(logger, "Closing idle connection from {}", peer_addr);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn serve_commands<E: KvsEngine>(
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut BufWriter<TcpStream>,
+    conn: &mut Connection<E>,
+) -> Result<()> {
+    loop {
+        let args = match read_command(reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(KvsError::Protocol(e)) => {
+                // the stream cannot be resynchronized
+                let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                write_reply(writer, &reply)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        write_reply(writer, &conn.execute(args))?;
+        if quit {
+            break;
+        }
+        // replies to pipelined commands are sent together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Reads the next command, either an array of bulk strings or an inline
+// command. Returns `None` at the end of the stream.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_len(&line[1..])?.unwrap_or(0);
+    if count > MAX_ARGS {
+        return Err(KvsError::Protocol("too many arguments".to_owned()));
+    }
+    // as for the bulk strings, the announced count is not trusted up front
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?
+            .ok_or_else(|| KvsError::Protocol("unexpected end of stream".to_owned()))?;
+        if line.first() != Some(&b'$') {
+            return Err(KvsError::Protocol("expected a bulk string".to_owned()));
+        }
+        let len = parse_len(&line[1..])?
+            .ok_or_else(|| KvsError::Protocol("invalid bulk length".to_owned()))?;
+        if len > MAX_FRAME_LEN as usize {
+            return Err(KvsError::Protocol("bulk string too long".to_owned()));
+        }
+        // the buffer grows with the bytes received, not the announced length
+        let mut arg = Vec::new();
+        reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(KvsError::Protocol("unexpected end of stream".to_owned()));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(KvsError::Protocol("bulk string not terminated".to_owned()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Reads a line without its terminating CRLF or LF. Returns `None` at the end
+// of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(KvsError::Protocol(if line.len() >= MAX_LINE {
+            "line too long".to_owned()
+        } else {
+            "unexpected end of stream".to_owned()
+        }));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+// Parses the length of an array or bulk string, `None` being a negative one.
+fn parse_len(digits: &[u8]) -> Result<Option<usize>> {
+    let len: i64 = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| KvsError::Protocol("invalid length".to_owned()))?;
+    Ok(if len < 0 { None } else { Some(len as usize) })
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn wrong_arity(command: &str) -> Reply {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_ascii_lowercase()
+        ))
+    }
+
+    fn syntax_error() -> Reply {
+        Reply::Error("ERR syntax error".to_owned())
+    }
+
+    fn from_error(e: KvsError) -> Reply {
+        match e {
+            KvsError::PermissionDenied(reason) => Reply::Error(format!("NOPERM {}", reason)),
+            KvsError::AuthenticationFailed => {
+                Reply::Error("WRONGPASS invalid username-password pair".to_owned())
+            }
+            KvsError::ReadOnly => {
+                Reply::Error("READONLY You can't write against a read only replica.".to_owned())
+            }
+            e => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Status(status) => write!(writer, "+{}\r\n", status),
+        // error lines cannot hold line breaks
+        Reply::Error(e) => write!(writer, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+        Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+        Reply::Bulk(Some(value)) => {
+            write!(writer, "${}\r\n", value.len())?;
+            writer.write_all(value.as_bytes())?;
+            writer.write_all(b"\r\n")
+        }
+        Reply::Array(items) => {
+            write!(writer, "*{}\r\n", items.len())?;
+            for item in items {
+                write_reply(writer, item)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// The state of a connection.
+struct Connection<E: KvsEngine> {
+    engine: ExpiringEngine<E>,
+    session: Session,
+    cursors: Arc<Cursors>,
+}
+
+impl<E: KvsEngine> Connection<E> {
+    fn execute(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let args: Vec<String> = match args.into_iter().map(String::from_utf8).collect() {
+            Ok(args) => args,
+            Err(_) => return Reply::Error("ERR arguments must be valid UTF-8".to_owned()),
+        };
+        let command = args[0].to_ascii_uppercase();
+        self.command(&command, &args[1..])
+            .unwrap_or_else(Reply::from_error)
+    }
+
+    fn command(&mut self, command: &str, args: &[String]) -> Result<Reply> {
+        let reply = match (command, args) {
+            ("PING", []) => Reply::Status("PONG"),
+            ("PING", [message]) => Reply::Bulk(Some(message.clone())),
+            ("QUIT", []) => Reply::Status("OK"),
+            // some clients ask for the commands of the server when connecting
+            ("COMMAND", _) => Reply::Array(Vec::new()),
+            ("SELECT", [db]) if db == "0" => Reply::Status("OK"),
+            ("SELECT", [_]) => Reply::Error("ERR DB index is out of range".to_owned()),
+            ("AUTH", [token]) => self.auth(Credentials::Token(token.clone()))?,
+            ("AUTH", [username, password]) => self.auth(Credentials::Password {
+                username: username.clone(),
+                password: password.clone(),
+            })?,
+            ("GET", [key]) => {
+                self.session.authorize_key(key, false)?;
+                Reply::Bulk(self.engine.get(key.clone())?)
+            }
+            ("SET", [key, value, options @ ..]) => self.set(key, value, options)?,
+            ("DEL", keys) if !keys.is_empty() => {
+                self.authorize_all(keys, true)?;
+                let mut removed = 0;
+                for key in keys {
+                    match self.engine.remove(key.clone()) {
+                        Ok(()) => removed += 1,
+                        Err(KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Reply::Integer(removed)
+            }
+            ("EXISTS", keys) if !keys.is_empty() => {
+                self.authorize_all(keys, false)?;
+                let mut found = 0;
+                for key in keys {
+                    if self.engine.get(key.clone())?.is_some() {
+                        found += 1;
+                    }
+                }
+                Reply::Integer(found)
+            }
+            ("MGET", keys) if !keys.is_empty() => {
+                self.authorize_all(keys, false)?;
+                let values = self.engine.get_many(keys.to_vec())?;
+                Reply::Array(values.into_iter().map(Reply::Bulk).collect())
+            }
+            ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                for pair in pairs.chunks(2) {
+                    self.session.authorize_key(&pair[0], true)?;
+                }
+                let pairs = pairs
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                self.engine.set_many(pairs)?;
+                Reply::Status("OK")
+            }
+            ("SCAN", [cursor, options @ ..]) => self.scan(cursor, options)?,
+            ("EXPIRE", [key, seconds]) => {
+                let ttl = match parse_ttl(seconds, 1000) {
+                    Some(ttl) => ttl,
+                    None => return Ok(invalid_ttl("expire")),
+                };
+                self.session.authorize_key(key, true)?;
+                Reply::Integer(self.engine.expire(key.clone(), ttl)? as i64)
+            }
+            (
+                "PING" | "QUIT" | "SELECT" | "AUTH" | "GET" | "SET" | "DEL" | "EXISTS" | "MGET"
+                | "MSET" | "SCAN" | "EXPIRE",
+                _,
+            ) => Reply::wrong_arity(command),
+            _ => Reply::Error(format!(
+                "ERR unknown command '{}'",
+                command.chars().take(64).collect::<String>()
+            )),
+        };
+        Ok(reply)
+    }
+
+    fn auth(&mut self, credentials: Credentials) -> Result<Reply> {
+        if self.session.authenticate(&credentials) {
+            Ok(Reply::Status("OK"))
+        } else {
+            Err(KvsError::AuthenticationFailed)
+        }
+    }
+
+    // Checks every key before executing anything.
+    fn authorize_all(&self, keys: &[String], write: bool) -> Result<()> {
+        keys.iter()
+            .try_for_each(|key| self.session.authorize_key(key, write))
+    }
+
+    fn set(&self, key: &str, value: &str, options: &[String]) -> Result<Reply> {
+        let ttl = match options {
+            [] => None,
+            [unit, amount] => {
+                let millis_per_unit = match unit.to_ascii_uppercase().as_str() {
+                    "EX" => 1000,
+                    "PX" => 1,
+                    _ => return Ok(Reply::syntax_error()),
+                };
+                match parse_ttl(amount, millis_per_unit) {
+                    Some(ttl) if !ttl.is_zero() => Some(ttl),
+                    _ => return Ok(invalid_ttl("set")),
+                }
+            }
+            _ => return Ok(Reply::syntax_error()),
+        };
+        self.session.authorize_key(key, true)?;
+        match ttl {
+            Some(ttl) => self
+                .engine
+                .set_with_ttl(key.to_owned(), value.to_owned(), ttl)?,
+            None => self.engine.set(key.to_owned(), value.to_owned())?,
+        }
+        Ok(Reply::Status("OK"))
+    }
+
+    // Returns the next cursor and a page of the keys the user may read.
+    fn scan(&self, cursor: &str, options: &[String]) -> Result<Reply> {
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in options.chunks(2) {
+            match (option[0].to_ascii_uppercase().as_str(), option.get(1)) {
+                ("MATCH", Some(glob)) => pattern = Some(glob.as_str()),
+                ("COUNT", Some(n)) => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => count = n.min(MAX_SCAN_COUNT),
+                    _ => return Ok(Reply::syntax_error()),
+                },
+                _ => return Ok(Reply::syntax_error()),
+            }
+        }
+        let after = if cursor == "0" {
+            None
+        } else {
+            match cursor.parse().ok().and_then(|id| self.cursors.get(id)) {
+                Some(key) => Some(key),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+            }
+        };
+
+        let keys = self.engine.scan(after, count)?;
+        let next = match keys.last() {
+            Some(last) if keys.len() == count => self.cursors.open(last.clone()),
+            _ => 0,
+        };
+        let keys = keys
+            .into_iter()
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .filter(|key| self.session.authorize_key(key, false).is_ok())
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string())),
+            Reply::Array(keys),
+        ]))
+    }
+}
+
+// Parses a time to live in units of the given number of milliseconds. Times
+// below zero are zero, which expires keys right away.
+fn parse_ttl(amount: &str, millis_per_unit: u64) -> Option<Duration> {
+    let amount: i64 = amount.parse().ok()?;
+    if amount > MAX_TTL_SECS {
+        return None;
+    }
+    Some(Duration::from_millis(
+        amount.max(0) as u64 * millis_per_unit,
+    ))
+}
+
+fn invalid_ttl(command: &str) -> Reply {
+    Reply::Error(format!("ERR invalid expire time in '{}' command", command))
+}
+
+// Matches a Redis glob pattern: `*` matches any characters, `?` any single
+// character, and `\` makes the next character match itself.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` is in the pattern, and where it stops matching
+    let mut star = None;
+    while t < text.len() {
+        let matched = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => 1,
+            Some('\\') if p + 1 < pattern.len() && pattern[p + 1] == text[t] => 2,
+            Some('\\') if p + 1 < pattern.len() => 0,
+            Some(&c) if c == text[t] => 1,
+            _ => 0,
+        };
+        if matched > 0 {
+            p += matched;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // let the last `*` match one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Where the SCAN iterations in progress stopped, by cursor.
+#[derive(Default)]
+struct Cursors {
+    state: Mutex<(u64, BTreeMap<u64, String>)>,
+}
+
+impl Cursors {
+    // Remembers the last key of a page, returning the cursor of the next one.
+    fn open(&self, last_key: String) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let (next_id, cursors) = &mut *state;
+        *next_id += 1;
+        cursors.insert(*next_id, last_key);
+        if cursors.len() > MAX_CURSORS {
+            cursors.pop_first();
+        }
+        *next_id
+    }
+
+    fn get(&self, cursor: u64) -> Option<String> {
+        self.state.lock().unwrap().1.get(&cursor).cloned()
+    }
+}
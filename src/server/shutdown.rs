@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Handle to stop a running `KvsServer` from another thread.
+///
+/// Obtained from `KvsServer::shutdown_handle`. Clones stop the same server.
+/// `RespServer` and `Follower` hand out handles stopping them alike.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    // wake the server up while it waits for connections, one per listener
+    wakes: Mutex<Vec<Box<dyn Fn() + Send>>>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to shut down.
+    ///
+    /// The server stops accepting connections, lets the requests it already
+    /// received finish, flushes the engine and returns from `run`. Calling it
+    /// before `run` makes `run` return right away.
+    pub fn shutdown(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        for wake in &*self.state.wakes.lock().unwrap() {
+            wake();
+        }
+    }
+
+    /// Whether a shutdown was asked for.
+    pub fn is_requested(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    // Adds a way to wake the server up.
+    pub(super) fn add_wake(&self, wake: Box<dyn Fn() + Send>) {
+        self.state.wakes.lock().unwrap().push(wake);
+    }
+
+    // Forgets how to wake the server up once it stopped.
+    pub(super) fn clear_wakes(&self) {
+        self.state.wakes.lock().unwrap().clear();
+    }
+}
+
+/// Connections served by pool threads, so that a shutdown can stop reading
+/// from them and wait for their last responses.
+#[derive(Default)]
+pub(super) struct Connections {
+    streams: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+    closed: Condvar,
+}
+
+/// Keeps a connection in `Connections` until dropped.
+pub(super) struct Tracked {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connections {
+    pub(super) fn track(self: &Arc<Self>, stream: &TcpStream) -> io::Result<Tracked> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let stream = stream.try_clone()?;
+        self.streams.lock().unwrap().insert(id, stream);
+        Ok(Tracked {
+            connections: self.clone(),
+            id,
+        })
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub(super) fn shutdown(&self, how: Shutdown) {
+        for stream in self.streams.lock().unwrap().values() {
+            let _ = stream.shutdown(how);
+        }
+    }
+
+    // Returns whether every connection was closed before the timeout.
+    pub(super) fn wait_closed(&self, timeout: Duration) -> bool {
+        let streams = self.streams.lock().unwrap();
+        let (streams, _) = self
+            .closed
+            .wait_timeout_while(streams, timeout, |streams| !streams.is_empty())
+            .unwrap();
+        streams.is_empty()
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.streams.lock().unwrap().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// A transaction open on a connection, from `Request::Begin` on.
+///
+/// Writes are buffered until the commit, and reads see them. Every key read
+/// from the engine keeps the value and version it was first read with, and
+/// the commit checks that the version is still current before applying the
+/// writes, which requires an engine supporting versions.
+#[derive(Clone, Default)]
+pub(crate) struct Transaction {
+    // value and version of each key read from the engine, `None` if it was
+    // missing
+    reads: BTreeMap<String, Option<(String, u64)>>,
+    // latest value written to each key, `None` if it was removed
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction {
+    pub(crate) fn get<E: KvsEngine>(&mut self, engine: &E, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(read) = self.reads.get(&key) {
+            return Ok(read.as_ref().map(|(value, _)| value.clone()));
+        }
+        let read = engine.get_with_version(key.clone())?;
+        let value = read.as_ref().map(|(value, _)| value.clone());
+        self.reads.insert(key, read);
+        Ok(value)
+    }
+
+    pub(crate) fn get_many<E: KvsEngine>(
+        &mut self,
+        engine: &E,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(engine, key)).collect()
+    }
+
+    pub(crate) fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub(crate) fn set_many(&mut self, pairs: Vec<(String, String)>) {
+        for (key, value) in pairs {
+            self.set(key, value);
+        }
+    }
+
+    /// Buffer the removal of a key, which must exist as far as the
+    /// transaction sees.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::KeyNotFound` if the key does not exist, or an error
+    /// if it is not read successfully.
+    pub(crate) fn remove<E: KvsEngine>(&mut self, engine: &E, key: String) -> Result<()> {
+        if self.get(engine, key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Apply the writes to the engine, unless a key read changed since.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::Conflict` if a key read changed, or any error of
+    /// the engine.
+    pub(crate) fn commit<E: KvsEngine>(self, engine: &E) -> Result<()> {
+        let reads = self
+            .reads
+            .into_iter()
+            .map(|(key, read)| (key, read.map(|(_, version)| version)))
+            .collect();
+        engine.transact(reads, self.writes.into_iter().collect())
+    }
+}
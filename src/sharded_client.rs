@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::thread;
+
+use crate::{ClientConfig, KvsClient, KvsError, Result};
+
+// the points of each server on the ring, so that keys spread evenly
+const VIRTUAL_NODES: usize = 160;
+
+/// Client spreading the keys over several kvs servers.
+///
+/// Each key is stored on one server, picked by consistent hashing: every
+/// server owns many points of a ring of hashes, and a key belongs to the
+/// server owning the first point following the hash of the key. Adding or
+/// removing a server only moves the keys of the points it gains or loses,
+/// about one key in the number of servers.
+///
+/// Hashes do not depend on the order of the addresses nor on the process,
+/// so that every client agrees on where each key is. Multi-key requests are
+/// split by server and sent to all of them at once. They are not atomic
+/// across servers: a failure may leave some of the keys set.
+///
+/// ```rust,no_run
+/// # use kvs::{Result, ShardedClient};
+/// # fn try_main() -> Result<()> {
+/// let addrs = ["127.0.0.1:4000".parse().unwrap(), "127.0.0.1:4001".parse().unwrap()];
+/// let mut client = ShardedClient::connect(&addrs)?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct ShardedClient {
+    addrs: Vec<SocketAddr>,
+    clients: Vec<KvsClient>,
+    // hash of each point of the ring, and index of the server owning it
+    ring: BTreeMap<u64, usize>,
+}
+
+impl ShardedClient {
+    /// Connect to every given server, using the binary codec.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::NoServers` if no address is given, or an error if
+    /// a connection or a handshake fails.
+    pub fn connect(addrs: &[SocketAddr]) -> Result<ShardedClient> {
+        ShardedClient::connect_with_config(addrs, &ClientConfig::default())
+    }
+
+    /// Connect to every given server with the given options.
+    ///
+    /// # Error
+    ///
+    /// Return `KvsError::NoServers` if no address is given, or an error if
+    /// a connection or a handshake fails or times out.
+    pub fn connect_with_config(
+        addrs: &[SocketAddr],
+        config: &ClientConfig,
+    ) -> Result<ShardedClient> {
+        if addrs.is_empty() {
+            return Err(KvsError::NoServers);
+        }
+        let mut unique = addrs.to_vec();
+        unique.sort();
+        unique.dedup();
+
+        let mut ring = BTreeMap::new();
+        for (i, addr) in unique.iter().enumerate() {
+            for point in 0..VIRTUAL_NODES {
+                ring.insert(hash(format!("{}#{}", addr, point).as_bytes()), i);
+            }
+        }
+        let clients = unique
+            .iter()
+            .map(|addr| KvsClient::connect_with_config(addr, config))
+            .collect::<Result<_>>()?;
+        Ok(ShardedClient {
+            addrs: unique,
+            clients,
+            ring,
+        })
+    }
+
+    /// The address of the server storing the key.
+    pub fn server_for(&self, key: &str) -> SocketAddr {
+        self.addrs[self.shard(key)]
+    }
+
+    /// Set the given string value to the given string key on its server.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let shard = self.shard(&key);
+        self.clients[shard].set(key, value)
+    }
+
+    /// Get the string value of the given string key from its server.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let shard = self.shard(&key);
+        self.clients[shard].get(key)
+    }
+
+    /// Remove the given string key from its server.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if the request is not
+    /// processed successfully on the server side.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let shard = self.shard(&key);
+        self.clients[shard].remove(key)
+    }
+
+    /// Get the string values of several keys, in the order of the keys,
+    /// asking every server for its keys at once.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if a request is not
+    /// processed successfully on the server side.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut positions = vec![Vec::new(); self.clients.len()];
+        let mut batches = vec![Vec::new(); self.clients.len()];
+        for (pos, key) in keys.into_iter().enumerate() {
+            let shard = self.shard(&key);
+            positions[shard].push(pos);
+            batches[shard].push(key);
+        }
+        let count = positions.iter().map(Vec::len).sum();
+
+        let results = self.fan_out(batches, |client, keys| client.get_many(keys));
+        let mut values = vec![None; count];
+        for (positions, res) in positions.into_iter().zip(results) {
+            for (pos, value) in positions.into_iter().zip(res?) {
+                values[pos] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Set several keys to string values, sending every server its keys at
+    /// once. The keys of a server are set in order.
+    ///
+    /// # Error
+    ///
+    /// Return an error if the network fails or if a request is not
+    /// processed successfully on the server side, in which case some of the
+    /// keys may have been set.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batches = vec![Vec::new(); self.clients.len()];
+        for (key, value) in pairs {
+            let shard = self.shard(&key);
+            batches[shard].push((key, value));
+        }
+        self.fan_out(batches, |client, pairs| client.set_many(pairs))
+            .into_iter()
+            .collect()
+    }
+
+    // Runs the operation with the batch of every server which has one, on a
+    // thread per server. Returns the results by server, those without batch
+    // getting the result of an empty batch.
+    fn fan_out<B, T, F>(&mut self, batches: Vec<Vec<B>>, op: F) -> Vec<Result<T>>
+    where
+        B: Send,
+        T: Default + Send,
+        F: Fn(&mut KvsClient, Vec<B>) -> Result<T> + Sync,
+    {
+        let op = &op;
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .clients
+                .iter_mut()
+                .zip(batches)
+                .map(|(client, batch)| {
+                    if batch.is_empty() {
+                        None
+                    } else {
+                        Some(scope.spawn(move || op(client, batch)))
+                    }
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| match handle {
+                    Some(handle) => handle.join().expect("request thread panicked"),
+                    None => Ok(T::default()),
+                })
+                .collect()
+        })
+    }
+
+    // Index of the server owning the first point following the hash of the
+    // key, wrapping around the ring.
+    fn shard(&self, key: &str) -> usize {
+        let hash = hash(key.as_bytes());
+        let mut points = self.ring.range(hash..).chain(self.ring.iter());
+        *points.next().expect("servers on the ring").1
+    }
+}
+
+// 64-bit FNV-1a, whose bits are then mixed as in splitmix64 since similar
+// inputs such as the labels of the points of a server hash close together.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
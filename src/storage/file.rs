@@ -0,0 +1,87 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use super::{Storage, StorageFile};
+
+/// Storage backed by a directory on the local filesystem.
+///
+/// Every file of the storage is a regular file directly inside the directory.
+#[derive(Clone, Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// Create a storage rooted at the given directory.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStorage { dir })
+    }
+}
+
+impl Storage for FileStorage {
+    type File = LocalFile;
+
+    fn open(&self, name: &str) -> io::Result<LocalFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(self.dir.join(name))?;
+        let len = file.metadata()?.len();
+        Ok(LocalFile { file, len })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.dir.join(from), self.dir.join(to))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(name))
+    }
+}
+
+/// A file of a `FileStorage`.
+pub struct LocalFile {
+    file: File,
+    len: u64,
+}
+
+impl StorageFile for LocalFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let pos = self.len;
+        self.file.write_all(buf)?;
+        self.len += buf.len() as u64;
+        Ok(pos)
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.read(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+}
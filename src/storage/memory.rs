@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::{Storage, StorageFile};
+
+type FileData = Arc<RwLock<Vec<u8>>>;
+
+/// Storage that keeps all files in memory.
+///
+/// Clones share the same files, so a `KvStore` can be "reopened" by passing
+/// a clone of the storage it was created with. Nothing survives the process.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<String, FileData>>>,
+}
+
+impl MemoryStorage {
+    /// Create an empty in-memory storage.
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    type File = MemoryFile;
+
+    fn open(&self, name: &str) -> io::Result<MemoryFile> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.entry(name.to_owned()).or_default().clone();
+        Ok(MemoryFile { data })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| not_found(name))
+    }
+}
+
+/// A file of a `MemoryStorage`.
+pub struct MemoryFile {
+    data: FileData,
+}
+
+impl StorageFile for MemoryFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+        let mut data = self.data.write().unwrap();
+        let pos = data.len() as u64;
+        data.extend_from_slice(buf);
+        Ok(pos)
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        if pos >= data.len() as u64 {
+            return Ok(0);
+        }
+        let available = &data[pos as usize..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        Ok(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.data.read().unwrap().len() as u64
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name))
+}
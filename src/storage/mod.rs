@@ -0,0 +1,101 @@
+//! This module provides the `Storage` trait used by `KvStore` to persist its
+//! log files, along with a local filesystem and an in-memory implementation.
+use std::io;
+
+pub use self::file::{FileStorage, LocalFile};
+pub use self::memory::{MemoryFile, MemoryStorage};
+
+mod file;
+mod memory;
+
+/// A flat namespace of append-only files.
+///
+/// Operations return `std::io::Result` so that implementations can be used
+/// wherever the standard `Read` / `Write` traits are expected.
+pub trait Storage: Send + 'static {
+    /// Handle to a single file of the storage.
+    type File: StorageFile;
+
+    /// Open the file with the given name, creating an empty one if it does
+    /// not exist.
+    fn open(&self, name: &str) -> io::Result<Self::File>;
+
+    /// Rename a file, replacing the destination if it already exists.
+    ///
+    /// Handles opened before the rename should not be used afterwards.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// List the names of all files in the storage.
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// Delete the file with the given name.
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+/// An append-only file that supports random reads.
+pub trait StorageFile: Send + 'static {
+    /// Append `buf` to the end of the file.
+    ///
+    /// Returns the offset at which `buf` was written.
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64>;
+
+    /// Read bytes starting at `pos` into `buf`.
+    ///
+    /// Returns the number of bytes read, which is 0 at the end of the file.
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Make sure all appended data reaches durable storage.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Return the current size of the file in bytes.
+    fn size(&self) -> u64;
+
+    /// Read exactly `buf.len()` bytes starting at `pos`.
+    ///
+    /// # Error
+    ///
+    /// Return an `UnexpectedEof` error if the file ends before `buf` is filled.
+    fn read_exact_at(&mut self, mut pos: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(pos, buf)? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                n => {
+                    pos += n as u64;
+                    buf = &mut buf[n..];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sequential `Read` adapter over a `StorageFile`, starting at offset 0
+/// unless created with `at`.
+pub(crate) struct StorageReader<'a, F: StorageFile> {
+    file: &'a mut F,
+    pos: u64,
+}
+
+impl<'a, F: StorageFile> StorageReader<'a, F> {
+    pub(crate) fn new(file: &'a mut F) -> Self {
+        StorageReader::at(file, 0)
+    }
+
+    /// Read the file from the given offset on.
+    pub(crate) fn at(file: &'a mut F, pos: u64) -> Self {
+        StorageReader { file, pos }
+    }
+}
+
+impl<'a, F: StorageFile> io::Read for StorageReader<'a, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.file.read_at(self.pos, buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
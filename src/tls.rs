@@ -0,0 +1,401 @@
+//! TLS settings of `KvsServer` and `KvsClient`, built on rustls.
+//!
+//! A server with TLS settings only accepts TLS connections. The handshake of
+//! the kvs protocol and every frame travel inside the TLS session.
+//!
+//! ```rust,no_run
+//! # use kvs::tls::{ClientTlsConfig, ServerTlsConfig};
+//! # use std::path::Path;
+//! # fn try_main() -> kvs::Result<()> {
+//! let server = ServerTlsConfig::from_pem_files(
+//!     Path::new("server.crt"),
+//!     Path::new("server.key"),
+//!     None,
+//! )?;
+//! let client = ClientTlsConfig::from_pem_files(Path::new("ca.crt"), None)?
+//!     .with_server_name("kvs.example.com");
+//! # Ok(())
+//! # }
+//! ```
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConnection, RootCertStore, ServerConnection, StreamOwned};
+
+use crate::{KvsError, Result};
+
+/// TLS settings of a `KvsServer`.
+#[derive(Clone)]
+pub struct ServerTlsConfig {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl ServerTlsConfig {
+    /// Load the certificate chain and private key of the server from PEM
+    /// files.
+    ///
+    /// With `client_ca`, clients must authenticate with a certificate signed
+    /// by one of the certificates of that PEM file (mutual TLS).
+    ///
+    /// # Error
+    ///
+    /// Return an error if a file cannot be read or holds invalid
+    /// certificates or keys.
+    pub fn from_pem_files(
+        cert: &Path,
+        key: &Path,
+        client_ca: Option<&Path>,
+    ) -> Result<ServerTlsConfig> {
+        let provider = provider();
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(ca) => {
+                let roots = Arc::new(load_roots(ca)?);
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider)
+                    .build()
+                    .map_err(|e| KvsError::Tls(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        // without session tickets nothing is sent before the first response,
+        // which lets clients check the health of idle connections
+        config.send_tls13_tickets = 0;
+        Ok(ServerTlsConfig::from_rustls(Arc::new(config)))
+    }
+
+    /// Use a rustls configuration built by the caller.
+    pub fn from_rustls(config: Arc<rustls::ServerConfig>) -> ServerTlsConfig {
+        ServerTlsConfig { config }
+    }
+
+    pub(crate) fn accept(&self) -> Result<ServerConnection> {
+        Ok(ServerConnection::new(self.config.clone())?)
+    }
+}
+
+impl fmt::Debug for ServerTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerTlsConfig").finish()
+    }
+}
+
+/// TLS settings of a `KvsClient`.
+#[derive(Clone)]
+pub struct ClientTlsConfig {
+    config: Arc<rustls::ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl ClientTlsConfig {
+    /// Trust the certificates of the given PEM file to sign the certificate
+    /// of the server.
+    ///
+    /// With `identity`, a certificate chain and private key PEM files, the
+    /// client authenticates itself to servers asking for it.
+    ///
+    /// # Error
+    ///
+    /// Return an error if a file cannot be read or holds invalid
+    /// certificates or keys.
+    pub fn from_pem_files(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<ClientTlsConfig> {
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(ClientTlsConfig::from_rustls(Arc::new(config)))
+    }
+
+    /// Use a rustls configuration built by the caller.
+    pub fn from_rustls(config: Arc<rustls::ClientConfig>) -> ClientTlsConfig {
+        ClientTlsConfig {
+            config,
+            server_name: None,
+        }
+    }
+
+    /// Expect the certificate of the server to be issued for the given DNS
+    /// name. By default it must be issued for the IP address connected to.
+    pub fn with_server_name(mut self, name: impl Into<String>) -> ClientTlsConfig {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    pub(crate) fn connect(&self, addr: &SocketAddr) -> Result<ClientConnection> {
+        let name = match &self.server_name {
+            Some(name) => ServerName::try_from(name.clone())
+                .map_err(|_| KvsError::Tls(format!("invalid server name {}", name)))?,
+            None => ServerName::IpAddress(addr.ip().into()),
+        };
+        Ok(ClientConnection::new(self.config.clone(), name)?)
+    }
+}
+
+impl fmt::Debug for ClientTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientTlsConfig")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| KvsError::Tls(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "{}: no certificate found",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| KvsError::Tls(format!("{}: {}", path.display(), e)))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// A connection, encrypted or not.
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// The underlying TCP connection.
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Client(stream) => stream.get_ref(),
+            Stream::Server(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Client(stream) => stream.read(buf),
+            Stream::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Client(stream) => stream.write(buf),
+            Stream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Client(stream) => stream.flush(),
+            Stream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A `Stream` shared by the reading and the writing half of a connection,
+/// since a TLS session cannot be split.
+#[derive(Clone)]
+pub(crate) struct SharedStream(Arc<Mutex<Stream>>);
+
+impl SharedStream {
+    pub(crate) fn new(stream: Stream) -> SharedStream {
+        SharedStream(Arc::new(Mutex::new(stream)))
+    }
+
+    /// Tell the peer that nothing more will be sent, so that it can tell the
+    /// end of the session from a truncation.
+    pub(crate) fn close_notify(&self) -> io::Result<()> {
+        let mut stream = self.0.lock().unwrap();
+        match &mut *stream {
+            Stream::Plain(_) => return Ok(()),
+            Stream::Client(stream) => stream.conn.send_close_notify(),
+            Stream::Server(stream) => stream.conn.send_close_notify(),
+        }
+        stream.flush()
+    }
+
+    /// Run a function on the underlying TCP connection.
+    pub(crate) fn with_tcp<T>(&self, f: impl FnOnce(&TcpStream) -> T) -> T {
+        f(self.0.lock().unwrap().tcp())
+    }
+}
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Split a client connection, encrypted with `tls` or not, into halves used
+/// by different threads. Unlike with a `SharedStream`, a read blocked on the
+/// socket does not hold the TLS session, so writes go on meanwhile. The TLS
+/// handshake is completed before splitting.
+pub(crate) fn split_client(
+    mut tcp: TcpStream,
+    tls: Option<ClientConnection>,
+) -> io::Result<(ReadHalf, WriteHalf)> {
+    let conn = match tls {
+        Some(mut conn) => {
+            while conn.is_handshaking() {
+                conn.complete_io(&mut tcp)?;
+            }
+            Some(Arc::new(Mutex::new(conn)))
+        }
+        None => None,
+    };
+    let read = ReadHalf {
+        tcp: tcp.try_clone()?,
+        conn: conn.clone(),
+        received: Vec::new(),
+        eof: false,
+    };
+    Ok((read, WriteHalf { tcp, conn }))
+}
+
+/// The reading half of a split client connection.
+pub(crate) struct ReadHalf {
+    tcp: TcpStream,
+    conn: Option<Arc<Mutex<ClientConnection>>>,
+    // records read from the socket which the session did not take yet
+    received: Vec<u8>,
+    eof: bool,
+}
+
+impl ReadHalf {
+    /// The underlying TCP connection.
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let conn = match &self.conn {
+            Some(conn) => conn,
+            None => return self.tcp.read(buf),
+        };
+        loop {
+            let mut conn = conn.lock().unwrap();
+            match conn.reader().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            if self.received.is_empty() && !self.eof {
+                // wait for more records without holding the session
+                drop(conn);
+                let mut records = [0; 16 * 1024];
+                let n = self.tcp.read(&mut records)?;
+                self.received.extend_from_slice(&records[..n]);
+                self.eof = n == 0;
+                continue;
+            }
+            // taking no records tells the session that the socket is closed
+            let taken = conn.read_tls(&mut &self.received[..])?;
+            self.received.drain(..taken);
+            conn.process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+}
+
+/// The writing half of a split client connection. Only this half sends
+/// records on the socket.
+pub(crate) struct WriteHalf {
+    tcp: TcpStream,
+    conn: Option<Arc<Mutex<ClientConnection>>>,
+}
+
+impl WriteHalf {
+    /// Tell the server that nothing more will be sent.
+    pub(crate) fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(conn) = &self.conn {
+            conn.lock().unwrap().send_close_notify();
+            self.send_records()?;
+        }
+        self.tcp.shutdown(Shutdown::Write)
+    }
+
+    fn send_records(&mut self) -> io::Result<()> {
+        let mut records = Vec::new();
+        if let Some(conn) = &self.conn {
+            let mut conn = conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+        }
+        // write without holding the session, which the reader needs
+        self.tcp.write_all(&records)
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let conn = match &self.conn {
+            Some(conn) => conn.clone(),
+            None => return self.tcp.write(buf),
+        };
+        let written = conn.lock().unwrap().writer().write(buf)?;
+        if written > 0 || buf.is_empty() {
+            return Ok(written);
+        }
+        // the session buffers no more plaintext until its records are sent
+        self.send_records()?;
+        let written = conn.lock().unwrap().writer().write(buf);
+        written
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.conn.is_some() {
+            self.send_records()?;
+        }
+        self.tcp.flush()
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use futures::executor::block_on;
+use futures::future::join_all;
+use kvs::auth::AuthConfig;
+use kvs::protocol::Codec;
+use kvs::{AsyncKvsClient, ClientConfig, Credentials, KvsError, Result};
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+#[test]
+fn async_set_get_remove() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4201").0;
+    block_on(async {
+        let client = AsyncKvsClient::connect(addr).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        client.remove("key1".to_owned()).await?;
+        assert_eq!(client.get("key1".to_owned()).await?, None);
+        match client.remove("key1".to_owned()).await {
+            Err(KvsError::KeyNotFound) => {}
+            res => panic!("expected KeyNotFound, got {:?}", res),
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn async_many_in_flight() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4202").0;
+    block_on(async {
+        let client = AsyncKvsClient::connect_with_codec(addr, Codec::Json).await?;
+
+        let sets = (0..1000).map(|i| client.set(format!("key{}", i), format!("value{}", i)));
+        for res in join_all(sets).await {
+            res?;
+        }
+
+        // clones share the connection
+        let gets = (0..1000).map(|i| client.clone().get(format!("key{}", i)));
+        for (i, res) in join_all(gets).await.into_iter().enumerate() {
+            assert_eq!(res?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn async_connection_closed() -> Result<()> {
+    // a server which accepts the handshake and then hangs up
+    let listener = TcpListener::bind("127.0.0.1:4203")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        use kvs::protocol::Hello;
+        let (mut stream, _) = listener.accept().unwrap();
+        let hello = Hello::read_from(&mut stream).unwrap().unwrap();
+        hello.negotiate().unwrap().write_to(&mut stream).unwrap();
+    });
+
+    block_on(async {
+        let client = AsyncKvsClient::connect(addr).await?;
+        assert!(client.get("key1".to_owned()).await.is_err());
+        assert!(client.get("key1".to_owned()).await.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn async_connect_refused() {
+    // nothing listens on this port
+    let addr = "127.0.0.1:4204".parse().unwrap();
+    assert!(block_on(AsyncKvsClient::connect(addr)).is_err());
+}
+
+// A server which accepts the handshake and then never answers.
+fn silent_server(addr: &str) -> Result<std::net::SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        use kvs::protocol::Hello;
+        let (mut stream, _) = listener.accept().unwrap();
+        let hello = Hello::read_from(&mut stream).unwrap().unwrap();
+        hello.negotiate().unwrap().write_to(&mut stream).unwrap();
+        thread::sleep(Duration::from_secs(5));
+    });
+    Ok(addr)
+}
+
+// The read timeout fails requests waiting too long for their response, but
+// not idle connections.
+#[test]
+fn async_read_timeout() -> Result<()> {
+    let config = ClientConfig {
+        read_timeout: Some(Duration::from_millis(100)),
+        ..ClientConfig::default()
+    };
+
+    let addr = TestServer::new().start("127.0.0.1:4205").0;
+    block_on(async {
+        let client = AsyncKvsClient::connect_with_config(addr, &config).await?;
+        thread::sleep(Duration::from_millis(300));
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        Ok::<_, KvsError>(())
+    })?;
+
+    let addr = silent_server("127.0.0.1:4206")?;
+    block_on(async {
+        let client = AsyncKvsClient::connect_with_config(addr, &config).await?;
+        match client.get("key1".to_owned()).await {
+            Err(KvsError::Io(e)) => assert!(
+                e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+            ),
+            res => panic!("expected a timeout, got {:?}", res),
+        }
+        Ok(())
+    })
+}
+
+const AUTH_CONFIG: &str = r#"{
+    "users": [
+        {
+            "name": "alice",
+            "password": "secret",
+            "grants": [{ "prefix": "alice/", "access": "read_write" }]
+        }
+    ]
+}"#;
+
+fn credentials(password: &str) -> ClientConfig {
+    ClientConfig {
+        credentials: Some(Credentials::Password {
+            username: "alice".to_owned(),
+            password: password.to_owned(),
+        }),
+        ..ClientConfig::default()
+    }
+}
+
+#[test]
+fn async_authenticate() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("auth.json");
+    fs::write(&path, AUTH_CONFIG)?;
+    let addr = TestServer::new()
+        .auth(Some(AuthConfig::from_file(&path)?))
+        .start("127.0.0.1:4207")
+        .0;
+
+    block_on(async {
+        let client = AsyncKvsClient::connect_with_config(addr, &credentials("secret")).await?;
+        client
+            .set("alice/1".to_owned(), "value1".to_owned())
+            .await?;
+        match client.set("bob/1".to_owned(), "value1".to_owned()).await {
+            Err(KvsError::PermissionDenied(_)) => {}
+            res => panic!("expected PermissionDenied, got {:?}", res),
+        }
+
+        match AsyncKvsClient::connect_with_config(addr, &credentials("wrong")).await {
+            Err(KvsError::AuthenticationFailed) => {}
+            res => panic!("expected AuthenticationFailed, got {:?}", res.map(|_| ())),
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,254 @@
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+
+use kvs::auth::AuthConfig;
+use kvs::protocol::{decode_response, encode_request, read_frame, write_frame, Codec, Hello};
+use kvs::{
+    ClientConfig, Credentials, KvsClient, KvsError, Request, Response, Result, TaggedRequest,
+};
+use serde_json::Deserializer;
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+const CONFIG: &str = r#"{
+    "users": [
+        {
+            "name": "team-a",
+            "token": "token-a",
+            "grants": [
+                { "prefix": "a/", "access": "read_write" },
+                { "prefix": "", "access": "read" }
+            ]
+        },
+        {
+            "name": "alice",
+            "password": "secret",
+            "grants": [{ "prefix": "alice/", "access": "read_write" }]
+        }
+    ],
+    "anonymous": [{ "prefix": "public/", "access": "read" }]
+}"#;
+
+fn auth_config(json: &str) -> Result<AuthConfig> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("auth.json");
+    fs::write(&path, json)?;
+    AuthConfig::from_file(&path)
+}
+
+fn assert_denied<T: std::fmt::Debug>(res: Result<T>) {
+    match res {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("expected a permission error, got {:?}", res),
+    }
+}
+
+fn token(token: &str) -> Credentials {
+    Credentials::Token(token.to_owned())
+}
+
+fn password(username: &str, password: &str) -> Credentials {
+    Credentials::Password {
+        username: username.to_owned(),
+        password: password.to_owned(),
+    }
+}
+
+fn grants_enforced(addr: &str, evented: bool) -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new()
+        .evented(evented)
+        .auth(Some(auth_config(CONFIG)?))
+        .start(addr);
+
+    let mut team_a = KvsClient::connect(&addr)?;
+    team_a.authenticate(token("token-a"))?;
+    team_a.set("a/1".to_owned(), "value1".to_owned())?;
+    team_a.remove("a/1".to_owned())?;
+    team_a.set("a/2".to_owned(), "value2".to_owned())?;
+    assert_denied(team_a.set("alice/1".to_owned(), "value1".to_owned()));
+    assert_denied(team_a.remove("public/1".to_owned()));
+
+    let config = ClientConfig {
+        credentials: Some(password("alice", "secret")),
+        ..ClientConfig::default()
+    };
+    let mut alice = KvsClient::connect_with_config(&addr, &config)?;
+    alice.set("alice/1".to_owned(), "value1".to_owned())?;
+    assert_eq!(alice.get("alice/1".to_owned())?, Some("value1".to_owned()));
+    assert_denied(alice.get("a/2".to_owned()));
+
+    // read grants cover the keys of other teams
+    assert_eq!(team_a.get("alice/1".to_owned())?, Some("value1".to_owned()));
+
+    // anonymous connections only get what is granted to everyone
+    let mut anonymous = KvsClient::connect(&addr)?;
+    assert_eq!(anonymous.get("public/1".to_owned())?, None);
+    assert_denied(anonymous.set("public/1".to_owned(), "value1".to_owned()));
+    assert_denied(anonymous.get("a/2".to_owned()));
+
+    // failed authentications leave the connection anonymous
+    match alice.authenticate(password("alice", "wrong")) {
+        Err(KvsError::AuthenticationFailed) => {}
+        res => panic!("expected an authentication failure, got {:?}", res),
+    }
+    assert_denied(alice.get("alice/1".to_owned()));
+    let config = ClientConfig {
+        credentials: Some(token("token-b")),
+        ..ClientConfig::default()
+    };
+    assert!(KvsClient::connect_with_config(&addr, &config).is_err());
+
+    // requests pipelined after an authentication are authorized for its user
+    let mut client = KvsClient::connect(&addr)?;
+    let results = client.pipeline(vec![
+        Request::Get {
+            key: "a/2".to_owned(),
+        },
+        Request::Auth {
+            credentials: token("token-a"),
+        },
+        Request::Get {
+            key: "a/2".to_owned(),
+        },
+        Request::Get {
+            key: "alice/1".to_owned(),
+        },
+        Request::Set {
+            key: "a/3".to_owned(),
+            value: "value3".to_owned(),
+        },
+    ])?;
+    let mut results = results.into_iter();
+    assert_denied(results.next().unwrap());
+    assert_eq!(results.next().unwrap()?, None);
+    assert_eq!(results.next().unwrap()?, Some("value2".to_owned()));
+    assert_eq!(results.next().unwrap()?, Some("value1".to_owned()));
+    assert_eq!(results.next().unwrap()?, None);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn threaded_grants_enforced() -> Result<()> {
+    grants_enforced("127.0.0.1:4801", false)
+}
+
+#[test]
+fn evented_grants_enforced() -> Result<()> {
+    grants_enforced("127.0.0.1:4802", true)
+}
+
+// Clients without the refusal responses get them as errors.
+fn old_clients_denied(addr: &str, evented: bool) -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new()
+        .evented(evented)
+        .auth(Some(auth_config(CONFIG)?))
+        .start(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Hello {
+        version: 3,
+        codec: Codec::Json,
+    };
+    hello.write_to(&mut stream)?;
+    let hello = Hello::read_from(&mut stream)?.unwrap();
+    assert_eq!(hello.version, 3);
+    let req = TaggedRequest::new(
+        1,
+        Request::Remove {
+            key: "a/1".to_owned(),
+        },
+    );
+    write_frame(&mut stream, &encode_request(&hello, &req)?)?;
+    let payload = read_frame(&mut stream)?.unwrap();
+    match decode_response(&hello, &payload)?.response {
+        Response::Err(e) => assert!(e.starts_with("Permission denied")),
+        resp => panic!("expected an error, got {:?}", resp),
+    }
+
+    // version 4 has its own refusal
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Hello {
+        version: 4,
+        codec: Codec::Bincode,
+    };
+    hello.write_to(&mut stream)?;
+    let hello = Hello::read_from(&mut stream)?.unwrap();
+    write_frame(&mut stream, &encode_request(&hello, &req)?)?;
+    let payload = read_frame(&mut stream)?.unwrap();
+    match decode_response(&hello, &payload)?.response {
+        Response::PermissionDenied(e) => assert_eq!(e, "anonymous may not write key a/1"),
+        resp => panic!("expected a refusal, got {:?}", resp),
+    }
+
+    // legacy clients are anonymous
+    let mut stream = TcpStream::connect(addr)?;
+    let req = Request::Set {
+        key: "public/1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    serde_json::to_writer(&mut stream, &req)?;
+    stream.flush()?;
+    let mut responses = Deserializer::from_reader(&mut stream).into_iter::<Response>();
+    match responses.next().unwrap()? {
+        Response::Err(e) => assert!(e.starts_with("Permission denied")),
+        resp => panic!("expected an error, got {:?}", resp),
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn threaded_old_clients_denied() -> Result<()> {
+    old_clients_denied("127.0.0.1:4803", false)
+}
+
+#[test]
+fn evented_old_clients_denied() -> Result<()> {
+    old_clients_denied("127.0.0.1:4804", true)
+}
+
+#[test]
+fn open_server_accepts_any_credentials() -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new().start("127.0.0.1:4805");
+    let config = ClientConfig {
+        credentials: Some(token("anything")),
+        ..ClientConfig::default()
+    };
+    let mut client = KvsClient::connect_with_config(&addr, &config)?;
+    client.set("a/1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("a/1".to_owned())?, Some("value1".to_owned()));
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn invalid_configs_rejected() {
+    assert!(auth_config("{ \"users\": [").is_err());
+
+    let duplicate_name = r#"{ "users": [{ "name": "a" }, { "name": "a" }] }"#;
+    match auth_config(duplicate_name) {
+        Err(KvsError::AuthConfig(_)) => {}
+        res => panic!("expected an invalid config, got {:?}", res.is_ok()),
+    }
+
+    let duplicate_token = r#"{
+        "users": [{ "name": "a", "token": "t" }, { "name": "b", "token": "t" }]
+    }"#;
+    match auth_config(duplicate_token) {
+        Err(KvsError::AuthConfig(_)) => {}
+        res => panic!("expected an invalid config, got {:?}", res.is_ok()),
+    }
+
+    let unknown_access = r#"{
+        "users": [{ "name": "a", "grants": [{ "prefix": "", "access": "all" }] }]
+    }"#;
+    assert!(auth_config(unknown_access).is_err());
+}
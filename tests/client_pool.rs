@@ -0,0 +1,120 @@
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use kvs::{KvsClientPool, KvsEngine, MemoryKvsEngine, PoolConfig, Result, ShutdownHandle};
+
+mod common;
+
+use common::TestServer;
+
+// Run a server on the given engine until it is stopped.
+fn start_server(
+    addr: SocketAddr,
+    engine: MemoryKvsEngine,
+) -> (SocketAddr, ShutdownHandle, JoinHandle<Result<()>>) {
+    TestServer::with_engine(engine).start(&addr.to_string())
+}
+
+fn stop_server(
+    (_, shutdown, handle): (SocketAddr, ShutdownHandle, JoinHandle<Result<()>>),
+) -> Result<()> {
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn shared_between_threads() -> Result<()> {
+    let addr = "127.0.0.1:4501".parse().unwrap();
+    let _server = start_server(addr, MemoryKvsEngine::new());
+    let config = PoolConfig {
+        max_size: 2,
+        ..PoolConfig::default()
+    };
+    let pool = KvsClientPool::new(addr, config);
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for j in 0..20 {
+                    let key = format!("key{}-{}", i, j);
+                    pool.set(key.clone(), format!("value{}", j))?;
+                    assert_eq!(pool.get(key.clone())?, Some(format!("value{}", j)));
+                    pool.remove(key.clone())?;
+                    assert_eq!(pool.get(key)?, None);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // connections are reused, never more than the maximum
+    assert!(pool.idle_connections() >= 1);
+    assert!(pool.idle_connections() <= 2);
+
+    // errors of the server are reported as they are
+    assert!(pool.remove("missing".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn reconnect_after_server_restart() -> Result<()> {
+    let addr = "127.0.0.1:4502".parse().unwrap();
+    let engine = MemoryKvsEngine::new();
+    let server = start_server(addr, engine.clone());
+    let pool = KvsClientPool::new(addr, PoolConfig::default());
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.idle_connections(), 1);
+
+    stop_server(server)?;
+    let server = start_server(addr, engine);
+
+    // the broken connection is replaced for writes as well as reads
+    pool.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(pool.idle_connections(), 1);
+    stop_server(server)
+}
+
+#[test]
+fn get_waits_for_server_with_backoff() -> Result<()> {
+    let addr = "127.0.0.1:4503".parse().unwrap();
+    let engine = MemoryKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let config = PoolConfig {
+        max_retries: 10,
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(100),
+        ..PoolConfig::default()
+    };
+    let pool = KvsClientPool::new(addr, config);
+
+    let starter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        start_server(addr, engine)
+    });
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    stop_server(starter.join().unwrap())
+}
+
+#[test]
+fn gives_up_after_retries() {
+    let addr = "127.0.0.1:4504".parse().unwrap();
+    let config = PoolConfig {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(40),
+        ..PoolConfig::default()
+    };
+    let pool = KvsClientPool::new(addr, config);
+
+    let start = Instant::now();
+    assert!(pool.get("key1".to_owned()).is_err());
+    // backoffs of 20, 40 and 40 milliseconds
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(pool.idle_connections(), 0);
+}
@@ -0,0 +1,127 @@
+// The server fixture of the integration tests, shared by the suites through
+// `mod common;`. No suite uses all of it.
+#![allow(dead_code)]
+
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use kvs::auth::AuthConfig;
+use kvs::tls::ServerTlsConfig;
+use kvs::{
+    KvsEngine, KvsServer, MemoryKvsEngine, Result, SharedQueueThreadPool, ShutdownHandle,
+    ThreadPool,
+};
+use slog::{o, Discard, Logger};
+
+// A server to run in the background: threaded, on an in-memory engine,
+// without TLS or authentication unless told otherwise.
+pub struct TestServer<E> {
+    engine: E,
+    threads: u32,
+    evented: bool,
+    tls: Option<ServerTlsConfig>,
+    auth: Option<AuthConfig>,
+    http_addr: Option<SocketAddr>,
+    idle_timeout: Option<Duration>,
+    shutdown_timeout: Option<Duration>,
+}
+
+impl TestServer<MemoryKvsEngine> {
+    pub fn new() -> Self {
+        TestServer::with_engine(MemoryKvsEngine::new())
+    }
+}
+
+impl<E: KvsEngine> TestServer<E> {
+    pub fn with_engine(engine: E) -> Self {
+        TestServer {
+            engine,
+            threads: 4,
+            evented: false,
+            tls: None,
+            auth: None,
+            http_addr: None,
+            idle_timeout: None,
+            shutdown_timeout: None,
+        }
+    }
+
+    // The threads of the pool, which the threaded mode takes one of per
+    // connection.
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn evented(mut self, evented: bool) -> Self {
+        self.evented = evented;
+        self
+    }
+
+    pub fn tls(mut self, tls: ServerTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn auth(mut self, auth: Option<AuthConfig>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn http_addr(mut self, http_addr: &str) -> Self {
+        self.http_addr = Some(http_addr.parse().unwrap());
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    // Run the server on the given address until it is shut down through the
+    // returned handle.
+    pub fn start(self, addr: &str) -> (SocketAddr, ShutdownHandle, JoinHandle<Result<()>>) {
+        let addr: SocketAddr = addr.parse().unwrap();
+        let logger = Logger::root(Discard, o!());
+        let pool = SharedQueueThreadPool::new(self.threads).unwrap();
+        let mut server = KvsServer::new(self.engine, logger, pool);
+        server.set_tls(self.tls);
+        server.set_auth(self.auth);
+        server.set_http_addr(self.http_addr);
+        server.set_idle_timeout(self.idle_timeout);
+        if let Some(timeout) = self.shutdown_timeout {
+            server.set_shutdown_timeout(timeout);
+        }
+        let shutdown = server.shutdown_handle();
+        let evented = self.evented;
+        let handle = thread::spawn(move || {
+            if evented {
+                server.run_evented(&addr)
+            } else {
+                server.run(&addr)
+            }
+        });
+        wait_for(addr);
+        if let Some(http_addr) = self.http_addr {
+            wait_for(http_addr);
+        }
+        (addr, shutdown, handle)
+    }
+}
+
+// Wait until a server listens on the address.
+pub fn wait_for(addr: SocketAddr) {
+    for _ in 0..50 {
+        if TcpStream::connect(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server did not start on {}", addr);
+}
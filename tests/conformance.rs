@@ -0,0 +1,65 @@
+use std::path::Path;
+
+mod kvs_store {
+    use super::*;
+    use kvs::KvStore;
+
+    kvs::engine_conformance_tests!(persistent |path: &Path| KvStore::open(path),
+        scan, transactions, versions);
+}
+
+mod kvs_store_in_memory {
+    use super::*;
+    use kvs::{KvStore, MemoryStorage};
+
+    kvs::engine_conformance_tests!(
+        |_: &Path| KvStore::with_storage(MemoryStorage::new()),
+        scan,
+        transactions,
+        versions
+    );
+}
+
+mod sled {
+    use super::*;
+    use kvs::SledKvsEngine;
+
+    kvs::engine_conformance_tests!(persistent |path: &Path| SledKvsEngine::new(path),
+        scan, transactions, versions);
+}
+
+mod memory {
+    use super::*;
+    use kvs::MemoryKvsEngine;
+
+    kvs::engine_conformance_tests!(
+        |_: &Path| Ok(MemoryKvsEngine::new()),
+        scan,
+        transactions,
+        versions
+    );
+}
+
+mod boxed_kvs_store {
+    use super::*;
+    use kvs::EngineRegistry;
+
+    kvs::engine_conformance_tests!(persistent |path: &Path| EngineRegistry::default()
+        .open("kvs", path), scan, transactions, versions);
+}
+
+mod expiring {
+    use super::*;
+    use kvs::{ExpiringEngine, KvStore};
+
+    kvs::engine_conformance_tests!(persistent |path: &Path| KvStore::open(path)
+        .map(ExpiringEngine::new), scan, transactions, versions);
+}
+
+mod watched {
+    use super::*;
+    use kvs::{KvStore, WatchedEngine};
+
+    kvs::engine_conformance_tests!(persistent |path: &Path| KvStore::open(path)
+        .map(WatchedEngine::new), scan, transactions, versions);
+}
@@ -0,0 +1,158 @@
+use std::io::{BufReader, Read};
+use std::net::TcpStream;
+
+use futures::executor::block_on;
+use futures::future::join_all;
+use kvs::protocol::{Codec, Hello};
+use kvs::{AsyncKvsClient, KvsClient, Request, Response, Result};
+use serde::Deserialize;
+
+mod common;
+
+use common::TestServer;
+
+// Far more long-lived connections than pool threads are all served.
+#[test]
+fn connections_outnumber_threads() -> Result<()> {
+    let addr = TestServer::new()
+        .threads(2)
+        .evented(true)
+        .start("127.0.0.1:4301")
+        .0;
+
+    let mut clients = (0..64)
+        .map(|_| KvsClient::connect(&addr))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // every connection is still usable, and sees the writes of the others
+    for (i, client) in clients.iter_mut().enumerate() {
+        let key_id = (i + 1) % 64;
+        assert_eq!(
+            client.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = TestServer::new()
+        .threads(2)
+        .evented(true)
+        .start("127.0.0.1:4302")
+        .0;
+    let mut client = KvsClient::connect_with_codec(&addr, Codec::Json)?;
+
+    let sets = (0..1000)
+        .map(|i| Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .collect();
+    for res in client.pipeline(sets)? {
+        assert_eq!(res?, None);
+    }
+
+    let mut requests: Vec<_> = (0..1000)
+        .map(|i| Request::Get {
+            key: format!("key{}", i),
+        })
+        .collect();
+    requests.push(Request::Remove {
+        key: "missing".to_owned(),
+    });
+    let results = client.pipeline(requests)?;
+    for (i, res) in results.iter().take(1000).enumerate() {
+        assert_eq!(res.as_ref().unwrap(), &Some(format!("value{}", i)));
+    }
+    assert!(results[1000].is_err());
+    Ok(())
+}
+
+#[test]
+fn legacy_json_client() -> Result<()> {
+    let addr = TestServer::new()
+        .threads(2)
+        .evented(true)
+        .start("127.0.0.1:4303")
+        .0;
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+
+    // two requests in one write are both answered
+    let requests = [
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+    ];
+    serde_json::to_writer(&mut stream, &requests[0])?;
+    serde_json::to_writer(&mut stream, &requests[1])?;
+    match Response::deserialize(&mut reader)? {
+        Response::Ok(None) => {}
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    match Response::deserialize(&mut reader)? {
+        Response::Ok(Some(v)) => assert_eq!(v, "value1"),
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    Ok(())
+}
+
+#[test]
+fn unsupported_version_is_rejected() -> Result<()> {
+    let addr = TestServer::new()
+        .threads(2)
+        .evented(true)
+        .start("127.0.0.1:4304")
+        .0;
+    let mut stream = TcpStream::connect(addr)?;
+    Hello {
+        version: 0,
+        codec: Codec::Bincode,
+    }
+    .write_to(&mut stream)?;
+    assert_eq!(Hello::read_from(&mut stream)?, None);
+
+    // the server closes the connection after the rejection
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[test]
+fn async_clients() -> Result<()> {
+    let addr = TestServer::new()
+        .threads(2)
+        .evented(true)
+        .start("127.0.0.1:4305")
+        .0;
+    block_on(async {
+        let clients = join_all((0..16).map(|_| AsyncKvsClient::connect(addr)))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let sets = clients
+            .iter()
+            .enumerate()
+            .map(|(i, client)| client.set(format!("key{}", i), format!("value{}", i)));
+        for res in join_all(sets).await {
+            res?;
+        }
+        let gets = clients
+            .iter()
+            .enumerate()
+            .map(|(i, client)| client.get(format!("key{}", (i + 1) % 16)));
+        for (i, res) in join_all(gets).await.into_iter().enumerate() {
+            assert_eq!(res?, Some(format!("value{}", (i + 1) % 16)));
+        }
+        Ok(())
+    })
+}
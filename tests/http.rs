@@ -0,0 +1,332 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use kvs::auth::AuthConfig;
+use kvs::{KvsClient, KvsEngine, KvsError, Result};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+// A response, with the body parsed as JSON, `null` if empty.
+struct Response {
+    status_line: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Value,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn error_code(&self) -> &str {
+        self.body["error"]["code"].as_str().unwrap()
+    }
+}
+
+// A minimal HTTP/1.1 client, keeping its connection open.
+struct HttpClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl HttpClient {
+    fn connect(addr: &str) -> HttpClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        HttpClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn request(
+        &mut self,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> Response {
+        let mut req = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, target);
+        for (name, value) in headers {
+            req.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(body) = body {
+            req.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        req.push_str("\r\n");
+        req.push_str(body.unwrap_or(""));
+        self.writer.write_all(req.as_bytes()).unwrap();
+        self.read_response()
+    }
+
+    fn get(&mut self, target: &str) -> Response {
+        self.request("GET", target, &[], None)
+    }
+
+    fn put(&mut self, target: &str, value: &str) -> Response {
+        let body = json!({ "value": value }).to_string();
+        self.request("PUT", target, &[], Some(&body))
+    }
+
+    fn delete(&mut self, target: &str) -> Response {
+        self.request("DELETE", target, &[], None)
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "unterminated line {:?}", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    fn read_response(&mut self) -> Response {
+        let status_line = self.read_line();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_owned(), value.trim().to_owned()));
+        }
+        let mut resp = Response {
+            status_line,
+            status,
+            headers,
+            body: Value::Null,
+        };
+        let len: usize = resp.header("Content-Length").unwrap().parse().unwrap();
+        if len > 0 {
+            let mut body = vec![0; len];
+            self.reader.read_exact(&mut body).unwrap();
+            resp.body = serde_json::from_slice(&body).unwrap();
+        }
+        resp
+    }
+
+    // Whether the server closed the connection.
+    fn is_closed(&mut self) -> bool {
+        let mut buf = [0; 1];
+        matches!(self.reader.read(&mut buf), Ok(0))
+    }
+}
+
+fn round_trip(addr: &str, http_addr: &str, evented: bool) -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new()
+        .http_addr(http_addr)
+        .evented(evented)
+        .start(addr);
+    let mut client = HttpClient::connect(http_addr);
+
+    let resp = client.put("/keys/key1", "value1");
+    assert_eq!(resp.status, 204);
+    let resp = client.get("/keys/key1");
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Content-Type"), Some("application/json"));
+    assert_eq!(resp.body, json!({ "key": "key1", "value": "value1" }));
+
+    let resp = client.get("/keys/key2");
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.error_code(), "KeyNotFound");
+    assert_eq!(client.delete("/keys/key1").status, 204);
+    assert_eq!(client.delete("/keys/key1").status, 404);
+
+    // keys are percent-decoded and may hold slashes
+    assert_eq!(client.put("/keys/a/b%20c%2Fd", "value").status, 204);
+    let mut kvs_client = KvsClient::connect(&addr)?;
+    assert_eq!(
+        kvs_client.get("a/b c/d".to_owned())?,
+        Some("value".to_owned())
+    );
+    kvs_client.set("café".to_owned(), "crème".to_owned())?;
+    let resp = client.get("/keys/caf%C3%A9");
+    assert_eq!(resp.body, json!({ "key": "café", "value": "crème" }));
+    assert_eq!(client.get("/keys/%ZZ").status, 400);
+
+    // scans page through every key
+    for i in 0..5 {
+        client.put(&format!("/keys/key{}", i), "value");
+    }
+    let mut keys = Vec::new();
+    let mut target = "/keys?limit=2".to_owned();
+    loop {
+        let resp = client.get(&target);
+        assert_eq!(resp.status, 200);
+        keys.extend(resp.body["keys"].as_array().unwrap().iter().cloned());
+        match resp.body["next"].as_str() {
+            Some(next) => target = format!("/keys?limit=2&after={}", next),
+            None => break,
+        }
+    }
+    assert_eq!(
+        Value::Array(keys),
+        json!(["a/b c/d", "café", "key0", "key1", "key2", "key3", "key4"])
+    );
+    let resp = client.get("/keys?after=key2");
+    assert_eq!(resp.body, json!({ "keys": ["key3", "key4"], "next": null }));
+    assert_eq!(client.get("/keys?limit=0").status, 400);
+
+    // refused requests keep the connection usable
+    let resp = client.request("PUT", "/keys/key1", &[], Some("value1"));
+    assert_eq!(resp.status, 400);
+    assert_eq!(resp.error_code(), "MalformedRequest");
+    let resp = client.request("POST", "/keys/key1", &[], None);
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("Allow"), Some("GET, PUT, DELETE"));
+    assert_eq!(client.get("/other").status, 404);
+
+    // bodies may wait for the server to accept them
+    let body = json!({ "value": "value5" }).to_string();
+    let req = format!(
+        "PUT /keys/key5 HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    );
+    client.writer.write_all(req.as_bytes()).unwrap();
+    assert_eq!(client.read_line(), "HTTP/1.1 100 Continue");
+    assert_eq!(client.read_line(), "");
+    client.writer.write_all(body.as_bytes()).unwrap();
+    assert_eq!(client.read_response().status, 204);
+
+    let resp = client.request("GET", "/keys/key5", &[("Connection", "close")], None);
+    assert_eq!(resp.body["value"], "value5");
+    assert!(client.is_closed());
+
+    let mut client = HttpClient::connect(http_addr);
+    client
+        .writer
+        .write_all(b"GET /keys/key5 HTTP/1.0\r\n\r\n")?;
+    assert_eq!(client.read_response().status, 200);
+    assert!(client.is_closed());
+
+    let mut client = HttpClient::connect(http_addr);
+    client.writer.write_all(b"GET /keys/key5\r\n\r\n")?;
+    assert_eq!(client.read_response().status, 400);
+    assert!(client.is_closed());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn threaded_round_trip() -> Result<()> {
+    round_trip("127.0.0.1:5001", "127.0.0.1:5002", false)
+}
+
+#[test]
+fn evented_round_trip() -> Result<()> {
+    round_trip("127.0.0.1:5003", "127.0.0.1:5004", true)
+}
+
+const CONFIG: &str = r#"{
+    "users": [
+        {
+            "name": "team-a",
+            "token": "token-a",
+            "grants": [{ "prefix": "a/", "access": "read_write" }]
+        },
+        {
+            "name": "alice",
+            "password": "secret",
+            "grants": [{ "prefix": "alice/", "access": "read_write" }]
+        }
+    ],
+    "anonymous": [{ "prefix": "public/", "access": "read" }]
+}"#;
+
+#[test]
+fn grants_enforced() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("auth.json");
+    fs::write(&path, CONFIG)?;
+    let auth = AuthConfig::from_file(&path)?;
+    let (_, shutdown, handle) = TestServer::new()
+        .http_addr("127.0.0.1:5006")
+        .auth(Some(auth))
+        .start("127.0.0.1:5005");
+    let mut client = HttpClient::connect("127.0.0.1:5006");
+
+    let team_a = [("Authorization", "Bearer token-a")];
+    // "alice:secret"
+    let alice = [("Authorization", "Basic YWxpY2U6c2VjcmV0")];
+    let body = json!({ "value": "value1" }).to_string();
+
+    let resp = client.request("PUT", "/keys/a/1", &team_a, Some(&body));
+    assert_eq!(resp.status, 204);
+    let resp = client.request("PUT", "/keys/alice/1", &alice, Some(&body));
+    assert_eq!(resp.status, 204);
+    let resp = client.request("GET", "/keys/a/1", &alice, None);
+    assert_eq!(resp.status, 403);
+    assert_eq!(resp.error_code(), "PermissionDenied");
+    assert_eq!(resp.body["error"]["message"], "alice may not read key a/1");
+
+    // credentials only hold for their request
+    let resp = client.get("/keys/alice/1");
+    assert_eq!(resp.status, 403);
+    assert_eq!(client.get("/keys/public/1").status, 404);
+
+    // scans only return the keys the user may read
+    let resp = client.request("GET", "/keys", &alice, None);
+    assert_eq!(resp.body, json!({ "keys": ["alice/1"], "next": null }));
+
+    let resp = client.request("GET", "/keys/a/1", &[("Authorization", "Bearer b")], None);
+    assert_eq!(resp.status, 401);
+    assert_eq!(resp.error_code(), "AuthenticationFailed");
+    assert!(resp.header("WWW-Authenticate").is_some());
+    let resp = client.request("GET", "/keys/a/1", &[("Authorization", "Basic !!")], None);
+    assert_eq!(resp.status, 400);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+// An engine refusing writes: sets as a Raft follower does, removes as if a
+// transaction changed the key first.
+#[derive(Clone)]
+struct RefusingEngine;
+
+impl KvsEngine for RefusingEngine {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KvsError::NotLeader(None))
+    }
+
+    fn get(&self, _key: String) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        Err(KvsError::Conflict(key))
+    }
+}
+
+#[test]
+fn refused_writes() -> Result<()> {
+    let (_, shutdown, handle) = TestServer::with_engine(RefusingEngine)
+        .http_addr("127.0.0.1:5008")
+        .start("127.0.0.1:5007");
+    let mut client = HttpClient::connect("127.0.0.1:5008");
+
+    let resp = client.put("/keys/key1", "value1");
+    assert_eq!(resp.status_line, "HTTP/1.1 421 Misdirected Request");
+    assert_eq!(resp.error_code(), "NotLeader");
+    let resp = client.delete("/keys/key1");
+    assert_eq!(resp.status_line, "HTTP/1.1 409 Conflict");
+    assert_eq!(resp.error_code(), "Conflict");
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
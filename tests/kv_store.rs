@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{ChangeEvent, KvStore, KvsError, MemoryStorage, Result, Storage};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -209,3 +209,280 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should keep data across reopens when backed by a shared in-memory storage
+#[test]
+fn memory_storage_reopen() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::with_storage(storage.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::with_storage(storage)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Compaction on an in-memory storage should drop stale logs and keep the latest values.
+#[test]
+fn memory_storage_compaction() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::with_storage(storage.clone())?;
+    // no stale log is kept as history
+    store.set_retained_changes(0);
+
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{:0>64}", iter))?;
+        }
+    }
+    // 200 * 100 commands of over 64 bytes exceed the compaction threshold
+    let logs = storage.list().expect("unable to list storage");
+    assert!(logs.len() < 200, "no compaction detected: {:?}", logs);
+    assert!(logs
+        .iter()
+        .all(|name| name.ends_with(".log") || name == "history.id"));
+
+    drop(store);
+    let store = KvStore::with_storage(storage)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{:0>64}", 199))
+        );
+    }
+
+    Ok(())
+}
+
+fn change(seq: u64, key: &str, value: Option<&str>) -> ChangeEvent {
+    ChangeEvent {
+        seq,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+// Should read every change back in order, and resume where it stopped.
+#[test]
+fn tail_changes() -> Result<()> {
+    let store = KvStore::with_storage(MemoryStorage::new())?;
+    assert_eq!(store.last_seq(), 0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.remove("key3".to_owned()).is_err());
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.last_seq(), 3);
+
+    let mut changes = store.changes(1);
+    assert_eq!(
+        (&mut changes).collect::<Result<Vec<_>>>()?,
+        vec![
+            change(1, "key1", Some("value1")),
+            change(2, "key2", Some("value2")),
+            change(3, "key1", None),
+        ]
+    );
+    assert_eq!(changes.next_seq(), 4);
+
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store
+            .changes(changes.next_seq())
+            .collect::<Result<Vec<_>>>()?,
+        vec![change(4, "key2", Some("value3"))]
+    );
+    assert_eq!(
+        store.changes_since(2, 2)?,
+        vec![change(2, "key2", Some("value2")), change(3, "key1", None)]
+    );
+    Ok(())
+}
+
+// Compactions should keep the retained changes readable and drop older ones.
+#[test]
+fn changes_across_compaction() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::with_storage(storage.clone())?;
+    store.set_retained_changes(2);
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.first_retained_seq(), 1);
+    assert_eq!(store.changes(1).count(), 4);
+
+    store.compact()?;
+    assert_eq!(store.first_retained_seq(), 1);
+    store.compact()?;
+    // the history of the first compaction holds changes 1 to 3, with 3 retained
+    assert_eq!(store.first_retained_seq(), 1);
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.first_retained_seq(), 5);
+    assert_eq!(
+        store.changes(5).collect::<Result<Vec<_>>>()?,
+        vec![
+            change(5, "key5", Some("value5")),
+            change(6, "key6", Some("value6")),
+        ]
+    );
+
+    let mut changes = store.changes(2);
+    match changes.next() {
+        Some(Err(KvsError::HistoryTruncated(5))) => {}
+        res => panic!("expected the history to be truncated, got {:?}", res),
+    }
+    assert!(changes.next().is_none());
+
+    // with nothing retained, only the changes since the last compaction remain
+    store.set_retained_changes(0);
+    store.compact()?;
+    assert_eq!(store.first_retained_seq(), 7);
+    assert!(storage
+        .list()?
+        .iter()
+        .all(|name| name.ends_with(".log") || name == "history.id"));
+    Ok(())
+}
+
+// Sequence numbers and history should survive reopening the store.
+#[test]
+fn changes_reopen() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::with_storage(storage.clone())?;
+    store.set_retained_changes(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::with_storage(storage)?;
+    assert_eq!(store.last_seq(), 3);
+    assert_eq!(store.first_retained_seq(), 1);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store.changes(1).collect::<Result<Vec<_>>>()?,
+        vec![
+            change(1, "key1", Some("value1")),
+            change(2, "key2", Some("value2")),
+            change(3, "key1", None),
+            change(4, "key3", Some("value3")),
+        ]
+    );
+    Ok(())
+}
+
+// Reading changes from the middle of a log or a history file should start at
+// the right one, and compactions should keep the latest changes by default.
+#[test]
+fn changes_from_the_middle() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::with_storage(storage.clone())?;
+    for i in 1..=1000 {
+        store.set(format!("key{}", i % 10), i.to_string())?;
+    }
+    store.compact()?;
+    for i in 1001..=2000 {
+        store.set(format!("key{}", i % 10), i.to_string())?;
+    }
+    assert_eq!(store.first_retained_seq(), 1);
+
+    let check = |store: &KvStore<MemoryStorage>| -> Result<()> {
+        for &from in &[1, 255, 256, 257, 777, 1000, 1001, 1500, 2000] {
+            let changes = store.changes_since(from, 3)?;
+            let expected: Vec<_> = (from..(from + 3).min(2001))
+                .map(|seq| (seq, format!("key{}", seq % 10), Some(seq.to_string())))
+                .collect();
+            let changes: Vec<_> = changes
+                .into_iter()
+                .map(|change| (change.seq, change.key, change.value))
+                .collect();
+            assert_eq!(changes, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::with_storage(storage)?)
+}
+
+// Every store gets its own history identifier, which it keeps when reopened.
+#[test]
+fn history_id() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::with_storage(storage.clone())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let id = store.history_id();
+    drop(store);
+    assert_eq!(KvStore::with_storage(storage)?.history_id(), id);
+    assert_ne!(
+        KvStore::with_storage(MemoryStorage::new())?.history_id(),
+        id
+    );
+    Ok(())
+}
+
+// Every write should give its key a new version, kept across compactions and
+// reopens, which conditional writes check.
+#[test]
+fn key_versions() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::with_storage(storage.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.get_with_version("key1".to_owned())?,
+        Some(("value1".to_owned(), 1))
+    );
+    assert_eq!(store.get_with_version("key3".to_owned())?, None);
+
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "value3".to_owned(), Some(1))?,
+        3
+    );
+    match store.set_if_version("key1".to_owned(), "value4".to_owned(), Some(1)) {
+        Err(KvsError::Conflict(key)) => assert_eq!(key, "key1"),
+        res => panic!("expected KvsError::Conflict, got {:?}", res),
+    }
+    // `None` requires the key to be missing
+    match store.set_if_version("key2".to_owned(), "value4".to_owned(), None) {
+        Err(KvsError::Conflict(key)) => assert_eq!(key, "key2"),
+        res => panic!("expected KvsError::Conflict, got {:?}", res),
+    }
+    assert_eq!(
+        store.set_if_version("key3".to_owned(), "value3".to_owned(), None)?,
+        4
+    );
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.set_if_version("key2".to_owned(), "value5".to_owned(), None)?,
+        6
+    );
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::with_storage(storage)?;
+    assert_eq!(
+        store.get_with_version("key1".to_owned())?,
+        Some(("value3".to_owned(), 3))
+    );
+    assert_eq!(
+        store.get_with_version("key2".to_owned())?,
+        Some(("value5".to_owned(), 6))
+    );
+    store.set("key3".to_owned(), "value6".to_owned())?;
+    assert_eq!(
+        store.get_with_version("key3".to_owned())?,
+        Some(("value6".to_owned(), 7))
+    );
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// Model checking of `KvStore` against a `HashMap` oracle.
+//
+// Random operation sequences are applied to both; every step must produce the
+// same result and leave both with the same contents. proptest shrinks failing
+// sequences to a minimal reproduction.
+use std::collections::HashMap;
+
+use kvs::{KvStore, KvsError, MemoryStorage, Storage};
+use proptest::prelude::*;
+use tempfile::TempDir;
+
+// a small key space makes overwrites and removals of live keys likely
+const KEYS: u8 = 8;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(u8, String),
+    Get(u8),
+    Remove(u8),
+    Reopen,
+    Compact,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..KEYS, "[a-z]{0,12}").prop_map(|(k, v)| Op::Set(k, v)),
+        3 => (0..KEYS).prop_map(Op::Get),
+        2 => (0..KEYS).prop_map(Op::Remove),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+fn key(k: u8) -> String {
+    format!("key{}", k)
+}
+
+fn check_model<S, F>(ops: Vec<Op>, open: F) -> Result<(), TestCaseError>
+where
+    S: Storage,
+    F: Fn() -> KvStore<S>,
+{
+    let mut store = open();
+    let mut model = HashMap::new();
+
+    for op in ops {
+        match op {
+            Op::Set(k, v) => {
+                store.set(key(k), v.clone()).unwrap();
+                model.insert(key(k), v);
+            }
+            Op::Get(k) => {
+                prop_assert_eq!(store.get(key(k)).unwrap(), model.get(&key(k)).cloned());
+            }
+            Op::Remove(k) => match (store.remove(key(k)), model.remove(&key(k))) {
+                (Ok(()), Some(_)) | (Err(KvsError::KeyNotFound), None) => {}
+                (res, expected) => {
+                    return Err(TestCaseError::fail(format!(
+                        "remove {} returned {:?}, model had {:?}",
+                        key(k),
+                        res,
+                        expected
+                    )))
+                }
+            },
+            Op::Reopen => {
+                drop(store);
+                store = open();
+            }
+            Op::Compact => store.compact().unwrap(),
+        }
+
+        for k in 0..KEYS {
+            prop_assert_eq!(store.get(key(k)).unwrap(), model.get(&key(k)).cloned());
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn memory_store_matches_model(ops in prop::collection::vec(op(), 1..200)) {
+        let storage = MemoryStorage::new();
+        check_model(ops, || KvStore::with_storage(storage.clone()).unwrap())?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn file_store_matches_model(ops in prop::collection::vec(op(), 1..100)) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_model(ops, || KvStore::open(temp_dir.path()).unwrap())?;
+    }
+}
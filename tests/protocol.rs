@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+
+use kvs::protocol::{
+    decode_response, encode_request, encode_response, read_frame, write_frame, Codec, Hello, MAGIC,
+    MAX_FRAME_LEN, PROTOCOL_VERSION,
+};
+use kvs::{
+    ErrorCode, KvsClient, KvsError, Request, Response, Result, TaggedRequest, TaggedResponse,
+};
+use serde::Deserialize;
+
+mod common;
+
+use common::TestServer;
+
+// A frame whose bytes do not all arrive is an error, however long it says
+// it is.
+#[test]
+fn truncated_frame() {
+    let mut bytes = MAX_FRAME_LEN.to_be_bytes().to_vec();
+    bytes.extend_from_slice(b"partial payload");
+    match read_frame(&mut &bytes[..]) {
+        Err(KvsError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+        res => panic!("expected an I/O error, got {:?}", res),
+    }
+    let mut bytes = (MAX_FRAME_LEN + 1).to_be_bytes().to_vec();
+    bytes.extend_from_slice(b"partial payload");
+    assert!(matches!(
+        read_frame(&mut &bytes[..]),
+        Err(KvsError::Protocol(_))
+    ));
+}
+
+// The encoding of the variants a version knows does not change with later
+// versions, whose variants come after them.
+#[test]
+fn version_11_response_encoding() -> Result<()> {
+    let hello = Hello {
+        version: 11,
+        codec: Codec::Bincode,
+    };
+    let versioned = TaggedResponse {
+        id: 7,
+        response: Response::Versioned(Some(("k".to_owned(), 3))),
+    };
+    assert_eq!(
+        encode_response(&hello, &versioned)?,
+        vec![
+            7, 0, 0, 0, 0, 0, 0, 0, // id
+            8, 0, 0, 0, // variant
+            1, // Some
+            1, 0, 0, 0, 0, 0, 0, 0, b'k', // key
+            3, 0, 0, 0, 0, 0, 0, 0, // version
+        ]
+    );
+    let version = TaggedResponse {
+        id: 7,
+        response: Response::Version(4),
+    };
+    assert_eq!(
+        encode_response(&hello, &version)?,
+        vec![7, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0]
+    );
+    Ok(())
+}
+
+fn handshake(stream: &mut TcpStream, version: u16, codec: Codec) -> Result<Option<Hello>> {
+    Hello { version, codec }.write_to(stream)?;
+    Hello::read_from(stream)
+}
+
+#[test]
+fn client_codecs() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4101").0;
+
+    let mut client = KvsClient::connect(&addr)?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut client = KvsClient::connect_with_codec(&addr, Codec::Json)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(client.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Clients predating the handshake send bare JSON values.
+#[test]
+fn legacy_json_client() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4102").0;
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+
+    let req = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    serde_json::to_writer(&mut stream, &req)?;
+    match Response::deserialize(&mut reader)? {
+        Response::Ok(None) => {}
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+
+    serde_json::to_writer(
+        &mut stream,
+        &Request::Get {
+            key: "key1".to_owned(),
+        },
+    )?;
+    match Response::deserialize(&mut reader)? {
+        Response::Ok(Some(v)) => assert_eq!(v, "value1"),
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+
+    // framed clients see the data written by legacy ones
+    let mut client = KvsClient::connect(&addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn newer_client_version_is_downgraded() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4103").0;
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = handshake(&mut stream, PROTOCOL_VERSION + 1, Codec::Json)?;
+    assert_eq!(
+        hello,
+        Some(Hello {
+            version: PROTOCOL_VERSION,
+            codec: Codec::Json
+        })
+    );
+    Ok(())
+}
+
+#[test]
+fn unsupported_version_is_rejected() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4104").0;
+    let mut stream = TcpStream::connect(addr)?;
+    assert_eq!(handshake(&mut stream, 0, Codec::Bincode)?, None);
+
+    // the server closes the connection after the rejection
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[test]
+fn malformed_request_keeps_connection() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4105").0;
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = handshake(&mut stream, PROTOCOL_VERSION, Codec::Bincode)?.unwrap();
+
+    stream.write_all(&4u32.to_be_bytes())?;
+    stream.write_all(&[0xff; 4])?;
+    let payload = read_frame(&mut stream)?.unwrap();
+    match decode_response(&hello, &payload)? {
+        TaggedResponse {
+            id: 0,
+            response:
+                Response::Error {
+                    code: ErrorCode::MalformedRequest,
+                    message,
+                },
+        } => assert!(message.contains("Malformed request")),
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+
+    let req = TaggedRequest::new(
+        1,
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    );
+    write_frame(&mut stream, &encode_request(&hello, &req)?)?;
+    let payload = read_frame(&mut stream)?.unwrap();
+    match decode_response(&hello, &payload)? {
+        TaggedResponse {
+            id: 1,
+            response: Response::Ok(None),
+        } => {}
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    Ok(())
+}
+
+// Clients of version 1 send bare requests and get bare responses in order.
+#[test]
+fn version_1_client() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4107").0;
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = handshake(&mut stream, 1, Codec::Json)?.unwrap();
+    assert_eq!(hello.version, 1);
+
+    let requests = vec![
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+    ];
+    for req in &requests {
+        write_frame(&mut stream, &Codec::Json.encode(req)?)?;
+    }
+    let payload = read_frame(&mut stream)?.unwrap();
+    assert!(matches!(Codec::Json.decode(&payload)?, Response::Ok(None)));
+    let payload = read_frame(&mut stream)?.unwrap();
+    match Codec::Json.decode(&payload)? {
+        Response::Ok(Some(v)) => assert_eq!(v, "value1"),
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    Ok(())
+}
+
+// Clients of version 2 send requests without deadline.
+#[test]
+fn version_2_client() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4110").0;
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = handshake(&mut stream, 2, Codec::Json)?.unwrap();
+    assert_eq!(hello.version, 2);
+
+    write_frame(
+        &mut stream,
+        br#"{"id":7,"request":{"Set":{"key":"key1","value":"value1"}}}"#,
+    )?;
+    let payload = read_frame(&mut stream)?.unwrap();
+    match decode_response(&hello, &payload)? {
+        TaggedResponse {
+            id: 7,
+            response: Response::Ok(None),
+        } => {}
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+
+    let mut req = TaggedRequest::new(
+        8,
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+    );
+    req.timeout_ms = Some(1000);
+    let payload = encode_request(&hello, &req)?;
+    assert_eq!(
+        payload,
+        br#"{"id":8,"request":{"Get":{"key":"key1"}}}"#.to_vec()
+    );
+    write_frame(&mut stream, &payload)?;
+    let payload = read_frame(&mut stream)?.unwrap();
+    match decode_response(&hello, &payload)? {
+        TaggedResponse {
+            id: 8,
+            response: Response::Ok(Some(v)),
+        } => assert_eq!(v, "value1"),
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    Ok(())
+}
+
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4108").0;
+    let mut client = KvsClient::connect(&addr)?;
+
+    let sets = (0..1000)
+        .map(|i| Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .collect();
+    for res in client.pipeline(sets)? {
+        assert_eq!(res?, None);
+    }
+
+    let mut requests: Vec<_> = (0..1000)
+        .map(|i| Request::Get {
+            key: format!("key{}", i),
+        })
+        .collect();
+    requests.push(Request::Remove {
+        key: "missing".to_owned(),
+    });
+    let results = client.pipeline(requests)?;
+    for (i, res) in results.iter().take(1000).enumerate() {
+        assert_eq!(res.as_ref().unwrap(), &Some(format!("value{}", i)));
+    }
+    assert!(results[1000].is_err());
+    Ok(())
+}
+
+#[test]
+fn send_and_recv_match_ids() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4109").0;
+    let mut client = KvsClient::connect(&addr)?;
+
+    let set_id = client.send(Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    let get_id = client.send(Request::Get {
+        key: "key1".to_owned(),
+    })?;
+    assert_ne!(set_id, get_id);
+
+    // a blocking call in between keeps the pipelined responses for `recv`
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut responses = HashMap::new();
+    for _ in 0..2 {
+        let resp = client.recv()?;
+        responses.insert(resp.id, resp.response.into_result()?);
+    }
+    assert_eq!(responses[&set_id], None);
+    assert_eq!(responses[&get_id], Some("value1".to_owned()));
+    assert!(client.recv().is_err());
+    Ok(())
+}
+
+#[test]
+fn invalid_handshake_closes_connection() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4106").0;
+    let mut stream = TcpStream::connect(addr)?;
+    // starts like a handshake but the rest of the magic is wrong
+    stream.write_all(&[MAGIC[0], b'x', b'x', b'x', 0, 1, 1])?;
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[test]
+fn error_codes() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4111").0;
+    let mut client = KvsClient::connect(&addr)?;
+    match client.remove("missing".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("expected a missing key, got {:?}", res),
+    }
+    assert_eq!(KvsError::KeyNotFound.code(), ErrorCode::KeyNotFound);
+    assert!(!ErrorCode::KeyNotFound.is_retriable());
+    assert!(KvsError::DeadlineExceeded.code().is_retriable());
+    assert!(!ErrorCode::Io.is_retriable());
+
+    // errors without a matching variant keep their code
+    let resp = Response::Error {
+        code: ErrorCode::Io,
+        message: "disk full".to_owned(),
+    };
+    match resp.into_result() {
+        Err(e @ KvsError::Server { .. }) => {
+            assert_eq!(e.code(), ErrorCode::Io);
+            assert_eq!(e.to_string(), "disk full");
+        }
+        res => panic!("expected a server error, got {:?}", res),
+    }
+    Ok(())
+}
+
+// Clients before version 5 get errors as strings.
+#[test]
+fn version_4_client() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4112").0;
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = handshake(&mut stream, 4, Codec::Json)?.unwrap();
+    assert_eq!(hello.version, 4);
+
+    let req = TaggedRequest::new(
+        1,
+        Request::Remove {
+            key: "missing".to_owned(),
+        },
+    );
+    write_frame(&mut stream, &encode_request(&hello, &req)?)?;
+    let payload = read_frame(&mut stream)?.unwrap();
+    match decode_response(&hello, &payload)? {
+        TaggedResponse {
+            id: 1,
+            response: Response::Err(e),
+        } => assert_eq!(e, "Key not found"),
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    Ok(())
+}
+
+#[test]
+fn multi_key_requests() -> Result<()> {
+    let addr = TestServer::new().start("127.0.0.1:4113").0;
+    let mut client = KvsClient::connect(&addr)?;
+    client.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        client.get_many(vec![
+            "key1".to_owned(),
+            "key3".to_owned(),
+            "key2".to_owned()
+        ])?,
+        vec![Some("value1".to_owned()), None, Some("value2".to_owned())]
+    );
+
+    // multi-key reads are answered with values, even among other reads
+    let id = client.send(Request::MultiGet {
+        keys: vec!["key1".to_owned()],
+    })?;
+    client.send(Request::Get {
+        key: "key1".to_owned(),
+    })?;
+    let mut values = None;
+    for _ in 0..2 {
+        let resp = client.recv()?;
+        if resp.id == id {
+            values = Some(resp.response.into_values()?);
+        }
+    }
+    assert_eq!(values, Some(vec![Some("value1".to_owned())]));
+    Ok(())
+}
+
+// Serve `Get` and `Set` with protocol version 5 on the next connection.
+fn start_version_5_server(addr: &str) -> SocketAddr {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let listener = std::net::TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let client_hello = Hello::read_from(&mut stream).unwrap().unwrap();
+        assert_eq!(client_hello.version, PROTOCOL_VERSION);
+        let hello = Hello {
+            version: 5,
+            codec: client_hello.codec,
+        };
+        hello.write_to(&mut stream).unwrap();
+        let mut map = HashMap::new();
+        while let Some(payload) = read_frame(&mut stream).unwrap() {
+            let req: TaggedRequest = hello.codec.decode(&payload).unwrap();
+            let response = match req.request {
+                Request::Get { key } => Response::Ok(map.get(&key).cloned()),
+                Request::Set { key, value } => {
+                    map.insert(key, value);
+                    Response::Ok(None)
+                }
+                req => panic!("unexpected request for version 5: {:?}", req),
+            };
+            let resp = TaggedResponse {
+                id: req.id,
+                response,
+            };
+            write_frame(&mut stream, &hello.codec.encode(&resp).unwrap()).unwrap();
+        }
+    });
+    addr
+}
+
+// Clients before version 6 are sent single-key requests instead.
+#[test]
+fn multi_key_requests_to_version_5_server() -> Result<()> {
+    let addr = start_version_5_server("127.0.0.1:4114");
+    let mut client = KvsClient::connect(&addr)?;
+    assert_eq!(client.protocol_version(), 5);
+    client.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key1".to_owned(), "value2".to_owned()),
+    ])?;
+    assert_eq!(
+        client.get_many(vec!["key1".to_owned(), "key2".to_owned()])?,
+        vec![Some("value2".to_owned()), None]
+    );
+    Ok(())
+}
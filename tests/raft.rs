@@ -0,0 +1,614 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use kvs::raft::{ClusterConfig, Command, Message, NodeConfig, RaftEngine, RaftNode, Role};
+use kvs::{
+    ErrorCode, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, MemoryKvsEngine, MemoryStorage,
+    RaftServer, Result, SharedQueueThreadPool, ShutdownHandle, Storage, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+mod common;
+
+use common::wait_for;
+
+type Node = RaftNode<MemoryKvsEngine, MemoryStorage>;
+
+// A cluster of nodes exchanging messages over a simulated network, which
+// delivers them in order unless their sender or receiver is down or cut off.
+struct Cluster {
+    ids: Vec<u64>,
+    nodes: Vec<Option<Node>>,
+    engines: Vec<MemoryKvsEngine>,
+    storages: Vec<MemoryStorage>,
+    // nodes which only talk to each other, if any
+    partition: HashSet<u64>,
+    snapshot_threshold: Option<u64>,
+    snapshot_chunk_len: Option<usize>,
+}
+
+impl Cluster {
+    fn new(size: u64) -> Result<Cluster> {
+        let mut cluster = Cluster {
+            ids: (1..=size).collect(),
+            nodes: Vec::new(),
+            engines: Vec::new(),
+            storages: Vec::new(),
+            partition: HashSet::new(),
+            snapshot_threshold: None,
+            snapshot_chunk_len: None,
+        };
+        for id in cluster.ids.clone() {
+            cluster.engines.push(MemoryKvsEngine::new());
+            cluster.storages.push(MemoryStorage::new());
+            cluster.nodes.push(None);
+            cluster.restart(id)?;
+        }
+        Ok(cluster)
+    }
+
+    fn node(&mut self, id: u64) -> &mut Node {
+        self.nodes[id as usize - 1].as_mut().expect("node is up")
+    }
+
+    fn engine(&self, id: u64) -> &MemoryKvsEngine {
+        &self.engines[id as usize - 1]
+    }
+
+    fn set_snapshot_threshold(&mut self, entries: u64) {
+        self.snapshot_threshold = Some(entries);
+        for node in self.nodes.iter_mut().flatten() {
+            node.set_snapshot_threshold(entries);
+        }
+    }
+
+    fn set_snapshot_chunk_len(&mut self, len: usize) {
+        self.snapshot_chunk_len = Some(len);
+        for node in self.nodes.iter_mut().flatten() {
+            node.set_snapshot_chunk_len(len);
+        }
+    }
+
+    fn crash(&mut self, id: u64) {
+        self.nodes[id as usize - 1] = None;
+    }
+
+    // Restarts the node from its storage, with an empty engine.
+    fn restart(&mut self, id: u64) -> Result<()> {
+        let engine = MemoryKvsEngine::new();
+        self.engines[id as usize - 1] = engine.clone();
+        let storage = self.storages[id as usize - 1].clone();
+        let mut node = RaftNode::new(id, self.ids.clone(), engine, storage)?;
+        if let Some(entries) = self.snapshot_threshold {
+            node.set_snapshot_threshold(entries);
+        }
+        if let Some(len) = self.snapshot_chunk_len {
+            node.set_snapshot_chunk_len(len);
+        }
+        self.nodes[id as usize - 1] = Some(node);
+        Ok(())
+    }
+
+    fn connected(&self, from: u64, to: u64) -> bool {
+        self.partition.is_empty() || self.partition.contains(&from) == self.partition.contains(&to)
+    }
+
+    // Advances the clock of every node, then delivers the messages until
+    // none is left.
+    fn tick(&mut self) -> Result<()> {
+        let mut queue = VecDeque::new();
+        for node in self.nodes.iter_mut().flatten() {
+            node.tick()?;
+            snapshot(node)?;
+            queue.extend(node.take_messages());
+        }
+        while let Some(msg) = queue.pop_front() {
+            queue.extend(self.deliver(msg)?);
+        }
+        self.check_leaders();
+        Ok(())
+    }
+
+    fn deliver(&mut self, msg: Message) -> Result<Vec<Message>> {
+        if !self.connected(msg.from, msg.to) {
+            return Ok(Vec::new());
+        }
+        match self.nodes[msg.to as usize - 1].as_mut() {
+            Some(node) => {
+                node.step(msg)?;
+                snapshot(node)?;
+                Ok(node.take_messages())
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // Checks that no term has two leaders.
+    fn check_leaders(&self) {
+        let mut terms = HashSet::new();
+        for node in self.nodes.iter().flatten() {
+            if node.role() == Role::Leader {
+                assert!(terms.insert(node.term()), "two leaders in a term");
+            }
+        }
+    }
+
+    fn run(&mut self, ticks: usize) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    // Runs until the nodes among `ids` elect one of them, which every one of
+    // them follows.
+    fn wait_leader(&mut self, ids: &[u64]) -> Result<u64> {
+        for _ in 0..500 {
+            self.tick()?;
+            let leaders: HashSet<_> = ids.iter().map(|&id| self.node(id).leader()).collect();
+            if let [Some(leader)] = leaders.into_iter().collect::<Vec<_>>()[..] {
+                if ids.contains(&leader) && self.node(leader).role() == Role::Leader {
+                    return Ok(leader);
+                }
+            }
+        }
+        panic!("no leader elected among {:?}", ids);
+    }
+
+    // Proposes a write to the leader, then runs until the nodes among `ids`
+    // applied it.
+    fn write(&mut self, leader: u64, ids: &[u64], command: Command) -> Result<()> {
+        let (index, _) = self.node(leader).propose(command)?;
+        for _ in 0..100 {
+            self.tick()?;
+            if ids.iter().all(|&id| self.node(id).last_applied() >= index) {
+                return Ok(());
+            }
+        }
+        panic!("write {} not applied by {:?}", index, ids);
+    }
+}
+
+// Takes the snapshot the node is due for, if any.
+fn snapshot<E: KvsEngine, S: Storage>(node: &mut RaftNode<E, S>) -> Result<()> {
+    if let Some(mut task) = node.start_snapshot()? {
+        task.run()?;
+        node.finish_snapshot(task)?;
+    }
+    Ok(())
+}
+
+fn set(key: &str, value: &str) -> Command {
+    Command::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn remove(key: &str) -> Command {
+    Command::Remove {
+        key: key.to_owned(),
+    }
+}
+
+#[test]
+fn elect_leader() -> Result<()> {
+    let mut cluster = Cluster::new(3)?;
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    let term = cluster.node(leader).term();
+    for id in 1..=3 {
+        assert_eq!(cluster.node(id).term(), term);
+        if id != leader {
+            assert_eq!(cluster.node(id).role(), Role::Follower);
+        }
+    }
+
+    // heartbeats keep the leader in place
+    cluster.run(100)?;
+    assert_eq!(cluster.node(leader).role(), Role::Leader);
+    assert_eq!(cluster.node(leader).term(), term);
+    Ok(())
+}
+
+#[test]
+fn single_node_cluster() -> Result<()> {
+    let mut cluster = Cluster::new(1)?;
+    assert_eq!(cluster.wait_leader(&[1])?, 1);
+    cluster.write(1, &[1], set("key1", "value1"))?;
+    assert_eq!(
+        cluster.engine(1).get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn replicate_writes() -> Result<()> {
+    let mut cluster = Cluster::new(3)?;
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    cluster.write(leader, &[1, 2, 3], set("key1", "value1"))?;
+    cluster.write(leader, &[1, 2, 3], set("key2", "value2"))?;
+    cluster.write(leader, &[1, 2, 3], remove("key1"))?;
+    for id in 1..=3 {
+        assert_eq!(cluster.engine(id).get("key1".to_owned())?, None);
+        assert_eq!(
+            cluster.engine(id).get("key2".to_owned())?,
+            Some("value2".to_owned())
+        );
+    }
+
+    // the leader reports how its entries were applied
+    let applied = cluster.node(leader).take_applied();
+    let last = applied.last().expect("applied entries");
+    assert!(last.result.is_ok());
+    cluster.write(leader, &[1, 2, 3], remove("missing"))?;
+    let applied = cluster.node(leader).take_applied();
+    match &applied[..] {
+        [applied] => match applied.result {
+            Err(KvsError::KeyNotFound) => {}
+            ref res => panic!("expected KeyNotFound, got {:?}", res),
+        },
+        applied => panic!("expected one applied entry, got {:?}", applied),
+    }
+    Ok(())
+}
+
+#[test]
+fn replicate_transactions() -> Result<()> {
+    let mut cluster = Cluster::new(3)?;
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    cluster.write(leader, &[1, 2, 3], set("key1", "value1"))?;
+    let read = cluster.node(leader).version("key1");
+    for id in 1..=3 {
+        assert_eq!(cluster.node(id).version("key1"), read);
+    }
+    let transact = |version: Option<u64>, value: &str| Command::Transact {
+        reads: vec![("key1".to_owned(), version)],
+        writes: vec![
+            ("key1".to_owned(), None),
+            ("key2".to_owned(), Some(value.to_owned())),
+        ],
+    };
+
+    // a key written back to the value it was read with is at a new version
+    cluster.write(leader, &[1, 2, 3], set("key1", "other"))?;
+    cluster.write(leader, &[1, 2, 3], set("key1", "value1"))?;
+    cluster.write(leader, &[1, 2, 3], transact(read, "stale"))?;
+    match &cluster.node(leader).take_applied()[..] {
+        [.., applied] => match applied.result {
+            Err(KvsError::Conflict(ref key)) => assert_eq!(key, "key1"),
+            ref res => panic!("expected Conflict, got {:?}", res),
+        },
+        [] => panic!("expected applied entries"),
+    }
+    let read = cluster.node(leader).version("key1");
+    cluster.write(leader, &[1, 2, 3], transact(read, "value1"))?;
+    for id in 1..=3 {
+        assert_eq!(cluster.node(id).version("key1"), None);
+        assert_eq!(cluster.engine(id).get("key1".to_owned())?, None);
+        assert_eq!(
+            cluster.engine(id).get("key2".to_owned())?,
+            Some("value1".to_owned())
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn followers_refuse_proposals() -> Result<()> {
+    let mut cluster = Cluster::new(3)?;
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    let follower = if leader == 1 { 2 } else { 1 };
+    match cluster.node(follower).propose(set("key1", "value1")) {
+        Err(e @ KvsError::NotLeader(None)) => assert_eq!(e.code(), ErrorCode::NotLeader),
+        res => panic!("expected NotLeader, got {:?}", res),
+    }
+    Ok(())
+}
+
+#[test]
+fn survive_leader_loss() -> Result<()> {
+    let mut cluster = Cluster::new(3)?;
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    cluster.write(leader, &[1, 2, 3], set("key1", "value1"))?;
+
+    cluster.crash(leader);
+    let others: Vec<u64> = (1..=3).filter(|&id| id != leader).collect();
+    let new_leader = cluster.wait_leader(&others)?;
+    assert_ne!(new_leader, leader);
+    cluster.write(new_leader, &others, set("key2", "value2"))?;
+    for &id in &others {
+        assert_eq!(
+            cluster.engine(id).get("key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+    }
+
+    // the old leader catches up once back
+    cluster.restart(leader)?;
+    cluster.write(new_leader, &[1, 2, 3], set("key3", "value3"))?;
+    assert_eq!(cluster.node(leader).role(), Role::Follower);
+    for key in &["key1", "key2", "key3"] {
+        assert!(cluster.engine(leader).get((*key).to_owned())?.is_some());
+    }
+    Ok(())
+}
+
+// A leader cut off from the majority cannot commit, and its writes are
+// replaced by those of the majority once the partition heals.
+#[test]
+fn minority_leader_cannot_commit() -> Result<()> {
+    let mut cluster = Cluster::new(5)?;
+    let leader = cluster.wait_leader(&[1, 2, 3, 4, 5])?;
+    cluster.write(leader, &[1, 2, 3, 4, 5], set("key1", "value1"))?;
+    let commit_index = cluster.node(leader).commit_index();
+
+    let follower = if leader == 1 { 2 } else { 1 };
+    cluster.partition = [leader, follower].iter().copied().collect();
+    let (index, term) = cluster.node(leader).propose(set("key1", "lost"))?;
+    cluster.run(50)?;
+    assert_eq!(cluster.node(leader).commit_index(), commit_index);
+    assert!(cluster.node(leader).last_applied() < index);
+
+    let majority: Vec<u64> = (1..=5)
+        .filter(|id| *id != leader && *id != follower)
+        .collect();
+    let new_leader = cluster.wait_leader(&majority)?;
+    cluster.write(new_leader, &majority, set("key1", "value2"))?;
+
+    cluster.partition.clear();
+    cluster.write(new_leader, &[1, 2, 3, 4, 5], set("key2", "value2"))?;
+    assert_eq!(cluster.node(leader).role(), Role::Follower);
+    for id in 1..=5 {
+        assert_eq!(
+            cluster.engine(id).get("key1".to_owned())?,
+            Some("value2".to_owned())
+        );
+    }
+    // the lost write was applied with the entry of another term
+    let applied = cluster.node(leader).take_applied();
+    let replaced = applied.iter().find(|a| a.index == index).unwrap();
+    assert_ne!(replaced.term, term);
+    Ok(())
+}
+
+#[test]
+fn snapshot_catch_up() -> Result<()> {
+    let mut cluster = Cluster::new(3)?;
+    cluster.set_snapshot_threshold(5);
+    // the snapshot takes several messages
+    cluster.set_snapshot_chunk_len(64);
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    cluster.write(leader, &[1, 2, 3], set("stale", "value"))?;
+
+    let lagging = if leader == 1 { 2 } else { 1 };
+    cluster.crash(lagging);
+    let others: Vec<u64> = (1..=3).filter(|&id| id != lagging).collect();
+    cluster.write(leader, &others, remove("stale"))?;
+    for i in 0..20 {
+        cluster.write(leader, &others, set(&format!("key{}", i), &i.to_string()))?;
+    }
+    assert!(cluster.node(leader).snapshot_index() > 2);
+
+    // the log of the leader no longer has what the follower misses
+    cluster.restart(lagging)?;
+    cluster.write(leader, &[1, 2, 3], set("last", "value"))?;
+    let engine = cluster.engine(lagging);
+    assert_eq!(engine.get("stale".to_owned())?, None);
+    for i in 0..20 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    assert_eq!(engine.get("last".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn restart_whole_cluster() -> Result<()> {
+    let mut cluster = Cluster::new(3)?;
+    cluster.set_snapshot_threshold(4);
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    for i in 0..10 {
+        cluster.write(leader, &[1, 2, 3], set(&format!("key{}", i), "value"))?;
+    }
+    let term = cluster.node(leader).term();
+
+    for id in 1..=3 {
+        cluster.crash(id);
+    }
+    for id in 1..=3 {
+        cluster.restart(id)?;
+        assert_eq!(cluster.node(id).term(), term);
+    }
+    let leader = cluster.wait_leader(&[1, 2, 3])?;
+    assert!(cluster.node(leader).term() > term);
+    cluster.write(leader, &[1, 2, 3], set("key10", "value"))?;
+    for id in 1..=3 {
+        for i in 0..=10 {
+            assert_eq!(
+                cluster.engine(id).get(format!("key{}", i))?,
+                Some("value".to_owned())
+            );
+        }
+    }
+    Ok(())
+}
+
+// Drives a single-node cluster until the node leads it.
+fn lead(node: &mut RaftNode<KvStore, MemoryStorage>) -> Result<()> {
+    while node.role() != Role::Leader {
+        node.tick()?;
+    }
+    Ok(())
+}
+
+// A new node refuses an engine with keys the cluster never committed, and a
+// node keeps the keys it applied before restarting.
+#[test]
+fn keep_existing_data() -> Result<()> {
+    let dir = TempDir::new()?;
+    let store = KvStore::open(dir.path())?;
+    store.set("existing".to_owned(), "value".to_owned())?;
+    let storage = MemoryStorage::new();
+    let res = RaftNode::new(1, vec![1], store.clone(), storage.clone());
+    assert!(matches!(res, Err(KvsError::Raft(_))));
+    store.remove("existing".to_owned())?;
+    drop(store);
+
+    let mut node = RaftNode::new(1, vec![1], KvStore::open(dir.path())?, storage.clone())?;
+    node.set_snapshot_threshold(3);
+    lead(&mut node)?;
+    node.propose(set("existing", "value"))?;
+    for i in 0..10 {
+        node.propose(set(&format!("key{}", i), "value"))?;
+        snapshot(&mut node)?;
+    }
+    assert!(node.snapshot_index() > 0);
+    drop(node);
+
+    for _ in 0..2 {
+        let mut node = RaftNode::new(1, vec![1], KvStore::open(dir.path())?, storage.clone())?;
+        let engine = node.engine().clone();
+        assert_eq!(engine.get("existing".to_owned())?, Some("value".to_owned()));
+        for i in 0..10 {
+            assert_eq!(engine.get(format!("key{}", i))?, Some("value".to_owned()));
+        }
+        lead(&mut node)?;
+        node.propose(remove("key0"))?;
+        node.propose(set("key0", "value"))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn invalid_cluster_config() {
+    let addr: SocketAddr = "127.0.0.1:5390".parse().unwrap();
+    let node = |id| NodeConfig {
+        id,
+        addr,
+        raft_addr: addr,
+    };
+    let duplicate = ClusterConfig {
+        nodes: vec![node(1), node(1)],
+    };
+    assert!(matches!(
+        duplicate.validate(),
+        Err(KvsError::ClusterConfig(_))
+    ));
+    let cluster = ClusterConfig {
+        nodes: vec![node(1), node(2)],
+    };
+    let res = RaftEngine::new(3, cluster, MemoryKvsEngine::new(), MemoryStorage::new());
+    assert!(matches!(res, Err(KvsError::ClusterConfig(_))));
+}
+
+// A node of a cluster served over TCP.
+struct TcpNode {
+    addr: SocketAddr,
+    engine: RaftEngine<MemoryKvsEngine, MemoryStorage>,
+    shutdowns: Vec<ShutdownHandle>,
+    handles: Vec<JoinHandle<Result<()>>>,
+}
+
+impl TcpNode {
+    fn start(id: u64, cluster: &ClusterConfig) -> Result<TcpNode> {
+        let addr = cluster.node(id).unwrap().addr;
+        let engine = RaftEngine::new(
+            id,
+            cluster.clone(),
+            MemoryKvsEngine::new(),
+            MemoryStorage::new(),
+        )?;
+        let logger = Logger::root(Discard, o!());
+        let mut raft_server = RaftServer::new(engine.clone(), logger.clone());
+        raft_server.set_tick_interval(Duration::from_millis(20));
+        let pool = SharedQueueThreadPool::new(4)?;
+        let mut server = KvsServer::new(engine.clone(), logger, pool);
+        let shutdowns = vec![server.shutdown_handle(), raft_server.shutdown_handle()];
+        let handles = vec![
+            thread::spawn(move || server.run(&addr)),
+            thread::spawn(move || raft_server.run()),
+        ];
+        wait_for(addr);
+        Ok(TcpNode {
+            addr,
+            engine,
+            shutdowns,
+            handles,
+        })
+    }
+
+    fn stop(self) -> Result<()> {
+        for shutdown in &self.shutdowns {
+            shutdown.shutdown();
+        }
+        for handle in self.handles {
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    }
+}
+
+// Waits until one of the nodes is the leader, which every one follows.
+fn wait_tcp_leader(nodes: &[TcpNode]) -> usize {
+    let start = Instant::now();
+    loop {
+        let leaders: HashSet<_> = nodes.iter().map(|node| node.engine.leader()).collect();
+        if let [Some(leader)] = leaders.into_iter().collect::<Vec<_>>()[..] {
+            if let Some(pos) = nodes.iter().position(|node| node.engine.id() == leader) {
+                return pos;
+            }
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "no leader elected"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn tcp_cluster() -> Result<()> {
+    let cluster = ClusterConfig {
+        nodes: (1..=3)
+            .map(|id| NodeConfig {
+                id,
+                addr: format!("127.0.0.1:{}", 5300 + id).parse().unwrap(),
+                raft_addr: format!("127.0.0.1:{}", 5310 + id).parse().unwrap(),
+            })
+            .collect(),
+    };
+    // the cluster config is read from JSON files
+    let dir = TempDir::new()?;
+    let path = dir.path().join("cluster.json");
+    fs::write(&path, serde_json::to_vec(&cluster)?)?;
+    let cluster = ClusterConfig::from_file(&path)?;
+
+    let mut nodes = (1..=3)
+        .map(|id| TcpNode::start(id, &cluster))
+        .collect::<Result<Vec<_>>>()?;
+    let leader = wait_tcp_leader(&nodes);
+    let follower = (leader + 1) % nodes.len();
+
+    // clients of a follower are redirected to the leader
+    let mut client = KvsClient::connect(&nodes[follower].addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    // committed writes survive the loss of the leader
+    nodes.remove(leader).stop()?;
+    let mut client = KvsClient::connect(&nodes[0].addr)?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(client);
+
+    for node in nodes {
+        node.stop()?;
+    }
+    Ok(())
+}
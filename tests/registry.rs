@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use kvs::{BoxedKvsEngine, DynKvsEngine, EngineRegistry, KvsEngine, KvsError, Result};
+
+// An engine which is not `Clone`, implementing the object-safe trait directly.
+#[derive(Default)]
+struct MapEngine(Mutex<HashMap<String, String>>);
+
+impl DynKvsEngine for MapEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.lock().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+}
+
+fn use_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        engine.clone().get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn builtin_engines() {
+    let registry = EngineRegistry::default();
+    let names: Vec<_> = registry.names().collect();
+    assert_eq!(names, vec!["kvs", "memory", "sled"]);
+}
+
+#[test]
+fn open_registered_engine() -> Result<()> {
+    let mut registry = EngineRegistry::new();
+    registry.register("map", |_: &Path| Ok(MapEngine::default()));
+    assert!(registry.contains("map"));
+
+    let engine: BoxedKvsEngine = registry.open("map", Path::new("."))?;
+    use_engine(engine)
+}
+
+#[test]
+fn open_unknown_engine() {
+    let registry = EngineRegistry::default();
+    match registry.open("unknown", Path::new(".")) {
+        Err(KvsError::UnknownEngine(name)) => assert_eq!(name, "unknown"),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unknown engine opened"),
+    }
+}
@@ -0,0 +1,466 @@
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command as Process};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assert_cmd::prelude::*;
+use kvs::auth::AuthConfig;
+use kvs::protocol::{decode_response, encode_request, read_frame, write_frame, Codec, Hello};
+use kvs::{
+    ClientConfig, Credentials, ErrorCode, Follower, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsServer, MemoryKvsEngine, ReadOnlyEngine, Request, Response, Result, SharedQueueThreadPool,
+    TaggedRequest, ThreadPool, WatchedEngine,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+mod common;
+
+use common::{wait_for, TestServer};
+
+// Wait until the server at `addr` has the given value for the key.
+fn wait_value(addr: &SocketAddr, key: &str, value: Option<&str>) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    let start = Instant::now();
+    while client.get(key.to_owned())?.as_deref() != value {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "{} did not get {:?} for {}",
+            addr,
+            value,
+            key
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+#[test]
+fn follow_leader() -> Result<()> {
+    let leader_engine = WatchedEngine::new(MemoryKvsEngine::new());
+    leader_engine.set("key1".to_owned(), "value1".to_owned())?;
+    leader_engine.set("key2".to_owned(), "value2".to_owned())?;
+    let (leader, leader_shutdown, leader_handle) =
+        TestServer::with_engine(leader_engine).start("127.0.0.1:5201");
+
+    // the follower drops the keys the leader does not have
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    engine.set("stale".to_owned(), "value".to_owned())?;
+    let mut follower = Follower::new(leader, engine.clone(), Logger::root(Discard, o!()));
+    follower.set_retry_interval(Duration::from_millis(50));
+    let follower_shutdown = follower.shutdown_handle();
+    let follower_handle = thread::spawn(move || follower.run());
+    let (addr, shutdown, handle) =
+        TestServer::with_engine(ReadOnlyEngine::new(engine)).start("127.0.0.1:5202");
+
+    wait_value(&addr, "key2", Some("value2"))?;
+    let mut client = KvsClient::connect(&addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("stale".to_owned())?, None);
+
+    let mut leader_client = KvsClient::connect(&leader)?;
+    leader_client.set("key3".to_owned(), "value3".to_owned())?;
+    leader_client.remove("key1".to_owned())?;
+    wait_value(&addr, "key1", None)?;
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // followers refuse writes
+    for res in [
+        client.set("key4".to_owned(), "value4".to_owned()),
+        client.remove("key2".to_owned()),
+        client.set_many(vec![("key4".to_owned(), "value4".to_owned())]),
+    ] {
+        match res {
+            Err(e @ KvsError::ReadOnly) => assert_eq!(e.code(), ErrorCode::ReadOnly),
+            res => panic!("expected the follower to refuse, got {:?}", res),
+        }
+    }
+
+    follower_shutdown.shutdown();
+    follower_handle.join().unwrap()?;
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    leader_shutdown.shutdown();
+    leader_handle.join().unwrap()
+}
+
+// Followers apply the pages of a snapshot one after another, dropping the
+// keys the leader does not have between and after them.
+#[test]
+fn follow_leader_paged() -> Result<()> {
+    let leader_engine = WatchedEngine::new(MemoryKvsEngine::new());
+    leader_engine.set_many(
+        (0..2500)
+            .map(|i| (format!("key{:04}", i), i.to_string()))
+            .collect(),
+    )?;
+    let (leader, leader_shutdown, leader_handle) =
+        TestServer::with_engine(leader_engine).start("127.0.0.1:5208");
+
+    let engine = MemoryKvsEngine::new();
+    engine.set_many(vec![
+        ("key1500x".to_owned(), "stale".to_owned()),
+        ("key2499".to_owned(), "stale".to_owned()),
+        ("zzz".to_owned(), "stale".to_owned()),
+    ])?;
+    let mut follower = Follower::new(leader, engine.clone(), Logger::root(Discard, o!()));
+    let follower_shutdown = follower.shutdown_handle();
+    let follower_handle = thread::spawn(move || follower.run());
+    let (addr, shutdown, handle) =
+        TestServer::with_engine(ReadOnlyEngine::new(engine.clone())).start("127.0.0.1:5209");
+
+    wait_value(&addr, "zzz", None)?;
+    assert_eq!(engine.get("key2499".to_owned())?, Some("2499".to_owned()));
+    assert_eq!(engine.get("key1500x".to_owned())?, None);
+    assert_eq!(engine.scan(None, 3000)?.len(), 2500);
+
+    follower_shutdown.shutdown();
+    follower_handle.join().unwrap()?;
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    leader_shutdown.shutdown();
+    leader_handle.join().unwrap()
+}
+
+// Followers which reconnect get the changes they missed from the backlog, or
+// a snapshot if the backlog no longer has them, whose keys are read apart.
+#[test]
+fn watched_engine_replicate() -> Result<()> {
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    engine.set_backlog(2);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let (sender, changes) = mpsc::channel();
+
+    let sink = sender.clone();
+    let snapshot = engine
+        .replicate(0, 0, Box::new(move |change| sink.send(change).is_ok()))?
+        .expect("expected a snapshot");
+    // changes are only numbered once someone watches them
+    assert_eq!(snapshot.seq, 0);
+    assert!(snapshot.pairs.is_empty());
+
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(
+        changes
+            .try_iter()
+            .map(|change| change.seq)
+            .collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+
+    // changes 2 and 3 are in the backlog, change 1 is not
+    let sink = sender.clone();
+    let caught_up = engine.replicate(
+        snapshot.epoch,
+        2,
+        Box::new(move |change| sink.send(change).is_ok()),
+    )?;
+    assert!(caught_up.is_none());
+    assert_eq!(
+        changes
+            .try_iter()
+            .map(|change| change.seq)
+            .collect::<Vec<_>>(),
+        vec![2, 3]
+    );
+    let snapshot = engine
+        .replicate(snapshot.epoch, 1, Box::new(|_| true))?
+        .expect("expected a snapshot");
+    assert_eq!(snapshot.seq, 3);
+    // a follower refusing the changes it missed is not caught up
+    match engine.replicate(snapshot.epoch, 2, Box::new(|_| false)) {
+        Err(KvsError::SinkClosed) => {}
+        res => panic!("expected KvsError::SinkClosed, got {:?}", res),
+    }
+
+    // another history gets a snapshot
+    let snapshot = engine
+        .replicate(snapshot.epoch + 1, 4, Box::new(|_| true))?
+        .expect("expected a snapshot");
+    assert_eq!(snapshot.seq, 3);
+    Ok(())
+}
+
+// Engines keeping the history of their changes number them, so followers
+// resume from it once the engine is wrapped again, e.g. after a restart.
+#[test]
+fn persisted_history_replicate() -> Result<()> {
+    let dir = TempDir::new()?;
+    let engine = WatchedEngine::new(KvStore::open(dir.path())?);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = engine
+        .replicate(0, 0, Box::new(|_| true))?
+        .expect("expected a snapshot");
+    assert_eq!(snapshot.seq, 2);
+    engine.remove("key1".to_owned())?;
+    drop(engine);
+
+    let engine = WatchedEngine::new(KvStore::open(dir.path())?);
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    let (sender, changes) = mpsc::channel();
+    let caught_up = engine.replicate(
+        snapshot.epoch,
+        snapshot.seq + 1,
+        Box::new(move |change| sender.send(change).is_ok()),
+    )?;
+    assert!(caught_up.is_none());
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(
+        changes
+            .try_iter()
+            .map(|change| (change.seq, change.key))
+            .collect::<Vec<_>>(),
+        vec![
+            (3, "key1".to_owned()),
+            (4, "key3".to_owned()),
+            (5, "key4".to_owned()),
+        ]
+    );
+
+    // the history of another store, even a longer one, gets a snapshot
+    let other_dir = TempDir::new()?;
+    let other = WatchedEngine::new(KvStore::open(other_dir.path())?);
+    for i in 0..10 {
+        other.set(format!("key{}", i), i.to_string())?;
+    }
+    assert!(other
+        .replicate(snapshot.epoch, snapshot.seq + 1, Box::new(|_| true))?
+        .is_some());
+    Ok(())
+}
+
+// Snapshots reach followers in pages, unless they speak a protocol version
+// before 12, and the changes committed meanwhile follow them.
+fn paged_snapshot(addr: &str, evented: bool) -> Result<()> {
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    engine.set_many(
+        (0..2500)
+            .map(|i| (format!("key{:04}", i), i.to_string()))
+            .collect(),
+    )?;
+    let addr: SocketAddr = addr.parse().unwrap();
+    let mut server = KvsServer::new(
+        engine.clone(),
+        Logger::root(Discard, o!()),
+        SharedQueueThreadPool::new(4)?,
+    );
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || {
+        if evented {
+            server.run_evented(&addr)
+        } else {
+            server.run(&addr)
+        }
+    });
+    wait_for(addr);
+
+    let mut pages = Vec::new();
+    let mut last = None;
+    let (snapshot, mut changes) = KvsClient::connect(&addr)?.replicate_paged(0, 0, |pairs| {
+        assert!(pairs.first().map(|(key, _)| key) > last.as_ref());
+        last = pairs.last().map(|(key, _)| key.clone());
+        pages.push(pairs.len());
+        Ok(())
+    })?;
+    let snapshot = snapshot.expect("expected a snapshot");
+    assert_eq!(snapshot.seq, 0);
+    assert!(snapshot.pairs.is_empty());
+    assert_eq!(pages, vec![1024, 1024, 452]);
+    engine.set("key0000".to_owned(), "changed".to_owned())?;
+    let change = changes.next().expect("expected a change")?;
+    assert_eq!((change.seq, change.key), (1, "key0000".to_owned()));
+
+    let (snapshot, _) = KvsClient::connect(&addr)?.replicate(0, 0)?;
+    assert_eq!(snapshot.expect("expected a snapshot").pairs.len(), 2500);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Hello {
+        version: 11,
+        codec: Codec::Json,
+    };
+    hello.write_to(&mut stream)?;
+    assert_eq!(Hello::read_from(&mut stream)?, Some(hello));
+    let request = TaggedRequest::new(1, Request::Replicate { epoch: 0, from: 0 });
+    write_frame(&mut stream, &encode_request(&hello, &request)?)?;
+    let payload = read_frame(&mut stream)?.expect("expected a snapshot");
+    match decode_response(&hello, &payload)?.response {
+        Response::Snapshot(snapshot) => assert_eq!(snapshot.pairs.len(), 2500),
+        resp => panic!("expected a snapshot, got {:?}", resp),
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn paged_snapshot_threaded() -> Result<()> {
+    paged_snapshot("127.0.0.1:5206", false)
+}
+
+#[test]
+fn paged_snapshot_evented() -> Result<()> {
+    paged_snapshot("127.0.0.1:5207", true)
+}
+
+// Followers catch up on more changes than fit in the queue of their
+// connection, since these are read as they take them.
+fn long_catch_up(addr: &str, evented: bool) -> Result<()> {
+    let dir = TempDir::new()?;
+    let store = KvStore::open(dir.path())?;
+    store.set_retained_changes(100_000);
+    let engine = WatchedEngine::new(store);
+    let addr: SocketAddr = addr.parse().unwrap();
+    let mut server = KvsServer::new(
+        engine.clone(),
+        Logger::root(Discard, o!()),
+        SharedQueueThreadPool::new(4)?,
+    );
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || {
+        if evented {
+            server.run_evented(&addr)
+        } else {
+            server.run(&addr)
+        }
+    });
+    wait_for(addr);
+
+    let (snapshot, _) = KvsClient::connect(&addr)?.replicate(0, 0)?;
+    let snapshot = snapshot.expect("expected a snapshot");
+    for i in 0..20 {
+        engine.set_many(
+            (0..1000)
+                .map(|j| (format!("key{}", j), (i * 1000 + j).to_string()))
+                .collect(),
+        )?;
+    }
+    let (caught_up, mut changes) =
+        KvsClient::connect(&addr)?.replicate(snapshot.epoch, snapshot.seq + 1)?;
+    assert!(caught_up.is_none());
+    for seq in snapshot.seq + 1..=snapshot.seq + 20_000 {
+        assert_eq!(changes.next().expect("expected a change")?.seq, seq);
+    }
+    engine.set("key".to_owned(), "value".to_owned())?;
+    let change = changes.next().expect("expected a change")?;
+    assert_eq!(change.seq, snapshot.seq + 20_001);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn long_catch_up_threaded() -> Result<()> {
+    long_catch_up("127.0.0.1:5210", false)
+}
+
+#[test]
+fn long_catch_up_evented() -> Result<()> {
+    long_catch_up("127.0.0.1:5211", true)
+}
+
+const CONFIG: &str = r#"{
+    "users": [
+        {
+            "name": "alice",
+            "password": "secret",
+            "grants": [{ "prefix": "alice/", "access": "read" }]
+        },
+        {
+            "name": "follower",
+            "password": "secret",
+            "grants": [{ "prefix": "", "access": "read" }]
+        }
+    ]
+}"#;
+
+#[test]
+fn follow_only_with_every_key_readable() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("auth.json");
+    fs::write(&path, CONFIG)?;
+    let auth = AuthConfig::from_file(&path)?;
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let (addr, shutdown, handle) = TestServer::with_engine(engine)
+        .auth(Some(auth))
+        .start("127.0.0.1:5203");
+
+    let config = |username: &str| ClientConfig {
+        credentials: Some(Credentials::Password {
+            username: username.to_owned(),
+            password: "secret".to_owned(),
+        }),
+        ..ClientConfig::default()
+    };
+    match KvsClient::connect_with_config(&addr, &config("alice"))?.replicate(0, 0) {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("expected the follower to be refused, got {:?}", res.is_ok()),
+    }
+    let (snapshot, _) =
+        KvsClient::connect_with_config(&addr, &config("follower"))?.replicate(0, 0)?;
+    assert_eq!(snapshot.expect("expected a snapshot").pairs.len(), 1);
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(dir: &TempDir, args: &[&str]) -> Server {
+    let server = Process::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--mode", "evented"])
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    wait_for(args[1].parse().unwrap());
+    Server(server)
+}
+
+// A follower process catches up with a restarted leader through a snapshot.
+#[test]
+fn follower_process() -> Result<()> {
+    let leader_addr: SocketAddr = "127.0.0.1:5204".parse().unwrap();
+    let follower_addr: SocketAddr = "127.0.0.1:5205".parse().unwrap();
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+
+    let leader = spawn_server(&leader_dir, &["--addr", "127.0.0.1:5204"]);
+    KvsClient::connect(&leader_addr)?.set("key1".to_owned(), "value1".to_owned())?;
+    let _follower = spawn_server(
+        &follower_dir,
+        &["--addr", "127.0.0.1:5205", "--follow", "127.0.0.1:5204"],
+    );
+    wait_value(&follower_addr, "key1", Some("value1"))?;
+    KvsClient::connect(&leader_addr)?.set("key2".to_owned(), "value2".to_owned())?;
+    wait_value(&follower_addr, "key2", Some("value2"))?;
+
+    Process::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value3", "--addr", "127.0.0.1:5205"])
+        .assert()
+        .failure();
+
+    // the restarted leader starts another history, with other keys
+    drop(leader);
+    let _leader = spawn_server(&leader_dir, &["--addr", "127.0.0.1:5204"]);
+    KvsClient::connect(&leader_addr)?.set("key3".to_owned(), "value3".to_owned())?;
+    wait_value(&follower_addr, "key3", Some("value3"))?;
+    let mut client = KvsClient::connect(&follower_addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, None);
+    Ok(())
+}
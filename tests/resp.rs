@@ -0,0 +1,424 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use kvs::auth::AuthConfig;
+use kvs::{
+    ExpiringEngine, KvsClient, KvsEngine, KvsServer, MemoryKvsEngine, RespServer, Result,
+    SharedQueueThreadPool, ShutdownHandle, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+mod common;
+
+use common::wait_for;
+
+// A reply of the Redis protocol.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+fn bulk(value: &str) -> Value {
+    Value::Bulk(Some(value.to_owned()))
+}
+
+fn status(status: &str) -> Value {
+    Value::Status(status.to_owned())
+}
+
+// A minimal client of the Redis protocol.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: SocketAddr) -> RespClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        RespClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    // Sends a command as an array of bulk strings, without waiting.
+    fn send(&mut self, args: &[&str]) {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(command.as_bytes()).unwrap();
+    }
+
+    fn read(&mut self) -> Value {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "unterminated line {:?}", line);
+        let (kind, rest) = line[..line.len() - 2].split_at(1);
+        match kind {
+            "+" => Value::Status(rest.to_owned()),
+            "-" => Value::Error(rest.to_owned()),
+            ":" => Value::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Value::Bulk(None),
+                len => {
+                    let mut value = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut value).unwrap();
+                    value.truncate(len as usize);
+                    Value::Bulk(Some(String::from_utf8(value).unwrap()))
+                }
+            },
+            "*" => Value::Array((0..rest.parse().unwrap()).map(|_| self.read()).collect()),
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+
+    fn call(&mut self, args: &[&str]) -> Value {
+        self.send(args);
+        self.read()
+    }
+
+    // Whether the server closed the connection.
+    fn is_closed(&mut self) -> bool {
+        let mut buf = [0; 1];
+        matches!(self.reader.read(&mut buf), Ok(0))
+    }
+}
+
+fn assert_error(value: Value, prefix: &str) {
+    match value {
+        Value::Error(e) if e.starts_with(prefix) => {}
+        value => panic!("expected a {} error, got {:?}", prefix, value),
+    }
+}
+
+// Run a RESP server until it is shut down through the returned handle.
+fn start_server(
+    addr: &str,
+    engine: ExpiringEngine<MemoryKvsEngine>,
+    auth: Option<AuthConfig>,
+) -> (SocketAddr, ShutdownHandle, JoinHandle<Result<()>>) {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let logger = Logger::root(Discard, o!());
+    let pool = SharedQueueThreadPool::new(4).unwrap();
+    let mut server = RespServer::new(engine, logger, pool);
+    server.set_auth(auth);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(&addr));
+    wait_for(addr);
+    (addr, shutdown, handle)
+}
+
+#[test]
+fn commands() -> Result<()> {
+    let engine = ExpiringEngine::new(MemoryKvsEngine::new());
+    let (addr, shutdown, handle) = start_server("127.0.0.1:4901", engine, None);
+    let mut client = RespClient::connect(addr);
+
+    assert_eq!(client.call(&["PING"]), status("PONG"));
+    assert_eq!(client.call(&["ping", "hello"]), bulk("hello"));
+    assert_eq!(client.call(&["SET", "key1", "value1"]), status("OK"));
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(client.call(&["GET", "key2"]), Value::Bulk(None));
+    assert_eq!(client.call(&["SET", "key1", ""]), status("OK"));
+    assert_eq!(client.call(&["GET", "key1"]), bulk(""));
+
+    assert_eq!(
+        client.call(&["MSET", "key2", "value2", "key3", "value 3"]),
+        status("OK")
+    );
+    assert_eq!(
+        client.call(&["MGET", "key2", "key4", "key3"]),
+        Value::Array(vec![bulk("value2"), Value::Bulk(None), bulk("value 3")])
+    );
+    assert_eq!(
+        client.call(&["EXISTS", "key1", "key4", "key1"]),
+        Value::Integer(2)
+    );
+    assert_eq!(
+        client.call(&["DEL", "key1", "key2", "key4"]),
+        Value::Integer(2)
+    );
+    assert_eq!(client.call(&["EXISTS", "key1"]), Value::Integer(0));
+
+    assert_error(client.call(&["GET"]), "ERR wrong number of arguments");
+    assert_error(
+        client.call(&["MSET", "key1"]),
+        "ERR wrong number of arguments",
+    );
+    assert_error(
+        client.call(&["SET", "key1", "value1", "NX"]),
+        "ERR syntax error",
+    );
+    assert_error(client.call(&["FLUSHALL"]), "ERR unknown command");
+    assert_eq!(client.call(&["SELECT", "0"]), status("OK"));
+    assert_error(client.call(&["SELECT", "1"]), "ERR");
+
+    // inline commands, as typed in a terminal
+    client.writer.write_all(b"GET key3\r\nPING\n").unwrap();
+    assert_eq!(client.read(), bulk("value 3"));
+    assert_eq!(client.read(), status("PONG"));
+
+    // pipelined commands are answered in order
+    for i in 0..200 {
+        client.send(&["SET", &format!("key{}", i), &format!("value{}", i)]);
+    }
+    for i in 0..200 {
+        client.send(&["GET", &format!("key{}", i)]);
+    }
+    for _ in 0..200 {
+        assert_eq!(client.read(), status("OK"));
+    }
+    for i in 0..200 {
+        assert_eq!(client.read(), bulk(&format!("value{}", i)));
+    }
+
+    // arguments must be valid UTF-8
+    client
+        .writer
+        .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\n\xff\r\n")
+        .unwrap();
+    assert_error(client.read(), "ERR");
+
+    assert_eq!(client.call(&["QUIT"]), status("OK"));
+    assert!(client.is_closed());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn protocol_errors_close_the_connection() -> Result<()> {
+    let engine = ExpiringEngine::new(MemoryKvsEngine::new());
+    let (addr, shutdown, handle) = start_server("127.0.0.1:4902", engine, None);
+
+    let mut client = RespClient::connect(addr);
+    client.writer.write_all(b"*1\r\n+GET\r\n").unwrap();
+    assert_error(client.read(), "ERR Protocol error");
+    assert!(client.is_closed());
+
+    let mut client = RespClient::connect(addr);
+    client
+        .writer
+        .write_all(b"*1\r\n$3\r\nPINGPONG\r\n")
+        .unwrap();
+    assert_error(client.read(), "ERR Protocol error");
+    assert!(client.is_closed());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn scan() -> Result<()> {
+    let engine = ExpiringEngine::new(MemoryKvsEngine::new());
+    let (addr, shutdown, handle) = start_server("127.0.0.1:4903", engine, None);
+    let mut client = RespClient::connect(addr);
+
+    for i in 0..25 {
+        client.call(&["SET", &format!("key{:02}", i), "value"]);
+    }
+    client.call(&["SET", "other", "value"]);
+
+    // pages through every key, in any page size
+    let scan_all = |client: &mut RespClient, options: &[&str]| {
+        let mut keys = Vec::new();
+        let mut cursor = "0".to_owned();
+        loop {
+            let mut args = vec!["SCAN", &cursor];
+            args.extend_from_slice(options);
+            let reply = client.call(&args);
+            let (next, page) = match reply {
+                Value::Array(mut reply) => (reply.remove(0), reply.remove(0)),
+                reply => panic!("unexpected reply {:?}", reply),
+            };
+            match page {
+                Value::Array(page) => keys.extend(page),
+                page => panic!("unexpected page {:?}", page),
+            }
+            cursor = match next {
+                Value::Bulk(Some(next)) => next,
+                next => panic!("unexpected cursor {:?}", next),
+            };
+            if cursor == "0" {
+                return keys;
+            }
+        }
+    };
+    let mut expected: Vec<_> = (0..25).map(|i| bulk(&format!("key{:02}", i))).collect();
+    expected.push(bulk("other"));
+    assert_eq!(scan_all(&mut client, &[]), expected);
+    assert_eq!(scan_all(&mut client, &["COUNT", "7"]), expected);
+    assert_eq!(
+        scan_all(&mut client, &["MATCH", "key?5", "COUNT", "100"]),
+        vec![bulk("key05"), bulk("key15")]
+    );
+    assert_eq!(
+        scan_all(&mut client, &["MATCH", "*er"]),
+        vec![bulk("other")]
+    );
+
+    assert_error(client.call(&["SCAN", "12345678"]), "ERR invalid cursor");
+    assert_error(
+        client.call(&["SCAN", "0", "COUNT", "0"]),
+        "ERR syntax error",
+    );
+    assert_error(client.call(&["SCAN", "0", "MATCH"]), "ERR syntax error");
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn expire() -> Result<()> {
+    let memory = MemoryKvsEngine::new();
+    let engine = ExpiringEngine::new(memory.clone());
+    let (addr, shutdown, handle) = start_server("127.0.0.1:4904", engine.clone(), None);
+
+    // the kvs protocol serves the same keys
+    let kvs_addr: SocketAddr = "127.0.0.1:4905".parse().unwrap();
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut kvs_server = KvsServer::new(engine, Logger::root(Discard, o!()), pool);
+    let kvs_shutdown = kvs_server.shutdown_handle();
+    let kvs_handle = thread::spawn(move || kvs_server.run(&kvs_addr));
+    wait_for(kvs_addr);
+    let mut kvs_client = KvsClient::connect(&kvs_addr)?;
+
+    let mut client = RespClient::connect(addr);
+    assert_eq!(client.call(&["EXPIRE", "key1", "10"]), Value::Integer(0));
+    client.call(&["MSET", "key1", "value1", "key2", "value2", "key3", "value3"]);
+    assert_eq!(client.call(&["EXPIRE", "key1", "1"]), Value::Integer(1));
+    assert_eq!(
+        client.call(&["SET", "key4", "value4", "PX", "300"]),
+        status("OK")
+    );
+    assert_eq!(
+        client.call(&["SET", "key5", "value5", "EX", "1"]),
+        status("OK")
+    );
+    assert_eq!(client.call(&["EXPIRE", "key2", "1"]), Value::Integer(1));
+    // writes clear times to live, from either protocol
+    client.call(&["SET", "key2", "value2"]);
+    kvs_client.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(client.call(&["GET", "key4"]), bulk("value4"));
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.call(&["GET", "key4"]), Value::Bulk(None));
+    assert_eq!(client.call(&["EXISTS", "key4"]), Value::Integer(0));
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value1"));
+
+    thread::sleep(Duration::from_millis(800));
+    assert_eq!(kvs_client.get("key1".to_owned())?, None);
+    assert_eq!(client.call(&["DEL", "key1"]), Value::Integer(0));
+    assert_eq!(client.call(&["GET", "key2"]), bulk("value2"));
+    assert_eq!(client.call(&["GET", "key5"]), bulk("value5"));
+
+    // a time to live of zero expires the key right away
+    assert_eq!(client.call(&["EXPIRE", "key3", "0"]), Value::Integer(1));
+    assert_eq!(client.call(&["GET", "key3"]), Value::Bulk(None));
+
+    assert_error(
+        client.call(&["EXPIRE", "key2", "soon"]),
+        "ERR invalid expire time",
+    );
+    assert_error(
+        client.call(&["SET", "key2", "value2", "EX", "0"]),
+        "ERR invalid expire time",
+    );
+
+    // expired keys nobody reads are removed from the store too
+    client.call(&["SET", "key6", "value6", "PX", "10"]);
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(memory.get("key6".to_owned())?, None);
+    assert_eq!(memory.scan(None, 10)?, vec!["key2", "key5"]);
+
+    kvs_shutdown.shutdown();
+    kvs_handle.join().unwrap()?;
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+const CONFIG: &str = r#"{
+    "users": [
+        {
+            "name": "team-a",
+            "token": "token-a",
+            "grants": [
+                { "prefix": "a/", "access": "read_write" },
+                { "prefix": "", "access": "read" }
+            ]
+        },
+        {
+            "name": "alice",
+            "password": "secret",
+            "grants": [{ "prefix": "alice/", "access": "read_write" }]
+        }
+    ],
+    "anonymous": [{ "prefix": "public/", "access": "read" }]
+}"#;
+
+#[test]
+fn grants_enforced() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("auth.json");
+    fs::write(&path, CONFIG)?;
+    let auth = AuthConfig::from_file(&path)?;
+    let engine = ExpiringEngine::new(MemoryKvsEngine::new());
+    let (addr, shutdown, handle) = start_server("127.0.0.1:4906", engine, Some(auth));
+
+    let mut anonymous = RespClient::connect(addr);
+    assert_eq!(anonymous.call(&["GET", "public/1"]), Value::Bulk(None));
+    assert_error(anonymous.call(&["SET", "public/1", "value1"]), "NOPERM");
+    assert_error(anonymous.call(&["AUTH", "token-b"]), "WRONGPASS");
+    assert_eq!(anonymous.call(&["PING"]), status("PONG"));
+
+    let mut team_a = RespClient::connect(addr);
+    assert_eq!(team_a.call(&["AUTH", "token-a"]), status("OK"));
+    assert_eq!(
+        team_a.call(&["MSET", "a/1", "value1", "a/2", "value2"]),
+        status("OK")
+    );
+    assert_error(team_a.call(&["EXPIRE", "alice/1", "10"]), "NOPERM");
+    // nothing is written unless every key may be
+    assert_error(
+        team_a.call(&["MSET", "a/3", "value3", "public/1", "value1"]),
+        "NOPERM",
+    );
+    assert_eq!(team_a.call(&["EXISTS", "a/3"]), Value::Integer(0));
+
+    let mut alice = RespClient::connect(addr);
+    assert_eq!(alice.call(&["AUTH", "alice", "secret"]), status("OK"));
+    assert_eq!(alice.call(&["SET", "alice/1", "value1"]), status("OK"));
+    assert_error(alice.call(&["MGET", "alice/1", "a/1"]), "NOPERM");
+    assert_error(alice.call(&["DEL", "a/1"]), "NOPERM");
+    // scans only return the keys the user may read
+    assert_eq!(
+        alice.call(&["SCAN", "0"]),
+        Value::Array(vec![bulk("0"), Value::Array(vec![bulk("alice/1")])])
+    );
+    assert_eq!(
+        team_a.call(&["SCAN", "0"]),
+        Value::Array(vec![
+            bulk("0"),
+            Value::Array(vec![bulk("a/1"), bulk("a/2"), bulk("alice/1")])
+        ])
+    );
+
+    // failed authentications leave the connection anonymous
+    assert_error(alice.call(&["AUTH", "alice", "wrong"]), "WRONGPASS");
+    assert_error(alice.call(&["GET", "alice/1"]), "NOPERM");
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
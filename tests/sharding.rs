@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+
+use kvs::{KvsClient, KvsEngine, KvsError, MemoryKvsEngine, Result, ShardedClient, ShutdownHandle};
+
+mod common;
+
+use common::TestServer;
+
+struct Servers {
+    addrs: Vec<SocketAddr>,
+    engines: Vec<MemoryKvsEngine>,
+    shutdowns: Vec<(ShutdownHandle, JoinHandle<Result<()>>)>,
+}
+
+impl Servers {
+    fn start(addrs: &[&str]) -> Servers {
+        let mut servers = Servers {
+            addrs: Vec::new(),
+            engines: Vec::new(),
+            shutdowns: Vec::new(),
+        };
+        for addr in addrs {
+            let engine = MemoryKvsEngine::new();
+            let (addr, shutdown, handle) = TestServer::with_engine(engine.clone())
+                .threads(8)
+                .start(addr);
+            servers.addrs.push(addr);
+            servers.engines.push(engine);
+            servers.shutdowns.push((shutdown, handle));
+        }
+        servers
+    }
+
+    fn stop(self) -> Result<()> {
+        for (shutdown, handle) in self.shutdowns {
+            shutdown.shutdown();
+            handle.join().unwrap()?;
+        }
+        Ok(())
+    }
+}
+
+fn keys(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("key{}", i)).collect()
+}
+
+#[test]
+fn keys_spread_over_servers() -> Result<()> {
+    let servers = Servers::start(&["127.0.0.1:5401", "127.0.0.1:5402", "127.0.0.1:5403"]);
+    let mut client = ShardedClient::connect(&servers.addrs)?;
+    for key in keys(300) {
+        client.set(key.clone(), format!("value of {}", key))?;
+    }
+
+    // each key is on the server it is routed to only
+    for key in keys(300) {
+        let owner = client.server_for(&key);
+        for (addr, engine) in servers.addrs.iter().zip(&servers.engines) {
+            let value = engine.get(key.clone())?;
+            if *addr == owner {
+                assert_eq!(value, Some(format!("value of {}", key)));
+            } else {
+                assert_eq!(value, None);
+            }
+        }
+        assert_eq!(client.get(key.clone())?, Some(format!("value of {}", key)));
+    }
+    // virtual nodes even the load out
+    for engine in &servers.engines {
+        let stored = engine.scan(None, 300)?.len();
+        assert!(stored > 50, "unbalanced servers: {} keys", stored);
+    }
+
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("expected KeyNotFound, got {:?}", res),
+    }
+    drop(client);
+    servers.stop()
+}
+
+#[test]
+fn multi_key_fan_out() -> Result<()> {
+    let servers = Servers::start(&["127.0.0.1:5404", "127.0.0.1:5405", "127.0.0.1:5406"]);
+    let mut client = ShardedClient::connect(&servers.addrs)?;
+    let pairs: Vec<_> = keys(50)
+        .into_iter()
+        .map(|key| {
+            let value = format!("value of {}", key);
+            (key, value)
+        })
+        .collect();
+    client.set_many(pairs.clone())?;
+    let stored: usize = servers
+        .engines
+        .iter()
+        .map(|engine| engine.scan(None, 100).unwrap().len())
+        .sum();
+    assert_eq!(stored, 50);
+
+    // values come back in the order of the keys, missing ones included
+    let mut wanted = keys(60);
+    wanted.reverse();
+    let values = client.get_many(wanted.clone())?;
+    for (key, value) in wanted.iter().zip(values) {
+        let expected = pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        assert_eq!(value, expected);
+    }
+    assert_eq!(client.get_many(Vec::new())?, Vec::<Option<String>>::new());
+    drop(client);
+    servers.stop()
+}
+
+// Adding a server only moves keys to it, and about its share of them.
+#[test]
+fn consistent_routing() -> Result<()> {
+    let servers = Servers::start(&[
+        "127.0.0.1:5407",
+        "127.0.0.1:5408",
+        "127.0.0.1:5409",
+        "127.0.0.1:5410",
+    ]);
+    let three = ShardedClient::connect(&servers.addrs[..3])?;
+    let mut reversed = servers.addrs[..3].to_vec();
+    reversed.reverse();
+    let same = ShardedClient::connect(&reversed)?;
+    let four = ShardedClient::connect(&servers.addrs)?;
+
+    let mut moved = 0;
+    for key in keys(1000) {
+        assert_eq!(three.server_for(&key), same.server_for(&key));
+        if three.server_for(&key) != four.server_for(&key) {
+            assert_eq!(four.server_for(&key), servers.addrs[3]);
+            moved += 1;
+        }
+    }
+    assert!(moved > 150 && moved < 350, "{} keys moved", moved);
+
+    // a single server gets every key
+    let single = ShardedClient::connect(&servers.addrs[..1])?;
+    let owners: HashSet<_> = keys(100).iter().map(|key| single.server_for(key)).collect();
+    assert_eq!(owners.len(), 1);
+
+    drop((three, same, four, single));
+    servers.stop()
+}
+
+// Sharded clients store keys as plain clients do.
+#[test]
+fn interoperate_with_kvs_client() -> Result<()> {
+    let servers = Servers::start(&["127.0.0.1:5411", "127.0.0.1:5412"]);
+    let mut client = ShardedClient::connect(&servers.addrs)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let mut plain = KvsClient::connect(&client.server_for("key"))?;
+    assert_eq!(plain.get("key".to_owned())?, Some("value".to_owned()));
+    drop((client, plain));
+    servers.stop()
+}
+
+#[test]
+fn no_servers() {
+    match ShardedClient::connect(&[]) {
+        Err(KvsError::NoServers) => {}
+        res => panic!("expected KvsError::NoServers, got {:?}", res.err()),
+    }
+}
@@ -0,0 +1,131 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::protocol::{encode_request, write_frame, Codec, Hello, PROTOCOL_VERSION};
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsServer, MemoryKvsEngine, Request, Result,
+    SharedQueueThreadPool, TaggedRequest, ThreadPool,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+// The server stops with an idle connection open and refuses new ones.
+fn shutdown_with_idle_connection(addr: &str, evented: bool) -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new()
+        .threads(2)
+        .evented(evented)
+        .shutdown_timeout(Duration::from_secs(5))
+        .start(addr);
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let start = Instant::now();
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(TcpStream::connect(addr).is_err());
+    Ok(())
+}
+
+#[test]
+fn threaded_shutdown() -> Result<()> {
+    shutdown_with_idle_connection("127.0.0.1:4401", false)
+}
+
+#[test]
+fn evented_shutdown() -> Result<()> {
+    shutdown_with_idle_connection("127.0.0.1:4402", true)
+}
+
+#[test]
+fn shutdown_before_run() -> Result<()> {
+    let mut server = KvsServer::new(
+        MemoryKvsEngine::new(),
+        Logger::root(Discard, o!()),
+        SharedQueueThreadPool::new(1)?,
+    );
+    server.shutdown_handle().shutdown();
+    server.run(&"127.0.0.1:4403".parse().unwrap())?;
+    server.run_evented(&"127.0.0.1:4403".parse().unwrap())
+}
+
+// Writes acknowledged before the shutdown are found after reopening.
+#[test]
+fn shutdown_keeps_writes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let (addr, shutdown, handle) = TestServer::with_engine(store)
+        .threads(2)
+        .shutdown_timeout(Duration::from_secs(5))
+        .start("127.0.0.1:4404");
+
+    let mut client = KvsClient::connect(&addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A client which does not read its responses is disconnected once the
+// shutdown timeout expires.
+fn shutdown_times_out(addr: &str, evented: bool) -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    engine.set("big".to_owned(), "x".repeat(1024 * 1024))?;
+    let (addr, shutdown, handle) = TestServer::with_engine(engine)
+        .threads(2)
+        .evented(evented)
+        .shutdown_timeout(Duration::from_millis(500))
+        .start(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        codec: Codec::Bincode,
+    };
+    hello.write_to(&mut stream)?;
+    for id in 1..=64 {
+        let req = TaggedRequest::new(
+            id,
+            Request::Get {
+                key: "big".to_owned(),
+            },
+        );
+        write_frame(&mut stream, &encode_request(&hello, &req)?)?;
+    }
+    stream.flush()?;
+    // let the server fill the socket buffers
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(500));
+    assert!(elapsed < Duration::from_secs(5));
+    Ok(())
+}
+
+#[test]
+fn threaded_shutdown_times_out() -> Result<()> {
+    shutdown_times_out("127.0.0.1:4405", false)
+}
+
+#[test]
+fn evented_shutdown_times_out() -> Result<()> {
+    shutdown_times_out("127.0.0.1:4406", true)
+}
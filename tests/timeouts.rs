@@ -0,0 +1,162 @@
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::protocol::{Codec, Hello, PROTOCOL_VERSION};
+use kvs::{ClientConfig, KvsClient, KvsEngine, KvsError, MemoryKvsEngine, Request, Result};
+
+mod common;
+
+use common::TestServer;
+
+// An engine whose writes take a while.
+#[derive(Clone)]
+struct SlowEngine(MemoryKvsEngine);
+
+impl KvsEngine for SlowEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        thread::sleep(Duration::from_millis(300));
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+#[test]
+fn client_read_timeout() -> Result<()> {
+    // a server which completes the handshake and never answers
+    let listener = TcpListener::bind("127.0.0.1:4601")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let hello = Hello::read_from(&mut stream).unwrap().unwrap();
+        hello.write_to(&mut stream).unwrap();
+        thread::sleep(Duration::from_secs(5));
+    });
+
+    let config = ClientConfig {
+        read_timeout: Some(Duration::from_millis(200)),
+        ..ClientConfig::default()
+    };
+    let mut client = KvsClient::connect_with_config(&addr, &config)?;
+    assert!(client.is_healthy());
+
+    let start = Instant::now();
+    match client.get("key1".to_owned()) {
+        Err(KvsError::Io(_)) => {}
+        res => panic!("expected a timeout, got {:?}", res),
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(!client.is_healthy());
+    Ok(())
+}
+
+#[test]
+fn client_connect_timeout() {
+    // not routable: the connection attempt is never answered
+    let addr = "10.255.255.1:4602".parse().unwrap();
+    let config = ClientConfig {
+        connect_timeout: Some(Duration::from_millis(200)),
+        ..ClientConfig::default()
+    };
+    let start = Instant::now();
+    assert!(KvsClient::connect_with_config(&addr, &config).is_err());
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+// The server closes connections which stay idle past the timeout.
+fn idle_connection_closed(addr: &str, evented: bool) -> Result<()> {
+    let addr = TestServer::with_engine(SlowEngine(MemoryKvsEngine::new()))
+        .threads(2)
+        .evented(evented)
+        .idle_timeout(Some(Duration::from_millis(200)))
+        .start(addr)
+        .0;
+
+    // an active client is not disconnected
+    let mut client = KvsClient::connect(&addr)?;
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(100));
+        client.get("key1".to_owned())?;
+    }
+
+    let mut stream = TcpStream::connect(addr)?;
+    Hello {
+        version: PROTOCOL_VERSION,
+        codec: Codec::Bincode,
+    }
+    .write_to(&mut stream)?;
+    assert!(Hello::read_from(&mut stream)?.is_some());
+
+    let start = Instant::now();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    assert!(start.elapsed() < Duration::from_secs(2));
+    Ok(())
+}
+
+#[test]
+fn threaded_idle_timeout() -> Result<()> {
+    idle_connection_closed("127.0.0.1:4603", false)
+}
+
+#[test]
+fn evented_idle_timeout() -> Result<()> {
+    idle_connection_closed("127.0.0.1:4604", true)
+}
+
+// A request stuck behind a slow one is dropped once its deadline passed.
+fn expired_request_dropped(addr: &str, evented: bool) -> Result<()> {
+    let addr = TestServer::with_engine(SlowEngine(MemoryKvsEngine::new()))
+        .threads(2)
+        .evented(evented)
+        .start(addr)
+        .0;
+    let mut client = KvsClient::connect(&addr)?;
+
+    let set_id = client.send(Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    client.set_request_timeout(Some(Duration::from_millis(100)));
+    let get_id = client.send(Request::Get {
+        key: "key1".to_owned(),
+    })?;
+    client.flush()?;
+
+    for _ in 0..2 {
+        let resp = client.recv()?;
+        if resp.id == set_id {
+            assert_eq!(resp.response.into_result()?, None);
+        } else {
+            assert_eq!(resp.id, get_id);
+            match resp.response.into_result() {
+                Err(KvsError::DeadlineExceeded) => {}
+                res => panic!("expected an expired deadline, got {:?}", res),
+            }
+        }
+    }
+
+    // requests within their deadline are executed
+    client.set_request_timeout(Some(Duration::from_secs(5)));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn threaded_deadline() -> Result<()> {
+    expired_request_dropped("127.0.0.1:4605", false)
+}
+
+#[test]
+fn evented_deadline() -> Result<()> {
+    expired_request_dropped("127.0.0.1:4606", true)
+}
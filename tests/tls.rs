@@ -0,0 +1,238 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::executor::block_on;
+use futures::future::join_all;
+use kvs::tls::{ClientTlsConfig, ServerTlsConfig};
+use kvs::{AsyncKvsClient, ClientConfig, KvsClient, KvsClientPool, PoolConfig, Request, Result};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+// A certificate authority issuing certificates into a temporary directory.
+struct Ca {
+    dir: TempDir,
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new() -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "kvs test CA");
+        let cert = params.self_signed(&key).unwrap();
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("ca.crt"), cert.pem()).unwrap();
+        Ca { dir, cert, key }
+    }
+
+    fn cert_path(&self) -> PathBuf {
+        self.dir.path().join("ca.crt")
+    }
+
+    // Issues a certificate, returning the paths of the certificate and key
+    // PEM files.
+    fn issue(
+        &self,
+        name: &str,
+        sans: &[&str],
+        usage: ExtendedKeyUsagePurpose,
+    ) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let sans: Vec<String> = sans.iter().map(|&san| san.to_owned()).collect();
+        let mut params = CertificateParams::new(sans).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let cert_path = self.dir.path().join(format!("{}.crt", name));
+        let key_path = self.dir.path().join(format!("{}.key", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn server_tls(&self, client_ca: Option<&Path>) -> ServerTlsConfig {
+        let (cert, key) = self.issue(
+            "server",
+            &["localhost", "127.0.0.1"],
+            ExtendedKeyUsagePurpose::ServerAuth,
+        );
+        ServerTlsConfig::from_pem_files(&cert, &key, client_ca).unwrap()
+    }
+
+    fn client_identity(&self) -> (PathBuf, PathBuf) {
+        self.issue("client", &[], ExtendedKeyUsagePurpose::ClientAuth)
+    }
+}
+
+fn client_config(tls: ClientTlsConfig) -> ClientConfig {
+    ClientConfig {
+        read_timeout: Some(Duration::from_secs(5)),
+        tls: Some(tls),
+        ..ClientConfig::default()
+    }
+}
+
+fn round_trip(addr: &str, evented: bool) -> Result<()> {
+    let ca = Ca::new();
+    let (addr, shutdown, handle) = TestServer::new()
+        .evented(evented)
+        .tls(ca.server_tls(None))
+        .start(addr);
+
+    let tls = ClientTlsConfig::from_pem_files(&ca.cert_path(), None)?;
+    let mut client = KvsClient::connect_with_config(&addr, &client_config(tls.clone()))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let requests = (0..500)
+        .map(|i| Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .collect();
+    assert!(client.pipeline(requests)?.iter().all(|res| res.is_ok()));
+    assert_eq!(
+        client.get("key499".to_owned())?,
+        Some("value499".to_owned())
+    );
+    assert!(client.is_healthy());
+
+    // the certificate is checked against the expected name
+    let named = tls.clone().with_server_name("localhost");
+    let mut client = KvsClient::connect_with_config(&addr, &client_config(named))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let misnamed = tls.clone().with_server_name("other.example.com");
+    assert!(KvsClient::connect_with_config(&addr, &client_config(misnamed)).is_err());
+
+    // pooled connections stay healthy between requests
+    let config = PoolConfig {
+        client: client_config(tls),
+        ..PoolConfig::default()
+    };
+    let pool = KvsClientPool::new(addr, config);
+    for _ in 0..3 {
+        assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(pool.idle_connections(), 1);
+
+    // plain connections are refused
+    let plain = ClientConfig {
+        read_timeout: Some(Duration::from_secs(5)),
+        ..ClientConfig::default()
+    };
+    assert!(KvsClient::connect_with_config(&addr, &plain).is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn threaded_round_trip() -> Result<()> {
+    round_trip("127.0.0.1:4701", false)
+}
+
+#[test]
+fn evented_round_trip() -> Result<()> {
+    round_trip("127.0.0.1:4702", true)
+}
+
+#[test]
+fn unknown_ca_rejected() -> Result<()> {
+    let ca = Ca::new();
+    let (addr, shutdown, handle) = TestServer::new()
+        .tls(ca.server_tls(None))
+        .start("127.0.0.1:4703");
+
+    let other = Ca::new();
+    let tls = ClientTlsConfig::from_pem_files(&other.cert_path(), None)?;
+    assert!(KvsClient::connect_with_config(&addr, &client_config(tls)).is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn missing_files_rejected() {
+    let dir = TempDir::new().unwrap();
+    let missing = dir.path().join("missing.pem");
+    assert!(ServerTlsConfig::from_pem_files(&missing, &missing, None).is_err());
+    assert!(ClientTlsConfig::from_pem_files(&missing, None).is_err());
+}
+
+// Only clients with a certificate of the client CA are served.
+fn mutual_tls(addr: &str, evented: bool) -> Result<()> {
+    let ca = Ca::new();
+    let client_ca = Ca::new();
+    let server_tls = ca.server_tls(Some(&client_ca.cert_path()));
+    let (addr, shutdown, handle) = TestServer::new()
+        .evented(evented)
+        .tls(server_tls)
+        .start(addr);
+
+    let (cert, key) = client_ca.client_identity();
+    let tls = ClientTlsConfig::from_pem_files(&ca.cert_path(), Some((&cert, &key)))?;
+    let mut client = KvsClient::connect_with_config(&addr, &client_config(tls))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let anonymous = ClientTlsConfig::from_pem_files(&ca.cert_path(), None)?;
+    assert!(KvsClient::connect_with_config(&addr, &client_config(anonymous)).is_err());
+
+    // a certificate of another CA is refused
+    let (cert, key) = ca.client_identity();
+    let untrusted = ClientTlsConfig::from_pem_files(&ca.cert_path(), Some((&cert, &key)))?;
+    assert!(KvsClient::connect_with_config(&addr, &client_config(untrusted)).is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn threaded_mutual_tls() -> Result<()> {
+    mutual_tls("127.0.0.1:4704", false)
+}
+
+#[test]
+fn evented_mutual_tls() -> Result<()> {
+    mutual_tls("127.0.0.1:4705", true)
+}
+
+// Responses arrive while requests are still being written.
+#[test]
+fn async_round_trip() -> Result<()> {
+    let ca = Ca::new();
+    let (addr, shutdown, handle) = TestServer::new()
+        .tls(ca.server_tls(None))
+        .start("127.0.0.1:4706");
+
+    let tls = ClientTlsConfig::from_pem_files(&ca.cert_path(), None)?;
+    block_on(async {
+        let client = AsyncKvsClient::connect_with_config(addr, &client_config(tls)).await?;
+        let sets = (0..500).map(|i| client.set(format!("key{}", i), format!("value{}", i)));
+        for res in join_all(sets).await {
+            res?;
+        }
+        let gets = (0..500).map(|i| client.get(format!("key{}", i)));
+        for (i, res) in join_all(gets).await.into_iter().enumerate() {
+            assert_eq!(res?, Some(format!("value{}", i)));
+        }
+        Ok::<_, kvs::KvsError>(())
+    })?;
+
+    // plain connections are refused
+    assert!(block_on(AsyncKvsClient::connect(addr)).is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
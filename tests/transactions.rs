@@ -0,0 +1,245 @@
+use std::fs;
+use std::thread::{self, JoinHandle};
+
+use kvs::auth::AuthConfig;
+use kvs::{
+    ErrorCode, KvsClient, KvsEngine, KvsError, MemoryKvsEngine, ReadOnlyEngine, Result,
+    ShutdownHandle,
+};
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+fn stop(shutdown: ShutdownHandle, handle: JoinHandle<Result<()>>) {
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+fn assert_conflict(res: Result<()>, expected: &str) {
+    match res {
+        Err(KvsError::Conflict(key)) => assert_eq!(key, expected),
+        Err(e) => panic!("expected KvsError::Conflict, got error: {}", e),
+        Ok(()) => panic!("expected KvsError::Conflict, got Ok"),
+    }
+}
+
+fn commit_writes(addr: &str, evented: bool) -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    let (addr, shutdown, handle) = TestServer::with_engine(engine.clone())
+        .evented(evented)
+        .start(addr);
+    let mut client = KvsClient::connect(&addr)?;
+    let mut other = KvsClient::connect(&addr)?;
+    client.set("gone".to_owned(), "old".to_owned())?;
+
+    client.begin()?;
+    client.set("a".to_owned(), "1".to_owned())?;
+    client.set_many(vec![
+        ("b".to_owned(), "2".to_owned()),
+        ("a".to_owned(), "3".to_owned()),
+    ])?;
+    client.remove("gone".to_owned())?;
+    // reads see the writes of the transaction, others do not
+    assert_eq!(client.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(
+        client.get_many(vec!["b".to_owned(), "gone".to_owned(), "c".to_owned()])?,
+        vec![Some("2".to_owned()), None, None]
+    );
+    match client.remove("c".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("expected KvsError::KeyNotFound, got {:?}", res.err()),
+    }
+    assert_eq!(other.get("a".to_owned())?, None);
+    assert_eq!(other.get("gone".to_owned())?, Some("old".to_owned()));
+    client.commit()?;
+
+    assert_eq!(
+        other.get_many(vec!["a".to_owned(), "b".to_owned(), "gone".to_owned()])?,
+        vec![Some("3".to_owned()), Some("2".to_owned()), None]
+    );
+    // requests after the commit are applied right away
+    client.set("d".to_owned(), "4".to_owned())?;
+    assert_eq!(engine.get("d".to_owned())?, Some("4".to_owned()));
+
+    stop(shutdown, handle);
+    Ok(())
+}
+
+#[test]
+fn commit_writes_threaded() -> Result<()> {
+    commit_writes("127.0.0.1:5501", false)
+}
+
+#[test]
+fn commit_writes_evented() -> Result<()> {
+    commit_writes("127.0.0.1:5502", true)
+}
+
+fn conflicts(addr: &str, evented: bool) -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new().evented(evented).start(addr);
+    let mut client = KvsClient::connect(&addr)?;
+    let mut other = KvsClient::connect(&addr)?;
+    client.set("count".to_owned(), "1".to_owned())?;
+
+    // a key read changes
+    client.begin()?;
+    assert_eq!(client.get("count".to_owned())?, Some("1".to_owned()));
+    client.set("count".to_owned(), "2".to_owned())?;
+    client.set("side".to_owned(), "effect".to_owned())?;
+    other.set("count".to_owned(), "10".to_owned())?;
+    assert_conflict(client.commit(), "count");
+    assert_eq!(other.get("count".to_owned())?, Some("10".to_owned()));
+    assert_eq!(other.get("side".to_owned())?, None);
+
+    // a key read changes, then changes back
+    client.begin()?;
+    assert_eq!(client.get("count".to_owned())?, Some("10".to_owned()));
+    client.set("count".to_owned(), "11".to_owned())?;
+    other.set("count".to_owned(), "0".to_owned())?;
+    other.set("count".to_owned(), "10".to_owned())?;
+    assert_conflict(client.commit(), "count");
+    assert_eq!(other.get("count".to_owned())?, Some("10".to_owned()));
+
+    // a key read as missing is created
+    client.begin()?;
+    assert_eq!(client.get("new".to_owned())?, None);
+    other.set("new".to_owned(), "first".to_owned())?;
+    // later reads keep seeing the value first read
+    assert_eq!(client.get("new".to_owned())?, None);
+    client.set("new".to_owned(), "second".to_owned())?;
+    assert_conflict(client.commit(), "new");
+    assert_eq!(other.get("new".to_owned())?, Some("first".to_owned()));
+
+    // writes to keys the transaction did not read do not conflict
+    client.begin()?;
+    client.set("count".to_owned(), "20".to_owned())?;
+    other.set("count".to_owned(), "11".to_owned())?;
+    client.commit()?;
+    assert_eq!(other.get("count".to_owned())?, Some("20".to_owned()));
+
+    stop(shutdown, handle);
+    Ok(())
+}
+
+#[test]
+fn conflicts_threaded() -> Result<()> {
+    conflicts("127.0.0.1:5503", false)
+}
+
+#[test]
+fn conflicts_evented() -> Result<()> {
+    conflicts("127.0.0.1:5504", true)
+}
+
+#[test]
+fn concurrent_increments() -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new().start("127.0.0.1:5505");
+    let workers: Vec<_> = (0..3)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(&addr)?;
+                for _ in 0..20 {
+                    loop {
+                        client.begin()?;
+                        let count: u64 = client
+                            .get("count".to_owned())?
+                            .map_or(0, |value| value.parse().unwrap());
+                        client.set("count".to_owned(), (count + 1).to_string())?;
+                        match client.commit() {
+                            Err(KvsError::Conflict(_)) => continue,
+                            res => break res?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(&addr)?;
+    assert_eq!(client.get("count".to_owned())?, Some("60".to_owned()));
+    stop(shutdown, handle);
+    Ok(())
+}
+
+#[test]
+fn abort_and_misuse() -> Result<()> {
+    let (addr, shutdown, handle) = TestServer::new().start("127.0.0.1:5506");
+    let mut client = KvsClient::connect(&addr)?;
+
+    client.begin()?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert!(!client.is_healthy());
+    client.abort()?;
+    assert!(client.is_healthy());
+    assert_eq!(client.get("key".to_owned())?, None);
+
+    let malformed = |res: Result<()>| match res {
+        Err(e) => assert_eq!(e.code(), ErrorCode::MalformedRequest),
+        Ok(()) => panic!("expected an error"),
+    };
+    malformed(client.commit());
+    malformed(client.abort());
+    client.begin()?;
+    malformed(client.begin());
+    client.commit()?;
+
+    stop(shutdown, handle);
+    Ok(())
+}
+
+#[test]
+fn read_only_transactions() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    engine.set("key".to_owned(), "value".to_owned())?;
+    let (addr, shutdown, handle) =
+        TestServer::with_engine(ReadOnlyEngine::new(engine)).start("127.0.0.1:5507");
+    let mut client = KvsClient::connect(&addr)?;
+
+    client.begin()?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.commit()?;
+
+    client.begin()?;
+    client.set("key".to_owned(), "other".to_owned())?;
+    match client.commit() {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("expected KvsError::ReadOnly, got {:?}", res.err()),
+    }
+
+    stop(shutdown, handle);
+    Ok(())
+}
+
+#[test]
+fn transactions_are_authorized() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("auth.json");
+    fs::write(
+        &path,
+        r#"{ "users": [], "anonymous": [{ "prefix": "public/", "access": "read_write" }] }"#,
+    )?;
+    let auth = AuthConfig::from_file(&path)?;
+    let (addr, shutdown, handle) = TestServer::new().auth(Some(auth)).start("127.0.0.1:5508");
+    let mut client = KvsClient::connect(&addr)?;
+
+    client.begin()?;
+    client.set("public/key".to_owned(), "value".to_owned())?;
+    match client.set("private/key".to_owned(), "value".to_owned()) {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("expected KvsError::PermissionDenied, got {:?}", res.err()),
+    }
+    client.commit()?;
+    assert_eq!(
+        client.get("public/key".to_owned())?,
+        Some("value".to_owned())
+    );
+
+    stop(shutdown, handle);
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::thread;
+use std::time::Duration;
+
+use kvs::{
+    ErrorCode, ExpiringEngine, KvStore, KvsClient, KvsEngine, KvsError, MemoryKvsEngine,
+    MemoryStorage, Result,
+};
+
+mod common;
+
+use common::TestServer;
+
+fn conditional_writes(addr: &str, evented: bool) -> Result<()> {
+    let store = KvStore::with_storage(MemoryStorage::new())?;
+    let (addr, shutdown, handle) = TestServer::with_engine(store).evented(evented).start(addr);
+    let mut client = KvsClient::connect(&addr)?;
+    let mut other = KvsClient::connect(&addr)?;
+
+    assert_eq!(client.get_with_version("key".to_owned())?, None);
+    let version = client.set_if_version("key".to_owned(), "1".to_owned(), None)?;
+    assert_eq!(
+        client.get_with_version("key".to_owned())?,
+        Some(("1".to_owned(), version))
+    );
+
+    // another client writes in between
+    other.set("key".to_owned(), "2".to_owned())?;
+    match client.set_if_version("key".to_owned(), "3".to_owned(), Some(version)) {
+        Err(KvsError::Conflict(key)) => assert_eq!(key, "key"),
+        res => panic!("expected KvsError::Conflict, got {:?}", res),
+    }
+    let (value, latest) = client.get_with_version("key".to_owned())?.unwrap();
+    assert_eq!(value, "2");
+    assert!(latest > version);
+    let newer = client.set_if_version("key".to_owned(), "3".to_owned(), Some(latest))?;
+    assert!(newer > latest);
+    assert_eq!(other.get("key".to_owned())?, Some("3".to_owned()));
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn conditional_writes_threaded() -> Result<()> {
+    conditional_writes("127.0.0.1:5601", false)
+}
+
+#[test]
+fn conditional_writes_evented() -> Result<()> {
+    conditional_writes("127.0.0.1:5602", true)
+}
+
+#[test]
+fn versions_through_wrappers() -> Result<()> {
+    let store = KvStore::with_storage(MemoryStorage::new())?;
+    let engine = ExpiringEngine::new(store);
+    engine.set_with_ttl("key".to_owned(), "old".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    // an expired key is at no version
+    assert_eq!(engine.get_with_version("key".to_owned())?, None);
+    let version = engine.set_if_version("key".to_owned(), "new".to_owned(), None)?;
+    assert_eq!(
+        engine.get_with_version("key".to_owned())?,
+        Some(("new".to_owned(), version))
+    );
+    Ok(())
+}
+
+// An engine with none of the optional operations.
+#[derive(Clone, Default)]
+struct Unversioned(MemoryKvsEngine);
+
+impl KvsEngine for Unversioned {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+#[test]
+fn engines_without_versions() -> Result<()> {
+    let (addr, shutdown, handle) =
+        TestServer::with_engine(Unversioned::default()).start("127.0.0.1:5603");
+    let mut client = KvsClient::connect(&addr)?;
+
+    match client.get_with_version("key".to_owned()) {
+        Err(e) => assert_eq!(e.code(), ErrorCode::Internal),
+        Ok(res) => panic!("expected an error, got {:?}", res),
+    }
+    // the connection stays usable
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use kvs::auth::AuthConfig;
+use kvs::{
+    BoxedKvsEngine, ChangeEvent, ClientConfig, Credentials, ErrorCode, KvsClient, KvsEngine,
+    KvsError, MemoryKvsEngine, Result, WatchedEngine,
+};
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+fn change(seq: u64, key: &str, value: Option<&str>) -> ChangeEvent {
+    ChangeEvent {
+        seq,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+fn watch_changes(addr: &str, http_addr: &str, evented: bool) -> Result<()> {
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    let (addr, shutdown, handle) = TestServer::with_engine(engine)
+        .evented(evented)
+        .http_addr(http_addr)
+        .start(addr);
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("a/0".to_owned(), "before".to_owned())?;
+
+    let mut watch = KvsClient::connect(&addr)?.watch("a/".to_owned())?;
+    client.set("a/1".to_owned(), "value1".to_owned())?;
+    client.set("b/1".to_owned(), "value1".to_owned())?;
+    client.remove("a/1".to_owned())?;
+    client.set_many(vec![
+        ("a/2".to_owned(), "value2".to_owned()),
+        ("b/2".to_owned(), "value2".to_owned()),
+    ])?;
+    // writes through the HTTP gateway are changes too
+    let body = r#"{"value":"value3"}"#;
+    let mut stream = TcpStream::connect(http_addr)?;
+    write!(
+        stream,
+        "PUT /keys/a/3 HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+
+    let events = (&mut watch).take(4).collect::<Result<Vec<_>>>()?;
+    // the write made before anyone watched is not numbered
+    assert_eq!(
+        events,
+        vec![
+            change(1, "a/1", Some("value1")),
+            change(3, "a/1", None),
+            change(4, "a/2", Some("value2")),
+            change(6, "a/3", Some("value3")),
+        ]
+    );
+
+    // watches end with the server
+    shutdown.shutdown();
+    assert!(watch.next().is_none());
+    handle.join().unwrap()
+}
+
+#[test]
+fn threaded_watch() -> Result<()> {
+    watch_changes("127.0.0.1:5101", "127.0.0.1:5102", false)
+}
+
+#[test]
+fn evented_watch() -> Result<()> {
+    watch_changes("127.0.0.1:5103", "127.0.0.1:5104", true)
+}
+
+// Engines which do not publish their changes refuse watches.
+#[test]
+fn unwatched_engine() -> Result<()> {
+    for (addr, evented) in [("127.0.0.1:5105", false), ("127.0.0.1:5106", true)] {
+        let engine = BoxedKvsEngine::new(MemoryKvsEngine::new());
+        let (addr, shutdown, handle) = TestServer::with_engine(engine).evented(evented).start(addr);
+        match KvsClient::connect(&addr)?.watch(String::new()) {
+            Err(e @ KvsError::Server { .. }) => {
+                assert_eq!(e.code(), ErrorCode::Internal);
+                assert!(e.to_string().contains("watch"));
+            }
+            res => panic!("expected the watch to fail, got {:?}", res.map(|_| ())),
+        }
+        shutdown.shutdown();
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+const CONFIG: &str = r#"{
+    "users": [
+        {
+            "name": "alice",
+            "password": "secret",
+            "grants": [{ "prefix": "alice/", "access": "read_write" }]
+        }
+    ],
+    "anonymous": [{ "prefix": "", "access": "read_write" }]
+}"#;
+
+#[test]
+fn watch_only_readable_keys() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("auth.json");
+    fs::write(&path, CONFIG)?;
+    let auth = AuthConfig::from_file(&path)?;
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    let (addr, shutdown, handle) = TestServer::with_engine(engine)
+        .auth(Some(auth))
+        .start("127.0.0.1:5107");
+
+    let config = ClientConfig {
+        credentials: Some(Credentials::Password {
+            username: "alice".to_owned(),
+            password: "secret".to_owned(),
+        }),
+        ..ClientConfig::default()
+    };
+    let mut watch = KvsClient::connect_with_config(&addr, &config)?.watch(String::new())?;
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("bob/1".to_owned(), "value1".to_owned())?;
+    client.set("alice/1".to_owned(), "value1".to_owned())?;
+    assert_eq!(watch.next().unwrap()?, change(2, "alice/1", Some("value1")));
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
+
+#[test]
+fn watched_engine_sinks() -> Result<()> {
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    let (sender, events) = mpsc::channel();
+    let all = sender.clone();
+    engine.watch(
+        String::new(),
+        Box::new(move |event| all.send(event).is_ok()),
+    )?;
+    // a sink returning false gets no more changes
+    engine.watch(
+        "key".to_owned(),
+        Box::new(move |event| {
+            sender.send(event).unwrap();
+            false
+        }),
+    )?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(engine.remove("key3".to_owned()).is_err());
+    engine.remove("key1".to_owned())?;
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            change(1, "key1", Some("value1")),
+            change(1, "key1", Some("value1")),
+            change(2, "key2", Some("value2")),
+            change(3, "key1", None),
+        ]
+    );
+    Ok(())
+}
+
+// Changes are only published while someone watches them.
+#[test]
+fn unwatched_writes_not_published() -> Result<()> {
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let (sender, events) = mpsc::channel();
+    let first = sender.clone();
+    engine.watch(
+        String::new(),
+        Box::new(move |event| {
+            first.send(event).unwrap();
+            false
+        }),
+    )?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    // the sink is gone
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    engine.watch(
+        String::new(),
+        Box::new(move |event| sender.send(event).is_ok()),
+    )?;
+    engine.remove("key1".to_owned())?;
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![change(1, "key2", Some("value2")), change(2, "key1", None)]
+    );
+    Ok(())
+}
+
+// An engine failing to set one key, setting pairs one at a time.
+#[derive(Clone)]
+struct FailingEngine(MemoryKvsEngine);
+
+impl KvsEngine for FailingEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        if key == "bad" {
+            return Err(KvsError::ServerError("bad key".to_owned()));
+        }
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+// The pairs set before a failure are published.
+#[test]
+fn partial_set_many_published() -> Result<()> {
+    let engine = WatchedEngine::new(FailingEngine(MemoryKvsEngine::new()));
+    let (sender, events) = mpsc::channel();
+    engine.watch(
+        String::new(),
+        Box::new(move |event| sender.send(event).is_ok()),
+    )?;
+    let pairs = vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("bad".to_owned(), "value".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ];
+    assert!(engine.set_many(pairs).is_err());
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            change(1, "key1", Some("value1")),
+            change(2, "key2", Some("value2")),
+        ]
+    );
+    Ok(())
+}
+
+// A sink busy with a change holds up neither the other writers nor the
+// changes that follow, which it gets in order.
+#[test]
+fn slow_sinks_do_not_block_writes() -> Result<()> {
+    let engine = WatchedEngine::new(MemoryKvsEngine::new());
+    let (sender, events) = mpsc::channel();
+    let (holding, held) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let sink = Mutex::new((holding, released));
+    engine.watch(
+        String::new(),
+        Box::new(move |event| {
+            if event.seq == 1 {
+                let sink = sink.lock().unwrap();
+                sink.0.send(()).unwrap();
+                sink.1.recv().unwrap();
+            }
+            sender.send(event).is_ok()
+        }),
+    )?;
+
+    let writer = engine.clone();
+    let first = thread::spawn(move || writer.set("key1".to_owned(), "value1".to_owned()));
+    held.recv().unwrap();
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert!(events.try_recv().is_err());
+
+    release.send(()).unwrap();
+    first.join().unwrap()?;
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            change(1, "key1", Some("value1")),
+            change(2, "key2", Some("value2")),
+            change(3, "key1", None),
+        ]
+    );
+    Ok(())
+}